@@ -1,24 +1,84 @@
+use crate::backup_scan::ScannedFile;
+use crate::backup_store::BackupMode;
+use crate::compression::Compression;
+use crate::encryption::EncryptionKey;
+use crate::export_backup::ExportBackup;
+use crate::file_metadata::MetadataOptions;
+use crate::mirror::{MirrorPlan, MirrorReport};
+use crate::parity::{Parity, ParityReport};
+use crate::prune::PruneReport;
+use crate::replication::ReplicationReport;
+use crate::s3::RetentionMode;
+use crate::scrub::ScrubReport;
+use crate::snapshot::Snapshot;
+use crate::user_data::{is_valid_directory, FileInfo, SyncStatus, UserData};
+use iced::Event;
 use std::path::PathBuf;
-use crate::user_data::{is_valid_directory, UserData};
-use iced::{Event, Task};
 
 #[derive(Debug, Default)]
 pub struct App {
     pub current_directory: String,
     pub current_directory_valid: bool,
     pub user_data: UserData,
+    pub adopt_backup_directory: String,
+    pub adopt_files: Vec<ScannedFile>,
+    pub adopt_encryption_key: Option<EncryptionKey>,
+    pub passphrase_input: String,
+    /// 新しい鍵を作る時に、打ち間違いがないか確かめるためのもう 1 回の入力
+    pub passphrase_confirm_input: String,
+    pub status_message: String,
+    pub scrub_running: bool,
+    pub scrub_reports: Vec<ScrubReport>,
+    pub show_scrub_report: bool,
+    pub replication_running: bool,
+    /// 整理かチャンクの片付けを実行中
+    pub prune_running: bool,
+    /// ファイルのバックアップ、復元、エクスポートを別スレッドで実行中
+    pub file_task_running: bool,
+    /// パスフレーズを確かめている間は、もう一度送らない
+    pub unlock_running: bool,
+    /// `.ddbackupignore` で除外されたファイルも一覧に表示する
+    pub show_ignored: bool,
+    pub show_snapshots: bool,
+    /// カレントディレクトリのスナップショット (新しい順)
+    pub snapshots: Vec<Snapshot>,
+    pub snapshot_running: bool,
+    /// 中身を表示しているスナップショット
+    pub snapshot_selected: Option<String>,
+    /// snapshot_selected と比べるスナップショット
+    pub snapshot_compare: Option<String>,
+    /// 削除の確認を表示しているスナップショット
+    pub snapshot_delete_pending: Option<String>,
+    /// 実行前に確認するミラーの差分
+    pub mirror_plan: Option<MirrorPlan>,
+    pub mirror_running: bool,
+    /// 最後に始めた同期状態の確認 (古い確認の結果は捨てる)
+    pub sync_status_generation: u64,
 }
 
 #[derive(Debug, Clone)]
 pub enum FileMessage {
-    IgnoreInput(String),
+    IgnoreInput,
     Sync,
+    Restore,
     ExportPathInput(String),
     ExportPathSubmit,
+    /// エクスポート先の変更を取り込む
+    ExportPull,
+    /// エクスポート先の変更を捨てて上書きする
+    ExportOverwrite,
+    /// エクスポート先の内容を別名で残してから上書きする
+    ExportKeepBoth,
     Remove,
     RemoveAllowedToggled(bool),
 }
 
+#[derive(Debug, Clone)]
+pub enum AdoptMessage {
+    SourceDirectoryOpen,
+    SourceDirectoryInput(String),
+}
+
 #[derive(Debug, Clone)]
 pub enum Message {
     None,
@@ -30,24 +90,101 @@ pub enum Message {
     BackupDirectoryOpen,
     BackupDirectoryInput(String),
     BackupDirectorySubmit,
-    FileMessage(usize, FileMessage),
+    ReplicaDirectoryOpen,
+    ReplicaDirectoryInput(String),
+    ReplicationStart,
+    ReplicationScheduled,
+    ReplicationFinished(Vec<ReplicationReport>),
+    SshKeyInput(String),
+    KnownHostsInput(String),
+    S3EndpointInput(String),
+    S3RetentionDaysInput(String),
+    S3RetentionModeSelected(RetentionMode),
+    GitRemoteInput(String),
+    /// 別スレッドの処理が panic した
+    BackgroundTaskFailed(String),
+    /// 追跡しているファイルの同期状態を別スレッドで調べ直す
+    SyncStatusRefresh,
+    SyncStatusRefreshed(u64, Vec<SyncStatus>),
+    /// 前回中断された書き込みの一時ファイルを片付ける
+    TempFilesCleanup,
+    TempFilesCleaned(usize),
+    MirrorDirectoryOpen,
+    MirrorDirectoryInput(String),
+    MirrorDeletionsToggled(bool),
+    MirrorPreview,
+    MirrorPlanned(MirrorPlan),
+    MirrorStart,
+    /// 削除を反映する前に調べた差分
+    MirrorChecked(MirrorPlan),
+    MirrorFinished(MirrorReport),
+    MirrorPlanClose,
+    TrackIncludeInput(String),
+    TrackExcludeInput(String),
+    TrackRulesSubmit,
+    ShowIgnoredToggle,
+    BackupModeSelected(BackupMode),
+    CompressionSelected(Compression),
+    CompressionLevelInput(String),
+    ParitySelected(Parity),
+    ParityCreated(usize),
+    ParityRepair,
+    ParityRepaired(ParityReport),
+    EncryptionToggled(bool),
+    PassphraseInput(String),
+    PassphraseConfirmInput(String),
+    PassphraseSubmit,
+    /// 取り込み中のバックアップの鍵と、鍵で開いて見つかったファイル
+    AdoptUnlocked(Option<EncryptionKey>, Vec<ScannedFile>),
+    /// ディレクトリのパスと、開いたか作った鍵
+    PassphraseChecked(String, Result<EncryptionKey, String>),
+    LockBackup,
+    CollectGarbage,
+    GarbageCollected(Result<usize, String>),
+    KeepVersionsInput(String),
+    /// 古いバージョンを削除し、使われなくなったチャンクを片付ける
+    PruneStart,
+    PruneFinished(Result<PruneReport, String>),
+    ExportBackupSelected(ExportBackup),
+    MetadataOptionsChanged(MetadataOptions),
+    /// エクスポート先が古いか無いファイルをまとめてエクスポートする
+    ExportStale,
+    /// ディレクトリのパスと、エクスポートを試したファイル、結果の要約
+    ExportStaleFinished(String, Vec<FileInfo>, String),
+    VerifyBackup,
+    /// バックアップ先が使えなければ None
+    VerifyFinished(Option<String>),
+    ScrubStart,
+    ScrubScheduled,
+    ScrubFinished(Vec<ScrubReport>),
+    ScrubRepair(usize),
+    ScrubRepaired(usize, ScrubReport),
+    ScrubReportToggle,
+    ScrubIntervalInput(String),
+    ScrubSpeedLimitInput(String),
+    SnapshotsToggle,
+    SnapshotTake,
+    SnapshotTaken(Result<Snapshot, String>),
+    SnapshotBrowse(String),
+    SnapshotCompare(String),
+    SnapshotOpen(String),
+    SnapshotDelete(String),
+    SnapshotDeleteConfirm,
+    SnapshotDeleteCancel,
+    File(usize, FileMessage),
+    /// ディレクトリのパスと、別スレッドで操作した後のファイル
+    FileTaskFinished(String, FileInfo, Result<(), String>),
     AddFileInCurrentDirectory,
     OpenCurrentDirectory,
     OpenSaveData,
+    AdoptBackupOpen,
+    AdoptBackupInput(String),
+    Adopt(usize, AdoptMessage),
+    AdoptSubmit,
+    AdoptCancel,
 }
 
 impl App {
-    pub fn new() -> (Self, Task<Message>) {
-        (
-            Self {
-                current_directory: "".to_string(),
-                current_directory_valid: false,
-                user_data: UserData::new(),
-            },
-            Task::none(),
-        )
-    }
-
     pub fn change_current_directory(&mut self, current_directory: String) {
         self.current_directory = current_directory;
         self.current_directory_valid = is_valid_directory(&self.current_directory);
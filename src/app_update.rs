@@ -1,11 +1,30 @@
-use crate::app::{App, FileMessage, Message};
+use crate::app::{AdoptMessage, App, FileMessage, Message};
+use crate::atomic_file::remove_temp_files;
+use crate::backup_scan::scan_backup_store;
+use crate::backup_store::{
+    is_remote_backup_directory, open_existing_backup_store, open_plain_backup_store, BackupMode,
+};
+use crate::encryption::{create_key, has_key_file, unlock};
 use crate::get_directory_of_file;
+use crate::ignore_rules::IGNORE_FILENAME;
+use crate::manifest::verify;
+use crate::mirror::{check_mirror_directory, has_mirrored, plan_mirror, run_mirror};
+use crate::parity::{create_missing_parity, repair_directory, Parity};
+use crate::prune::prune_versions;
+use crate::replication::{is_replication_due, replicate_all};
 use crate::save_data::{store_save_data, SAVE_PATH};
-use crate::user_data::{is_valid_file, FileInfo};
+use crate::scrub::{is_scrub_due, repair, scrub_all};
+use crate::snapshot::{delete_snapshot, list_snapshots, snapshot_directory, take_snapshot};
+use crate::user_data::{
+    check_sync_status, is_valid_directory, is_valid_file, relative_path, DirectoryInfo,
+    ExportStatus, FileInfo,
+};
+use iced::futures::channel::oneshot;
 use iced::{window, Event, Task};
 use rfd::FileDialog;
-use std::path::Path;
+use std::panic::{self, AssertUnwindSafe};
 use std::process::Command;
+use std::thread;
 
 impl App {
     pub fn update(&mut self, message: Message) -> Task<Message> {
@@ -13,7 +32,7 @@ impl App {
             Message::None => Task::none(),
             Message::EventOccurred(event) => {
                 if let Event::Window(window::Event::CloseRequested) = event {
-                    store_save_data(&self);
+                    store_save_data(self);
                     window::get_latest().and_then(window::close)
                 } else if let Event::Window(window::Event::FileDropped(path)) = event {
                     Task::done(Message::DropFile(path))
@@ -22,22 +41,55 @@ impl App {
                 }
             }
             Message::DropFile(path) => {
-                if !is_valid_file(&path.to_str().unwrap_or("").to_string()) {
-                    return Task::none();
+                // ディレクトリはそのディレクトリをカレントにする
+                // 中のファイルは、追跡の条件を入力するまで追加しない
+                if is_valid_directory(path.to_str().unwrap_or("")) {
+                    self.change_current_directory(path.display().to_string());
+                    let current_directory = self
+                        .user_data
+                        .touch_directory_or_insert(&self.current_directory);
+                    current_directory.refresh_files();
+                    current_directory.sort_files_by_last_edited();
+
+                    return Task::done(Message::SyncStatusRefresh);
                 }
 
-                let dir_path = get_directory_of_file(&path);
-                if dir_path.is_none() {
+                if !is_valid_file(path.to_str().unwrap_or("")) {
                     return Task::none();
                 }
 
-                self.change_current_directory(dir_path.unwrap().display().to_string());
+                // カレントディレクトリの中のファイルは、サブディレクトリにあっても相対パスで追加する
+                let name = match self
+                    .current_directory_valid
+                    .then(|| relative_path(&self.current_directory, &path))
+                    .flatten()
+                {
+                    Some(name) => name,
+                    None => {
+                        let Some(dir_path) = get_directory_of_file(&path) else {
+                            return Task::none();
+                        };
+
+                        self.change_current_directory(dir_path.display().to_string());
+                        FileInfo::from_path(&path).name
+                    }
+                };
+
                 let current_directory = self
                     .user_data
                     .touch_directory_or_insert(&self.current_directory);
-                current_directory.files.push(FileInfo::from_path(&path));
+                if current_directory.ignore_rules.is_ignored(&name, false) {
+                    self.status_message = format!("{} is ignored by {}", name, IGNORE_FILENAME);
+                    return Task::none();
+                }
 
-                Task::none()
+                if !current_directory.files.iter().any(|f| f.name == name) {
+                    let mut file_info = FileInfo::from_path(&path);
+                    file_info.name = name;
+                    current_directory.files.push(file_info);
+                }
+
+                Task::done(Message::SyncStatusRefresh)
             }
             Message::CurrentDirectoryOpen => {
                 Task::perform(async { FileDialog::new().pick_folder() }, |result| {
@@ -57,7 +109,7 @@ impl App {
                     current_directory_info.refresh_files();
                 }
 
-                Task::none()
+                Task::done(Message::SyncStatusRefresh)
             }
             Message::CurrentDirectorySubmit => Task::none(),
             Message::BackupDirectoryOpen => {
@@ -77,45 +129,840 @@ impl App {
                 let current_directory = self
                     .user_data
                     .touch_directory_or_insert(&self.current_directory);
+                // リモートの場合は入力の途中で接続しないよう、確定した時に読み込む
+                let is_remote = is_remote_backup_directory(&backup_dir);
                 current_directory.backup_directory = backup_dir;
+                if !is_remote {
+                    current_directory.refresh_files();
+                }
+
+                Task::done(Message::SyncStatusRefresh)
+            }
+            Message::BackupDirectorySubmit => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.refresh_files();
+                }
+
+                Task::done(Message::SyncStatusRefresh)
+            }
+            Message::ReplicaDirectoryOpen => {
+                Task::perform(async { FileDialog::new().pick_folder() }, |result| {
+                    if let Some(path) = result {
+                        return Message::ReplicaDirectoryInput(path.display().to_string());
+                    }
+
+                    Message::None
+                })
+            }
+            Message::ReplicaDirectoryInput(replica_dir) => {
+                if !self.current_directory_valid {
+                    return Task::none();
+                }
+
+                let current_directory = self
+                    .user_data
+                    .touch_directory_or_insert(&self.current_directory);
+                current_directory.replica_directory = replica_dir;
+
+                Task::none()
+            }
+            Message::ReplicationScheduled => {
+                if self.replication_running {
+                    return Task::none();
+                }
+
+                let directories = self.user_data.directories.clone();
+                perform_blocking(
+                    move || is_replication_due(&directories),
+                    |due| {
+                        if due {
+                            Message::ReplicationStart
+                        } else {
+                            Message::None
+                        }
+                    },
+                )
+            }
+            Message::BackgroundTaskFailed(e) => {
+                // どの処理が失敗したかは分からないため、実行中の表示をすべて戻す
+                self.scrub_running = false;
+                self.replication_running = false;
+                self.snapshot_running = false;
+                self.mirror_running = false;
+                self.prune_running = false;
+                self.file_task_running = false;
+                self.unlock_running = false;
+                self.status_message = format!("Background task failed: {}", e);
+                Task::none()
+            }
+            Message::SyncStatusRefresh => {
+                self.sync_status_generation += 1;
+                let generation = self.sync_status_generation;
+                let directories = self.user_data.directories.clone();
+                perform_blocking(
+                    move || check_sync_status(&directories),
+                    move |statuses| Message::SyncStatusRefreshed(generation, statuses),
+                )
+            }
+            Message::SyncStatusRefreshed(generation, statuses) => {
+                // 後から始めた確認があれば、その結果を待つ
+                if generation == self.sync_status_generation {
+                    self.user_data.apply_sync_status(statuses);
+                }
+
+                Task::none()
+            }
+            Message::TempFilesCleanup => {
+                let directories = self.user_data.directories.clone();
+                perform_blocking(
+                    move || remove_temp_files(&directories),
+                    Message::TempFilesCleaned,
+                )
+            }
+            Message::TempFilesCleaned(removed) => {
+                if removed > 0 {
+                    self.status_message = format!(
+                        "Removed {} unfinished files from an interrupted sync",
+                        removed
+                    );
+                }
+
+                Task::none()
+            }
+            Message::ReplicationStart => {
+                if self.replication_running {
+                    return Task::none();
+                }
+
+                self.replication_running = true;
+                let directories = self.user_data.directories.clone();
+                perform_blocking(
+                    move || replicate_all(&directories),
+                    Message::ReplicationFinished,
+                )
+            }
+            Message::ReplicationFinished(reports) => {
+                self.replication_running = false;
+                self.status_message = reports
+                    .iter()
+                    .map(|r| format!("Replication: {}", r.summary()))
+                    .collect::<Vec<String>>()
+                    .join(" / ");
+
+                Task::none()
+            }
+            Message::MirrorDirectoryOpen => {
+                Task::perform(async { FileDialog::new().pick_folder() }, |result| {
+                    if let Some(path) = result {
+                        return Message::MirrorDirectoryInput(path.display().to_string());
+                    }
+
+                    Message::None
+                })
+            }
+            Message::MirrorDirectoryInput(mirror_dir) => {
+                if !self.current_directory_valid {
+                    return Task::none();
+                }
+
+                let current_directory = self
+                    .user_data
+                    .touch_directory_or_insert(&self.current_directory);
+                current_directory.mirror_directory = mirror_dir;
+                self.mirror_plan = None;
+
+                Task::none()
+            }
+            Message::MirrorDeletionsToggled(enabled) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.mirror_deletions = enabled;
+                }
+                self.mirror_plan = None;
+
+                Task::none()
+            }
+            Message::MirrorPreview => {
+                let Some(dir) = self.user_data.find_directory(&self.current_directory) else {
+                    return Task::none();
+                };
+                if let Err(e) = check_mirror_directory(dir) {
+                    self.status_message = e;
+                    return Task::none();
+                }
+
+                let dir = dir.clone();
+                perform_blocking(move || plan_mirror(&dir), Message::MirrorPlanned)
+            }
+            Message::MirrorPlanned(plan) => {
+                self.mirror_plan = Some(plan);
+                Task::none()
+            }
+            Message::MirrorStart => {
+                let Some(dir) = self.user_data.find_directory(&self.current_directory) else {
+                    return Task::none();
+                };
+                if self.mirror_running {
+                    return Task::none();
+                }
+                if let Err(e) = check_mirror_directory(dir) {
+                    self.status_message = e;
+                    return Task::none();
+                }
+
+                // 初めてのミラー先では、差分を確認してからでないと実行しない
+                let plan = self.mirror_plan.take();
+                if plan.is_none() && !has_mirrored(dir) {
+                    return Task::done(Message::MirrorPreview);
+                }
+
+                self.mirror_running = true;
+                if let Some(plan) = plan {
+                    return perform_blocking(move || run_mirror(&plan), Message::MirrorFinished);
+                }
+
+                // 削除を反映する場合は、ごみ箱へ移すファイルがあれば確認してから実行する
+                let dir = dir.clone();
+                if dir.mirror_deletions {
+                    return perform_blocking(move || plan_mirror(&dir), Message::MirrorChecked);
+                }
+                perform_blocking(
+                    move || run_mirror(&plan_mirror(&dir)),
+                    Message::MirrorFinished,
+                )
+            }
+            Message::MirrorChecked(plan) => {
+                if !plan.deleted.is_empty() {
+                    self.mirror_running = false;
+                    self.status_message = format!(
+                        "{} files will be moved to trash in the mirror; press Run Mirror to confirm",
+                        plan.deleted.len()
+                    );
+                    self.mirror_plan = Some(plan);
+                    return Task::none();
+                }
+
+                perform_blocking(move || run_mirror(&plan), Message::MirrorFinished)
+            }
+            Message::MirrorFinished(report) => {
+                self.mirror_running = false;
+                self.status_message = format!("Mirror: {}", report.summary());
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.refresh_files();
+                }
+
+                Task::done(Message::SyncStatusRefresh)
+            }
+            Message::MirrorPlanClose => {
+                self.mirror_plan = None;
+                Task::none()
+            }
+            Message::TrackIncludeInput(include) => {
+                if !self.current_directory_valid {
+                    return Task::none();
+                }
+
+                let current_directory = self
+                    .user_data
+                    .touch_directory_or_insert(&self.current_directory);
+                current_directory.track_rules.include = include;
+
+                Task::none()
+            }
+            Message::TrackExcludeInput(exclude) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.track_rules.exclude = exclude;
+                }
+
+                Task::none()
+            }
+            Message::ShowIgnoredToggle => {
+                self.show_ignored = !self.show_ignored;
+                Task::none()
+            }
+            Message::TrackRulesSubmit => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    let invalid_patterns = dir.track_rules.invalid_patterns();
+                    if !invalid_patterns.is_empty() {
+                        self.status_message =
+                            format!("Invalid pattern: {}", invalid_patterns.join(" "));
+                        return Task::none();
+                    }
+
+                    dir.refresh_files();
+                    dir.sort_files_by_last_edited();
+                }
+
+                Task::done(Message::SyncStatusRefresh)
+            }
+            Message::BackupModeSelected(mode) => {
+                if !self.current_directory_valid {
+                    return Task::none();
+                }
+
+                let current_directory = self
+                    .user_data
+                    .touch_directory_or_insert(&self.current_directory);
+                current_directory.backup_mode = mode;
                 current_directory.refresh_files();
 
+                Task::done(Message::SyncStatusRefresh)
+            }
+            Message::CompressionSelected(compression) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.compression = compression;
+                }
+
                 Task::none()
             }
-            Message::BackupDirectorySubmit => Task::none(),
-            Message::FileMessage(index, file_message) => {
-                let current_directory = self.user_data.touch_directory(&self.current_directory);
-                if let Some(dir) = current_directory {
-                    let dir_path = dir.path.clone();
-                    let backup_directory = dir.backup_directory.clone();
-                    if let Some(file) = dir.touch_file(index) {
-                        match file_message {
-                            FileMessage::IgnoreInput(_) => {}
-                            FileMessage::Sync => {
-                                file.refresh_last_edited(&dir_path);
-                                file.sync(&dir_path, &backup_directory);
-                                file.refresh_synced(&backup_directory);
-                                file.refresh_export_valid();
-                            }
-                            FileMessage::ExportPathInput(path) => {
-                                file.export_path = path;
-                                file.refresh_export_valid();
-                            }
-                            FileMessage::ExportPathSubmit => {}
-                            FileMessage::Remove => {
-                                dir.files.remove(index);
-                            }
-                            FileMessage::RemoveAllowedToggled(allowed) => {
-                                file.remove_allowed = allowed
-                            }
+            Message::CompressionLevelInput(level) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    if let Ok(level) = level.parse::<i32>() {
+                        dir.compression = dir.compression.with_level(level);
+                    }
+                }
+
+                Task::none()
+            }
+            Message::ParitySelected(parity) => {
+                let Some(dir) = self.user_data.touch_directory(&self.current_directory) else {
+                    return Task::none();
+                };
+
+                dir.parity = parity;
+                if parity == Parity::None
+                    || dir.backup_mode != BackupMode::Flat
+                    || is_remote_backup_directory(&dir.backup_directory)
+                {
+                    return Task::none();
+                }
+
+                // 既に保存されているバージョンのパリティをまとめて作る
+                let backup_directory = dir.backup_directory.clone();
+                perform_blocking(
+                    move || create_missing_parity(&backup_directory, parity),
+                    Message::ParityCreated,
+                )
+            }
+            Message::ParityCreated(count) => {
+                self.status_message = format!("Parity created for {} versions", count);
+                Task::none()
+            }
+            Message::ParityRepair => {
+                let Some(dir) = self.user_data.find_directory(&self.current_directory) else {
+                    return Task::none();
+                };
+
+                let backup_directory = dir.backup_directory.clone();
+                perform_blocking(
+                    move || repair_directory(&backup_directory),
+                    Message::ParityRepaired,
+                )
+            }
+            Message::ParityRepaired(report) => {
+                self.status_message = format!("Parity repair: {}", report.summary());
+                Task::none()
+            }
+            Message::EncryptionToggled(encrypted) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.encrypted = encrypted;
+                    dir.encryption_key = None;
+                    dir.refresh_files();
+                }
+
+                Task::done(Message::SyncStatusRefresh)
+            }
+            Message::PassphraseInput(passphrase) => {
+                self.passphrase_input = passphrase;
+                Task::none()
+            }
+            Message::PassphraseConfirmInput(passphrase) => {
+                self.passphrase_confirm_input = passphrase;
+                Task::none()
+            }
+            Message::PassphraseSubmit => {
+                if self.unlock_running {
+                    return Task::none();
+                }
+
+                let passphrase = std::mem::take(&mut self.passphrase_input);
+                let confirmation = std::mem::take(&mut self.passphrase_confirm_input);
+
+                // 取り込み中の暗号化されたバックアップを開く
+                if !self.adopt_backup_directory.is_empty() {
+                    self.unlock_running = true;
+                    let adopt_backup_directory = self.adopt_backup_directory.clone();
+                    return perform_blocking(
+                        move || {
+                            let key = unlock(
+                                open_plain_backup_store(&adopt_backup_directory).as_ref(),
+                                &passphrase,
+                            );
+                            let Some(key) = key else {
+                                return (None, Vec::new());
+                            };
+
+                            let store = open_existing_backup_store(
+                                &adopt_backup_directory,
+                                Some(key.clone()),
+                            );
+                            (Some(key), scan_backup_store(store.as_ref()))
+                        },
+                        |(key, files)| Message::AdoptUnlocked(key, files),
+                    );
+                }
+
+                let Some(dir) = self.user_data.find_directory(&self.current_directory) else {
+                    return Task::none();
+                };
+                self.unlock_running = true;
+                let dir = dir.clone();
+                let dir_path = dir.path.clone();
+                perform_blocking(
+                    move || {
+                        let key_store = dir.plain_store(&dir.backup_directory);
+                        if has_key_file(key_store.as_ref()) {
+                            unlock(key_store.as_ref(), &passphrase)
+                                .ok_or_else(|| "Wrong passphrase".to_string())
+                        } else if passphrase != confirmation {
+                            Err("Passphrases do not match".to_string())
+                        } else {
+                            create_key(key_store.as_ref(), &passphrase)
+                                .map_err(|e| format!("Failed to create the key: {}", e))
                         }
+                    },
+                    move |result| Message::PassphraseChecked(dir_path.clone(), result),
+                )
+            }
+            Message::AdoptUnlocked(key, files) => {
+                self.unlock_running = false;
+                if key.is_some() {
+                    self.adopt_files = files;
+                    self.adopt_encryption_key = key;
+                } else {
+                    self.status_message = "Wrong passphrase".to_string();
+                }
+
+                Task::none()
+            }
+            Message::PassphraseChecked(dir_path, result) => {
+                self.unlock_running = false;
+                if let Some(dir) = self.user_data.touch_directory(&dir_path) {
+                    match result {
+                        Ok(key) => dir.encryption_key = Some(key),
+                        Err(e) => self.status_message = e,
                     }
+                    dir.refresh_files();
+                }
+
+                Task::done(Message::SyncStatusRefresh)
+            }
+            Message::LockBackup => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.encryption_key = None;
+                    dir.refresh_files();
+                }
+
+                Task::done(Message::SyncStatusRefresh)
+            }
+            Message::ExportBackupSelected(export_backup) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.export_backup = export_backup;
+                }
+
+                Task::none()
+            }
+            Message::MetadataOptionsChanged(options) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.metadata_options = options;
                 }
 
                 Task::none()
             }
+            Message::ExportStale => {
+                let Some(dir) = self.user_data.find_directory(&self.current_directory) else {
+                    return Task::none();
+                };
+                if self.file_task_running {
+                    return Task::none();
+                }
+
+                self.file_task_running = true;
+                let dir = dir.clone();
+                perform_blocking(
+                    move || {
+                        let (files, summary) = export_stale(&dir);
+                        (dir.path, files, summary)
+                    },
+                    |(dir_path, files, summary)| {
+                        Message::ExportStaleFinished(dir_path, files, summary)
+                    },
+                )
+            }
+            Message::ExportStaleFinished(dir_path, files, summary) => {
+                self.file_task_running = false;
+                self.user_data.apply_file_updates(&dir_path, files);
+                self.status_message = summary;
+                Task::done(Message::SyncStatusRefresh)
+            }
+            Message::VerifyBackup => {
+                let Some(dir) = self.user_data.find_directory(&self.current_directory) else {
+                    return Task::none();
+                };
+
+                let dir = dir.clone();
+                self.status_message = "Verifying...".to_string();
+                perform_blocking(
+                    move || {
+                        let store = dir.backup_store();
+                        store
+                            .is_available()
+                            .then(|| verify(store.as_ref()).summary())
+                    },
+                    Message::VerifyFinished,
+                )
+            }
+            Message::VerifyFinished(summary) => {
+                self.status_message = summary.unwrap_or_default();
+                Task::none()
+            }
+            Message::ScrubScheduled => {
+                if self.scrub_running {
+                    return Task::none();
+                }
+
+                // リモートのバックアップ先の確認に時間がかかるため、別スレッドで判断する
+                let directories = self.user_data.directories.clone();
+                perform_blocking(
+                    move || is_scrub_due(&directories),
+                    |due| {
+                        if due {
+                            Message::ScrubStart
+                        } else {
+                            Message::None
+                        }
+                    },
+                )
+            }
+            Message::ScrubStart => {
+                if self.scrub_running {
+                    return Task::none();
+                }
+
+                self.scrub_running = true;
+                let directories = self.user_data.directories.clone();
+                perform_blocking(move || scrub_all(&directories), Message::ScrubFinished)
+            }
+            Message::ScrubFinished(reports) => {
+                let corrupted: usize = reports.iter().map(|r| r.corrupted.len()).sum();
+                let checked: usize = reports.iter().map(|r| r.checked).sum();
+                self.status_message =
+                    format!("Scrub: {} checked, {} corrupted", checked, corrupted);
+                self.scrub_running = false;
+                self.scrub_reports = reports;
+                if corrupted > 0 {
+                    self.show_scrub_report = true;
+                }
+
+                Task::none()
+            }
+            Message::ScrubRepair(index) => {
+                let Some(report) = self.scrub_reports.get(index).cloned() else {
+                    return Task::none();
+                };
+
+                let directories = self.user_data.directories.clone();
+                perform_blocking(
+                    move || repair(&directories, &report),
+                    move |report| Message::ScrubRepaired(index, report),
+                )
+            }
+            Message::ScrubRepaired(index, report) => {
+                if let Some(old_report) = self.scrub_reports.get_mut(index) {
+                    *old_report = report;
+                }
+
+                Task::none()
+            }
+            Message::ScrubReportToggle => {
+                self.show_scrub_report = !self.show_scrub_report;
+                Task::none()
+            }
+            Message::SnapshotsToggle => {
+                self.show_snapshots = !self.show_snapshots;
+                self.snapshot_selected = None;
+                self.snapshot_compare = None;
+                self.snapshot_delete_pending = None;
+                self.refresh_snapshots();
+                Task::none()
+            }
+            Message::SnapshotTake => {
+                let Some(dir) = self.user_data.find_directory(&self.current_directory) else {
+                    return Task::none();
+                };
+                if self.snapshot_running {
+                    return Task::none();
+                }
+
+                self.snapshot_running = true;
+                let dir = dir.clone();
+                perform_blocking(
+                    move || take_snapshot(&dir).map_err(|e| e.to_string()),
+                    Message::SnapshotTaken,
+                )
+            }
+            Message::SnapshotTaken(result) => {
+                self.snapshot_running = false;
+                match result {
+                    Ok(snapshot) => {
+                        self.status_message = format!(
+                            "Snapshot: {} files, {} unchanged",
+                            snapshot.entries.len(),
+                            snapshot.linked_count()
+                        );
+                        self.snapshot_selected = Some(snapshot.id);
+                        self.snapshot_compare = None;
+                    }
+                    Err(e) => self.status_message = format!("Snapshot failed: {}", e),
+                }
+
+                self.refresh_snapshots();
+                Task::none()
+            }
+            Message::SnapshotBrowse(id) => {
+                self.snapshot_selected = Some(id);
+                self.snapshot_compare = None;
+                Task::none()
+            }
+            Message::SnapshotCompare(id) => {
+                self.snapshot_compare = Some(id);
+                Task::none()
+            }
+            Message::SnapshotOpen(id) => {
+                if let Some(dir) = self.user_data.find_directory(&self.current_directory) {
+                    open_in_explorer(&snapshot_directory(dir, &id)).ok();
+                }
+
+                Task::none()
+            }
+            Message::SnapshotDelete(id) => {
+                self.snapshot_delete_pending = Some(id);
+                Task::none()
+            }
+            Message::SnapshotDeleteCancel => {
+                self.snapshot_delete_pending = None;
+                Task::none()
+            }
+            Message::SnapshotDeleteConfirm => {
+                let Some(id) = self.snapshot_delete_pending.take() else {
+                    return Task::none();
+                };
+                if let Some(dir) = self.user_data.find_directory(&self.current_directory) {
+                    if let Err(e) = delete_snapshot(dir, &id) {
+                        self.status_message = format!("Failed to delete snapshot: {}", e);
+                    }
+                }
+
+                if self.snapshot_selected.as_ref() == Some(&id) {
+                    self.snapshot_selected = None;
+                }
+                if self.snapshot_compare.as_ref() == Some(&id) {
+                    self.snapshot_compare = None;
+                }
+                self.refresh_snapshots();
+                Task::none()
+            }
+            Message::ScrubIntervalInput(days) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    if days.is_empty() {
+                        dir.scrub_interval_days = 0;
+                    } else if let Ok(days) = days.parse::<u32>() {
+                        dir.scrub_interval_days = days;
+                    }
+                }
+
+                Task::none()
+            }
+            Message::SshKeyInput(key) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.remote_options.sftp.key = key;
+                }
+
+                Task::none()
+            }
+            Message::KnownHostsInput(known_hosts) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.remote_options.sftp.known_hosts = known_hosts;
+                }
+
+                Task::none()
+            }
+            Message::S3EndpointInput(endpoint) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.remote_options.s3.endpoint = endpoint;
+                }
+
+                Task::none()
+            }
+            Message::S3RetentionDaysInput(days) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    if days.is_empty() {
+                        dir.remote_options.s3.retention_days = 0;
+                    } else if let Ok(days) = days.parse::<u32>() {
+                        dir.remote_options.s3.retention_days = days;
+                    }
+                }
+
+                Task::none()
+            }
+            Message::S3RetentionModeSelected(mode) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.remote_options.s3.retention_mode = mode;
+                }
+
+                Task::none()
+            }
+            Message::GitRemoteInput(remote) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    dir.remote_options.git_remote = remote;
+                }
+
+                Task::none()
+            }
+            Message::ScrubSpeedLimitInput(limit) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    if limit.is_empty() {
+                        dir.scrub_speed_limit = 0;
+                    } else if let Ok(limit) = limit.parse::<u64>() {
+                        dir.scrub_speed_limit = limit;
+                    }
+                }
+
+                Task::none()
+            }
+            Message::KeepVersionsInput(keep) => {
+                if let Some(dir) = self.user_data.touch_directory(&self.current_directory) {
+                    if keep.is_empty() {
+                        dir.keep_versions = 0;
+                    } else if let Ok(keep) = keep.parse::<usize>() {
+                        dir.keep_versions = keep;
+                    }
+                }
+
+                Task::none()
+            }
+            Message::PruneStart => {
+                let Some(dir) = self.user_data.find_directory(&self.current_directory) else {
+                    return Task::none();
+                };
+                if self.prune_running || dir.keep_versions == 0 {
+                    return Task::none();
+                }
+
+                self.prune_running = true;
+                let dir = dir.clone();
+                perform_blocking(
+                    move || {
+                        prune_versions(dir.backup_store().as_ref(), dir.keep_versions)
+                            .map_err(|e| e.to_string())
+                    },
+                    Message::PruneFinished,
+                )
+            }
+            Message::PruneFinished(result) => {
+                self.prune_running = false;
+                self.status_message = match result {
+                    Ok(report) => report.summary(),
+                    Err(e) => format!("Pruning stopped: {}", e),
+                };
+                Task::done(Message::SyncStatusRefresh)
+            }
+            Message::CollectGarbage => {
+                let Some(dir) = self.user_data.find_directory(&self.current_directory) else {
+                    return Task::none();
+                };
+                // 整理の中でも片付けるため、同時には実行しない
+                if self.prune_running {
+                    return Task::none();
+                }
+
+                self.prune_running = true;
+                let dir = dir.clone();
+                perform_blocking(
+                    move || {
+                        dir.backup_store()
+                            .collect_garbage()
+                            .map_err(|e| e.to_string())
+                    },
+                    Message::GarbageCollected,
+                )
+            }
+            Message::GarbageCollected(result) => {
+                self.prune_running = false;
+                self.status_message = match result {
+                    Ok(removed) => format!("Removed {} unused chunks", removed),
+                    Err(e) => format!("Garbage collection stopped: {}", e),
+                };
+                Task::none()
+            }
+            Message::File(index, file_message) => {
+                let Some(dir) = self.user_data.touch_directory(&self.current_directory) else {
+                    return Task::none();
+                };
+                let dir_path = dir.path.clone();
+                let Some(file) = dir.touch_file(index) else {
+                    return Task::none();
+                };
+
+                match file_message {
+                    FileMessage::IgnoreInput => {}
+                    FileMessage::ExportPathInput(path) => {
+                        file.export_path = path;
+                        file.refresh_export_valid();
+                        // 入力中はエクスポート先を読まず、確定した時に調べる
+                        file.export_status = ExportStatus::default();
+                    }
+                    FileMessage::ExportPathSubmit => {
+                        file.refresh_export_status(&dir_path);
+                    }
+                    FileMessage::RemoveAllowedToggled(allowed) => file.remove_allowed = allowed,
+                    FileMessage::Remove => {
+                        dir.files.remove(index);
+                    }
+                    // バックアップ先やエクスポート先を読み書きするため、別スレッドで実行する
+                    FileMessage::Sync
+                    | FileMessage::Restore
+                    | FileMessage::ExportPull
+                    | FileMessage::ExportOverwrite
+                    | FileMessage::ExportKeepBoth => {
+                        if self.file_task_running {
+                            return Task::none();
+                        }
+
+                        self.file_task_running = true;
+                        let file = file.clone();
+                        let dir = dir.clone();
+                        return perform_blocking(
+                            move || run_file_task(&dir, file, file_message),
+                            move |(file, result)| {
+                                Message::FileTaskFinished(dir_path.clone(), file, result)
+                            },
+                        );
+                    }
+                }
+
+                Task::done(Message::SyncStatusRefresh)
+            }
+            Message::FileTaskFinished(dir_path, file, result) => {
+                self.file_task_running = false;
+                self.user_data.apply_file_updates(&dir_path, vec![file]);
+                if let Err(e) = result {
+                    self.status_message = format!("Failed to resolve export: {}", e);
+                }
+                Task::done(Message::SyncStatusRefresh)
+            }
             Message::AddFileInCurrentDirectory => {
-                Task::perform(async { FileDialog::new().pick_file() }, |result| {
+                let dialog = FileDialog::new().set_directory(&self.current_directory);
+                Task::perform(async move { dialog.pick_file() }, |result| {
                     if let Some(path) = result {
                         return Message::DropFile(path);
                     }
@@ -131,7 +978,197 @@ impl App {
                 open_in_explorer(SAVE_PATH).ok();
                 Task::none()
             }
+            Message::AdoptBackupOpen => {
+                Task::perform(async { FileDialog::new().pick_folder() }, |result| {
+                    if let Some(path) = result {
+                        return Message::AdoptBackupInput(path.display().to_string());
+                    }
+
+                    Message::None
+                })
+            }
+            Message::AdoptBackupInput(backup_dir) => {
+                let store = open_existing_backup_store(&backup_dir, None);
+                self.adopt_files = scan_backup_store(store.as_ref());
+                self.adopt_backup_directory = backup_dir;
+                self.adopt_encryption_key = None;
+                Task::none()
+            }
+            Message::Adopt(index, adopt_message) => match adopt_message {
+                AdoptMessage::SourceDirectoryOpen => {
+                    Task::perform(async { FileDialog::new().pick_folder() }, move |result| {
+                        if let Some(path) = result {
+                            return Message::Adopt(
+                                index,
+                                AdoptMessage::SourceDirectoryInput(path.display().to_string()),
+                            );
+                        }
+
+                        Message::None
+                    })
+                }
+                AdoptMessage::SourceDirectoryInput(dir) => {
+                    if let Some(file) = self.adopt_files.get_mut(index) {
+                        file.source_directory = dir;
+                    }
+
+                    Task::none()
+                }
+            },
+            Message::AdoptSubmit => {
+                self.user_data.adopt_backup(
+                    &self.adopt_backup_directory,
+                    &self.adopt_files,
+                    self.adopt_encryption_key.clone(),
+                );
+
+                if let Some(file) = self.adopt_files.iter().find(|f| f.source_directory_valid()) {
+                    self.change_current_directory(file.source_directory.clone());
+                }
+
+                Task::batch([
+                    Task::done(Message::AdoptCancel),
+                    Task::done(Message::SyncStatusRefresh),
+                ])
+            }
+            Message::AdoptCancel => {
+                self.adopt_backup_directory.clear();
+                self.adopt_files.clear();
+                self.adopt_encryption_key = None;
+                Task::none()
+            }
+        }
+    }
+}
+
+/// 時間のかかる処理を別スレッドで実行し、完了を待つ
+/// panic した場合は、その内容をエラーとして返す
+async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, String> {
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+            payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string())
+        });
+        sender.send(result).ok();
+    });
+    receiver
+        .await
+        .unwrap_or_else(|_| Err("background task stopped".to_string()))
+}
+
+/// ファイルのバックアップ、復元、エクスポート先の解決を行い、操作した後のファイルを返す
+/// それ以外の操作は update の中で済ませる
+fn run_file_task(
+    dir: &DirectoryInfo,
+    mut file: FileInfo,
+    message: FileMessage,
+) -> (FileInfo, Result<(), String>) {
+    let store = dir.backup_store();
+    let sftp = &dir.remote_options.sftp;
+    let result = match message {
+        FileMessage::Sync => {
+            file.refresh_last_edited(&dir.path);
+            file.sync(
+                &dir.path,
+                store.as_ref(),
+                dir.export_backup,
+                dir.metadata_options,
+                sftp,
+            );
+            file.refresh_export_valid();
+            Ok(())
+        }
+        FileMessage::Restore => {
+            file.restore(&dir.path, store.as_ref()).ok();
+            file.refresh_metadata(&dir.path);
+            Ok(())
+        }
+        FileMessage::ExportPull => {
+            let result = file.pull_export(&dir.path, dir.metadata_options, store.as_ref(), sftp);
+            file.refresh_metadata(&dir.path);
+            result
+        }
+        FileMessage::ExportOverwrite => {
+            let result = file.overwrite_export(
+                &dir.path,
+                dir.export_backup,
+                dir.metadata_options,
+                store.as_ref(),
+                sftp,
+            );
+            file.refresh_export_valid();
+            result
+        }
+        FileMessage::ExportKeepBoth => {
+            let result =
+                file.keep_both_exports(&dir.path, dir.metadata_options, store.as_ref(), sftp);
+            file.refresh_export_valid();
+            result
+        }
+        _ => Ok(()),
+    };
+
+    (file, result.map_err(|e| e.to_string()))
+}
+
+/// エクスポート先が古いか無いファイルをまとめてエクスポートし、試したファイルと結果の要約を返す
+fn export_stale(dir: &DirectoryInfo) -> (Vec<FileInfo>, String) {
+    let store = dir.backup_store();
+    let (mut exported, mut failed, mut source_missing) = (0, 0, 0);
+    let mut files = Vec::new();
+    for file in &dir.files {
+        let mut file = file.clone();
+        file.refresh_export_status(&dir.path);
+        if file.export_status == ExportStatus::SourceMissing {
+            source_missing += 1;
+        }
+        if file.export_status.needs_export() {
+            match file.export(
+                &dir.path,
+                dir.export_backup,
+                dir.metadata_options,
+                store.as_ref(),
+                &dir.remote_options.sftp,
+            ) {
+                Ok(_) => exported += 1,
+                Err(_) => failed += 1,
+            }
         }
+        files.push(file);
+    }
+
+    let mut summary = format!("{} exported, {} failed", exported, failed);
+    if source_missing > 0 {
+        summary += &format!(", {} skipped because the source is missing", source_missing);
+    }
+    (files, summary)
+}
+
+/// run_blocking の結果を on_done で Message にする
+/// 失敗した場合は BackgroundTaskFailed を送る
+fn perform_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+    on_done: impl Fn(T) -> Message + Send + 'static,
+) -> Task<Message> {
+    Task::perform(run_blocking(f), move |result| match result {
+        Ok(value) => on_done(value),
+        Err(e) => Message::BackgroundTaskFailed(e),
+    })
+}
+
+impl App {
+    fn refresh_snapshots(&mut self) {
+        self.snapshots = self
+            .user_data
+            .find_directory(&self.current_directory)
+            .map(list_snapshots)
+            .unwrap_or_default();
     }
 }
 
@@ -147,3 +1184,194 @@ fn open_in_explorer(path: &str) -> Result<(), Box<dyn std::error::Error>> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_store::BackupStore;
+    use crate::mirror::MirrorPlan;
+    use crate::user_data::append_path;
+    use iced::futures::executor::block_on;
+    use std::fs;
+
+    #[test]
+    fn run_blocking_returns_the_result() {
+        assert_eq!(block_on(run_blocking(|| 1 + 1)), Ok(2));
+    }
+
+    #[test]
+    fn run_blocking_reports_a_panic() {
+        let result: Result<(), String> = block_on(run_blocking(|| panic!("disk on fire")));
+        assert_eq!(result, Err("disk on fire".to_string()));
+    }
+
+    #[test]
+    fn snapshot_delete_waits_for_confirmation() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let source = append_path(&root, "source");
+        let backup = append_path(&root, "backup");
+        fs::create_dir(&source).unwrap();
+        fs::create_dir(&backup).unwrap();
+
+        let mut app = App::default();
+        app.change_current_directory(source.clone());
+        app.user_data
+            .touch_directory_or_insert(&source)
+            .backup_directory = backup;
+        let snapshot = take_snapshot(app.user_data.find_directory(&source).unwrap()).unwrap();
+        app.refresh_snapshots();
+
+        let _ = app.update(Message::SnapshotDelete(snapshot.id.clone()));
+        let _ = app.update(Message::SnapshotDeleteCancel);
+        assert_eq!(app.snapshots.len(), 1);
+
+        let _ = app.update(Message::SnapshotDelete(snapshot.id.clone()));
+        assert_eq!(app.snapshots.len(), 1);
+        let _ = app.update(Message::SnapshotDeleteConfirm);
+        assert!(app.snapshots.is_empty());
+        assert_eq!(app.snapshot_delete_pending, None);
+    }
+
+    #[test]
+    fn mirror_deletions_wait_for_confirmation() {
+        let plan = MirrorPlan {
+            deleted: vec!["old.txt".to_string()],
+            propagate_deletions: true,
+            ..MirrorPlan::default()
+        };
+
+        let mut app = App {
+            mirror_running: true,
+            ..App::default()
+        };
+        let _ = app.update(Message::MirrorChecked(plan));
+        assert!(!app.mirror_running);
+        assert!(app
+            .status_message
+            .starts_with("1 files will be moved to trash"));
+        assert!(app.mirror_plan.is_some());
+    }
+
+    #[test]
+    fn export_stale_reports_missing_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let source = append_path(&root, "source");
+        let export = append_path(&root, "export");
+        fs::create_dir(&source).unwrap();
+        fs::create_dir(&export).unwrap();
+        fs::write(append_path(&source, "a.txt"), "a").unwrap();
+
+        let mut app = App::default();
+        app.change_current_directory(source.clone());
+        let directory = app.user_data.touch_directory_or_insert(&source);
+        for name in ["a.txt", "gone.txt"] {
+            directory.add_file(FileInfo::new(
+                name.to_string(),
+                chrono::Utc::now(),
+                export.clone(),
+            ));
+        }
+
+        let _ = app.update(Message::ExportStale);
+        // 別スレッドで実行するため、update の中ではまだ書き込まない
+        assert!(app.file_task_running);
+        assert!(!fs::exists(append_path(&export, "a.txt")).unwrap());
+
+        let (files, summary) = export_stale(app.user_data.find_directory(&source).unwrap());
+        let _ = app.update(Message::ExportStaleFinished(source.clone(), files, summary));
+        assert!(!app.file_task_running);
+        assert_eq!(
+            app.status_message,
+            "1 exported, 0 failed, 1 skipped because the source is missing"
+        );
+        assert!(fs::exists(append_path(&export, "a.txt")).unwrap());
+        assert_eq!(
+            app.user_data.find_directory(&source).unwrap().files[0].export_status,
+            ExportStatus::UpToDate
+        );
+    }
+
+    /// source/a.txt を追跡し、backup をバックアップ先にした App
+    fn app_with_backup() -> (tempfile::TempDir, App, String, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let source = append_path(&root, "source");
+        let backup = append_path(&root, "backup");
+        fs::create_dir(&source).unwrap();
+        fs::create_dir(&backup).unwrap();
+        fs::write(append_path(&source, "a.txt"), "a").unwrap();
+
+        let mut app = App::default();
+        app.change_current_directory(source.clone());
+        let directory = app.user_data.touch_directory_or_insert(&source);
+        directory.backup_directory = backup.clone();
+        let mut file = FileInfo::new("a.txt".to_string(), chrono::Utc::now(), String::new());
+        file.refresh_metadata(&source);
+        directory.add_file(file);
+        (dir, app, source, backup)
+    }
+
+    fn app_store(app: &App, source: &str) -> Box<dyn BackupStore> {
+        app.user_data.find_directory(source).unwrap().backup_store()
+    }
+
+    #[test]
+    fn file_sync_runs_in_the_background() {
+        let (_dir, mut app, source, _) = app_with_backup();
+        let _ = app.update(Message::File(0, FileMessage::Sync));
+        assert!(app.file_task_running);
+        assert!(app_store(&app, &source).list_versions().is_empty());
+
+        let directory = app.user_data.find_directory(&source).unwrap();
+        let (file, result) =
+            run_file_task(directory, directory.files[0].clone(), FileMessage::Sync);
+        let _ = app.update(Message::FileTaskFinished(source.clone(), file, result));
+        assert!(!app.file_task_running);
+        assert_eq!(app_store(&app, &source).list_versions().len(), 1);
+    }
+
+    #[test]
+    fn passphrase_is_checked_in_the_background() {
+        let (_dir, mut app, source, backup) = app_with_backup();
+        app.user_data.touch_directory(&source).unwrap().encrypted = true;
+        app.passphrase_input = "correct horse".to_string();
+        app.passphrase_confirm_input = "correct horse".to_string();
+
+        let _ = app.update(Message::PassphraseSubmit);
+        assert!(app.unlock_running);
+        assert!(!has_key_file(open_plain_backup_store(&backup).as_ref()));
+
+        let _ = app.update(Message::PassphraseChecked(
+            source.clone(),
+            Err("Wrong passphrase".to_string()),
+        ));
+        assert!(!app.unlock_running);
+        assert_eq!(app.status_message, "Wrong passphrase");
+        assert!(app
+            .user_data
+            .find_directory(&source)
+            .unwrap()
+            .encryption_key
+            .is_none());
+    }
+
+    #[test]
+    fn verify_and_garbage_collection_report_when_finished() {
+        let (_dir, mut app, _, _) = app_with_backup();
+
+        let _ = app.update(Message::VerifyBackup);
+        assert_eq!(app.status_message, "Verifying...");
+        let _ = app.update(Message::VerifyFinished(Some(
+            "All versions verified".to_string(),
+        )));
+        assert_eq!(app.status_message, "All versions verified");
+
+        let _ = app.update(Message::CollectGarbage);
+        assert!(app.prune_running);
+        let _ = app.update(Message::GarbageCollected(Ok(3)));
+        assert!(!app.prune_running);
+        assert_eq!(app.status_message, "Removed 3 unused chunks");
+    }
+}
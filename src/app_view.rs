@@ -1,15 +1,28 @@
 use crate::app::FileMessage::RemoveAllowedToggled;
-use crate::app::{App, FileMessage, Message};
-use crate::user_data::{is_valid_directory, DirectoryInfo, FileInfo};
-use iced::widget::rule::Catalog;
+use crate::app::{AdoptMessage, App, FileMessage, Message};
+use crate::backup_scan::ScannedFile;
+use crate::backup_store::{is_remote_backup_directory, BackupMode};
+use crate::compression::Compression;
+use crate::encryption::is_encrypted_target;
+use crate::export_backup::ExportBackup;
+use crate::file_metadata::MetadataOptions;
+use crate::ignore_rules::IGNORE_FILENAME;
+use crate::mirror::{check_mirror_directory, MirrorPlan};
+use crate::parity::Parity;
+use crate::s3::RetentionMode;
+use crate::scrub::ScrubReport;
+use crate::snapshot::{compare_snapshots, is_snapshot_available, Snapshot, SnapshotEntry};
+use crate::user_data::{is_valid_backup_directory, DirectoryInfo, ExportStatus, FileInfo};
+use chrono::{DateTime, Local, Utc};
 use iced::widget::text::Shaping;
 use iced::widget::text::Shaping::Advanced;
 use iced::widget::text_input::Status;
 use iced::widget::{
-    button, center, horizontal_rule, horizontal_space, row, scrollable, text, text_input, Column,
-    Row, Text,
+    button, center, container, horizontal_rule, horizontal_space, pick_list, row, scrollable, text,
+    text_input, Column, Row, Text,
 };
 use iced::{widget, Center, Element, Fill, Left, Length, Padding, Theme};
+use std::collections::BTreeMap;
 
 fn text_input_style_by_status(
     is_valid: bool,
@@ -25,14 +38,60 @@ fn text_input_style_by_status(
     }
 }
 
+/// 保存している UTC の日時を、表示用にローカル時刻へ変換する
+fn format_local_time(time: &DateTime<Utc>) -> String {
+    time.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
 fn text_input_borderless_style(theme: &Theme, status: text_input::Status) -> text_input::Style {
     let mut style = text_input::default(theme, status);
     style.border.color = theme.palette().background;
     style
 }
 
+/// エクスポートの状態を、エクスポート先の入力欄の横に表示する
+fn export_status_view<'a>(status: ExportStatus) -> Text<'a> {
+    let icon = match status {
+        ExportStatus::NoExport => return text(""),
+        ExportStatus::Unchecked => "\u{F02D6}",
+        ExportStatus::UpToDate => "\u{F012C}",
+        ExportStatus::Stale => "\u{F0450}",
+        ExportStatus::Missing => "\u{F0026}",
+        ExportStatus::ModifiedExternally => "\u{F0A7B}",
+        ExportStatus::SourceMissing => "\u{F0026}",
+    };
+    let style = match status {
+        ExportStatus::UpToDate => text::success,
+        ExportStatus::Stale | ExportStatus::Missing => text::primary,
+        ExportStatus::ModifiedExternally | ExportStatus::SourceMissing => text::danger,
+        _ => text::secondary,
+    };
+
+    text(format!("{} {}", icon, status))
+        .shaping(Advanced)
+        .style(style)
+}
+
+/// ファイルをディレクトリ (パスの要素の並び) ごとにまとめる
+/// 途中のディレクトリにも見出しを出すため、ファイルの無いディレクトリも空で入れる
+fn file_tree(files: &[FileInfo]) -> BTreeMap<Vec<&str>, Vec<(usize, &FileInfo)>> {
+    let mut tree: BTreeMap<Vec<&str>, Vec<(usize, &FileInfo)>> = BTreeMap::new();
+    for (index, file) in files.iter().enumerate() {
+        let components: Vec<&str> = file.name.split('/').collect();
+        let directory = components[..components.len() - 1].to_vec();
+        for depth in 0..directory.len() {
+            tree.entry(directory[..depth].to_vec()).or_default();
+        }
+        tree.entry(directory).or_default().push((index, file));
+    }
+
+    tree
+}
+
 impl App {
-    pub fn view(&self) -> Element<Message> {
+    pub fn view(&self) -> Element<'_, Message> {
         let current_directory_info = self.user_data.find_directory(&self.current_directory);
 
         // カレントディレクトリ
@@ -41,23 +100,72 @@ impl App {
         // バックアップディレクトリ
         let backup_dir_elem = self.view_backup_dir(current_directory_info);
 
+        // 複製先ディレクトリ
+        let replica_dir_elem = self.view_replica_dir(current_directory_info);
+
+        // リモートへの接続の設定 (使う時だけ表示する)
+        let remote_options_elem = Self::view_remote_options(current_directory_info);
+
+        // ミラー先ディレクトリ
+        let mirror_dir_elem = self.view_mirror_dir(current_directory_info);
+
+        // ディレクトリごと追跡する条件
+        let track_rules_elem = self.view_track_rules(current_directory_info);
+
+        // 引き継ぐメタデータ
+        let metadata_options_elem = Self::view_metadata_options(current_directory_info);
+
         let make_bottom_button = |text: &'static str, message: Message| {
-            button(text.clone())
+            button(text)
                 .on_press(message)
                 .style(button::primary)
                 .width(Length::Shrink)
         };
 
+        // 検証などの結果があれば、案内の代わりに表示する
+        let bottom_message = if self.status_message.is_empty() {
+            "\u{F0966} Drop files here to backup".to_string()
+        } else {
+            format!("\u{F0CC4} {}", self.status_message)
+        };
+
         // ファイルリスト (底辺)
         let file_list_bottom = widget::column![
             horizontal_rule(0.5),
             widget::row![
-                text("\u{F0966} Drop files here to backup")
+                text(bottom_message)
                     .shaping(Advanced)
                     .style(text::secondary),
                 horizontal_space(),
+                pick_list(
+                    ExportBackup::ALL,
+                    current_directory_info.map(|dir| dir.export_backup),
+                    Message::ExportBackupSelected
+                ),
+                make_bottom_button("Export Stale", Message::ExportStale),
+                make_bottom_button("Verify", Message::VerifyBackup),
+                // スクラブ中は二重に開始しない
+                button(if self.scrub_running {
+                    "Scrubbing..."
+                } else {
+                    "Scrub"
+                })
+                .on_press_maybe((!self.scrub_running).then_some(Message::ScrubStart))
+                .style(button::primary)
+                .width(Length::Shrink),
+                make_bottom_button("Scrub Report", Message::ScrubReportToggle),
+                make_bottom_button("Snapshots", Message::SnapshotsToggle),
+                make_bottom_button("Adopt Backup", Message::AdoptBackupOpen),
                 make_bottom_button("Open Save Data", Message::OpenSaveData),
                 make_bottom_button("Open Current Directory", Message::OpenCurrentDirectory),
+                make_bottom_button(
+                    if self.show_ignored {
+                        "Hide Ignored"
+                    } else {
+                        "Show Ignored"
+                    },
+                    Message::ShowIgnoredToggle
+                ),
                 make_bottom_button("Add File", Message::AddFileInCurrentDirectory),
             ]
             .width(Fill)
@@ -67,16 +175,17 @@ impl App {
         .spacing(10);
 
         // ファイルリスト (本体)
-        let file_list_elem = scrollable(if let Some(dir) = current_directory_info {
-            let files = &dir.files;
-            files
-                .into_iter()
-                .enumerate()
-                .fold(Column::new(), |col, (index, file)| {
-                    let file_row = Self::file_row_view(file)
-                        .map(move |message| Message::FileMessage(index, message));
-                    col.push(file_row)
-                })
+        let file_list_elem = scrollable(if !self.adopt_backup_directory.is_empty() {
+            self.view_adopt_list()
+        } else if self.show_scrub_report {
+            self.view_scrub_report()
+        } else if let Some(plan) = &self.mirror_plan {
+            self.view_mirror_plan(plan)
+        } else if self.show_snapshots {
+            self.view_snapshots(current_directory_info)
+        } else if let Some(dir) = current_directory_info {
+            Self::view_file_tree(dir)
+                .push_maybe(self.show_ignored.then(|| Self::view_ignored_files(dir)))
                 .push(file_list_bottom)
                 .spacing(10)
                 .width(Fill)
@@ -87,14 +196,62 @@ impl App {
         .spacing(5)
         .height(Fill);
 
-        let content = widget::column![current_dir_elem, backup_dir_elem, file_list_elem]
+        let content = widget::column![current_dir_elem, backup_dir_elem, replica_dir_elem,]
+            .push_maybe(remote_options_elem)
+            .push(mirror_dir_elem)
+            .push(track_rules_elem)
+            .push(metadata_options_elem)
+            .push(file_list_elem)
             .align_x(Center)
             .spacing(20)
             .padding(20);
         center(content).into()
     }
 
-    fn file_row_view(file: &FileInfo) -> Element<FileMessage> {
+    /// サブディレクトリのファイルは、ディレクトリの見出しの下に字下げして並べる
+    fn view_file_tree(dir: &DirectoryInfo) -> Column<'_, Message> {
+        file_tree(&dir.files)
+            .into_iter()
+            .fold(Column::new(), |col, (directory, files)| {
+                let indent = 20.0 * directory.len() as f32;
+                let col = match directory.last() {
+                    Some(name) => col.push(
+                        container(
+                            text(format!("\u{F024B} {}", name))
+                                .shaping(Advanced)
+                                .style(text::secondary),
+                        )
+                        .width(Fill)
+                        .padding(Padding::ZERO.left(indent - 10.0)),
+                    ),
+                    None => col,
+                };
+
+                files.into_iter().fold(col, |col, (index, file)| {
+                    let display_name = file.name.rsplit('/').next().unwrap_or(&file.name);
+                    let file_row = Self::file_row_view(file, display_name)
+                        .map(move |message| Message::File(index, message));
+                    col.push(container(file_row).padding(Padding::ZERO.left(indent)))
+                })
+            })
+    }
+
+    /// 除外されたファイルは操作できないため、名前だけを薄く表示する
+    fn view_ignored_files(dir: &DirectoryInfo) -> Column<'_, Message> {
+        dir.ignore_rules
+            .ignored
+            .iter()
+            .fold(Column::new().spacing(5), |col, name| {
+                col.push(
+                    text(format!("\u{F0209} {}", name))
+                        .shaping(Advanced)
+                        .style(text::secondary),
+                )
+            })
+            .padding(Padding::from([5, 10]))
+    }
+
+    fn file_row_view<'a>(file: &'a FileInfo, display_name: &'a str) -> Element<'a, FileMessage> {
         let mut sync_button = button(
             text("\u{F1378}")
                 .width(Fill)
@@ -111,6 +268,20 @@ impl App {
             sync_button = sync_button.style(button::secondary)
         }
 
+        // 元のファイルが無い場合は、同期の代わりにバックアップからの復元を行う
+        if !file.source_exists {
+            sync_button = button(
+                text("\u{F006F}")
+                    .width(Fill)
+                    .align_x(Center)
+                    .shaping(Advanced),
+            )
+            .width(50)
+            .padding(10)
+            .on_press(FileMessage::Restore)
+            .style(button::primary);
+        }
+
         let remove_allowed_toggle = widget::toggler(file.remove_allowed)
             .text_shaping(Advanced)
             .on_toggle(RemoveAllowedToggled)
@@ -141,20 +312,321 @@ impl App {
             remove_button,
             widget::column![
                 widget::row![
-                    text_input("", &file.name)
-                        .on_input(FileMessage::IgnoreInput)
+                    text_input("", display_name)
+                        .on_input(|_| FileMessage::IgnoreInput)
                         .style(text_input_borderless_style),
                     horizontal_space(),
-                    Text::new(&file.last_edited).style(text::primary)
-                ],
-                text_input("(no export)", &file.export_path)
+                ]
+                .push_maybe(file.ignored.then(|| {
+                    text(format!("\u{F0209} Ignored by {}", IGNORE_FILENAME))
+                        .shaping(Advanced)
+                        .style(text::secondary)
+                }))
+                .push(Text::new(format_local_time(&file.last_edited)).style(text::primary))
+                .spacing(10),
+                widget::row![
+                    text_input("(no export)", &file.export_path)
+                        .padding(Padding::from([5, 10]))
+                        .style(text_input_style_by_status(
+                            file.export_path.is_empty() || file.export_valid
+                        ))
+                        .on_input(FileMessage::ExportPathInput)
+                        .on_submit(FileMessage::ExportPathSubmit),
+                    export_status_view(file.export_status),
+                ]
+                .align_y(Center)
+                .spacing(10)
+            ]
+            .push_maybe(
+                (file.export_status == ExportStatus::ModifiedExternally)
+                    .then(Self::export_conflict_view)
+            )
+        ]
+        .align_y(Center)
+        .spacing(10)
+        .padding(Padding::from([5, 10]))
+        .into()
+    }
+
+    /// エクスポート先が他の人に変更された時の選択肢
+    fn export_conflict_view<'a>() -> Row<'a, FileMessage> {
+        row![
+            text("\u{F0026} Diverged: the export was changed since the last sync")
+                .shaping(Advanced)
+                .style(text::danger),
+            horizontal_space(),
+            button("Pull Theirs")
+                .on_press(FileMessage::ExportPull)
+                .style(button::secondary),
+            button("Keep Both")
+                .on_press(FileMessage::ExportKeepBoth)
+                .style(button::secondary),
+            button("Overwrite")
+                .on_press(FileMessage::ExportOverwrite)
+                .style(button::danger),
+        ]
+        .align_y(Center)
+        .spacing(10)
+        .padding(Padding::from([5, 10]))
+    }
+
+    /// バックアップディレクトリから復元したファイル一覧と、取り込み先の指定
+    fn view_adopt_list(&self) -> Column<'_, Message> {
+        let header = widget::row![
+            text(format!(
+                "\u{F0B7D} Adopt backup: {}",
+                self.adopt_backup_directory
+            ))
+            .shaping(Advanced)
+            .style(text::secondary),
+            horizontal_space(),
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        // 暗号化されたバックアップは、パスフレーズを入力するまで一覧を取得できない
+        let header = if is_encrypted_target(&self.adopt_backup_directory)
+            && self.adopt_encryption_key.is_none()
+        {
+            header.push(self.view_passphrase_input())
+        } else {
+            header
+        };
+
+        let adopt_bottom = widget::column![
+            horizontal_rule(0.5),
+            widget::row![
+                text(format!("{} files found", self.adopt_files.len())).style(text::secondary),
+                horizontal_space(),
+                button("Cancel")
+                    .on_press(Message::AdoptCancel)
+                    .style(button::secondary),
+                button("Adopt")
+                    .on_press(Message::AdoptSubmit)
+                    .style(button::primary),
+            ]
+            .width(Fill)
+            .align_y(Center)
+            .spacing(10),
+        ]
+        .spacing(10);
+
+        self.adopt_files
+            .iter()
+            .enumerate()
+            .fold(Column::new().push(header), |col, (index, file)| {
+                let adopt_row =
+                    Self::adopt_row_view(file).map(move |message| Message::Adopt(index, message));
+                col.push(adopt_row)
+            })
+            .push(adopt_bottom)
+            .spacing(10)
+            .width(Fill)
+            .align_x(Left)
+    }
+
+    fn adopt_row_view(file: &ScannedFile) -> Element<'_, AdoptMessage> {
+        let latest = file
+            .latest_version()
+            .map(format_local_time)
+            .unwrap_or_default();
+
+        row![widget::column![
+            widget::row![
+                text(&file.name),
+                horizontal_space(),
+                text(format!(
+                    "{} versions, latest {}",
+                    file.versions.len(),
+                    latest
+                ))
+                .style(text::secondary),
+            ],
+            widget::row![
+                button(text("\u{F0770}").shaping(Advanced))
+                    .padding(Padding::from([5, 10]))
+                    .on_press(AdoptMessage::SourceDirectoryOpen),
+                text_input("(source directory)", &file.source_directory)
                     .padding(Padding::from([5, 10]))
                     .style(text_input_style_by_status(
-                        file.export_path.is_empty() || file.export_valid
+                        file.source_directory.is_empty() || file.source_directory_valid()
                     ))
-                    .on_input(FileMessage::ExportPathInput)
-                    .on_submit(FileMessage::ExportPathSubmit)
+                    .on_input(AdoptMessage::SourceDirectoryInput),
+            ]
+            .align_y(Center)
+            .spacing(10),
+        ]
+        .spacing(5)]
+        .align_y(Center)
+        .spacing(10)
+        .padding(Padding::from([5, 10]))
+        .into()
+    }
+
+    /// バックアップ先ごとの前回のスクラブ結果
+    fn view_scrub_report(&self) -> Column<'_, Message> {
+        let header = widget::row![
+            text("\u{F0BE0} Scrub report")
+                .shaping(Advanced)
+                .style(text::secondary),
+            horizontal_space(),
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let report_bottom = widget::column![
+            horizontal_rule(0.5),
+            widget::row![
+                text(if self.scrub_reports.is_empty() {
+                    "No scrub has been run in this session".to_string()
+                } else {
+                    format!("{} backup directories", self.scrub_reports.len())
+                })
+                .style(text::secondary),
+                horizontal_space(),
+                button("Close")
+                    .on_press(Message::ScrubReportToggle)
+                    .style(button::secondary),
+            ]
+            .width(Fill)
+            .align_y(Center)
+            .spacing(10),
+        ]
+        .spacing(10);
+
+        self.scrub_reports
+            .iter()
+            .enumerate()
+            .fold(Column::new().push(header), |col, (index, report)| {
+                col.push(Self::scrub_report_row_view(index, report))
+            })
+            .push(report_bottom)
+            .spacing(10)
+            .width(Fill)
+            .align_x(Left)
+    }
+
+    /// スナップショットの一覧と、選んだスナップショットの中身または比較
+    fn view_snapshots(&self, current_directory: Option<&DirectoryInfo>) -> Column<'_, Message> {
+        let available = current_directory.is_some_and(is_snapshot_available);
+        let header = widget::row![
+            text(format!("\u{F0100} Snapshots: {}", self.current_directory))
+                .shaping(Advanced)
+                .style(text::secondary),
+            horizontal_space(),
+            // スナップショットの作成中は二重に開始しない
+            button(if self.snapshot_running {
+                "Taking snapshot..."
+            } else {
+                "Take Snapshot"
+            })
+            .on_press_maybe((available && !self.snapshot_running).then_some(Message::SnapshotTake))
+            .style(button::primary),
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let snapshots_bottom = widget::column![
+            horizontal_rule(0.5),
+            widget::row![
+                text(if available {
+                    format!("{} snapshots", self.snapshots.len())
+                } else {
+                    "Snapshots need an unencrypted local backup directory".to_string()
+                })
+                .style(text::secondary),
+                horizontal_space(),
+                button("Close")
+                    .on_press(Message::SnapshotsToggle)
+                    .style(button::secondary),
             ]
+            .width(Fill)
+            .align_y(Center)
+            .spacing(10),
+        ]
+        .spacing(10);
+
+        let selected = self
+            .snapshots
+            .iter()
+            .find(|s| Some(&s.id) == self.snapshot_selected.as_ref());
+        let compare = self
+            .snapshots
+            .iter()
+            .find(|s| Some(&s.id) == self.snapshot_compare.as_ref());
+        let detail = match (selected, compare) {
+            (Some(selected), Some(compare)) => Some(Self::view_snapshot_diff(selected, compare)),
+            (Some(selected), None) => Some(Self::view_snapshot_tree(selected)),
+            _ => None,
+        };
+
+        self.snapshots
+            .iter()
+            .fold(Column::new().push(header), |col, snapshot| {
+                col.push(self.snapshot_row_view(snapshot))
+            })
+            .push_maybe(detail)
+            .push(snapshots_bottom)
+            .spacing(10)
+            .width(Fill)
+            .align_x(Left)
+    }
+
+    fn snapshot_row_view<'a>(&self, snapshot: &'a Snapshot) -> Element<'a, Message> {
+        let is_selected = self.snapshot_selected.as_ref() == Some(&snapshot.id);
+        let summary = format!(
+            "{} files, {} unchanged, {} bytes",
+            snapshot.entries.len(),
+            snapshot.linked_count(),
+            snapshot.total_size()
+        );
+
+        // 削除は取り消せないため、確認してから行う
+        if self.snapshot_delete_pending.as_ref() == Some(&snapshot.id) {
+            return widget::row![
+                text(format!(
+                    "Delete the snapshot of {}?",
+                    format_local_time(&snapshot.time)
+                ))
+                .style(text::danger),
+                horizontal_space(),
+                button("Delete")
+                    .on_press(Message::SnapshotDeleteConfirm)
+                    .style(button::danger),
+                button("Cancel")
+                    .on_press(Message::SnapshotDeleteCancel)
+                    .style(button::secondary),
+            ]
+            .align_y(Center)
+            .spacing(10)
+            .padding(Padding::from([5, 10]))
+            .into();
+        }
+
+        widget::row![
+            text(format_local_time(&snapshot.time)).style(if is_selected {
+                text::primary
+            } else {
+                text::default
+            }),
+            horizontal_space(),
+            text(summary).style(text::secondary),
+            button("Browse")
+                .on_press(Message::SnapshotBrowse(snapshot.id.clone()))
+                .style(button::secondary),
+            // 中身を表示しているスナップショットと比べる
+            button("Compare")
+                .on_press_maybe(
+                    (self.snapshot_selected.is_some() && !is_selected)
+                        .then(|| Message::SnapshotCompare(snapshot.id.clone())),
+                )
+                .style(button::secondary),
+            button(text("\u{F0256}").shaping(Advanced))
+                .on_press(Message::SnapshotOpen(snapshot.id.clone()))
+                .style(button::secondary),
+            button(text("\u{F0A7A}").shaping(Advanced))
+                .on_press(Message::SnapshotDelete(snapshot.id.clone()))
+                .style(button::danger),
         ]
         .align_y(Center)
         .spacing(10)
@@ -162,7 +634,154 @@ impl App {
         .into()
     }
 
-    fn view_current_dir(&self) -> Row<Message> {
+    /// スナップショット内のファイルを、ディレクトリごとに字下げして並べる
+    fn view_snapshot_tree(snapshot: &Snapshot) -> Column<'_, Message> {
+        let mut tree: BTreeMap<Vec<&str>, Vec<&SnapshotEntry>> = BTreeMap::new();
+        for entry in &snapshot.entries {
+            let components: Vec<&str> = entry.name.split('/').collect();
+            let directory = components[..components.len() - 1].to_vec();
+            for depth in 0..directory.len() {
+                tree.entry(directory[..depth].to_vec()).or_default();
+            }
+            tree.entry(directory).or_default().push(entry);
+        }
+
+        tree.into_iter()
+            .fold(Column::new().spacing(5), |col, (directory, entries)| {
+                let indent = 20.0 * directory.len() as f32;
+                let col = match directory.last() {
+                    Some(name) => col.push(
+                        container(
+                            text(format!("\u{F024B} {}", name))
+                                .shaping(Advanced)
+                                .style(text::secondary),
+                        )
+                        .padding(Padding::ZERO.left(indent - 10.0)),
+                    ),
+                    None => col,
+                };
+
+                entries.into_iter().fold(col, |col, entry| {
+                    let name = entry.name.rsplit('/').next().unwrap_or(&entry.name);
+                    col.push(
+                        widget::row![
+                            text(name),
+                            horizontal_space(),
+                            text(format!("{} bytes", entry.size)).style(text::secondary),
+                            text(format_local_time(&entry.last_edited)).style(text::secondary),
+                        ]
+                        .spacing(10)
+                        .padding(Padding::ZERO.left(indent + 10.0).right(10.0)),
+                    )
+                })
+            })
+            .padding(Padding::from([5, 10]))
+    }
+
+    fn view_snapshot_diff<'a>(
+        selected: &'a Snapshot,
+        compare: &'a Snapshot,
+    ) -> Column<'a, Message> {
+        let (older, newer) = if selected.time <= compare.time {
+            (selected, compare)
+        } else {
+            (compare, selected)
+        };
+        let diff = compare_snapshots(older, newer);
+
+        let title = text(format!(
+            "{} -> {}: {} added, {} removed, {} modified, {} unchanged",
+            format_local_time(&older.time),
+            format_local_time(&newer.time),
+            diff.added.len(),
+            diff.removed.len(),
+            diff.modified.len(),
+            diff.unchanged
+        ))
+        .style(text::secondary);
+
+        [
+            ("\u{F0415}", diff.added),
+            ("\u{F0374}", diff.removed),
+            ("\u{F03EB}", diff.modified),
+        ]
+        .into_iter()
+        .flat_map(|(icon, names)| names.into_iter().map(move |name| (icon, name)))
+        .fold(Column::new().push(title).spacing(5), |col, (icon, name)| {
+            col.push(text(format!("{} {}", icon, name)).shaping(Advanced))
+        })
+        .padding(Padding::from([5, 10]))
+    }
+
+    fn scrub_report_row_view(index: usize, report: &ScrubReport) -> Element<'_, Message> {
+        let last_scrub = report
+            .last_scrub
+            .as_ref()
+            .map(format_local_time)
+            .unwrap_or("never".to_string());
+
+        let summary = if report.locked {
+            format!("locked, last scrub {}", last_scrub)
+        } else if let Some(error) = &report.error {
+            format!("not checked ({}), last scrub {}", error, last_scrub)
+        } else {
+            format!(
+                "{} checked, {} new, {} corrupted, last scrub {}",
+                report.checked,
+                report.indexed,
+                report.corrupted.len(),
+                last_scrub
+            )
+        };
+
+        let title = widget::row![
+            text(&report.backup_directory),
+            horizontal_space(),
+            text(summary).style(text::secondary),
+        ]
+        .align_y(Center)
+        .spacing(10);
+
+        // 他のバックアップ先に正しい内容があるものだけ修復できる
+        let title = if report.repairable.is_empty() {
+            title
+        } else {
+            title.push(
+                button("Repair")
+                    .on_press(Message::ScrubRepair(index))
+                    .style(button::danger),
+            )
+        };
+
+        let corrupted = report.corrupted.iter().fold(Column::new(), |col, version| {
+            let label = if report.repairable.contains(version) {
+                "corrupted (repairable)"
+            } else {
+                "corrupted"
+            };
+            col.push(text(format!("{}: {}", label, version)).style(text::danger))
+        });
+        let repaired = report.repaired.iter().fold(Column::new(), |col, version| {
+            col.push(text(format!("repaired: {}", version)).style(text::success))
+        });
+
+        row![widget::column![title, corrupted, repaired].spacing(5)]
+            .align_y(Center)
+            .padding(Padding::from([5, 10]))
+            .into()
+    }
+
+    fn view_passphrase_input(&self) -> Element<'_, Message> {
+        text_input("Passphrase", &self.passphrase_input)
+            .secure(true)
+            .width(200)
+            .padding(10)
+            .on_input(Message::PassphraseInput)
+            .on_submit(Message::PassphraseSubmit)
+            .into()
+    }
+
+    fn view_current_dir(&self) -> Row<'_, Message> {
         let open_directory_button = button(text("Current Directory".to_string()).align_x(Center))
             .width(200)
             .padding(10)
@@ -181,7 +800,7 @@ impl App {
             .padding(Padding::from([0, 20]))
     }
 
-    fn view_backup_dir(&self, current_directory: Option<&DirectoryInfo>) -> Row<Message> {
+    fn view_backup_dir(&self, current_directory: Option<&DirectoryInfo>) -> Row<'_, Message> {
         let open_directory_button = button(text("Backup Directory".to_string()).align_x(Center))
             .width(200)
             .padding(10)
@@ -193,7 +812,7 @@ impl App {
             &String::from("")
         };
 
-        let backup_dir_valid = is_valid_directory(&backup_dir);
+        let backup_dir_valid = is_valid_backup_directory(backup_dir);
         let directory_input = text_input("", backup_dir)
             .width(Fill)
             .padding(10)
@@ -201,9 +820,471 @@ impl App {
             .on_input(Message::BackupDirectoryInput)
             .on_submit(Message::BackupDirectorySubmit);
 
-        row![open_directory_button, directory_input]
+        let backup_mode = current_directory.map(|dir| dir.backup_mode);
+        let backup_mode_list =
+            pick_list(BackupMode::ALL, backup_mode, Message::BackupModeSelected).padding(10);
+
+        let mut backup_dir_row = row![open_directory_button, directory_input, backup_mode_list];
+        if let Some(dir) =
+            current_directory.filter(|d| d.backup_mode == BackupMode::Flat && !d.encrypted)
+        {
+            backup_dir_row = backup_dir_row.push(
+                pick_list(
+                    Compression::ALL,
+                    Some(dir.compression),
+                    Message::CompressionSelected,
+                )
+                .padding(10),
+            );
+
+            if let Some(level) = dir.compression.level() {
+                backup_dir_row = backup_dir_row.push(
+                    text_input("level", &level.to_string())
+                        .width(50)
+                        .padding(10)
+                        .on_input(Message::CompressionLevelInput),
+                );
+            }
+        }
+
+        // パリティは手元に保存したファイルごとに作るため、ローカルの Flat のときだけ選べる
+        if let Some(dir) = current_directory.filter(|d| {
+            d.backup_mode == BackupMode::Flat && !is_remote_backup_directory(&d.backup_directory)
+        }) {
+            backup_dir_row = backup_dir_row.push(
+                pick_list(Parity::ALL, Some(dir.parity), Message::ParitySelected).padding(10),
+            );
+
+            if dir.parity != Parity::None {
+                backup_dir_row = backup_dir_row.push(
+                    button(text("\u{F0AD4}").shaping(Advanced))
+                        .padding(10)
+                        .on_press(Message::ParityRepair)
+                        .style(button::secondary),
+                );
+            }
+        }
+
+        if let Some(dir) = current_directory.filter(|d| {
+            d.backup_mode == BackupMode::Git && !is_remote_backup_directory(&d.backup_directory)
+        }) {
+            backup_dir_row = backup_dir_row.push(
+                text_input("push to (optional)", &dir.remote_options.git_remote)
+                    .width(200)
+                    .padding(10)
+                    .on_input(Message::GitRemoteInput),
+            );
+        }
+
+        if let Some(dir) = current_directory {
+            backup_dir_row = backup_dir_row.push(
+                widget::toggler(dir.encrypted)
+                    .label("Encrypt")
+                    .on_toggle(Message::EncryptionToggled)
+                    .width(Length::Shrink),
+            );
+
+            if dir.encrypted && dir.encryption_key.is_none() {
+                backup_dir_row = backup_dir_row.push(self.view_passphrase_input());
+                // 鍵をまだ作っていないバックアップ先では、パスフレーズを 2 回入力してもらう
+                if !dir.key_file_exists {
+                    backup_dir_row = backup_dir_row.push(
+                        text_input("Confirm Passphrase", &self.passphrase_confirm_input)
+                            .secure(true)
+                            .width(200)
+                            .padding(10)
+                            .on_input(Message::PassphraseConfirmInput)
+                            .on_submit(Message::PassphraseSubmit),
+                    );
+                }
+            } else if dir.encrypted {
+                backup_dir_row = backup_dir_row.push(
+                    button(text("\u{F033E}").shaping(Advanced))
+                        .padding(10)
+                        .on_press(Message::LockBackup)
+                        .style(button::secondary),
+                );
+            }
+        }
+
+        if let Some(dir) = current_directory {
+            let keep_versions = match dir.keep_versions {
+                0 => String::new(),
+                keep => keep.to_string(),
+            };
+            backup_dir_row = backup_dir_row
+                .push(
+                    text_input("keep all", &keep_versions)
+                        .width(80)
+                        .padding(10)
+                        .on_input(Message::KeepVersionsInput),
+                )
+                .push(
+                    // 整理中は二重に開始しない
+                    button(if self.prune_running {
+                        "Pruning..."
+                    } else {
+                        "Prune"
+                    })
+                    .padding(10)
+                    .on_press_maybe(
+                        (!self.prune_running && dir.keep_versions > 0)
+                            .then_some(Message::PruneStart),
+                    )
+                    .style(button::secondary),
+                );
+
+            // 空欄は 0 (定期スクラブをしない、読み込みを制限しない) とみなす
+            let scrub_interval = match dir.scrub_interval_days {
+                0 => String::new(),
+                days => days.to_string(),
+            };
+            let scrub_speed_limit = match dir.scrub_speed_limit {
+                0 => String::new(),
+                limit => limit.to_string(),
+            };
+            backup_dir_row = backup_dir_row
+                .push(
+                    text_input("no scrub", &scrub_interval)
+                        .width(80)
+                        .padding(10)
+                        .on_input(Message::ScrubIntervalInput),
+                )
+                .push(text("days"))
+                .push(
+                    text_input("no limit", &scrub_speed_limit)
+                        .width(80)
+                        .padding(10)
+                        .on_input(Message::ScrubSpeedLimitInput),
+                )
+                .push(text("MiB/s"));
+        }
+
+        if backup_mode == Some(BackupMode::Chunked) {
+            backup_dir_row = backup_dir_row.push(
+                button(text("\u{F00E2}").shaping(Advanced))
+                    .padding(10)
+                    .on_press_maybe((!self.prune_running).then_some(Message::CollectGarbage))
+                    .style(button::secondary),
+            );
+        }
+
+        backup_dir_row
+            .align_y(Center)
+            .spacing(10)
+            .padding(Padding::from([0, 20]))
+    }
+
+    fn view_replica_dir(&self, current_directory: Option<&DirectoryInfo>) -> Row<'_, Message> {
+        let open_directory_button = button(text("Replica Directory".to_string()).align_x(Center))
+            .width(200)
+            .padding(10)
+            .on_press(Message::ReplicaDirectoryOpen);
+
+        let replica_dir = current_directory
+            .map(|dir| dir.replica_directory.as_str())
+            .unwrap_or_default();
+
+        let directory_input = text_input("(optional)", replica_dir)
+            .width(Fill)
+            .padding(10)
+            .style(text_input_style_by_status(
+                replica_dir.is_empty() || is_valid_backup_directory(replica_dir),
+            ))
+            .on_input(Message::ReplicaDirectoryInput);
+
+        // 複製中は二重に開始しない
+        let replicate_button = button(if self.replication_running {
+            "Replicating..."
+        } else {
+            "Replicate"
+        })
+        .padding(10)
+        .on_press_maybe(
+            (!self.replication_running && !replica_dir.is_empty())
+                .then_some(Message::ReplicationStart),
+        )
+        .style(button::secondary);
+
+        row![open_directory_button, directory_input, replicate_button]
+            .align_y(Center)
+            .spacing(10)
+            .padding(Padding::from([0, 20]))
+    }
+
+    /// バックアップ先、複製先、エクスポート先が使う接続先の種類ごとに 1 行ずつ
+    fn view_remote_options(
+        current_directory: Option<&DirectoryInfo>,
+    ) -> Option<Column<'_, Message>> {
+        let dir = current_directory.filter(|dir| dir.uses_sftp() || dir.uses_s3())?;
+        let mut rows = Column::new().spacing(20);
+
+        if dir.uses_sftp() {
+            let sftp = &dir.remote_options.sftp;
+            rows = rows.push(
+                row![
+                    text("SFTP Connection").width(200).align_x(Center),
+                    text_input("SSH key (default keys)", &sftp.key)
+                        .width(Fill)
+                        .padding(10)
+                        .on_input(Message::SshKeyInput),
+                    text_input("known_hosts (~/.ssh/known_hosts)", &sftp.known_hosts)
+                        .width(Fill)
+                        .padding(10)
+                        .on_input(Message::KnownHostsInput),
+                ]
+                .align_y(Center)
+                .spacing(10),
+            );
+        }
+
+        if dir.uses_s3() {
+            let s3 = &dir.remote_options.s3;
+            let retention_days = match s3.retention_days {
+                0 => String::new(),
+                days => days.to_string(),
+            };
+            rows = rows.push(
+                row![
+                    text("S3 Connection").width(200).align_x(Center),
+                    text_input("endpoint (AWS or profile)", &s3.endpoint)
+                        .width(Fill)
+                        .padding(10)
+                        .on_input(Message::S3EndpointInput),
+                    text("Object Lock"),
+                    text_input("no lock", &retention_days)
+                        .width(80)
+                        .padding(10)
+                        .on_input(Message::S3RetentionDaysInput),
+                    text("days"),
+                    pick_list(
+                        RetentionMode::ALL,
+                        Some(s3.retention_mode),
+                        Message::S3RetentionModeSelected,
+                    )
+                    .padding(10),
+                ]
+                .align_y(Center)
+                .spacing(10),
+            );
+        }
+
+        Some(rows.padding(Padding::from([0, 20])))
+    }
+
+    fn view_mirror_dir(&self, current_directory: Option<&DirectoryInfo>) -> Row<'_, Message> {
+        let open_directory_button = button(text("Mirror Directory".to_string()).align_x(Center))
+            .width(200)
+            .padding(10)
+            .on_press(Message::MirrorDirectoryOpen);
+
+        let mirror_dir = current_directory
+            .map(|dir| dir.mirror_directory.as_str())
+            .unwrap_or_default();
+        let mirror_deletions = current_directory.is_some_and(|dir| dir.mirror_deletions);
+
+        let mirror_valid = current_directory.is_some_and(|dir| check_mirror_directory(dir).is_ok());
+        let directory_input = text_input("(optional)", mirror_dir)
+            .width(Fill)
+            .padding(10)
+            .style(text_input_style_by_status(
+                mirror_dir.is_empty() || mirror_valid,
+            ))
+            .on_input(Message::MirrorDirectoryInput);
+
+        let deletions_toggle = widget::toggler(mirror_deletions)
+            .label("Propagate deletions")
+            .on_toggle(Message::MirrorDeletionsToggled)
+            .width(Length::Shrink);
+
+        let preview_button = button("Preview")
+            .padding(10)
+            .on_press_maybe(mirror_valid.then_some(Message::MirrorPreview))
+            .style(button::secondary);
+
+        // ミラー中は二重に開始しない
+        let mirror_button = button(if self.mirror_running {
+            "Mirroring..."
+        } else {
+            "Mirror"
+        })
+        .padding(10)
+        .on_press_maybe((mirror_valid && !self.mirror_running).then_some(Message::MirrorStart))
+        .style(button::secondary);
+
+        row![
+            open_directory_button,
+            directory_input,
+            deletions_toggle,
+            preview_button,
+            mirror_button
+        ]
+        .align_y(Center)
+        .spacing(10)
+        .padding(Padding::from([0, 20]))
+    }
+
+    /// ミラーを実行する前の差分 (dry-run)
+    fn view_mirror_plan<'a>(&self, plan: &'a MirrorPlan) -> Column<'a, Message> {
+        let header = widget::row![
+            text(format!(
+                "\u{F1045} Mirror preview: {} -> {}",
+                plan.source_directory, plan.mirror_directory
+            ))
+            .shaping(Advanced)
+            .style(text::secondary),
+            horizontal_space(),
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let deleted_icon = if plan.propagate_deletions {
+            "\u{F0A7A}"
+        } else {
+            "\u{F0209}"
+        };
+        let rows = [
+            ("\u{F0415}", &plan.added),
+            ("\u{F03EB}", &plan.changed),
+            (deleted_icon, &plan.deleted),
+        ]
+        .into_iter()
+        .flat_map(|(icon, names)| names.iter().map(move |name| (icon, name)))
+        .fold(Column::new().spacing(5), |col, (icon, name)| {
+            col.push(text(format!("{} {}", icon, name)).shaping(Advanced))
+        })
+        .padding(Padding::from([5, 10]));
+
+        let deleted_summary = if plan.propagate_deletions {
+            format!("{} moved to trash", plan.deleted.len())
+        } else {
+            format!("{} only in mirror (kept)", plan.deleted.len())
+        };
+        let plan_bottom = widget::column![
+            horizontal_rule(0.5),
+            widget::row![
+                text(format!(
+                    "{} new, {} changed, {}, {} unchanged",
+                    plan.added.len(),
+                    plan.changed.len(),
+                    deleted_summary,
+                    plan.unchanged
+                ))
+                .style(text::secondary),
+                horizontal_space(),
+                button("Cancel")
+                    .on_press(Message::MirrorPlanClose)
+                    .style(button::secondary),
+                button("Run Mirror")
+                    .on_press_maybe(
+                        (!plan.is_empty() && !self.mirror_running).then_some(Message::MirrorStart)
+                    )
+                    .style(button::primary),
+            ]
+            .width(Fill)
+            .align_y(Center)
+            .spacing(10),
+        ]
+        .spacing(10);
+
+        widget::column![header, rows, plan_bottom]
+            .spacing(10)
+            .width(Fill)
+            .align_x(Left)
+    }
+
+    fn view_metadata_options<'a>(current_directory: Option<&DirectoryInfo>) -> Row<'a, Message> {
+        let options = current_directory
+            .map(|dir| dir.metadata_options)
+            .unwrap_or_default();
+        let make_toggle =
+            |label: &'static str,
+             value: bool,
+             update: fn(MetadataOptions, bool) -> MetadataOptions| {
+                widget::toggler(value)
+                    .label(label)
+                    .on_toggle_maybe(current_directory.is_some().then_some(move |value| {
+                        Message::MetadataOptionsChanged(update(options, value))
+                    }))
+                    .width(Length::Shrink)
+            };
+
+        row![
+            text("Preserve Metadata").width(200).align_x(Center),
+            make_toggle("Times", options.times, |o, times| MetadataOptions {
+                times,
+                ..o
+            }),
+            make_toggle("Permissions", options.permissions, |o, permissions| {
+                MetadataOptions { permissions, ..o }
+            }),
+            make_toggle("Owner (as root)", options.ownership, |o, ownership| {
+                MetadataOptions { ownership, ..o }
+            }),
+            make_toggle("Extended Attributes", options.xattrs, |o, xattrs| {
+                MetadataOptions { xattrs, ..o }
+            }),
+        ]
+        .align_y(Center)
+        .spacing(20)
+        .padding(Padding::from([0, 20]))
+    }
+
+    fn view_track_rules(&self, current_directory: Option<&DirectoryInfo>) -> Row<'_, Message> {
+        let label = text("Track Directory".to_string())
+            .width(200)
+            .align_x(Center);
+
+        let (include, exclude) = current_directory
+            .map(|dir| {
+                (
+                    dir.track_rules.include.as_str(),
+                    dir.track_rules.exclude.as_str(),
+                )
+            })
+            .unwrap_or_default();
+
+        let include_input = text_input("Include (e.g. **/*.docx)", include)
+            .width(Fill)
+            .padding(10)
+            .on_input(Message::TrackIncludeInput)
+            .on_submit(Message::TrackRulesSubmit);
+        let exclude_input = text_input("Exclude (e.g. ~$*)", exclude)
+            .width(Fill)
+            .padding(10)
+            .on_input(Message::TrackExcludeInput)
+            .on_submit(Message::TrackRulesSubmit);
+
+        row![label, include_input, exclude_input]
             .align_y(Center)
             .spacing(10)
             .padding(Padding::from([0, 20]))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn file_tree_groups_by_directory() {
+        let files: Vec<FileInfo> = ["a.txt", "docs/ch/1.tex", "b.txt", "docs/x.md"]
+            .iter()
+            .map(|name| FileInfo::new(name.to_string(), Utc::now(), String::new()))
+            .collect();
+
+        let tree: Vec<(Vec<&str>, Vec<usize>)> = file_tree(&files)
+            .into_iter()
+            .map(|(directory, files)| (directory, files.iter().map(|(i, _)| *i).collect()))
+            .collect();
+        assert_eq!(
+            tree,
+            [
+                (vec![], vec![0, 2]),
+                (vec!["docs"], vec![3]),
+                (vec!["docs", "ch"], vec![1]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,422 @@
+use crate::atomic_file::write_atomically;
+use crate::backup_scan::{encode_name, parse_backup_filename};
+use crate::backup_store::{missing_as_none, BackupStore};
+use crate::user_data::{append_path, is_valid_directory, is_valid_file};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::UNIX_EPOCH;
+
+/// バージョンごとの、アーカイブ内の位置の記録
+/// アーカイブを読まずに一覧や読み出しができるようにする
+pub const ARCHIVE_INDEX_FILENAME: &str = ".dd-backup-archive-index.yaml";
+
+const ARCHIVE_SUFFIX: &str = ".tar";
+const TEMPORARY_SUFFIX: &str = ".tmp";
+
+const TAR_BLOCK_SIZE: u64 = 512;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct ArchiveEntry {
+    archive: String,
+    /// アーカイブの先頭から、内容の先頭までのバイト数
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ArchiveIndex {
+    entries: BTreeMap<String, ArchiveEntry>,
+}
+
+/// バージョン名から、そのバージョンを入れるアーカイブのファイル名を決める
+fn archive_filename(backup_filename: &str) -> String {
+    let name = parse_backup_filename(backup_filename)
+        .map(|(_, name)| encode_name(&name))
+        .unwrap_or_else(|| backup_filename.to_string());
+    format!("{}{}", name, ARCHIVE_SUFFIX)
+}
+
+/// アーカイブ内のすべてのバージョンと、その位置を読む
+/// 追記の途中で止まったアーカイブでも、その手前までのバージョンは返す
+fn scan_archive(path: &str, archive: &str) -> io::Result<Vec<(String, ArchiveEntry)>> {
+    let file = fs::File::open(path)?;
+    let length = file.metadata()?.len();
+    let mut tar = tar::Archive::new(file);
+    let mut entries = Vec::new();
+    for entry in tar.entries()? {
+        let Ok(entry) = entry else {
+            break;
+        };
+        let offset = entry.raw_file_position();
+        let size = entry.size();
+        if offset + size > length {
+            break;
+        }
+
+        let backup_filename = entry.path()?.to_string_lossy().to_string();
+        entries.push((
+            backup_filename,
+            ArchiveEntry {
+                archive: archive.to_string(),
+                offset,
+                size,
+            },
+        ));
+    }
+
+    Ok(entries)
+}
+
+/// tracked file ごとに 1 つの tar へ、すべてのバージョンを追記する方式
+/// <backup_directory>/<name>.tar の中に、`backup_filename` の名前で各バージョンが入る
+/// 小さなファイルが大量に並ぶと遅くなる FAT/exFAT のドライブ向け
+pub struct ArchiveStore {
+    directory: String,
+}
+
+impl ArchiveStore {
+    pub fn new(directory: &str) -> Self {
+        ArchiveStore {
+            directory: directory.to_string(),
+        }
+    }
+
+    fn archive_path(&self, archive: &str) -> String {
+        append_path(&self.directory, archive)
+    }
+
+    /// 記録が無い場合は、アーカイブを読んで作り直す
+    fn load_index(&self) -> ArchiveIndex {
+        let loaded = self
+            .read_index(ARCHIVE_INDEX_FILENAME)
+            .and_then(|content| serde_yaml::from_str(&content).ok());
+        match loaded {
+            Some(index) => index,
+            None => self.rebuild_index(),
+        }
+    }
+
+    fn save_index(&self, index: &ArchiveIndex) -> io::Result<()> {
+        let yaml = serde_yaml::to_string(index).map_err(io::Error::other)?;
+        self.write_index(ARCHIVE_INDEX_FILENAME, &yaml)
+    }
+
+    fn rebuild_index(&self) -> ArchiveIndex {
+        let mut index = ArchiveIndex::default();
+        let Ok(read_dir) = fs::read_dir(&self.directory) else {
+            return index;
+        };
+
+        for dir_entry in read_dir.flatten() {
+            let archive = dir_entry.file_name().to_string_lossy().to_string();
+            if archive.starts_with('.') || !archive.ends_with(ARCHIVE_SUFFIX) {
+                continue;
+            }
+
+            if let Ok(entries) = scan_archive(&self.archive_path(&archive), &archive) {
+                index.entries.extend(entries);
+            }
+        }
+
+        index
+    }
+
+    /// 記録にある最後のバージョンの直後を返す
+    /// 終端の 0 や、追記の途中で止まった書きかけの部分はここから上書きする
+    fn append_position(index: &ArchiveIndex, archive: &str, length: u64) -> io::Result<u64> {
+        let end = index
+            .entries
+            .values()
+            .filter(|entry| entry.archive == archive)
+            .map(|entry| entry.offset + entry.size.next_multiple_of(TAR_BLOCK_SIZE))
+            .max()
+            .unwrap_or(0);
+        if end > length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive is shorter than its index",
+            ));
+        }
+
+        Ok(end)
+    }
+
+    fn header_for(source_path: &str, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        let mtime = fs::metadata(source_path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        header.set_mtime(mtime);
+        header
+    }
+}
+
+impl BackupStore for ArchiveStore {
+    fn is_available(&self) -> bool {
+        is_valid_directory(&self.directory)
+    }
+
+    fn list_versions(&self) -> Vec<String> {
+        self.load_index().entries.into_keys().collect()
+    }
+
+    fn has_version(&self, backup_filename: &str) -> bool {
+        self.load_index().entries.contains_key(backup_filename)
+    }
+
+    fn write_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()> {
+        let mut index = self.load_index();
+        let archive = archive_filename(backup_filename);
+
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.archive_path(&archive))?;
+        let position = Self::append_position(&index, &archive, file.metadata()?.len())?;
+        file.set_len(position)?;
+        file.seek(SeekFrom::Start(position))?;
+
+        let source = fs::File::open(source_path)?;
+        let size = source.metadata()?.len();
+        let mut header = Self::header_for(source_path, size);
+
+        let mut builder = tar::Builder::new(file);
+        builder.append_data(&mut header, backup_filename, source)?;
+        // 長い名前は拡張ヘッダーが入るため、書き終えた位置から内容の先頭を求める
+        let end = builder.get_mut().stream_position()?;
+        let offset = end - size.next_multiple_of(TAR_BLOCK_SIZE);
+        let mut file = builder.into_inner()?;
+        file.flush()?;
+        file.sync_all()?;
+
+        // 記録はアーカイブに書き終えてから更新する
+        index.entries.insert(
+            backup_filename.to_string(),
+            ArchiveEntry {
+                archive,
+                offset,
+                size,
+            },
+        );
+        self.save_index(&index)
+    }
+
+    fn read_version(&self, backup_filename: &str, dest_path: &str) -> io::Result<()> {
+        let index = self.load_index();
+        let entry = index
+            .entries
+            .get(backup_filename)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, backup_filename))?;
+
+        let mut archive = fs::File::open(self.archive_path(&entry.archive))?;
+        archive.seek(SeekFrom::Start(entry.offset))?;
+        let mut dest = fs::File::create(dest_path)?;
+        let copied = io::copy(&mut archive.take(entry.size), &mut dest)?;
+        if copied != entry.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                backup_filename,
+            ));
+        }
+
+        dest.flush()
+    }
+
+    /// tar からは取り除けないため、残すバージョンだけでアーカイブを作り直す
+    fn remove_version(&self, backup_filename: &str) -> io::Result<()> {
+        let mut index = self.load_index();
+        let Some(removed) = index.entries.remove(backup_filename) else {
+            return Ok(());
+        };
+
+        let archive_path = self.archive_path(&removed.archive);
+        let temporary_path = format!("{}{}", archive_path, TEMPORARY_SUFFIX);
+        let mut remaining = 0;
+        {
+            let mut source = tar::Archive::new(fs::File::open(&archive_path)?);
+            let mut builder = tar::Builder::new(fs::File::create(&temporary_path)?);
+            for entry in source.entries()? {
+                let entry = entry?;
+                let name = entry.path()?.to_string_lossy().to_string();
+                if name == backup_filename {
+                    continue;
+                }
+
+                let mut header = entry.header().clone();
+                builder.append_data(&mut header, &name, entry)?;
+                remaining += 1;
+            }
+            builder.into_inner()?.sync_all()?;
+        }
+
+        if remaining == 0 {
+            fs::remove_file(&temporary_path)?;
+            fs::remove_file(&archive_path)?;
+        } else {
+            fs::rename(&temporary_path, &archive_path)?;
+        }
+
+        // 作り直したアーカイブでは位置が変わるため、読み直して記録する
+        index
+            .entries
+            .retain(|_, entry| entry.archive != removed.archive);
+        if is_valid_file(&archive_path) {
+            index
+                .entries
+                .extend(scan_archive(&archive_path, &removed.archive)?);
+        }
+        self.save_index(&index)
+    }
+
+    fn load_index(&self, index_name: &str) -> io::Result<Option<String>> {
+        missing_as_none(fs::read_to_string(append_path(&self.directory, index_name)))
+    }
+
+    fn write_index(&self, index_name: &str, content: &str) -> io::Result<()> {
+        write_atomically(&append_path(&self.directory, index_name), content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST: &str = "2024-05-06T07-08-09.000000001Z_a.txt";
+    const SECOND: &str = "2024-05-07T07-08-09.000000001Z_a.txt";
+
+    struct TestArchive {
+        _dir: tempfile::TempDir,
+        directory: String,
+        source: String,
+        dest: String,
+    }
+
+    impl TestArchive {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().to_string_lossy().to_string();
+            let directory = append_path(&root, "backup");
+            fs::create_dir(&directory).unwrap();
+            TestArchive {
+                source: append_path(&root, "source"),
+                dest: append_path(&root, "dest"),
+                directory,
+                _dir: dir,
+            }
+        }
+
+        fn write(&self, store: &ArchiveStore, backup_filename: &str, content: &str) {
+            fs::write(&self.source, content).unwrap();
+            store.write_version(&self.source, backup_filename).unwrap();
+        }
+
+        fn read(&self, store: &ArchiveStore, backup_filename: &str) -> String {
+            store.read_version(backup_filename, &self.dest).unwrap();
+            fs::read_to_string(&self.dest).unwrap()
+        }
+
+        fn archive_path(&self) -> String {
+            append_path(&self.directory, &archive_filename(FIRST))
+        }
+
+        /// 次のバージョンの追記が途中で止まり、終端の 0 が無くなった状態にする
+        fn interrupt_append(&self) {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .open(self.archive_path())
+                .unwrap();
+            let length = file.metadata().unwrap().len();
+            file.set_len(length - 2 * TAR_BLOCK_SIZE).unwrap();
+            file.seek(SeekFrom::End(0)).unwrap();
+            file.write_all(&[b'x'; 700]).unwrap();
+        }
+
+        /// tar として読んだ時のバージョン名
+        fn tar_names(&self) -> Vec<String> {
+            let mut tar = tar::Archive::new(fs::File::open(self.archive_path()).unwrap());
+            tar.entries()
+                .unwrap()
+                .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().to_string())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn versions_share_one_archive() {
+        let test = TestArchive::new();
+        let store = ArchiveStore::new(&test.directory);
+        test.write(&store, FIRST, "first");
+        test.write(&store, SECOND, "second");
+
+        assert_eq!(store.list_versions(), [FIRST, SECOND]);
+        assert_eq!(test.read(&store, FIRST), "first");
+        assert_eq!(test.read(&store, SECOND), "second");
+        assert_eq!(test.tar_names(), [FIRST, SECOND]);
+    }
+
+    #[test]
+    fn interrupted_append_is_recovered() {
+        let test = TestArchive::new();
+        let store = ArchiveStore::new(&test.directory);
+        test.write(&store, FIRST, "first");
+        test.interrupt_append();
+
+        test.write(&store, SECOND, "second");
+        assert_eq!(test.read(&store, FIRST), "first");
+        assert_eq!(test.read(&store, SECOND), "second");
+        assert_eq!(test.tar_names(), [FIRST, SECOND]);
+    }
+
+    #[test]
+    fn interrupted_append_is_recovered_without_index() {
+        let test = TestArchive::new();
+        let store = ArchiveStore::new(&test.directory);
+        test.write(&store, FIRST, "first");
+        test.interrupt_append();
+        fs::remove_file(append_path(&test.directory, ARCHIVE_INDEX_FILENAME)).unwrap();
+
+        assert_eq!(store.list_versions(), [FIRST]);
+        test.write(&store, SECOND, "second");
+        assert_eq!(test.read(&store, FIRST), "first");
+        assert_eq!(test.tar_names(), [FIRST, SECOND]);
+    }
+
+    #[test]
+    fn archive_shorter_than_index_is_rejected() {
+        let test = TestArchive::new();
+        let store = ArchiveStore::new(&test.directory);
+        test.write(&store, FIRST, "first");
+        fs::File::create(test.archive_path()).unwrap();
+
+        fs::write(&test.source, "second").unwrap();
+        let error = store.write_version(&test.source, SECOND).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn remove_rewrites_archive() {
+        let test = TestArchive::new();
+        let store = ArchiveStore::new(&test.directory);
+        test.write(&store, FIRST, "first");
+        test.write(&store, SECOND, "second");
+
+        store.remove_version(FIRST).unwrap();
+        assert_eq!(store.list_versions(), [SECOND]);
+        assert_eq!(test.read(&store, SECOND), "second");
+        assert_eq!(test.tar_names(), [SECOND]);
+
+        store.remove_version(SECOND).unwrap();
+        assert!(store.list_versions().is_empty());
+        assert!(!is_valid_file(&test.archive_path()));
+    }
+}
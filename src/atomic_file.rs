@@ -0,0 +1,282 @@
+use crate::user_data::{append_path, get_parent_path, DirectoryInfo};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// 書き込み中のファイルに付ける接尾辞
+/// 書き終えるまでは `.<ファイル名>.<プロセス ID>.dd-backup-tmp` という隠しファイルにしておく
+pub const TEMP_SUFFIX: &str = ".dd-backup-tmp";
+
+/// これより新しい一時ファイルは、他のプロセスが書き込み中かもしれないため消さない
+const TEMP_FILE_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// path と同じディレクトリに置く、書き込み中の名前
+fn temp_path(path: &str) -> String {
+    let file_name = Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    append_path(
+        &get_parent_path(&path.to_string()),
+        &format!(".{}.{}{}", file_name, std::process::id(), TEMP_SUFFIX),
+    )
+}
+
+/// temp_path で付けた名前か (`.<ファイル名>.<プロセス ID>.dd-backup-tmp`)
+pub fn is_temp_file(name: &str) -> bool {
+    let Some(stem) = name
+        .strip_prefix('.')
+        .and_then(|name| name.strip_suffix(TEMP_SUFFIX))
+    else {
+        return false;
+    };
+
+    stem.rsplit_once('.').is_some_and(|(file_name, pid)| {
+        !file_name.is_empty() && !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit())
+    })
+}
+
+/// write に一時ファイルのパスを渡して書かせ、ディスクへ書き出してから path へ名前を変える
+/// 途中で失敗しても中断されても、path に書きかけの内容が見えることはない
+pub fn replace_atomically(
+    path: &str,
+    write: impl FnOnce(&str) -> io::Result<()>,
+) -> io::Result<()> {
+    let temp = temp_path(path);
+    let result = write(&temp)
+        .and_then(|_| fs::OpenOptions::new().write(true).open(&temp))
+        .and_then(|file| file.sync_all())
+        .and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+
+    result
+}
+
+/// fs::copy の代わりに使う
+pub fn copy_atomically(source_path: &str, dest_path: &str) -> io::Result<()> {
+    replace_atomically(dest_path, |temp| fs::copy(source_path, temp).map(|_| ()))
+}
+
+/// fs::write の代わりに使う
+pub fn write_atomically(path: &str, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    replace_atomically(path, |temp| fs::write(temp, contents))
+}
+
+/// 他のユーザーからは中を見られない一時ディレクトリ
+/// 復号した内容など、共有の一時ディレクトリに直接置けないファイルはこの中に作る
+/// drop した時に中身ごと削除する
+pub struct PrivateTempDir {
+    path: String,
+}
+
+impl PrivateTempDir {
+    /// 推測できない名前で新しく作る (同じ名前が既にあれば失敗する)
+    pub fn new(label: &str) -> io::Result<Self> {
+        let mut id = [0u8; 16];
+        getrandom::fill(&mut id).map_err(|_| io::Error::other("failed to get random bytes"))?;
+        let path = env::temp_dir()
+            .join(format!("dd-backup-{}-{}", label, hex::encode(id)))
+            .display()
+            .to_string();
+
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&path)?;
+        Ok(PrivateTempDir { path })
+    }
+
+    pub fn file(&self, name: &str) -> String {
+        append_path(&self.path, name)
+    }
+}
+
+impl Drop for PrivateTempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+/// 中断された書き込みの一時ファイルのうち、now より TEMP_FILE_MIN_AGE 以上前のものを削除し、削除した数を返す
+/// シンボリックリンクの先のディレクトリには入らない
+fn remove_temp_files_in(directory: &str, recursive: bool, now: SystemTime) -> usize {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return 0;
+    };
+
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+
+        let path = entry.path();
+        if file_type.is_dir() {
+            if recursive {
+                removed += remove_temp_files_in(&path.to_string_lossy(), true, now);
+            }
+            continue;
+        }
+
+        let old_enough = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| {
+                now.duration_since(modified)
+                    .is_ok_and(|age| age >= TEMP_FILE_MIN_AGE)
+            });
+        if file_type.is_file()
+            && is_temp_file(&entry.file_name().to_string_lossy())
+            && old_enough
+            && fs::remove_file(&path).is_ok()
+        {
+            removed += 1;
+        }
+    }
+
+    removed
+}
+
+/// 起動時に、前回中断された書き込みの一時ファイルを片付ける
+/// バックアップ先とミラー先は中まで、ソースとエクスポート先はファイルのあるディレクトリだけを探す
+pub fn remove_temp_files(directories: &[DirectoryInfo]) -> usize {
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for dir in directories {
+        removed += remove_temp_files_in(&dir.backup_directory, true, now);
+        removed += remove_temp_files_in(&dir.mirror_directory, true, now);
+
+        let mut parents: Vec<String> = dir
+            .files
+            .iter()
+            .flat_map(|file| {
+                [
+                    get_parent_path(&append_path(&dir.path, &file.name)),
+                    get_parent_path(&append_path(&file.export_path, &file.name)),
+                    get_parent_path(&file.export_path),
+                ]
+            })
+            .filter(|parent| !parent.is_empty())
+            .collect();
+        parents.sort();
+        parents.dedup();
+        removed += parents
+            .iter()
+            .map(|parent| remove_temp_files_in(parent, false, now))
+            .sum::<usize>();
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_names(directory: &str) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// 更新日時を age だけ前にした一時ファイルを作る
+    fn temp_file(directory: &str, name: &str, age: Duration) -> String {
+        let path = append_path(directory, name);
+        fs::File::create(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn temp_file_names_follow_the_pattern() {
+        assert!(is_temp_file(".a.txt.1234.dd-backup-tmp"));
+        assert!(is_temp_file(
+            &Path::new(&temp_path("/x/b"))
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+        ));
+        assert!(!is_temp_file("a.txt.1234.dd-backup-tmp"));
+        assert!(!is_temp_file(".a.txt.dd-backup-tmp"));
+        assert!(!is_temp_file(".1234.dd-backup-tmp"));
+        assert!(!is_temp_file(".a.txt.12x4.dd-backup-tmp"));
+    }
+
+    #[test]
+    fn failed_write_leaves_no_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().to_string_lossy().to_string();
+        let path = append_path(&directory, "a.txt");
+        fs::write(&path, "old").unwrap();
+
+        let result = replace_atomically(&path, |temp| {
+            fs::write(temp, "half")?;
+            Err(io::Error::other("interrupted"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(file_names(&directory), ["a.txt"]);
+    }
+
+    #[test]
+    fn failed_rename_leaves_no_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().to_string_lossy().to_string();
+        // ディレクトリの上には名前を変えられない
+        let path = append_path(&directory, "a.txt");
+        fs::create_dir_all(append_path(&path, "inside")).unwrap();
+
+        assert!(write_atomically(&path, "new").is_err());
+        assert_eq!(file_names(&directory), ["a.txt"]);
+    }
+
+    #[test]
+    fn only_old_temp_files_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().to_string_lossy().to_string();
+        let hour = Duration::from_secs(60 * 60);
+        temp_file(&directory, ".a.txt.1234.dd-backup-tmp", 2 * hour);
+        temp_file(&directory, ".b.txt.1234.dd-backup-tmp", Duration::ZERO);
+        temp_file(&directory, "notes.dd-backup-tmp", 2 * hour);
+
+        assert_eq!(
+            remove_temp_files_in(&directory, false, SystemTime::now()),
+            1
+        );
+        assert_eq!(
+            file_names(&directory),
+            [".b.txt.1234.dd-backup-tmp", "notes.dd-backup-tmp"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories_are_not_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let directory = dir.path().to_string_lossy().to_string();
+        let outside_directory = outside.path().to_string_lossy().to_string();
+        let hour = Duration::from_secs(60 * 60);
+        temp_file(&outside_directory, ".a.txt.1234.dd-backup-tmp", 2 * hour);
+        std::os::unix::fs::symlink(&outside_directory, append_path(&directory, "link")).unwrap();
+
+        assert_eq!(remove_temp_files_in(&directory, true, SystemTime::now()), 0);
+        assert_eq!(
+            file_names(&outside_directory),
+            [".a.txt.1234.dd-backup-tmp"]
+        );
+    }
+}
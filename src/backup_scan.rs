@@ -0,0 +1,179 @@
+use crate::backup_store::BackupStore;
+use crate::user_data::is_valid_directory;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use std::collections::BTreeMap;
+
+/// バックアップディレクトリ内で見つかった追跡ファイル
+#[derive(Debug, Clone)]
+pub struct ScannedFile {
+    pub name: String,
+    /// 更新日時 (降順)
+    pub versions: Vec<DateTime<Utc>>,
+    pub source_directory: String,
+}
+
+impl ScannedFile {
+    pub fn latest_version(&self) -> Option<&DateTime<Utc>> {
+        self.versions.first()
+    }
+
+    pub fn source_directory_valid(&self) -> bool {
+        is_valid_directory(&self.source_directory)
+    }
+}
+
+/// 旧形式 (ローカル時刻, 秒まで) のバージョン識別子
+const LEGACY_VERSION_ID_FORMAT: &str = "%Y-%m-%d-%H-%M-%S";
+
+/// バージョン識別子を UTC の更新日時に変換する
+/// 衝突回避の連番 (例: "...Z.1") は無視する
+pub fn parse_version_id(version: &str) -> Option<DateTime<Utc>> {
+    if let Some((timestamp, sequence)) = version.split_once('Z') {
+        let sequence_valid = sequence.is_empty()
+            || sequence
+                .strip_prefix('.')
+                .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+        if !sequence_valid {
+            return None;
+        }
+
+        let naive = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H-%M-%S%.f").ok()?;
+        return Some(naive.and_utc());
+    }
+
+    let naive = NaiveDateTime::parse_from_str(version, LEGACY_VERSION_ID_FORMAT).ok()?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// `<version>_<name>` 形式のバックアップファイル名を (更新日時, ファイル名) に分解する
+pub fn parse_backup_filename(filename: &str) -> Option<(DateTime<Utc>, String)> {
+    let (version, name) = filename.split_once('_')?;
+    if name.is_empty() {
+        return None;
+    }
+
+    Some((parse_version_id(version)?, decode_name(name)))
+}
+
+/// 相対パスで追跡しているファイルは、バックアップ先で 1 つのファイル名になるよう区切りを置き換える
+const ENCODED_SEPARATOR: &str = "%2F";
+/// 元の名前に含まれる `%` (`%2F` という名前と区切りを区別するため)
+const ENCODED_PERCENT: &str = "%25";
+
+pub fn encode_name(name: &str) -> String {
+    name.replace('%', ENCODED_PERCENT)
+        .replace('/', ENCODED_SEPARATOR)
+}
+
+/// `%2F` と `%25` 以外の `%` はそのまま残す (`%` を置き換えていなかった頃のバックアップ)
+pub fn decode_name(name: &str) -> String {
+    let mut decoded = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(position) = rest.find('%') {
+        decoded.push_str(&rest[..position]);
+        rest = &rest[position..];
+        if let Some(after) = rest.strip_prefix(ENCODED_SEPARATOR) {
+            decoded.push('/');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix(ENCODED_PERCENT) {
+            decoded.push('%');
+            rest = after;
+        } else {
+            decoded.push('%');
+            rest = &rest[1..];
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// バックアップ先を走査して、ファイル名ごとのバージョン履歴を復元する
+pub fn scan_backup_store(store: &dyn BackupStore) -> Vec<ScannedFile> {
+    let mut versions_by_name: BTreeMap<String, Vec<DateTime<Utc>>> = BTreeMap::new();
+
+    for backup_filename in store.list_versions() {
+        if let Some((last_edited, name)) = parse_backup_filename(&backup_filename) {
+            versions_by_name.entry(name).or_default().push(last_edited);
+        }
+    }
+
+    versions_by_name
+        .into_iter()
+        .map(|(name, mut versions)| {
+            versions.sort_by(|a, b| b.cmp(a));
+            ScannedFile {
+                name,
+                versions,
+                source_directory: "".to_string(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Timelike;
+
+    #[test]
+    fn parse_version_id_reads_nanoseconds_and_ignores_sequence() {
+        let time = parse_version_id("2024-05-06T07-08-09.123456789Z").unwrap();
+        assert_eq!(time.to_rfc3339(), "2024-05-06T07:08:09.123456789+00:00");
+        assert_eq!(
+            parse_version_id("2024-05-06T07-08-09.123456789Z.2"),
+            Some(time)
+        );
+    }
+
+    #[test]
+    fn parse_version_id_rejects_malformed_sequence() {
+        assert_eq!(parse_version_id("2024-05-06T07-08-09.1Z."), None);
+        assert_eq!(parse_version_id("2024-05-06T07-08-09.1Z.a"), None);
+        assert_eq!(parse_version_id("2024-05-06T07-08-09.1Zx"), None);
+        assert_eq!(parse_version_id("not a version"), None);
+    }
+
+    #[test]
+    fn parse_version_id_reads_legacy_local_time() {
+        let time = parse_version_id("2024-05-06-07-08-09").unwrap();
+        let local = time.with_timezone(&Local);
+        assert_eq!((local.hour(), local.minute(), local.second()), (7, 8, 9));
+    }
+
+    #[test]
+    fn parse_backup_filename_splits_version_and_name() {
+        let (time, name) =
+            parse_backup_filename("2024-05-06T07-08-09.000000001Z_docs%2Fa_b.txt").unwrap();
+        assert_eq!(time.nanosecond(), 1);
+        assert_eq!(name, "docs/a_b.txt");
+
+        assert_eq!(parse_backup_filename("2024-05-06T07-08-09.1Z_"), None);
+        assert_eq!(parse_backup_filename("no-underscore"), None);
+        assert_eq!(parse_backup_filename("garbage_name.txt"), None);
+    }
+
+    #[test]
+    fn encode_name_round_trips() {
+        for name in [
+            "a.txt",
+            "docs/a.txt",
+            "a%2Fb.txt",
+            "dir/a%2Fb/%25%.txt",
+            "100%",
+        ] {
+            let encoded = encode_name(name);
+            assert!(!encoded.contains('/'));
+            assert_eq!(decode_name(&encoded), name);
+        }
+        assert_ne!(encode_name("a%2Fb"), encode_name("a/b"));
+    }
+
+    #[test]
+    fn decode_name_keeps_unescaped_percent() {
+        assert_eq!(decode_name("100%.txt"), "100%.txt");
+        assert_eq!(decode_name("docs%2F50%off"), "docs/50%off");
+    }
+}
@@ -0,0 +1,637 @@
+use crate::archive_store::{ArchiveStore, ARCHIVE_INDEX_FILENAME};
+use crate::atomic_file::{copy_atomically, replace_atomically, write_atomically};
+use crate::compression::{compress_file, decompress_file, is_already_compressed, Compression};
+use crate::encryption::{has_key_file, EncryptedStore, EncryptionKey};
+use crate::file_metadata::FileMetadata;
+use crate::git_store::{is_git_repository, GitStore};
+use crate::hash_index::hash_file;
+use crate::parity::{create_parity, remove_parity, Parity};
+use crate::s3::{is_s3_url, S3Options, S3Store};
+use crate::sftp::{is_sftp_url, SftpOptions, SftpStore};
+use crate::user_data::{append_path, is_valid_directory, is_valid_file};
+use crate::webdav::{is_webdav_url, WebDavStore};
+use fastcdc::v2020::StreamCDC;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+
+/// バックアップディレクトリへの保存方式
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackupMode {
+    /// バージョンごとに `<version>_<name>` としてそのままコピーする
+    #[default]
+    Flat,
+    /// 内容をチャンクに分割し、ハッシュで重複を除いて保存する
+    Chunked,
+    /// git のリポジトリに、同期のたびにコミットする
+    Git,
+    /// ファイルごとに 1 つの tar へ、すべてのバージョンを追記する
+    Archive,
+}
+
+impl BackupMode {
+    pub const ALL: [BackupMode; 4] = [
+        BackupMode::Flat,
+        BackupMode::Chunked,
+        BackupMode::Git,
+        BackupMode::Archive,
+    ];
+}
+
+impl fmt::Display for BackupMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupMode::Flat => write!(f, "Flat copies"),
+            BackupMode::Chunked => write!(f, "Deduplicated"),
+            BackupMode::Git => write!(f, "Git repository"),
+            BackupMode::Archive => write!(f, "Archive per file"),
+        }
+    }
+}
+
+/// バックアップ先に対する操作
+/// バージョンは `FileInfo::backup_filename` の名前で識別する
+pub trait BackupStore {
+    fn is_available(&self) -> bool;
+
+    /// 保存されているバージョン名の一覧
+    fn list_versions(&self) -> Vec<String>;
+
+    fn has_version(&self, backup_filename: &str) -> bool;
+
+    fn write_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()>;
+
+    fn read_version(&self, backup_filename: &str, dest_path: &str) -> io::Result<()>;
+
+    fn remove_version(&self, backup_filename: &str) -> io::Result<()>;
+
+    /// 既にあるバージョンの実体を source_path の内容で置き換える
+    /// 書き終えるまでは元の実体を残すため、失敗しても元のバージョンは消えない
+    fn replace_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()> {
+        self.write_version(source_path, backup_filename)
+    }
+
+    /// ハッシュの履歴など、バックアップ先に置く小さな管理ファイルを読む
+    /// 無い場合は Ok(None) を返し、あるのに読めない場合はエラーにする
+    fn load_index(&self, index_name: &str) -> io::Result<Option<String>>;
+
+    /// 読めない管理ファイルを無いものとして扱ってよい場合に使う
+    fn read_index(&self, index_name: &str) -> Option<String> {
+        self.load_index(index_name).ok().flatten()
+    }
+
+    fn write_index(&self, index_name: &str, content: &str) -> io::Result<()>;
+
+    /// どのバージョンからも参照されていないデータを削除し、削除した数を返す
+    fn collect_garbage(&self) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    /// バージョンの実体に元のファイルのメタデータを付ける (実体がそのまま置かれている場合だけ)
+    fn apply_metadata(&self, _backup_filename: &str, _metadata: &FileMetadata) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 無いことを表すエラーを None にする
+pub fn missing_as_none<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// リモートのバックアップ先へ接続するための、ディレクトリごとの設定
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RemoteOptions {
+    pub sftp: SftpOptions,
+    pub s3: S3Options,
+    /// git のバックアップ先から、コミットのたびにブランチを push するリポジトリ (空なら push しない)
+    pub git_remote: String,
+}
+
+pub fn open_backup_store(
+    mode: BackupMode,
+    backup_directory: &str,
+    compression: Compression,
+    parity: Parity,
+    remote: &RemoteOptions,
+) -> Box<dyn BackupStore> {
+    if is_sftp_url(backup_directory) {
+        return Box::new(SftpStore::new(backup_directory, &remote.sftp));
+    }
+
+    if is_s3_url(backup_directory) {
+        return Box::new(S3Store::new(backup_directory, &remote.s3));
+    }
+
+    if is_webdav_url(backup_directory) {
+        return Box::new(WebDavStore::new(backup_directory));
+    }
+
+    match mode {
+        BackupMode::Flat => Box::new(FlatStore::new(backup_directory, compression, parity)),
+        BackupMode::Chunked => Box::new(ChunkStore::new(backup_directory)),
+        BackupMode::Git => Box::new(GitStore::new(backup_directory, &remote.git_remote)),
+        BackupMode::Archive => Box::new(ArchiveStore::new(backup_directory)),
+    }
+}
+
+/// 既存のバックアップディレクトリを、保存方式だけ判別して暗号化せずに開く
+pub fn open_plain_backup_store(backup_directory: &str) -> Box<dyn BackupStore> {
+    open_backup_store(
+        detect_backup_mode(backup_directory),
+        backup_directory,
+        Compression::None,
+        Parity::None,
+        &RemoteOptions::default(),
+    )
+}
+
+/// 既存のバックアップディレクトリを、保存方式と暗号化の有無を判別して開く
+pub fn open_existing_backup_store(
+    backup_directory: &str,
+    key: Option<EncryptionKey>,
+) -> Box<dyn BackupStore> {
+    let store = open_plain_backup_store(backup_directory);
+    if has_key_file(store.as_ref()) {
+        Box::new(EncryptedStore::new(store, key))
+    } else {
+        store
+    }
+}
+
+/// ローカルのディレクトリではなく、ネットワーク越しのバックアップ先か
+pub fn is_remote_backup_directory(backup_directory: &str) -> bool {
+    is_sftp_url(backup_directory) || is_s3_url(backup_directory) || is_webdav_url(backup_directory)
+}
+
+/// 既存のバックアップディレクトリの保存方式を判別する
+pub fn detect_backup_mode(backup_directory: &str) -> BackupMode {
+    if is_valid_directory(&append_path(backup_directory, CHUNK_STORE_DIRECTORY)) {
+        BackupMode::Chunked
+    } else if is_git_repository(backup_directory) {
+        BackupMode::Git
+    } else if is_valid_file(&append_path(backup_directory, ARCHIVE_INDEX_FILENAME)) {
+        BackupMode::Archive
+    } else {
+        BackupMode::Flat
+    }
+}
+
+/// バージョンごとのファイルを並べる従来の方式
+/// 圧縮したバージョンは `<backup_filename>.dd.zst` のように拡張子を付けて保存する
+/// パリティを有効にすると、保存したファイルごとにパリティファイルを作る
+pub struct FlatStore {
+    directory: String,
+    compression: Compression,
+    parity: Parity,
+}
+
+impl FlatStore {
+    pub fn new(directory: &str, compression: Compression, parity: Parity) -> Self {
+        FlatStore {
+            directory: directory.to_string(),
+            compression,
+            parity,
+        }
+    }
+
+    /// 圧縮の有無にかかわらず、バージョンの実体のパスと圧縮方式を探す
+    fn find_version(&self, backup_filename: &str) -> Option<(String, Compression)> {
+        Compression::ALL.into_iter().find_map(|compression| {
+            let path = append_path(
+                &self.directory,
+                &format!("{}{}", backup_filename, compression.suffix()),
+            );
+            is_valid_file(&path).then_some((path, compression))
+        })
+    }
+}
+
+impl BackupStore for FlatStore {
+    fn is_available(&self) -> bool {
+        is_valid_directory(&self.directory)
+    }
+
+    fn list_versions(&self) -> Vec<String> {
+        list_file_names(&self.directory)
+            .into_iter()
+            .filter(|name| !name.starts_with('.'))
+            .map(|name| Compression::from_stored_name(&name).1.to_string())
+            .collect()
+    }
+
+    fn has_version(&self, backup_filename: &str) -> bool {
+        self.find_version(backup_filename).is_some()
+    }
+
+    fn write_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()> {
+        let backup_path =
+            if self.compression == Compression::None || is_already_compressed(source_path) {
+                let backup_path = append_path(&self.directory, backup_filename);
+                copy_atomically(source_path, &backup_path)?;
+                backup_path
+            } else {
+                let backup_path = append_path(
+                    &self.directory,
+                    &format!("{}{}", backup_filename, self.compression.suffix()),
+                );
+                replace_atomically(&backup_path, |temp| {
+                    compress_file(source_path, temp, self.compression)
+                })?;
+                backup_path
+            };
+
+        create_parity(&backup_path, self.parity)
+    }
+
+    fn read_version(&self, backup_filename: &str, dest_path: &str) -> io::Result<()> {
+        let (backup_path, compression) = self
+            .find_version(backup_filename)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, backup_filename))?;
+        decompress_file(&backup_path, dest_path, compression)
+    }
+
+    fn remove_version(&self, backup_filename: &str) -> io::Result<()> {
+        match self.find_version(backup_filename) {
+            Some((backup_path, _)) => {
+                fs::remove_file(&backup_path)?;
+                remove_parity(&backup_path)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// 圧縮の設定が変わっていても、元の実体と同じ圧縮方式で同じ名前に書く
+    fn replace_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()> {
+        let Some((backup_path, compression)) = self.find_version(backup_filename) else {
+            return self.write_version(source_path, backup_filename);
+        };
+
+        replace_atomically(&backup_path, |temp| {
+            compress_file(source_path, temp, compression)
+        })?;
+        // 壊れた内容から作ったパリティは使えない
+        remove_parity(&backup_path)?;
+        create_parity(&backup_path, self.parity)
+    }
+
+    fn load_index(&self, index_name: &str) -> io::Result<Option<String>> {
+        missing_as_none(fs::read_to_string(append_path(&self.directory, index_name)))
+    }
+
+    fn write_index(&self, index_name: &str, content: &str) -> io::Result<()> {
+        write_atomically(&append_path(&self.directory, index_name), content)
+    }
+
+    /// 圧縮したバージョンは内容が違うため、日時などを付けても意味がない
+    fn apply_metadata(&self, backup_filename: &str, metadata: &FileMetadata) -> io::Result<()> {
+        match self.find_version(backup_filename) {
+            Some((backup_path, Compression::None)) => metadata.apply(&backup_path),
+            _ => Ok(()),
+        }
+    }
+}
+
+const CHUNK_STORE_DIRECTORY: &str = ".dd-backup-store";
+const CHUNK_MIN_SIZE: u32 = 16 * 1024;
+const CHUNK_AVG_SIZE: u32 = 64 * 1024;
+const CHUNK_MAX_SIZE: u32 = 256 * 1024;
+
+/// 1 バージョン分の内容を、チャンクのハッシュ列として表したもの
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ChunkManifest {
+    size: u64,
+    hash: String,
+    chunks: Vec<String>,
+}
+
+/// 内容で分割したチャンクをハッシュをキーに保存し、バージョンごとにはマニフェストだけを持つ方式
+/// <backup_directory>/.dd-backup-store/chunks/<ハッシュ先頭 2 文字>/<ハッシュ>
+/// <backup_directory>/.dd-backup-store/manifests/<backup_filename>.yaml
+pub struct ChunkStore {
+    directory: String,
+}
+
+impl ChunkStore {
+    pub fn new(directory: &str) -> Self {
+        ChunkStore {
+            directory: directory.to_string(),
+        }
+    }
+
+    fn store_path(&self, path: &str) -> String {
+        append_path(&append_path(&self.directory, CHUNK_STORE_DIRECTORY), path)
+    }
+
+    fn chunk_path(&self, hash: &str) -> String {
+        self.store_path(&format!("chunks/{}/{}", &hash[..2], hash))
+    }
+
+    fn manifest_path(&self, backup_filename: &str) -> String {
+        self.store_path(&format!("manifests/{}.yaml", backup_filename))
+    }
+
+    fn load_manifest(&self, backup_filename: &str) -> Option<ChunkManifest> {
+        let content = fs::read_to_string(self.manifest_path(backup_filename)).ok()?;
+        serde_yaml::from_str(&content).ok()
+    }
+}
+
+impl BackupStore for ChunkStore {
+    fn is_available(&self) -> bool {
+        is_valid_directory(&self.directory)
+    }
+
+    fn list_versions(&self) -> Vec<String> {
+        list_file_names(&self.store_path("manifests"))
+            .into_iter()
+            .filter_map(|name| name.strip_suffix(".yaml").map(|n| n.to_string()))
+            .collect()
+    }
+
+    fn has_version(&self, backup_filename: &str) -> bool {
+        is_valid_file(&self.manifest_path(backup_filename))
+    }
+
+    fn write_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()> {
+        let file = fs::File::open(source_path)?;
+        let mut manifest = ChunkManifest {
+            size: 0,
+            hash: hash_file(source_path).unwrap_or_default(),
+            chunks: Vec::new(),
+        };
+
+        for chunk in StreamCDC::new(file, CHUNK_MIN_SIZE, CHUNK_AVG_SIZE, CHUNK_MAX_SIZE) {
+            let chunk = chunk.map_err(io::Error::other)?;
+            let hash = hex::encode(Sha256::digest(&chunk.data));
+            let chunk_path = self.chunk_path(&hash);
+            if !is_valid_file(&chunk_path) {
+                fs::create_dir_all(self.store_path(&format!("chunks/{}", &hash[..2])))?;
+                write_atomically(&chunk_path, &chunk.data)?;
+            }
+
+            manifest.size += chunk.length as u64;
+            manifest.chunks.push(hash);
+        }
+
+        // マニフェストはチャンクを書き終えてから作る
+        fs::create_dir_all(self.store_path("manifests"))?;
+        let yaml = serde_yaml::to_string(&manifest).map_err(io::Error::other)?;
+        write_atomically(&self.manifest_path(backup_filename), yaml)
+    }
+
+    fn read_version(&self, backup_filename: &str, dest_path: &str) -> io::Result<()> {
+        let manifest = self
+            .load_manifest(backup_filename)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, backup_filename))?;
+
+        let mut dest = fs::File::create(dest_path)?;
+        for hash in &manifest.chunks {
+            let data = fs::read(self.chunk_path(hash))?;
+            if hex::encode(Sha256::digest(&data)) != *hash {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, hash.clone()));
+            }
+
+            dest.write_all(&data)?;
+        }
+
+        dest.flush()
+    }
+
+    /// チャンクは collect_garbage で削除する
+    fn remove_version(&self, backup_filename: &str) -> io::Result<()> {
+        let manifest_path = self.manifest_path(backup_filename);
+        if is_valid_file(&manifest_path) {
+            fs::remove_file(manifest_path)?;
+        }
+
+        Ok(())
+    }
+
+    fn load_index(&self, index_name: &str) -> io::Result<Option<String>> {
+        missing_as_none(fs::read_to_string(append_path(&self.directory, index_name)))
+    }
+
+    fn write_index(&self, index_name: &str, content: &str) -> io::Result<()> {
+        write_atomically(&append_path(&self.directory, index_name), content)
+    }
+
+    /// 読めないマニフェストが 1 つでもあれば、使われているチャンクを消さないよう何もしない
+    fn collect_garbage(&self) -> io::Result<usize> {
+        let chunks_directory = self.store_path("chunks");
+        if !is_valid_directory(&chunks_directory) {
+            return Ok(0);
+        }
+
+        fs::read_dir(self.store_path("manifests"))?;
+        let mut referenced: HashSet<String> = HashSet::new();
+        for version in self.list_versions() {
+            let content = fs::read_to_string(self.manifest_path(&version))?;
+            let manifest: ChunkManifest = serde_yaml::from_str(&content).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", version, e))
+            })?;
+            referenced.extend(manifest.chunks);
+        }
+
+        let mut removed = 0;
+        for prefix in list_directory_names(&chunks_directory) {
+            let prefix_directory = append_path(&chunks_directory, &prefix);
+            for hash in list_file_names(&prefix_directory) {
+                if !referenced.contains(&hash) {
+                    fs::remove_file(append_path(&prefix_directory, &hash))?;
+                    removed += 1;
+                }
+            }
+
+            // 空になったディレクトリは失敗しても構わない
+            fs::remove_dir(&prefix_directory).ok();
+        }
+
+        Ok(removed)
+    }
+}
+
+fn list_file_names(directory: &str) -> Vec<String> {
+    list_entries(directory, |entry| entry.path().is_file())
+}
+
+fn list_directory_names(directory: &str) -> Vec<String> {
+    list_entries(directory, |entry| entry.path().is_dir())
+}
+
+fn list_entries(directory: &str, filter: impl Fn(&fs::DirEntry) -> bool) -> Vec<String> {
+    match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|entry| filter(entry))
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// チャンクに分かれる大きさの、繰り返しの少ない内容
+    fn sample_data(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    fn chunk_count(store: &ChunkStore) -> usize {
+        list_directory_names(&store.store_path("chunks"))
+            .iter()
+            .map(|prefix| list_file_names(&append_path(&store.store_path("chunks"), prefix)).len())
+            .sum()
+    }
+
+    fn setup() -> (tempfile::TempDir, ChunkStore, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let backup = append_path(&root, "backup");
+        fs::create_dir(&backup).unwrap();
+        (dir, ChunkStore::new(&backup), root)
+    }
+
+    #[test]
+    fn chunk_store_round_trip() {
+        let (_dir, store, root) = setup();
+        let source = append_path(&root, "source.bin");
+        let data = sample_data(1, 1024 * 1024);
+        fs::write(&source, &data).unwrap();
+
+        store.write_version(&source, "v1_source.bin").unwrap();
+        assert!(store.has_version("v1_source.bin"));
+        assert!(chunk_count(&store) > 1);
+
+        let restored = append_path(&root, "restored.bin");
+        store.read_version("v1_source.bin", &restored).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), data);
+    }
+
+    #[test]
+    fn garbage_collection_keeps_shared_chunks() {
+        let (_dir, store, root) = setup();
+        let source = append_path(&root, "source.bin");
+        let mut data = sample_data(2, 1024 * 1024);
+        fs::write(&source, &data).unwrap();
+        store.write_version(&source, "v1_source.bin").unwrap();
+        let first_chunks = chunk_count(&store);
+
+        // 末尾だけを変えた版は、先頭のチャンクを共有する
+        data.extend(sample_data(3, 64 * 1024));
+        fs::write(&source, &data).unwrap();
+        store.write_version(&source, "v2_source.bin").unwrap();
+        assert!(chunk_count(&store) < first_chunks * 2);
+
+        assert_eq!(store.collect_garbage().unwrap(), 0);
+        store.remove_version("v1_source.bin").unwrap();
+        assert!(store.collect_garbage().unwrap() > 0);
+
+        let restored = append_path(&root, "restored.bin");
+        store.read_version("v2_source.bin", &restored).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), data);
+    }
+
+    #[test]
+    fn garbage_collection_stops_on_unreadable_manifest() {
+        let (_dir, store, root) = setup();
+        let source = append_path(&root, "source.bin");
+        fs::write(&source, sample_data(4, 256 * 1024)).unwrap();
+        store.write_version(&source, "v1_source.bin").unwrap();
+        store.write_version(&source, "v2_source.bin").unwrap();
+        let chunks = chunk_count(&store);
+
+        fs::write(store.manifest_path("v2_source.bin"), "chunks: [").unwrap();
+        store.remove_version("v1_source.bin").unwrap();
+        let error = store.collect_garbage().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(chunk_count(&store), chunks);
+    }
+
+    #[test]
+    fn garbage_collection_on_empty_store() {
+        let (_dir, store, _root) = setup();
+        assert_eq!(store.collect_garbage().unwrap(), 0);
+    }
+
+    #[test]
+    fn flat_store_round_trips_every_compression() {
+        let (_dir, _, root) = setup();
+        let source = append_path(&root, "notes.txt");
+        let data = "a line that repeats\n".repeat(2000);
+        fs::write(&source, &data).unwrap();
+
+        for compression in Compression::ALL {
+            let backup = append_path(&root, &format!("flat-{}", compression.suffix()));
+            fs::create_dir(&backup).unwrap();
+            let store = FlatStore::new(&backup, compression, Parity::None);
+            store.write_version(&source, "v1_notes.txt").unwrap();
+
+            let stored = append_path(&backup, &format!("v1_notes.txt{}", compression.suffix()));
+            assert!(is_valid_file(&stored), "{}", compression);
+            assert_eq!(store.list_versions(), ["v1_notes.txt"]);
+
+            let restored = append_path(&root, "restored.txt");
+            store.read_version("v1_notes.txt", &restored).unwrap();
+            assert_eq!(fs::read_to_string(&restored).unwrap(), data);
+
+            store.remove_version("v1_notes.txt").unwrap();
+            assert!(!store.has_version("v1_notes.txt"));
+        }
+    }
+
+    #[test]
+    fn flat_store_keeps_compressed_formats_as_they_are() {
+        let (_dir, _, root) = setup();
+        let backup = append_path(&root, "flat");
+        fs::create_dir(&backup).unwrap();
+        let source = append_path(&root, "archive.zip");
+        fs::write(&source, "PK\x03\x04 pretend zip").unwrap();
+
+        let store = FlatStore::new(&backup, Compression::Zstd(3), Parity::None);
+        store.write_version(&source, "v1_archive.zip").unwrap();
+        assert!(is_valid_file(&append_path(&backup, "v1_archive.zip")));
+        assert!(!is_valid_file(&append_path(
+            &backup,
+            "v1_archive.zip.dd.zst"
+        )));
+
+        let restored = append_path(&root, "restored.zip");
+        store.read_version("v1_archive.zip", &restored).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), fs::read(&source).unwrap());
+    }
+
+    #[test]
+    fn flat_store_reads_versions_written_with_another_compression() {
+        let (_dir, _, root) = setup();
+        let backup = append_path(&root, "flat");
+        fs::create_dir(&backup).unwrap();
+        let source = append_path(&root, "notes.txt");
+        fs::write(&source, "written with gzip").unwrap();
+
+        FlatStore::new(&backup, Compression::Gzip(6), Parity::None)
+            .write_version(&source, "v1_notes.txt")
+            .unwrap();
+        let store = FlatStore::new(&backup, Compression::None, Parity::None);
+        let restored = append_path(&root, "restored.txt");
+        store.read_version("v1_notes.txt", &restored).unwrap();
+        assert_eq!(fs::read_to_string(&restored).unwrap(), "written with gzip");
+    }
+}
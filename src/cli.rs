@@ -0,0 +1,212 @@
+use crate::backup_scan::scan_backup_store;
+use crate::backup_store::{
+    detect_backup_mode, open_existing_backup_store, open_plain_backup_store, BackupStore,
+};
+use crate::encryption::{has_key_file, unlock, EncryptionKey};
+use crate::manifest::verify;
+use crate::parity::repair_directory;
+use crate::prune::prune_versions;
+use crate::replication::replicate;
+use crate::user_data::{is_valid_backup_directory, is_valid_directory, DirectoryInfo, FileInfo};
+use chrono::Local;
+
+const USAGE: &str = "\
+Usage:
+  dd-backup list <backup_directory>
+  dd-backup restore <backup_directory> <name> <dest_directory>
+  dd-backup verify <backup_directory>
+  dd-backup repair <backup_directory>
+  dd-backup prune <backup_directory> <keep>
+  dd-backup replicate <backup_directory> <replica_directory>";
+
+/// コマンドライン引数が与えられた場合の処理
+/// 終了コードを返す
+pub fn run_cli(args: &[String]) -> i32 {
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    match args.as_slice() {
+        ["list", backup_directory] => list(backup_directory),
+        ["restore", backup_directory, name, dest_directory] => {
+            restore(backup_directory, name, dest_directory)
+        }
+        ["verify", backup_directory] => verify_manifest(backup_directory),
+        ["repair", backup_directory] => repair_parity(backup_directory),
+        ["prune", backup_directory, keep] => prune(backup_directory, keep),
+        ["replicate", backup_directory, replica_directory] => {
+            replicate_backup(backup_directory, replica_directory)
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    }
+}
+
+/// 暗号化されたバックアップ先であれば、パスフレーズを尋ねて鍵を導出する
+/// 開けない場合は None を返す
+fn ask_key(backup_directory: &str) -> Option<Option<EncryptionKey>> {
+    if !is_valid_backup_directory(backup_directory) {
+        eprintln!("Not a directory: {}", backup_directory);
+        return None;
+    }
+
+    let store = open_plain_backup_store(backup_directory);
+    if !has_key_file(store.as_ref()) {
+        return Some(None);
+    }
+
+    let passphrase = rpassword::prompt_password("Passphrase: ").ok()?;
+    let key = unlock(store.as_ref(), &passphrase);
+    if key.is_none() {
+        eprintln!("Wrong passphrase");
+        return None;
+    }
+
+    Some(key)
+}
+
+fn open_store(backup_directory: &str) -> Option<Box<dyn BackupStore>> {
+    let key = ask_key(backup_directory)?;
+    Some(open_existing_backup_store(backup_directory, key))
+}
+
+fn list(backup_directory: &str) -> i32 {
+    let Some(store) = open_store(backup_directory) else {
+        return 1;
+    };
+
+    for file in scan_backup_store(store.as_ref()) {
+        println!("{}", file.name);
+        for version in &file.versions {
+            println!(
+                "  {}",
+                version
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S%.3f")
+            );
+        }
+    }
+
+    0
+}
+
+fn restore(backup_directory: &str, name: &str, dest_directory: &str) -> i32 {
+    let Some(store) = open_store(backup_directory) else {
+        return 1;
+    };
+
+    let mut file = FileInfo::empty();
+    file.name = name.to_string();
+    match file.restore(dest_directory, store.as_ref()) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Failed to restore {}: {}", name, e);
+            1
+        }
+    }
+}
+
+fn verify_manifest(backup_directory: &str) -> i32 {
+    let Some(store) = open_store(backup_directory) else {
+        return 1;
+    };
+
+    let report = verify(store.as_ref());
+    if report.unknown_key {
+        println!("manifest is not signed by the local signing key");
+    }
+
+    for (label, names) in [
+        ("broken", &report.broken),
+        ("missing", &report.missing),
+        ("modified", &report.modified),
+        ("unexpected", &report.unexpected),
+    ] {
+        for name in names {
+            println!("{}: {}", label, name);
+        }
+    }
+
+    println!("{}", report.summary());
+    if report.is_ok() {
+        0
+    } else {
+        1
+    }
+}
+
+/// パリティで壊れたバージョンを修復する
+/// 保存したファイルをそのまま扱うため、暗号化されていてもパスフレーズは不要
+fn repair_parity(backup_directory: &str) -> i32 {
+    if !is_valid_directory(backup_directory) {
+        eprintln!("Not a directory: {}", backup_directory);
+        return 1;
+    }
+
+    let report = repair_directory(backup_directory);
+    for name in &report.repaired {
+        println!("repaired: {}", name);
+    }
+
+    for name in &report.unrepairable {
+        println!("unrepairable: {}", name);
+    }
+
+    println!("{}", report.summary());
+    if report.unrepairable.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+/// ファイルごとに新しい方から keep 個のバージョンを残して、古いものを削除する
+fn prune(backup_directory: &str, keep: &str) -> i32 {
+    let Ok(keep) = keep.parse::<usize>() else {
+        eprintln!("Not a number: {}", keep);
+        return 2;
+    };
+    let Some(store) = open_store(backup_directory) else {
+        return 1;
+    };
+
+    match prune_versions(store.as_ref(), keep) {
+        Ok(report) => {
+            println!("{}", report.summary());
+            0
+        }
+        Err(e) => {
+            eprintln!("Pruning stopped: {}", e);
+            1
+        }
+    }
+}
+
+/// 定期実行から呼べるよう、GUI と同じ複製をコマンドラインからも行う
+/// 複製先には圧縮とパリティを付けずに保存する
+fn replicate_backup(backup_directory: &str, replica_directory: &str) -> i32 {
+    let Some(key) = ask_key(backup_directory) else {
+        return 1;
+    };
+
+    let mut dir = DirectoryInfo::new("".to_string(), backup_directory.to_string());
+    dir.replica_directory = replica_directory.to_string();
+    dir.backup_mode = detect_backup_mode(backup_directory);
+    dir.encrypted = key.is_some();
+    dir.encryption_key = key;
+
+    let report = replicate(&dir);
+    for name in &report.copied {
+        println!("copied: {}", name);
+    }
+
+    for name in &report.failed {
+        println!("failed: {}", name);
+    }
+
+    println!("{}", report.summary());
+    if report.unavailable || !report.failed.is_empty() {
+        1
+    } else {
+        0
+    }
+}
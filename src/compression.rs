@@ -0,0 +1,237 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// バックアップの圧縮方式と圧縮レベル
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd(i32),
+    Gzip(u32),
+}
+
+impl Compression {
+    pub const ALL: [Compression; 3] = [
+        Compression::None,
+        Compression::Zstd(3),
+        Compression::Gzip(6),
+    ];
+
+    /// 圧縮したバージョンに付ける拡張子
+    /// 元のファイル名の拡張子と区別できるよう ".dd" を挟む
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Zstd(_) => ".dd.zst",
+            Compression::Gzip(_) => ".dd.gz",
+        }
+    }
+
+    pub fn level(&self) -> Option<i32> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd(level) => Some(*level),
+            Compression::Gzip(level) => Some(*level as i32),
+        }
+    }
+
+    /// 圧縮方式ごとの範囲に収めて圧縮レベルを変更する
+    pub fn with_level(&self, level: i32) -> Self {
+        match self {
+            Compression::None => Compression::None,
+            Compression::Zstd(_) => Compression::Zstd(level.clamp(1, 22)),
+            Compression::Gzip(_) => Compression::Gzip(level.clamp(0, 9) as u32),
+        }
+    }
+
+    /// バージョンのファイル名から圧縮方式を判別し、元の名前と共に返す
+    pub fn from_stored_name(stored_name: &str) -> (Compression, &str) {
+        for compression in Compression::ALL {
+            if compression == Compression::None {
+                continue;
+            }
+
+            if let Some(name) = stored_name.strip_suffix(compression.suffix()) {
+                return (compression, name);
+            }
+        }
+
+        (Compression::None, stored_name)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "No compression"),
+            Compression::Zstd(_) => write!(f, "zstd"),
+            Compression::Gzip(_) => write!(f, "gzip"),
+        }
+    }
+}
+
+/// 圧縮しても小さくならない形式の拡張子
+const COMPRESSED_EXTENSIONS: [&str; 24] = [
+    "zip", "gz", "tgz", "zst", "xz", "bz2", "7z", "rar", "jpg", "jpeg", "png", "gif", "webp",
+    "heic", "mp4", "mkv", "mov", "avi", "webm", "mp3", "aac", "docx", "xlsx", "pptx",
+];
+
+/// 圧縮しても小さくならない形式のファイル先頭のシグネチャ
+const COMPRESSED_SIGNATURES: [&[u8]; 8] = [
+    b"PK\x03\x04",
+    b"\x1f\x8b",
+    b"\x28\xb5\x2f\xfd",
+    b"\xff\xd8\xff",
+    b"\x89PNG",
+    b"7z\xbc\xaf",
+    b"Rar!",
+    b"\xfd7zXZ",
+];
+
+/// 既に圧縮されている形式かを拡張子と内容から判断する
+pub fn is_already_compressed(path: &str) -> bool {
+    let extension = Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+        return true;
+    }
+
+    let mut header = [0u8; 12];
+    let read = fs::File::open(path)
+        .and_then(|mut file| file.read(&mut header))
+        .unwrap_or(0);
+    let header = &header[..read];
+
+    // MP4 / MOV は 4 バイト目から "ftyp" が続く
+    COMPRESSED_SIGNATURES.iter().any(|s| header.starts_with(s))
+        || header.get(4..8) == Some(b"ftyp".as_slice())
+}
+
+pub fn compress_file(
+    source_path: &str,
+    dest_path: &str,
+    compression: Compression,
+) -> io::Result<()> {
+    let mut source = fs::File::open(source_path)?;
+    let mut dest = fs::File::create(dest_path)?;
+
+    match compression {
+        Compression::None => {
+            io::copy(&mut source, &mut dest)?;
+        }
+        Compression::Zstd(level) => zstd::stream::copy_encode(source, &mut dest, level)?,
+        Compression::Gzip(level) => {
+            let mut encoder = GzEncoder::new(&mut dest, flate2::Compression::new(level));
+            io::copy(&mut source, &mut encoder)?;
+            encoder.finish()?;
+        }
+    }
+
+    dest.flush()
+}
+
+pub fn decompress_file(
+    source_path: &str,
+    dest_path: &str,
+    compression: Compression,
+) -> io::Result<()> {
+    let mut source = fs::File::open(source_path)?;
+    let mut dest = fs::File::create(dest_path)?;
+
+    match compression {
+        Compression::None => {
+            io::copy(&mut source, &mut dest)?;
+        }
+        Compression::Zstd(_) => zstd::stream::copy_decode(source, &mut dest)?,
+        Compression::Gzip(_) => {
+            io::copy(&mut GzDecoder::new(source), &mut dest)?;
+        }
+    }
+
+    dest.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_data::append_path;
+
+    /// よく縮む内容
+    fn text_data() -> Vec<u8> {
+        "the quick brown fox jumps over the lazy dog\n"
+            .repeat(1000)
+            .into_bytes()
+    }
+
+    #[test]
+    fn every_compression_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let source = append_path(&root, "source.txt");
+        fs::write(&source, text_data()).unwrap();
+
+        for compression in Compression::ALL {
+            let compressed = append_path(&root, &format!("compressed{}", compression.suffix()));
+            let restored = append_path(&root, "restored.txt");
+            compress_file(&source, &compressed, compression).unwrap();
+            decompress_file(&compressed, &restored, compression).unwrap();
+
+            assert_eq!(fs::read(&restored).unwrap(), text_data(), "{}", compression);
+            if compression != Compression::None {
+                assert!(fs::metadata(&compressed).unwrap().len() < text_data().len() as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn stored_name_gives_back_the_compression() {
+        for compression in Compression::ALL {
+            let stored = format!("v1_a.txt{}", compression.suffix());
+            let (found, name) = Compression::from_stored_name(&stored);
+            assert_eq!(found.suffix(), compression.suffix());
+            assert_eq!(name, "v1_a.txt");
+        }
+        // 元のファイル名の拡張子は圧縮とみなさない
+        assert_eq!(
+            Compression::from_stored_name("v1_a.gz"),
+            (Compression::None, "v1_a.gz")
+        );
+    }
+
+    #[test]
+    fn levels_are_clamped() {
+        assert_eq!(Compression::Zstd(3).with_level(99), Compression::Zstd(22));
+        assert_eq!(Compression::Gzip(6).with_level(-1), Compression::Gzip(0));
+        assert_eq!(Compression::None.with_level(5), Compression::None);
+    }
+
+    #[test]
+    fn already_compressed_files_are_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+
+        let by_extension = append_path(&root, "photo.JPG");
+        fs::write(&by_extension, "not really a jpeg").unwrap();
+        assert!(is_already_compressed(&by_extension));
+
+        let by_signature = append_path(&root, "image.bin");
+        fs::write(&by_signature, b"\x89PNG\r\n\x1a\n....").unwrap();
+        assert!(is_already_compressed(&by_signature));
+
+        let mp4 = append_path(&root, "video.bin");
+        fs::write(&mp4, b"\0\0\0\x18ftypmp42").unwrap();
+        assert!(is_already_compressed(&mp4));
+
+        let text = append_path(&root, "notes.txt");
+        fs::write(&text, text_data()).unwrap();
+        assert!(!is_already_compressed(&text));
+    }
+}
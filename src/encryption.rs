@@ -0,0 +1,588 @@
+use crate::atomic_file::PrivateTempDir;
+use crate::backup_store::{open_plain_backup_store, BackupStore};
+use argon2::Argon2;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::XChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+
+/// 鍵の導出に使うソルトと、パスフレーズの確認用データ
+/// 鍵そのものはどこにも保存しない
+const KEY_FILENAME: &str = ".dd-backup-key.yaml";
+/// 暗号化したファイル名と元の名前の対応表
+const NAME_INDEX_FILENAME: &str = ".dd-backup-names";
+
+const FILE_MAGIC: &[u8] = b"DDBENC1\n";
+const KEY_CHECK: &[u8] = b"dd-backup";
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const STREAM_NONCE_SIZE: usize = 19;
+const NONCE_SIZE: usize = 24;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct KeyFile {
+    salt: String,
+    check: String,
+}
+
+/// パスフレーズから導出した鍵 (メモリ上にのみ保持する)
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    fn derive(passphrase: &str, salt: &[u8]) -> Option<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .ok()?;
+        Some(EncryptionKey(key))
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+fn encryption_error(_: chacha20poly1305::Error) -> io::Error {
+    io::Error::other("encryption failed")
+}
+
+fn random_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    getrandom::fill(&mut bytes).map_err(|_| io::Error::other("failed to get random bytes"))?;
+    Ok(bytes)
+}
+
+/// バックアップ先に鍵ファイルがあるか
+/// store には暗号化していない保存方式を渡す
+pub fn has_key_file(store: &dyn BackupStore) -> bool {
+    store.read_index(KEY_FILENAME).is_some()
+}
+
+/// 既存のバックアップディレクトリが暗号化されているか
+pub fn is_encrypted_target(backup_directory: &str) -> bool {
+    has_key_file(open_plain_backup_store(backup_directory).as_ref())
+}
+
+/// 複製先でも同じパスフレーズで開けるよう、ソルトと確認用データをコピーする
+pub fn copy_key_file(source: &dyn BackupStore, dest: &dyn BackupStore) -> io::Result<()> {
+    if dest.load_index(KEY_FILENAME)?.is_some() {
+        return Ok(());
+    }
+
+    let content = source
+        .load_index(KEY_FILENAME)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the backup has no key"))?;
+    dest.write_index(KEY_FILENAME, &content)
+}
+
+/// パスフレーズから鍵を導出する
+/// ソルトと確認用データが無いバックアップ先や、パスフレーズが違う場合は None を返す
+pub fn unlock(store: &dyn BackupStore, passphrase: &str) -> Option<EncryptionKey> {
+    if passphrase.is_empty() {
+        return None;
+    }
+
+    let content = store.read_index(KEY_FILENAME)?;
+    let key_file: KeyFile = serde_yaml::from_str(&content).ok()?;
+    let key = EncryptionKey::derive(passphrase, &hex::decode(key_file.salt).ok()?)?;
+    let check = decrypt_bytes(&key, &hex::decode(key_file.check).ok()?).ok()?;
+    (check == KEY_CHECK).then_some(key)
+}
+
+/// 初めて使うバックアップ先に、ソルトと確認用データを作成して鍵を導出する
+/// 打ち間違えると元に戻せないため、呼び出し側でパスフレーズを 2 回入力してもらう
+/// 鍵ファイルがあるか確かめられない場合も、上書きしないようエラーにする
+pub fn create_key(store: &dyn BackupStore, passphrase: &str) -> io::Result<EncryptionKey> {
+    if passphrase.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "empty passphrase",
+        ));
+    }
+
+    if store.load_index(KEY_FILENAME)?.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the backup directory already has a key",
+        ));
+    }
+
+    let salt = random_bytes::<16>()?;
+    let key = EncryptionKey::derive(passphrase, &salt)
+        .ok_or_else(|| io::Error::other("failed to derive the key"))?;
+    let key_file = KeyFile {
+        salt: hex::encode(salt),
+        check: hex::encode(encrypt_bytes(&key, KEY_CHECK)?),
+    };
+    store.write_index(
+        KEY_FILENAME,
+        &serde_yaml::to_string(&key_file).map_err(io::Error::other)?,
+    )?;
+    Ok(key)
+}
+
+pub fn encrypt_bytes(key: &EncryptionKey, data: &[u8]) -> io::Result<Vec<u8>> {
+    let nonce = random_bytes::<NONCE_SIZE>()?;
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend(
+        key.cipher()
+            .encrypt(&nonce.into(), data)
+            .map_err(encryption_error)?,
+    );
+    Ok(encrypted)
+}
+
+pub fn decrypt_bytes(key: &EncryptionKey, data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() < NONCE_SIZE {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    let (nonce, encrypted) = data.split_at(NONCE_SIZE);
+    key.cipher()
+        .decrypt(nonce.into(), encrypted)
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+/// 途中で EOF になるまで buffer を埋め、読めたバイト数を返す
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    Ok(read)
+}
+
+/// 64 KiB ごとに認証付きで暗号化する (STREAM 構成)
+pub fn encrypt_file(key: &EncryptionKey, source_path: &str, dest_path: &str) -> io::Result<()> {
+    let mut source = fs::File::open(source_path)?;
+    let mut dest = fs::File::create(dest_path)?;
+
+    let nonce = random_bytes::<STREAM_NONCE_SIZE>()?;
+    dest.write_all(FILE_MAGIC)?;
+    dest.write_all(&nonce)?;
+
+    let mut encryptor = EncryptorBE32::from_aead(key.cipher(), GenericArray::from_slice(&nonce));
+    let mut buffer = vec![0u8; SEGMENT_SIZE];
+    loop {
+        let read = read_full(&mut source, &mut buffer)?;
+        if read < SEGMENT_SIZE {
+            let encrypted = encryptor
+                .encrypt_last(&buffer[..read])
+                .map_err(encryption_error)?;
+            dest.write_all(&encrypted)?;
+            break;
+        }
+
+        let encrypted = encryptor
+            .encrypt_next(&buffer[..])
+            .map_err(encryption_error)?;
+        dest.write_all(&encrypted)?;
+    }
+
+    dest.flush()
+}
+
+pub fn decrypt_file(key: &EncryptionKey, source_path: &str, dest_path: &str) -> io::Result<()> {
+    let invalid_data = || io::Error::from(io::ErrorKind::InvalidData);
+
+    let mut source = fs::File::open(source_path)?;
+    let mut header = [0u8; FILE_MAGIC.len() + STREAM_NONCE_SIZE];
+    source.read_exact(&mut header)?;
+    let (magic, nonce) = header.split_at(FILE_MAGIC.len());
+    if magic != FILE_MAGIC {
+        return Err(invalid_data());
+    }
+
+    let mut dest = fs::File::create(dest_path)?;
+    let mut decryptor = DecryptorBE32::from_aead(key.cipher(), GenericArray::from_slice(nonce));
+    let mut buffer = vec![0u8; SEGMENT_SIZE + TAG_SIZE];
+    loop {
+        let read = read_full(&mut source, &mut buffer)?;
+        if read < SEGMENT_SIZE + TAG_SIZE {
+            let decrypted = decryptor
+                .decrypt_last(&buffer[..read])
+                .map_err(|_| invalid_data())?;
+            dest.write_all(&decrypted)?;
+            break;
+        }
+
+        let decrypted = decryptor
+            .decrypt_next(&buffer[..])
+            .map_err(|_| invalid_data())?;
+        dest.write_all(&decrypted)?;
+    }
+
+    dest.flush()
+}
+
+/// 別の保存方式の上で、内容とファイル名を暗号化する
+/// 暗号文は圧縮できないため、内側の保存方式では圧縮しない
+pub struct EncryptedStore {
+    inner: Box<dyn BackupStore>,
+    key: Option<EncryptionKey>,
+}
+
+impl EncryptedStore {
+    pub fn new(inner: Box<dyn BackupStore>, key: Option<EncryptionKey>) -> Self {
+        EncryptedStore { inner, key }
+    }
+
+    fn key(&self) -> io::Result<&EncryptionKey> {
+        self.key
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "locked"))
+    }
+
+    /// 鍵付きハッシュで元の名前を隠す
+    fn encrypted_name(&self, key: &EncryptionKey, backup_filename: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(key.0);
+        hasher.update(backup_filename.as_bytes());
+        hex::encode(&hasher.finalize()[..20])
+    }
+
+    /// 対応表が無ければ空を返し、あるのに読めない場合はエラーにする
+    /// 読めないまま書き込むと、それまでの名前が失われるため
+    fn load_names(&self) -> io::Result<BTreeMap<String, String>> {
+        match self.load_index(NAME_INDEX_FILENAME)? {
+            Some(content) => serde_yaml::from_str(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+}
+
+impl BackupStore for EncryptedStore {
+    fn is_available(&self) -> bool {
+        self.key.is_some() && self.inner.is_available()
+    }
+
+    fn list_versions(&self) -> Vec<String> {
+        self.load_names()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(encrypted_name, _)| self.inner.has_version(encrypted_name))
+            .map(|(_, backup_filename)| backup_filename)
+            .collect()
+    }
+
+    fn has_version(&self, backup_filename: &str) -> bool {
+        self.key.as_ref().is_some_and(|key| {
+            self.inner
+                .has_version(&self.encrypted_name(key, backup_filename))
+        })
+    }
+
+    fn write_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()> {
+        let key = self.key()?;
+        let encrypted_name = self.encrypted_name(key, backup_filename);
+        let mut names = self.load_names()?;
+
+        let temp_dir = PrivateTempDir::new("encrypt")?;
+        let temporary = temp_dir.file("version");
+        encrypt_file(key, source_path, &temporary)?;
+        self.inner.write_version(&temporary, &encrypted_name)?;
+
+        names.insert(encrypted_name, backup_filename.to_string());
+        let yaml = serde_yaml::to_string(&names).map_err(io::Error::other)?;
+        self.write_index(NAME_INDEX_FILENAME, &yaml)
+    }
+
+    /// 名前の対応表は書き込んだ時のままで変わらない
+    fn replace_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()> {
+        let key = self.key()?;
+        let temp_dir = PrivateTempDir::new("encrypt")?;
+        let temporary = temp_dir.file("version");
+        encrypt_file(key, source_path, &temporary)?;
+        self.inner
+            .replace_version(&temporary, &self.encrypted_name(key, backup_filename))
+    }
+
+    fn read_version(&self, backup_filename: &str, dest_path: &str) -> io::Result<()> {
+        let key = self.key()?;
+        let encrypted_name = self.encrypted_name(key, backup_filename);
+
+        let temp_dir = PrivateTempDir::new("decrypt")?;
+        let temporary = temp_dir.file("version");
+        self.inner.read_version(&encrypted_name, &temporary)?;
+        decrypt_file(key, &temporary, dest_path)
+    }
+
+    fn remove_version(&self, backup_filename: &str) -> io::Result<()> {
+        let key = self.key()?;
+        self.inner
+            .remove_version(&self.encrypted_name(key, backup_filename))
+    }
+
+    fn load_index(&self, index_name: &str) -> io::Result<Option<String>> {
+        let key = self.key()?;
+        let Some(content) = self.inner.load_index(index_name)? else {
+            return Ok(None);
+        };
+        let encrypted = hex::decode(content.trim())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        String::from_utf8(decrypt_bytes(key, &encrypted)?)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write_index(&self, index_name: &str, content: &str) -> io::Result<()> {
+        let encrypted = encrypt_bytes(self.key()?, content.as_bytes())?;
+        self.inner.write_index(index_name, &hex::encode(encrypted))
+    }
+
+    fn collect_garbage(&self) -> io::Result<usize> {
+        self.inner.collect_garbage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_store::FlatStore;
+    use crate::compression::Compression;
+    use crate::parity::Parity;
+    use crate::user_data::append_path;
+    use std::sync::{Arc, Mutex};
+
+    fn directory() -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_string_lossy().to_string();
+        (dir, path)
+    }
+
+    fn key_store(path: &str) -> FlatStore {
+        FlatStore::new(path, Compression::None, Parity::None)
+    }
+
+    /// ローカルのディレクトリを持たないバックアップ先
+    #[derive(Clone, Default)]
+    struct MemoryStore {
+        versions: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+        indexes: Arc<Mutex<BTreeMap<String, String>>>,
+    }
+
+    impl BackupStore for MemoryStore {
+        fn is_available(&self) -> bool {
+            true
+        }
+
+        fn list_versions(&self) -> Vec<String> {
+            self.versions.lock().unwrap().keys().cloned().collect()
+        }
+
+        fn has_version(&self, backup_filename: &str) -> bool {
+            self.versions.lock().unwrap().contains_key(backup_filename)
+        }
+
+        fn write_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()> {
+            let data = fs::read(source_path)?;
+            self.versions
+                .lock()
+                .unwrap()
+                .insert(backup_filename.to_string(), data);
+            Ok(())
+        }
+
+        fn read_version(&self, backup_filename: &str, dest_path: &str) -> io::Result<()> {
+            let versions = self.versions.lock().unwrap();
+            let data = versions
+                .get(backup_filename)
+                .ok_or(io::ErrorKind::NotFound)?;
+            fs::write(dest_path, data)
+        }
+
+        fn remove_version(&self, backup_filename: &str) -> io::Result<()> {
+            self.versions.lock().unwrap().remove(backup_filename);
+            Ok(())
+        }
+
+        fn load_index(&self, index_name: &str) -> io::Result<Option<String>> {
+            Ok(self.indexes.lock().unwrap().get(index_name).cloned())
+        }
+
+        fn write_index(&self, index_name: &str, content: &str) -> io::Result<()> {
+            self.indexes
+                .lock()
+                .unwrap()
+                .insert(index_name.to_string(), content.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let (_dir, path) = directory();
+        let key = create_key(&key_store(&path), "correct horse").unwrap();
+        let encrypted = encrypt_bytes(&key, b"secret").unwrap();
+        assert_ne!(&encrypted[NONCE_SIZE..], b"secret");
+        assert_eq!(decrypt_bytes(&key, &encrypted).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn file_round_trip_across_segments() {
+        let (_dir, path) = directory();
+        let key = create_key(&key_store(&path), "correct horse").unwrap();
+        // 区切りちょうどの大きさも、最後のセグメントとして扱えること
+        for len in [0, 10, SEGMENT_SIZE, SEGMENT_SIZE * 2 + 7] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let source = append_path(&path, "source");
+            let encrypted = append_path(&path, "encrypted");
+            let decrypted = append_path(&path, "decrypted");
+            fs::write(&source, &data).unwrap();
+
+            encrypt_file(&key, &source, &encrypted).unwrap();
+            decrypt_file(&key, &encrypted, &decrypted).unwrap();
+            assert_eq!(fs::read(&decrypted).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let (_dir, path) = directory();
+        let key = create_key(&key_store(&path), "correct horse").unwrap();
+        assert!(unlock(&key_store(&path), "correct horse").is_some());
+        assert!(unlock(&key_store(&path), "wrong horse").is_none());
+        assert!(unlock(&key_store(&path), "").is_none());
+
+        // 別のパスフレーズから導出した鍵では復号できない
+        let (_other_dir, other_path) = directory();
+        let other_key = create_key(&key_store(&other_path), "wrong horse").unwrap();
+        let encrypted = encrypt_bytes(&key, b"secret").unwrap();
+        assert!(decrypt_bytes(&other_key, &encrypted).is_err());
+    }
+
+    #[test]
+    fn tampered_file_is_rejected() {
+        let (_dir, path) = directory();
+        let key = create_key(&key_store(&path), "correct horse").unwrap();
+        let source = append_path(&path, "source");
+        let encrypted = append_path(&path, "encrypted");
+        fs::write(&source, b"secret").unwrap();
+        encrypt_file(&key, &source, &encrypted).unwrap();
+
+        let mut data = fs::read(&encrypted).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&encrypted, data).unwrap();
+        let error = decrypt_file(&key, &encrypted, &append_path(&path, "decrypted")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn key_is_created_only_once() {
+        let (_dir, path) = directory();
+        assert!(unlock(&key_store(&path), "correct horse").is_none());
+        assert!(!is_encrypted_target(&path));
+
+        create_key(&key_store(&path), "correct horse").unwrap();
+        assert!(is_encrypted_target(&path));
+        let error = create_key(&key_store(&path), "another").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(unlock(&key_store(&path), "correct horse").is_some());
+        assert!(create_key(&key_store(&directory().1), "").is_err());
+    }
+
+    #[test]
+    fn encrypted_store_round_trip() {
+        let (_dir, path) = directory();
+        let key = create_key(&key_store(&path), "correct horse").unwrap();
+        let backup = append_path(&path, "backup");
+        fs::create_dir(&backup).unwrap();
+        let store = EncryptedStore::new(
+            Box::new(FlatStore::new(&backup, Compression::None, Parity::None)),
+            Some(key),
+        );
+
+        let source = append_path(&path, "source.txt");
+        fs::write(&source, b"secret").unwrap();
+        store.write_version(&source, "v1_source.txt").unwrap();
+        assert_eq!(store.list_versions(), vec!["v1_source.txt".to_string()]);
+
+        let restored = append_path(&path, "restored.txt");
+        store.read_version("v1_source.txt", &restored).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), b"secret");
+
+        // 鍵が無ければ読めない
+        let locked = EncryptedStore::new(
+            Box::new(FlatStore::new(&backup, Compression::None, Parity::None)),
+            None,
+        );
+        assert!(locked.read_version("v1_source.txt", &restored).is_err());
+    }
+
+    #[test]
+    fn key_and_names_are_kept_in_a_remote_store() {
+        let (_dir, path) = directory();
+        let remote = MemoryStore::default();
+        let key = create_key(&remote, "correct horse").unwrap();
+        assert!(has_key_file(&remote));
+        assert!(unlock(&remote, "correct horse").is_some());
+        assert!(unlock(&remote, "wrong horse").is_none());
+
+        let store = EncryptedStore::new(Box::new(remote.clone()), Some(key));
+        let source = append_path(&path, "source.txt");
+        fs::write(&source, b"secret").unwrap();
+        store.write_version(&source, "v1_source.txt").unwrap();
+        assert_eq!(store.list_versions(), vec!["v1_source.txt".to_string()]);
+
+        let restored = append_path(&path, "restored.txt");
+        store.read_version("v1_source.txt", &restored).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), b"secret");
+
+        // 名前の対応表も暗号化されている
+        let indexes = remote.indexes.lock().unwrap();
+        assert!(!indexes[NAME_INDEX_FILENAME].contains("v1_source.txt"));
+        assert!(indexes.contains_key(KEY_FILENAME));
+    }
+
+    #[test]
+    fn key_file_is_copied_between_stores() {
+        let source = MemoryStore::default();
+        let replica = MemoryStore::default();
+        assert!(copy_key_file(&source, &replica).is_err());
+
+        create_key(&source, "correct horse").unwrap();
+        copy_key_file(&source, &replica).unwrap();
+        assert!(unlock(&replica, "correct horse").is_some());
+    }
+
+    #[test]
+    fn unreadable_name_index_is_not_overwritten() {
+        let (_dir, path) = directory();
+        let remote = MemoryStore::default();
+        let key = create_key(&remote, "correct horse").unwrap();
+        let store = EncryptedStore::new(Box::new(remote.clone()), Some(key));
+        let source = append_path(&path, "source.txt");
+        fs::write(&source, b"secret").unwrap();
+        store.write_version(&source, "v1_source.txt").unwrap();
+
+        // 対応表が壊れていたら、書き込みをやめて対応表を残す
+        remote.write_index(NAME_INDEX_FILENAME, "broken").unwrap();
+        let error = store.write_version(&source, "v2_source.txt").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            remote.load_index(NAME_INDEX_FILENAME).unwrap().unwrap(),
+            "broken"
+        );
+        assert_eq!(remote.list_versions().len(), 1);
+    }
+}
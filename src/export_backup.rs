@@ -0,0 +1,260 @@
+use crate::atomic_file::PrivateTempDir;
+use crate::backup_scan::encode_name;
+use crate::backup_store::BackupStore;
+use crate::hash_index::{hash_file, HashIndex};
+use crate::manifest::Manifest;
+use crate::sftp::RemoteExport;
+use crate::trash::create_trash_directory;
+use crate::user_data::{append_path, get_parent_path, version_id};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// エクスポート先を上書きする前に、そこにあったファイルをどこへ残すか
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportBackup {
+    /// 残さずに上書きする
+    #[default]
+    Off,
+    /// バックアップディレクトリに、そのファイルの 1 バージョンとして残す
+    BackupDirectory,
+    /// エクスポート先の隣の `.dd-backup-trash/<日時>/` へ移す
+    Trash,
+}
+
+impl ExportBackup {
+    pub const ALL: [ExportBackup; 3] = [
+        ExportBackup::Off,
+        ExportBackup::BackupDirectory,
+        ExportBackup::Trash,
+    ];
+}
+
+impl fmt::Display for ExportBackup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ExportBackup::Off => "Overwrite Export",
+                ExportBackup::BackupDirectory => "Keep Previous Export in Backup",
+                ExportBackup::Trash => "Keep Previous Export in Trash",
+            }
+        )
+    }
+}
+
+/// target を上書きする前に、設定に従って今の内容を残す
+/// これから書き込む内容と同じ場合や、同じ内容が既にバックアップにある場合は何もしない
+pub fn preserve_previous_export(
+    mode: ExportBackup,
+    name: &str,
+    source_path: &str,
+    target: &str,
+    store: &dyn BackupStore,
+) -> io::Result<()> {
+    if mode == ExportBackup::Off || !Path::new(target).is_file() {
+        return Ok(());
+    }
+
+    let hash = hash_file(target).unwrap_or_default();
+    if hash_file(source_path).as_ref() == Some(&hash) {
+        return Ok(());
+    }
+
+    match mode {
+        ExportBackup::Off => Ok(()),
+        ExportBackup::BackupDirectory => store_in_backup(name, target, &hash, store),
+        ExportBackup::Trash => move_to_trash(target),
+    }
+}
+
+/// リモートの target を上書きする前に、設定に従って今の内容を残す
+/// 内容を比べるため、一度ダウンロードする
+pub fn preserve_previous_remote_export(
+    mode: ExportBackup,
+    name: &str,
+    source_path: &str,
+    remote: &RemoteExport,
+    store: &dyn BackupStore,
+) -> io::Result<()> {
+    if mode == ExportBackup::Off || !remote.exists() {
+        return Ok(());
+    }
+
+    let temp = PrivateTempDir::new("export")?;
+    let current = temp.file("current");
+    remote.download(&current)?;
+    let hash = hash_file(&current).unwrap_or_default();
+    if hash_file(source_path).as_ref() == Some(&hash) {
+        return Ok(());
+    }
+
+    match mode {
+        ExportBackup::Off => Ok(()),
+        ExportBackup::BackupDirectory => store_in_backup(name, &current, &hash, store),
+        ExportBackup::Trash => remote.move_to_trash(),
+    }
+}
+
+/// エクスポート先の更新日時をバージョンとして書き込む
+/// 手元のファイルの最新のバックアップは変わらないよう、ハッシュの履歴には加えない
+fn store_in_backup(
+    name: &str,
+    target: &str,
+    hash: &str,
+    store: &dyn BackupStore,
+) -> io::Result<()> {
+    if !store.is_available() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no backup directory to keep the previous export",
+        ));
+    }
+
+    // 以前に残したエクスポート先はハッシュの履歴に無いため、署名付きの記録も調べる
+    let index = HashIndex::load(store);
+    let manifest = Manifest::load(store)?;
+    let already_stored = index
+        .entries
+        .iter()
+        .map(|e| (&e.name, &e.hash, &e.backup_filename))
+        .chain(
+            manifest
+                .entries
+                .iter()
+                .map(|e| (&e.name, &e.hash, &e.backup_filename)),
+        )
+        .any(|(n, h, backup_filename)| {
+            n == name && h == hash && store.has_version(backup_filename)
+        });
+    if already_stored {
+        return Ok(());
+    }
+
+    let metadata = fs::metadata(target)?;
+    let modified: DateTime<Utc> = metadata.modified()?.into();
+    let mut backup_filename = format!("{}_{}", version_id(&modified), encode_name(name));
+    let mut sequence = 1;
+    while store.has_version(&backup_filename) {
+        backup_filename = format!(
+            "{}.{}_{}",
+            version_id(&modified),
+            sequence,
+            encode_name(name)
+        );
+        sequence += 1;
+    }
+
+    store.write_version(target, &backup_filename)?;
+    Manifest::append(
+        store,
+        name,
+        &backup_filename,
+        modified,
+        metadata.len(),
+        hash,
+    )
+    .ok();
+    Ok(())
+}
+
+/// <エクスポート先のディレクトリ>/.dd-backup-trash/<日時>/<ファイル名>
+/// ごみ箱は毎回新しく作るため、以前に移したファイルを上書きしない
+fn move_to_trash(target: &str) -> io::Result<()> {
+    let file_name = Path::new(target)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let trash = create_trash_directory(&get_parent_path(&target.to_string()))?;
+    fs::rename(target, append_path(&trash, &file_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trash::TRASH_DIRECTORY;
+    use crate::user_data::DirectoryInfo;
+
+    /// 手元の a.txt と、内容の違うエクスポート先の a.txt
+    fn replaced_export(root: &str, previous: &str) -> (String, String) {
+        let source = append_path(root, "source.txt");
+        let target = append_path(root, "a.txt");
+        fs::write(&source, "new").unwrap();
+        fs::write(&target, previous).unwrap();
+        (source, target)
+    }
+
+    #[test]
+    fn trash_keeps_every_replaced_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let store = DirectoryInfo::new(root.clone(), String::new()).backup_store();
+
+        for previous in ["first", "second"] {
+            let (source, target) = replaced_export(&root, previous);
+            preserve_previous_export(
+                ExportBackup::Trash,
+                "a.txt",
+                &source,
+                &target,
+                store.as_ref(),
+            )
+            .unwrap();
+            assert!(!fs::exists(&target).unwrap());
+        }
+
+        let mut kept: Vec<String> = fs::read_dir(append_path(&root, TRASH_DIRECTORY))
+            .unwrap()
+            .flatten()
+            .map(|entry| fs::read_to_string(entry.path().join("a.txt")).unwrap())
+            .collect();
+        kept.sort();
+        assert_eq!(kept, ["first", "second"]);
+    }
+
+    #[test]
+    fn replaced_export_is_kept_in_backup_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let store = DirectoryInfo::new(root.clone(), backup.path().to_string_lossy().to_string())
+            .backup_store();
+
+        for _ in 0..2 {
+            let (source, target) = replaced_export(&root, "previous");
+            preserve_previous_export(
+                ExportBackup::BackupDirectory,
+                "a.txt",
+                &source,
+                &target,
+                store.as_ref(),
+            )
+            .unwrap();
+            assert_eq!(fs::read_to_string(&target).unwrap(), "previous");
+        }
+        assert_eq!(store.list_versions().len(), 1);
+    }
+
+    #[test]
+    fn same_content_is_not_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let store = DirectoryInfo::new(root.clone(), String::new()).backup_store();
+
+        let (source, target) = replaced_export(&root, "new");
+        preserve_previous_export(
+            ExportBackup::Trash,
+            "a.txt",
+            &source,
+            &target,
+            store.as_ref(),
+        )
+        .unwrap();
+        assert!(fs::exists(&target).unwrap());
+        assert!(!fs::exists(append_path(&root, TRASH_DIRECTORY)).unwrap());
+    }
+}
@@ -0,0 +1,288 @@
+use crate::backup_store::BackupStore;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::time::SystemTime;
+
+/// バージョンごとのメタデータを記録するファイル (バックアップディレクトリに置く)
+pub const METADATA_INDEX_FILENAME: &str = ".dd-backup-metadata.yaml";
+
+/// コピーする時に元のファイルから引き継ぐメタデータ (既定ではどれも引き継がない)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct MetadataOptions {
+    /// 更新日時とアクセス日時
+    pub times: bool,
+    pub permissions: bool,
+    /// 所有者 (root で実行している時だけ変更できる)
+    pub ownership: bool,
+    /// 拡張属性 (Linux のみ)
+    pub xattrs: bool,
+}
+
+/// 1 つのファイルから読み取ったメタデータ
+/// 引き継がない項目は None や空のままにしておく
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accessed: Option<DateTime<Utc>>,
+    /// Unix のパーミッション
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Unix 以外では読み取り専用かどうかだけを引き継ぐ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readonly: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    /// 拡張属性の名前と、値を 16 進数にしたもの
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid は引数を取らず、失敗しない
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(target_os = "linux")]
+fn read_xattrs(path: &str) -> BTreeMap<String, String> {
+    let Ok(names) = xattr::list(path) else {
+        return BTreeMap::new();
+    };
+
+    names
+        .filter_map(|name| {
+            let value = xattr::get(path, &name).ok()??;
+            Some((name.to_string_lossy().to_string(), hex::encode(value)))
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_xattrs(_path: &str) -> BTreeMap<String, String> {
+    BTreeMap::new()
+}
+
+/// 1 つずつ書き込み、書き込めない属性 (権限の要る名前空間や、対応していないファイルシステム) は飛ばす
+#[cfg(target_os = "linux")]
+fn write_xattrs(path: &str, xattrs: &BTreeMap<String, String>) {
+    for (name, value) in xattrs {
+        if let Ok(value) = hex::decode(value) {
+            xattr::set(path, name, &value).ok();
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_xattrs(_path: &str, _xattrs: &BTreeMap<String, String>) {}
+
+impl FileMetadata {
+    /// options で有効な項目だけを読み取る
+    pub fn read(path: &str, options: MetadataOptions) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mut file_metadata = FileMetadata::default();
+
+        if options.times {
+            file_metadata.modified = metadata.modified().ok().map(DateTime::from);
+            file_metadata.accessed = metadata.accessed().ok().map(DateTime::from);
+        }
+
+        if options.permissions {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file_metadata.mode = Some(metadata.permissions().mode());
+            }
+            #[cfg(not(unix))]
+            {
+                file_metadata.readonly = Some(metadata.permissions().readonly());
+            }
+        }
+
+        #[cfg(unix)]
+        if options.ownership {
+            use std::os::unix::fs::MetadataExt;
+            file_metadata.uid = Some(metadata.uid());
+            file_metadata.gid = Some(metadata.gid());
+        }
+
+        if options.xattrs {
+            file_metadata.xattrs = read_xattrs(path);
+        }
+
+        Ok(file_metadata)
+    }
+
+    /// 記録されている項目を path に書き戻す
+    /// 日時とパーミッションを先に戻し、所有者、拡張属性の順に続ける
+    /// 拡張属性は書き込めないものがあっても失敗にしない
+    pub fn apply(&self, path: &str) -> io::Result<()> {
+        if self.modified.is_some() || self.accessed.is_some() {
+            let mut times = fs::FileTimes::new();
+            if let Some(modified) = self.modified {
+                times = times.set_modified(SystemTime::from(modified));
+            }
+            if let Some(accessed) = self.accessed {
+                times = times.set_accessed(SystemTime::from(accessed));
+            }
+
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .or_else(|_| fs::File::open(path))?
+                .set_times(times)?;
+        }
+
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+
+        if let Some(readonly) = self.readonly {
+            let mut permissions = fs::metadata(path)?.permissions();
+            permissions.set_readonly(readonly);
+            fs::set_permissions(path, permissions)?;
+        }
+
+        #[cfg(unix)]
+        if (self.uid.is_some() || self.gid.is_some()) && is_root() {
+            std::os::unix::fs::chown(path, self.uid, self.gid)?;
+            // 所有者を変えると setuid と setgid が外れるため、付け直す
+            if let Some(mode) = self.mode.filter(|mode| mode & 0o6000 != 0) {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+            }
+        }
+
+        write_xattrs(path, &self.xattrs);
+        Ok(())
+    }
+}
+
+/// source_path のメタデータを dest_path へ引き継ぐ
+pub fn copy_metadata(
+    source_path: &str,
+    dest_path: &str,
+    options: MetadataOptions,
+) -> io::Result<()> {
+    FileMetadata::read(source_path, options)?.apply(dest_path)
+}
+
+/// バックアップファイル名ごとのメタデータ
+/// 圧縮や暗号化、リモートのバックアップ先でも元に戻せるよう、バージョンの実体とは別に記録する
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MetadataIndex {
+    pub entries: BTreeMap<String, FileMetadata>,
+}
+
+impl MetadataIndex {
+    pub fn load(store: &dyn BackupStore) -> Self {
+        store
+            .read_index(METADATA_INDEX_FILENAME)
+            .and_then(|content| serde_yaml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, store: &dyn BackupStore) {
+        if let Ok(yaml) = serde_yaml::to_string(self) {
+            store.write_index(METADATA_INDEX_FILENAME, &yaml).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_data::append_path;
+    use chrono::TimeZone;
+
+    fn sample_file(directory: &tempfile::TempDir, name: &str) -> String {
+        let path = append_path(&directory.path().to_string_lossy(), name);
+        fs::write(&path, "hello").unwrap();
+        path
+    }
+
+    #[test]
+    fn nothing_is_preserved_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = sample_file(&dir, "a.txt");
+        assert_eq!(
+            FileMetadata::read(&path, MetadataOptions::default()).unwrap(),
+            FileMetadata::default()
+        );
+
+        let options: MetadataOptions = serde_yaml::from_str("permissions: true").unwrap();
+        assert!(options.permissions && !options.times);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn times_are_applied_to_a_read_only_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = sample_file(&dir, "a.txt");
+        let modified = Utc.with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap();
+        let metadata = FileMetadata {
+            modified: Some(modified),
+            mode: Some(0o100444),
+            ..FileMetadata::default()
+        };
+        metadata.apply(&path).unwrap();
+
+        let applied = fs::metadata(&path).unwrap();
+        assert_eq!(DateTime::<Utc>::from(applied.modified().unwrap()), modified);
+        assert_eq!(applied.permissions().mode() & 0o777, 0o444);
+    }
+
+    #[test]
+    fn unwritable_xattrs_do_not_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = sample_file(&dir, "a.txt");
+        let modified = Utc.with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap();
+        let metadata = FileMetadata {
+            modified: Some(modified),
+            xattrs: BTreeMap::from([
+                ("no.such.namespace".to_string(), hex::encode("x")),
+                ("user.broken".to_string(), "not hex".to_string()),
+            ]),
+            ..FileMetadata::default()
+        };
+        metadata.apply(&path).unwrap();
+
+        let applied = fs::metadata(&path).unwrap();
+        assert_eq!(DateTime::<Utc>::from(applied.modified().unwrap()), modified);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn writable_xattrs_are_kept_when_others_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = sample_file(&dir, "a.txt");
+        // user 名前空間に対応していないファイルシステムでは確かめられない
+        if xattr::set(&path, "user.probe", b"1").is_err() {
+            return;
+        }
+
+        let metadata = FileMetadata {
+            xattrs: BTreeMap::from([
+                ("no.such.namespace".to_string(), hex::encode("x")),
+                ("user.comment".to_string(), hex::encode("kept")),
+            ]),
+            ..FileMetadata::default()
+        };
+        metadata.apply(&path).unwrap();
+        assert_eq!(
+            xattr::get(&path, "user.comment").unwrap(),
+            Some(b"kept".to_vec())
+        );
+    }
+}
@@ -0,0 +1,580 @@
+use crate::atomic_file::write_atomically;
+use crate::backup_scan::parse_backup_filename;
+use crate::backup_store::{missing_as_none, BackupStore};
+use crate::user_data::{append_path, is_valid_directory, is_valid_file};
+use chrono::{DateTime, Utc};
+use gix::object::tree::EntryKind;
+use gix::objs::Write as _;
+use gix::refs::transaction::PreviousValue;
+use gix::refs::Target;
+use gix::remote::Direction;
+use gix::ObjectId;
+use std::cell::{OnceCell, Ref, RefCell};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+/// バックアップを積み重ねるブランチ
+/// 既存のリポジトリでも作業ツリーや他のブランチには触れない
+pub const GIT_BRANCH: &str = "dd-backup";
+
+/// コミットメッセージの末尾に置く、バージョン名の trailer
+const VERSION_TRAILER: &str = "DD-Backup-Version: ";
+
+/// 削除したバージョンの一覧 (git の履歴からは消さず、一覧に出さないだけにする)
+const REMOVED_VERSIONS_FILENAME: &str = ".dd-backup-git-removed.yaml";
+
+const COMMITTER_NAME: &str = "dd-backup";
+const COMMITTER_EMAIL: &str = "dd-backup@localhost";
+
+/// ディレクトリが git のリポジトリ (作業ツリー付きか bare) か
+pub fn is_git_repository(directory: &str) -> bool {
+    is_valid_directory(&append_path(directory, ".git"))
+        || (is_valid_file(&append_path(directory, "HEAD"))
+            && is_valid_directory(&append_path(directory, "objects")))
+}
+
+fn git_error(error: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::other(error)
+}
+
+/// directory のリポジトリを開く (無ければ bare で作り、HEAD をバックアップのブランチに向ける)
+fn open_or_init(directory: &str) -> io::Result<gix::Repository> {
+    if is_git_repository(directory) {
+        return gix::open(directory).map_err(git_error);
+    }
+
+    if !is_valid_directory(directory) {
+        return Err(io::Error::from(io::ErrorKind::NotFound));
+    }
+
+    // 新しく作ったリポジトリでは、git log がそのままバックアップの履歴になるようにする
+    gix::init_bare(directory).map_err(git_error)?;
+    write_atomically(
+        &append_path(directory, "HEAD"),
+        format!("ref: refs/heads/{}\n", GIT_BRANCH),
+    )?;
+    gix::open(directory).map_err(git_error)
+}
+
+fn branch_reference() -> String {
+    format!("refs/heads/{}", GIT_BRANCH)
+}
+
+/// ブランチの先頭のコミット (まだ何もコミットしていなければ None)
+fn branch_tip(repository: &gix::Repository) -> Option<ObjectId> {
+    repository
+        .try_find_reference(branch_reference().as_str())
+        .ok()
+        .flatten()
+        .and_then(|mut reference| reference.peel_to_id().ok())
+        .map(|id| id.detach())
+}
+
+/// push 先のリポジトリのディレクトリ
+/// remote はリポジトリに設定したリモートの名前か、パス (`file://` も可)
+/// gix は送信側のプロトコルを持たないため、ssh や https のリモートには push できない
+fn push_directory(repository: &gix::Repository, remote: &str) -> io::Result<String> {
+    let url = match repository.try_find_remote(remote) {
+        Some(found) => found
+            .map_err(git_error)?
+            .url(Direction::Push)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, remote.to_string()))?,
+        None => gix::url::parse(remote.into()).map_err(git_error)?,
+    };
+
+    if url.scheme != gix::url::Scheme::File {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("only local repositories can be pushed to: {}", remote),
+        ));
+    }
+    Ok(url.path.to_string())
+}
+
+/// target に無いオブジェクトを source からコピーする
+/// 参照先を先に書くため、途中で失敗しても target に木の欠けたコミットは残らない
+fn copy_missing_objects(
+    source: &gix::Repository,
+    target: &gix::Repository,
+    tip: ObjectId,
+) -> io::Result<()> {
+    // (オブジェクト, 参照先を積み終えたか)
+    let mut stack = vec![(tip, false)];
+    while let Some((id, expanded)) = stack.pop() {
+        if target.has_object(id) {
+            continue;
+        }
+
+        let object = source.find_object(id).map_err(git_error)?;
+        if expanded {
+            target
+                .objects
+                .write_buf(object.kind, &object.data)
+                .map_err(io::Error::other)?;
+            continue;
+        }
+
+        stack.push((id, true));
+        match object.kind {
+            gix::objs::Kind::Commit => {
+                let mut commit = gix::objs::CommitRefIter::from_bytes(&object.data);
+                stack.push((commit.tree_id().map_err(git_error)?, false));
+                stack.extend(commit.parent_ids().map(|parent| (parent, false)));
+            }
+            gix::objs::Kind::Tree => {
+                for entry in gix::objs::TreeRefIter::from_bytes(&object.data) {
+                    let entry = entry.map_err(git_error)?;
+                    // サブモジュールのコミットは別のリポジトリのもの
+                    if !entry.mode.is_commit() {
+                        stack.push((entry.oid.to_owned(), false));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// バックアップ先のバージョン名から、リポジトリ内のパスにするファイル名を取り出す
+fn tracked_name(backup_filename: &str) -> String {
+    parse_backup_filename(backup_filename)
+        .map(|(_, name)| name)
+        .unwrap_or_else(|| backup_filename.to_string())
+}
+
+/// ローカルの git リポジトリに、同期のたびにファイルをコミットする方式
+/// ファイルは元の名前のパスに置くため、履歴は `git log dd-backup -- <name>` で追える
+/// 圧縮やパリティは行わない
+pub struct GitStore {
+    directory: String,
+    /// コミットのたびにブランチを push するリポジトリ (空なら push しない)
+    remote: String,
+    /// 最初の操作で開き (無ければ bare で作り)、このバックアップ先を使い終わるまで使い回す
+    repository: OnceCell<Option<gix::Repository>>,
+    /// バージョン名ごとのコミット (削除したものも含む)
+    /// 履歴をたどるのは最初に必要になった時だけにし、以降はコミットのたびに追加する
+    versions: RefCell<Option<HashMap<String, ObjectId>>>,
+}
+
+impl GitStore {
+    pub fn new(directory: &str, remote: &str) -> Self {
+        GitStore {
+            directory: directory.to_string(),
+            remote: remote.to_string(),
+            repository: OnceCell::new(),
+            versions: RefCell::new(None),
+        }
+    }
+
+    fn repository(&self) -> io::Result<&gix::Repository> {
+        self.repository
+            .get_or_init(|| open_or_init(&self.directory).ok())
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, self.directory.clone()))
+    }
+
+    /// ブランチの履歴をたどり、バージョン名ごとに最も新しいコミットを集める
+    fn walk_versions(&self) -> HashMap<String, ObjectId> {
+        let mut versions = HashMap::new();
+        let Ok(repository) = self.repository() else {
+            return versions;
+        };
+        let Some(tip) = branch_tip(repository) else {
+            return versions;
+        };
+        let Ok(walk) = repository.rev_walk([tip]).all() else {
+            return versions;
+        };
+
+        for info in walk.flatten() {
+            let Ok(commit) = info.object() else {
+                continue;
+            };
+            let message = commit.message_raw_sloppy().to_string();
+            if let Some(backup_filename) = message
+                .lines()
+                .find_map(|line| line.strip_prefix(VERSION_TRAILER))
+            {
+                versions
+                    .entry(backup_filename.trim().to_string())
+                    .or_insert(info.id);
+            }
+        }
+
+        versions
+    }
+
+    fn versions(&self) -> Ref<'_, HashMap<String, ObjectId>> {
+        if self.versions.borrow().is_none() {
+            let versions = self.walk_versions();
+            *self.versions.borrow_mut() = Some(versions);
+        }
+
+        Ref::map(self.versions.borrow(), |versions| {
+            versions.as_ref().expect("loaded above")
+        })
+    }
+
+    fn find_version(&self, backup_filename: &str) -> Option<ObjectId> {
+        if self.removed_versions().iter().any(|v| v == backup_filename) {
+            return None;
+        }
+
+        self.versions().get(backup_filename).copied()
+    }
+
+    fn removed_versions(&self) -> Vec<String> {
+        self.read_index(REMOVED_VERSIONS_FILENAME)
+            .and_then(|content| serde_yaml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// ブランチを remote に送る
+    /// remote の先頭がこちらの履歴に無い (他から書き込まれた) 場合は上書きしない
+    fn push(&self, repository: &gix::Repository) -> io::Result<()> {
+        let Some(tip) = branch_tip(repository) else {
+            return Ok(());
+        };
+        let target = open_or_init(&push_directory(repository, &self.remote)?)?;
+        let previous = branch_tip(&target);
+        if previous == Some(tip) {
+            return Ok(());
+        }
+
+        if let Some(previous) = previous {
+            let fast_forward = repository
+                .rev_walk([tip])
+                .all()
+                .map_err(git_error)?
+                .flatten()
+                .any(|info| info.id == previous);
+            if !fast_forward {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} has commits that are not in the backup", self.remote),
+                ));
+            }
+        }
+
+        copy_missing_objects(repository, &target, tip)?;
+        let constraint = match previous {
+            Some(previous) => PreviousValue::ExistingMustMatch(Target::Object(previous)),
+            None => PreviousValue::MustNotExist,
+        };
+        target
+            .reference(branch_reference(), tip, constraint, "dd-backup: push")
+            .map_err(git_error)?;
+        Ok(())
+    }
+
+    fn commit_message(source_path: &str, backup_filename: &str) -> String {
+        let last_edited = fs::metadata(source_path)
+            .and_then(|m| m.modified())
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(|_| Utc::now());
+
+        format!(
+            "{}\n\nSource: {}\nModified: {}\n{}{}\n",
+            tracked_name(backup_filename),
+            source_path,
+            last_edited.to_rfc3339(),
+            VERSION_TRAILER,
+            backup_filename
+        )
+    }
+}
+
+impl BackupStore for GitStore {
+    fn is_available(&self) -> bool {
+        self.repository().is_ok()
+    }
+
+    fn list_versions(&self) -> Vec<String> {
+        let removed = self.removed_versions();
+        self.versions()
+            .keys()
+            .filter(|backup_filename| !removed.contains(backup_filename))
+            .cloned()
+            .collect()
+    }
+
+    fn has_version(&self, backup_filename: &str) -> bool {
+        self.find_version(backup_filename).is_some()
+    }
+
+    fn write_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()> {
+        let repository = self.repository()?;
+        let content = fs::read(source_path)?;
+        let blob_id = repository.write_blob(&content).map_err(git_error)?;
+
+        let parent = branch_tip(repository);
+        let tree_id = match parent {
+            Some(parent) => repository
+                .find_commit(parent)
+                .map_err(git_error)?
+                .tree_id()
+                .map_err(git_error)?
+                .detach(),
+            None => ObjectId::empty_tree(repository.object_hash()),
+        };
+
+        let mut editor = repository.edit_tree(tree_id).map_err(git_error)?;
+        editor
+            .upsert(tracked_name(backup_filename), EntryKind::Blob, blob_id)
+            .map_err(git_error)?;
+        let tree_id = editor.write().map_err(git_error)?;
+
+        let signature = gix::actor::Signature {
+            name: COMMITTER_NAME.into(),
+            email: COMMITTER_EMAIL.into(),
+            time: gix::date::Time::now_local_or_utc(),
+        };
+        let mut time = gix::date::parse::TimeBuf::default();
+        let signature = signature.to_ref(&mut time);
+        let commit_id = repository
+            .commit_as(
+                signature,
+                signature,
+                branch_reference().as_str(),
+                Self::commit_message(source_path, backup_filename),
+                tree_id,
+                parent,
+            )
+            .map_err(git_error)?
+            .detach();
+
+        if let Some(versions) = self.versions.borrow_mut().as_mut() {
+            versions.insert(backup_filename.to_string(), commit_id);
+        }
+
+        // push できなくてもコミットは残っているため、次のコミットの push でまとめて送られる
+        if !self.remote.is_empty() {
+            self.push(repository).ok();
+        }
+        Ok(())
+    }
+
+    fn read_version(&self, backup_filename: &str, dest_path: &str) -> io::Result<()> {
+        let repository = self.repository()?;
+        let commit_id = self
+            .find_version(backup_filename)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, backup_filename))?;
+
+        let tree = repository
+            .find_commit(commit_id)
+            .map_err(git_error)?
+            .tree()
+            .map_err(git_error)?;
+        let entry = tree
+            .lookup_entry_by_path(tracked_name(backup_filename))
+            .map_err(git_error)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, backup_filename))?;
+        let blob = repository.find_blob(entry.object_id()).map_err(git_error)?;
+
+        fs::write(dest_path, &blob.data)
+    }
+
+    /// git の履歴は書き換えず、一覧から外したことだけを記録する
+    fn remove_version(&self, backup_filename: &str) -> io::Result<()> {
+        let mut removed = self.removed_versions();
+        if removed.iter().any(|v| v == backup_filename) {
+            return Ok(());
+        }
+
+        removed.push(backup_filename.to_string());
+        let yaml = serde_yaml::to_string(&removed).map_err(io::Error::other)?;
+        self.write_index(REMOVED_VERSIONS_FILENAME, &yaml)
+    }
+
+    fn load_index(&self, index_name: &str) -> io::Result<Option<String>> {
+        missing_as_none(fs::read_to_string(append_path(&self.directory, index_name)))
+    }
+
+    fn write_index(&self, index_name: &str, content: &str) -> io::Result<()> {
+        write_atomically(&append_path(&self.directory, index_name), content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST: &str = "2024-05-06T07-08-09.000000001Z_docs%2Fa.txt";
+    const SECOND: &str = "2024-05-07T07-08-09.000000001Z_docs%2Fa.txt";
+
+    /// まだリポジトリになっていない空のバックアップ先
+    fn new_repository(dir: &tempfile::TempDir) -> String {
+        let repository = dir.path().join("repository").to_string_lossy().to_string();
+        fs::create_dir(&repository).unwrap();
+        repository
+    }
+
+    fn write(store: &GitStore, dir: &tempfile::TempDir, backup_filename: &str, content: &str) {
+        let source = dir.path().join("source").to_string_lossy().to_string();
+        fs::write(&source, content).unwrap();
+        store.write_version(&source, backup_filename).unwrap();
+    }
+
+    fn read(store: &GitStore, dir: &tempfile::TempDir, backup_filename: &str) -> String {
+        let dest = dir.path().join("dest").to_string_lossy().to_string();
+        store.read_version(backup_filename, &dest).unwrap();
+        fs::read_to_string(dest).unwrap()
+    }
+
+    #[test]
+    fn versions_are_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = new_repository(&dir);
+        let store = GitStore::new(&repository, "");
+
+        assert!(store.is_available());
+        assert!(store.list_versions().is_empty());
+        write(&store, &dir, FIRST, "first");
+        write(&store, &dir, SECOND, "second");
+
+        let mut versions = store.list_versions();
+        versions.sort();
+        assert_eq!(versions, [FIRST, SECOND]);
+        assert_eq!(read(&store, &dir, FIRST), "first");
+        assert_eq!(read(&store, &dir, SECOND), "second");
+        assert!(is_git_repository(&repository));
+        assert_eq!(
+            fs::read_to_string(append_path(&repository, "HEAD")).unwrap(),
+            format!("ref: refs/heads/{}\n", GIT_BRANCH)
+        );
+    }
+
+    #[test]
+    fn reopened_store_finds_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = new_repository(&dir);
+        let store = GitStore::new(&repository, "");
+        write(&store, &dir, FIRST, "first");
+        // 一覧を読み込んだ後のコミットも見つかる
+        assert!(store.has_version(FIRST));
+        write(&store, &dir, SECOND, "second");
+        assert!(store.has_version(SECOND));
+
+        let reopened = GitStore::new(&repository, "");
+        assert!(reopened.has_version(FIRST));
+        assert_eq!(read(&reopened, &dir, SECOND), "second");
+    }
+
+    #[test]
+    fn removed_versions_are_hidden() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = new_repository(&dir);
+        let store = GitStore::new(&repository, "");
+        write(&store, &dir, FIRST, "first");
+        write(&store, &dir, SECOND, "second");
+
+        store.remove_version(FIRST).unwrap();
+        store.remove_version(FIRST).unwrap();
+        assert_eq!(store.list_versions(), [SECOND]);
+        assert!(!store.has_version(FIRST));
+        let dest = dir.path().join("dest").to_string_lossy().to_string();
+        assert!(store.read_version(FIRST, &dest).is_err());
+        assert_eq!(
+            read(&GitStore::new(&repository, ""), &dir, SECOND),
+            "second"
+        );
+    }
+
+    /// push 先の tip のコミット
+    fn pushed_tip(directory: &str) -> Option<ObjectId> {
+        branch_tip(&gix::open(directory).unwrap())
+    }
+
+    #[test]
+    fn branch_is_pushed_to_the_remote() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = new_repository(&dir);
+        let remote = dir.path().join("remote").to_string_lossy().to_string();
+        fs::create_dir(&remote).unwrap();
+        let store = GitStore::new(&repository, &remote);
+        write(&store, &dir, FIRST, "first");
+        write(&store, &dir, SECOND, "second");
+
+        let pushed = GitStore::new(&remote, "");
+        assert_eq!(read(&pushed, &dir, FIRST), "first");
+        assert_eq!(read(&pushed, &dir, SECOND), "second");
+        assert_eq!(pushed_tip(&remote), pushed_tip(&repository));
+    }
+
+    #[test]
+    fn failed_push_is_caught_up_by_the_next_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = new_repository(&dir);
+        let remote = dir.path().join("remote").to_string_lossy().to_string();
+        let store = GitStore::new(&repository, &remote);
+        // push 先がまだ無くてもコミットはできる
+        write(&store, &dir, FIRST, "first");
+        assert!(store.has_version(FIRST));
+
+        fs::create_dir(&remote).unwrap();
+        write(&store, &dir, SECOND, "second");
+        assert_eq!(read(&GitStore::new(&remote, ""), &dir, FIRST), "first");
+    }
+
+    #[test]
+    fn remote_is_found_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = new_repository(&dir);
+        let remote = dir.path().join("remote").to_string_lossy().to_string();
+        fs::create_dir(&remote).unwrap();
+        write(&GitStore::new(&repository, ""), &dir, FIRST, "first");
+        let mut config = fs::read_to_string(append_path(&repository, "config")).unwrap();
+        config.push_str(&format!("[remote \"backup\"]\n\turl = file://{}\n", remote));
+        fs::write(append_path(&repository, "config"), config).unwrap();
+
+        write(
+            &GitStore::new(&repository, "backup"),
+            &dir,
+            SECOND,
+            "second",
+        );
+        assert_eq!(pushed_tip(&remote), pushed_tip(&repository));
+    }
+
+    #[test]
+    fn diverged_remote_is_not_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = new_repository(&dir);
+        let remote = dir.path().join("remote").to_string_lossy().to_string();
+        fs::create_dir(&remote).unwrap();
+        write(&GitStore::new(&remote, ""), &dir, FIRST, "elsewhere");
+        let other_tip = pushed_tip(&remote);
+
+        write(&GitStore::new(&repository, &remote), &dir, SECOND, "second");
+        assert_eq!(pushed_tip(&remote), other_tip);
+        let error = GitStore::new(&repository, &remote)
+            .push(&gix::open(&repository).unwrap())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn network_remotes_are_not_supported() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = new_repository(&dir);
+        write(&GitStore::new(&repository, ""), &dir, FIRST, "first");
+        let error = push_directory(
+            &gix::open(&repository).unwrap(),
+            "https://example.com/backup.git",
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn missing_directory_is_unavailable() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = GitStore::new(&dir.path().join("missing").to_string_lossy(), "");
+        assert!(!store.is_available());
+        assert!(store.list_versions().is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::backup_store::BackupStore;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+
+pub const HASH_INDEX_FILENAME: &str = ".dd-backup-index.yaml";
+
+/// バックアップ 1 回分の記録
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HashIndexEntry {
+    pub name: String,
+    pub last_edited: DateTime<Utc>,
+    pub hash: String,
+    /// この内容を保持しているバックアップファイル名
+    pub backup_filename: String,
+    /// 直前のバージョンと内容が同じだったためコピーを省略した
+    #[serde(default)]
+    pub skipped: bool,
+    /// バックアップした時の大きさ (記録していなかった頃の履歴は None)
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+impl HashIndexEntry {
+    /// 大きさと更新日時が記録と同じなら、内容も変わっていないとみなす
+    pub fn matches_metadata(&self, metadata: &fs::Metadata) -> bool {
+        self.size == Some(metadata.len())
+            && metadata.modified().ok().map(DateTime::<Utc>::from) == Some(self.last_edited)
+    }
+}
+
+/// バックアップディレクトリごとの内容ハッシュの履歴
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HashIndex {
+    pub entries: Vec<HashIndexEntry>,
+}
+
+pub fn hash_file(path: &str) -> Option<String> {
+    let mut file = fs::File::open(path).ok()?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).ok()?;
+    Some(hex::encode(hasher.finalize()))
+}
+
+/// entry と大きさ、更新日時が同じなら、ファイルを読まずに記録のハッシュを返す
+pub fn hash_file_cached(path: &str, entry: Option<&HashIndexEntry>) -> Option<String> {
+    if let Some(entry) = entry {
+        if fs::metadata(path).is_ok_and(|m| entry.matches_metadata(&m)) {
+            return Some(entry.hash.clone());
+        }
+    }
+
+    hash_file(path)
+}
+
+impl HashIndex {
+    pub fn load(store: &dyn BackupStore) -> Self {
+        store
+            .read_index(HASH_INDEX_FILENAME)
+            .and_then(|content| serde_yaml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, store: &dyn BackupStore) {
+        if let Ok(yaml) = serde_yaml::to_string(self) {
+            store.write_index(HASH_INDEX_FILENAME, &yaml).ok();
+        }
+    }
+
+    /// name の最新の記録
+    pub fn latest(&self, name: &str) -> Option<&HashIndexEntry> {
+        self.entries.iter().rev().find(|e| e.name == name)
+    }
+
+    /// name の最新の記録のうち、実体がバックアップディレクトリに残っているもの
+    pub fn latest_stored(&self, name: &str, store: &dyn BackupStore) -> Option<&HashIndexEntry> {
+        self.latest(name)
+            .filter(|e| store.has_version(&e.backup_filename))
+    }
+
+    pub fn push(&mut self, entry: HashIndexEntry) {
+        self.entries.push(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn entry_for(path: &str, hash: &str) -> HashIndexEntry {
+        let metadata = fs::metadata(path).unwrap();
+        HashIndexEntry {
+            name: "a.txt".to_string(),
+            last_edited: metadata.modified().unwrap().into(),
+            hash: hash.to_string(),
+            backup_filename: "backup".to_string(),
+            skipped: false,
+            size: Some(metadata.len()),
+        }
+    }
+
+    #[test]
+    fn cached_hash_is_used_when_size_and_mtime_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt").to_string_lossy().to_string();
+        fs::write(&path, "hello").unwrap();
+
+        // 記録のハッシュが使われたことが分かるよう、実際とは違う値にしておく
+        let entry = entry_for(&path, "recorded");
+        assert_eq!(
+            hash_file_cached(&path, Some(&entry)).as_deref(),
+            Some("recorded")
+        );
+    }
+
+    #[test]
+    fn file_is_hashed_when_mtime_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt").to_string_lossy().to_string();
+        fs::write(&path, "hello").unwrap();
+        let entry = entry_for(&path, "recorded");
+
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(hash_file_cached(&path, Some(&entry)), hash_file(&path));
+    }
+
+    #[test]
+    fn file_is_hashed_when_size_changes_or_is_unknown() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt").to_string_lossy().to_string();
+        fs::write(&path, "hello").unwrap();
+
+        let mut entry = entry_for(&path, "recorded");
+        entry.size = Some(4);
+        assert_eq!(hash_file_cached(&path, Some(&entry)), hash_file(&path));
+
+        entry.size = None;
+        assert_eq!(hash_file_cached(&path, Some(&entry)), hash_file(&path));
+        assert_eq!(hash_file_cached(&path, None), hash_file(&path));
+    }
+
+    #[test]
+    fn entries_without_size_still_load() {
+        let yaml = "entries:\n- name: a.txt\n  last_edited: 2024-01-01T00:00:00Z\n  hash: abc\n  backup_filename: b\n";
+        let index: HashIndex = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(index.latest("a.txt").unwrap().size, None);
+        assert!(!index.latest("a.txt").unwrap().skipped);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::user_data::{append_path, is_valid_file};
+use ignore::gitignore::Gitignore;
+use std::fs;
+use std::path::Path;
+
+/// 追跡から除外するファイルを書いておくファイル (gitignore と同じ書式)
+pub const IGNORE_FILENAME: &str = ".ddbackupignore";
+
+/// ディレクトリ以下のすべての `.ddbackupignore`
+/// 深い場所のファイルほど優先し、`!` で除外を取り消せる
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    /// (置かれているディレクトリの相対パス, 内容) を浅い順に並べる
+    matchers: Vec<(String, Gitignore)>,
+    /// 除外されたファイルとディレクトリ (ディレクトリは末尾に `/` を付け、中は探さない)
+    pub ignored: Vec<String>,
+}
+
+fn depth(relative_directory: &str) -> usize {
+    if relative_directory.is_empty() {
+        0
+    } else {
+        relative_directory.split('/').count()
+    }
+}
+
+/// relative_path が directory (どちらも相対パス) の中にあれば、directory からの相対パスを返す
+fn strip_directory<'a>(relative_path: &'a str, directory: &str) -> Option<&'a str> {
+    if directory.is_empty() {
+        return Some(relative_path);
+    }
+
+    relative_path
+        .strip_prefix(directory)
+        .and_then(|rest| rest.strip_prefix('/'))
+}
+
+impl IgnoreRules {
+    /// directory 以下を探して、除外の条件と除外されたものを集める
+    pub fn load(directory: &str) -> Self {
+        let mut rules = IgnoreRules::default();
+        let mut pending = vec![String::new()];
+        while let Some(relative_directory) = pending.pop() {
+            let absolute_directory = append_path(directory, &relative_directory);
+            let ignore_path = append_path(&absolute_directory, IGNORE_FILENAME);
+            if is_valid_file(&ignore_path) {
+                let (matcher, _) = Gitignore::new(&ignore_path);
+                rules.matchers.push((relative_directory.clone(), matcher));
+                rules.matchers.sort_by_key(|(d, _)| depth(d));
+            }
+
+            let Ok(entries) = fs::read_dir(&absolute_directory) else {
+                continue;
+            };
+
+            for entry in entries.flatten() {
+                let Ok(file_type) = entry.file_type() else {
+                    continue;
+                };
+                let name = entry.file_name().to_string_lossy().to_string();
+                let relative_path = append_path(&relative_directory, &name);
+                let is_dir = if file_type.is_symlink() {
+                    entry.path().is_dir()
+                } else {
+                    file_type.is_dir()
+                };
+                if rules.is_ignored(&relative_path, is_dir) {
+                    rules.ignored.push(if is_dir {
+                        format!("{}/", relative_path)
+                    } else {
+                        relative_path
+                    });
+                } else if is_dir && !file_type.is_symlink() {
+                    // ディレクトリの追跡と同じく、シンボリックリンクの先は探さない
+                    pending.push(relative_path);
+                }
+            }
+        }
+
+        rules.ignored.sort();
+        rules
+    }
+
+    /// directory からの相対パス (区切りは `/`) が除外されるか
+    pub fn is_ignored(&self, relative_path: &str, is_dir: bool) -> bool {
+        for (directory, matcher) in self.matchers.iter().rev() {
+            let Some(path) = strip_directory(relative_path, directory) else {
+                continue;
+            };
+
+            let matched = matcher.matched_path_or_any_parents(Path::new(path), is_dir);
+            if matched.is_ignore() {
+                return true;
+            }
+            if matched.is_whitelist() {
+                return false;
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &str, relative_path: &str, content: &str) {
+        let path = append_path(root, relative_path);
+        fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn negation_re_includes_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        write(&root, IGNORE_FILENAME, "*.log\n!keep.log\nbuild/\n");
+        write(&root, "a.log", "");
+        write(&root, "keep.log", "");
+        write(&root, "a.txt", "");
+        write(&root, "build/out.bin", "");
+
+        let rules = IgnoreRules::load(&root);
+        assert!(rules.is_ignored("a.log", false));
+        assert!(!rules.is_ignored("keep.log", false));
+        assert!(!rules.is_ignored("a.txt", false));
+        assert!(rules.is_ignored("build/out.bin", false));
+        // 除外されたディレクトリの中は探さない
+        assert_eq!(rules.ignored, ["a.log", "build/"]);
+    }
+
+    #[test]
+    fn nested_rules_take_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        write(&root, IGNORE_FILENAME, "*.tmp\n");
+        write(&root, "docs/.ddbackupignore", "!draft.tmp\n*.md\n");
+        write(&root, "a.tmp", "");
+        write(&root, "docs/draft.tmp", "");
+        write(&root, "docs/other.tmp", "");
+        write(&root, "docs/a.md", "");
+        write(&root, "a.md", "");
+
+        let rules = IgnoreRules::load(&root);
+        assert!(rules.is_ignored("a.tmp", false));
+        assert!(!rules.is_ignored("docs/draft.tmp", false));
+        assert!(rules.is_ignored("docs/other.tmp", false));
+        // 下のディレクトリの条件は、その中にだけ効く
+        assert!(rules.is_ignored("docs/a.md", false));
+        assert!(!rules.is_ignored("a.md", false));
+        assert_eq!(rules.ignored, ["a.tmp", "docs/a.md", "docs/other.tmp"]);
+    }
+
+    #[test]
+    fn no_ignore_file_ignores_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        write(&root, "a.tmp", "");
+
+        let rules = IgnoreRules::load(&root);
+        assert!(!rules.is_ignored("a.tmp", false));
+        assert!(rules.ignored.is_empty());
+    }
+}
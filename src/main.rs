@@ -3,16 +3,43 @@
 mod app;
 mod app_update;
 mod app_view;
+mod archive_store;
+mod atomic_file;
+mod backup_scan;
+mod backup_store;
+mod cli;
+mod compression;
+mod encryption;
+mod export_backup;
+mod file_metadata;
+mod git_store;
+mod hash_index;
+mod ignore_rules;
+mod manifest;
+mod mirror;
+mod parity;
+mod prune;
+mod replication;
+mod s3;
 mod save_data;
+mod scrub;
+mod sftp;
+mod snapshot;
+#[cfg(test)]
+mod test_server;
+mod tracking;
+mod trash;
+mod uri;
 mod user_data;
+mod webdav;
 
 use crate::app::{App, Message};
 use crate::save_data::load_save_data;
 use iced::event::{self};
-use iced::{Subscription, Task};
+use iced::{time, Subscription, Task};
 use std::env;
 use std::path::{Path, PathBuf};
-use std::string::ToString;
+use std::time::Duration;
 
 /// カレントディレクトリを実行ファイルのディレクトリに設定する関数
 fn set_current_dir_to_executable_dir() -> Result<(), Box<dyn std::error::Error>> {
@@ -27,6 +54,12 @@ fn set_current_dir_to_executable_dir() -> Result<(), Box<dyn std::error::Error>>
 }
 
 pub fn main() -> iced::Result {
+    // 引数があればコマンドラインとして動作する (相対パスのためカレントディレクトリは変えない)
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run_cli(&args));
+    }
+
     if let Err(e) = set_current_dir_to_executable_dir() {
         eprintln!("Error: {}", e);
     }
@@ -39,7 +72,17 @@ pub fn main() -> iced::Result {
                 .as_slice(),
         )
         .exit_on_close_request(false)
-        .run_with(|| (load_save_data(), Task::none()))
+        .run_with(|| {
+            (
+                load_save_data(),
+                Task::batch([
+                    Task::done(Message::ScrubScheduled),
+                    Task::done(Message::ReplicationScheduled),
+                    Task::done(Message::TempFilesCleanup),
+                    Task::done(Message::SyncStatusRefresh),
+                ]),
+            )
+        })
 }
 
 fn get_directory_of_file(path: &Path) -> Option<PathBuf> {
@@ -54,6 +97,11 @@ fn get_directory_of_file(path: &Path) -> Option<PathBuf> {
 
 impl App {
     fn subscription(&self) -> Subscription<Message> {
-        event::listen().map(Message::EventOccurred)
+        Subscription::batch([
+            event::listen().map(Message::EventOccurred),
+            // 定期スクラブが必要かを 1 時間ごとに確認する
+            time::every(Duration::from_secs(60 * 60)).map(|_| Message::ScrubScheduled),
+            time::every(Duration::from_secs(60 * 60)).map(|_| Message::ReplicationScheduled),
+        ])
     }
 }
@@ -0,0 +1,564 @@
+use crate::atomic_file::{write_atomically, PrivateTempDir};
+use crate::backup_store::BackupStore;
+use crate::hash_index::hash_file;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const MANIFEST_FILENAME: &str = ".dd-backup-manifest.yaml";
+
+/// 署名鍵は設定ディレクトリに置き、バックアップ先には公開鍵だけを書く
+const SIGNING_KEY_FILENAME: &str = "signing_key.txt";
+const CONFIG_DIRECTORY_NAME: &str = "dd-backup";
+
+/// バージョン 1 つ分の記録
+/// previous で直前の記録のハッシュを持ち、記録全体のハッシュに署名する
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub backup_filename: String,
+    pub version: DateTime<Utc>,
+    pub size: u64,
+    pub hash: String,
+    pub previous: String,
+    pub signature: String,
+    /// 古いバージョンを整理して削除したことの記録
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub removed: bool,
+}
+
+impl ManifestEntry {
+    fn entry_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        let removed = self.removed.then_some("removed".to_string());
+        for field in [
+            &self.name,
+            &self.backup_filename,
+            &self.version.to_rfc3339(),
+            &self.size.to_string(),
+            &self.hash,
+            &self.previous,
+        ]
+        .into_iter()
+        .chain(removed.as_ref())
+        {
+            hasher.update((field.len() as u64).to_le_bytes());
+            hasher.update(field.as_bytes());
+        }
+
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// バックアップ先ごとの追記のみのマニフェスト
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    pub public_key: String,
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// verify の結果
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub verified: usize,
+    /// 署名やハッシュの連鎖が壊れている記録
+    pub broken: Vec<String>,
+    pub missing: Vec<String>,
+    pub modified: Vec<String>,
+    /// マニフェストに記録されていないバージョン
+    pub unexpected: Vec<String>,
+    /// マニフェストがこの環境の署名鍵で署名されていない
+    pub unknown_key: bool,
+    /// バージョンの内容を確かめられなかった理由
+    pub error: Option<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        !self.unknown_key
+            && self.error.is_none()
+            && self.broken.is_empty()
+            && self.missing.is_empty()
+            && self.modified.is_empty()
+            && self.unexpected.is_empty()
+    }
+
+    pub fn summary(&self) -> String {
+        if self.is_ok() {
+            return format!("{} versions verified", self.verified);
+        }
+
+        let mut problems = Vec::new();
+        if self.unknown_key {
+            problems.push("signed by an unknown key".to_string());
+        }
+        if let Some(error) = &self.error {
+            problems.push(format!("versions not checked ({})", error));
+        }
+
+        for (label, names) in [
+            ("broken", &self.broken),
+            ("missing", &self.missing),
+            ("modified", &self.modified),
+            ("unexpected", &self.unexpected),
+        ] {
+            if !names.is_empty() {
+                problems.push(format!("{} {}", names.len(), label));
+            }
+        }
+
+        format!("{} verified, {}", self.verified, problems.join(", "))
+    }
+}
+
+/// ユーザーごとの設定ディレクトリ
+/// Windows は %APPDATA%、macOS は ~/Library/Application Support、それ以外は $XDG_CONFIG_HOME か ~/.config
+fn config_directory() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        PathBuf::from(env::var_os("APPDATA")?)
+    } else if cfg!(target_os = "macos") {
+        PathBuf::from(env::var_os("HOME")?).join("Library/Application Support")
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".config")))?
+    };
+    Some(base.join(CONFIG_DIRECTORY_NAME))
+}
+
+/// 以前の版が実行ファイルの隣に作った署名鍵
+#[cfg(not(test))]
+fn legacy_signing_key_path() -> Option<PathBuf> {
+    Some(
+        env::current_exe()
+            .ok()?
+            .parent()?
+            .join(SIGNING_KEY_FILENAME),
+    )
+}
+
+#[cfg(test)]
+fn legacy_signing_key_path() -> Option<PathBuf> {
+    None
+}
+
+#[cfg(not(test))]
+fn signing_key_path() -> io::Result<PathBuf> {
+    config_directory()
+        .map(|dir| dir.join(SIGNING_KEY_FILENAME))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no config directory"))
+}
+
+#[cfg(test)]
+thread_local! {
+    /// テストごとに別の署名鍵を使うため、スレッドごとに差し替えられるようにする
+    static TEST_SIGNING_KEY_PATH: std::cell::RefCell<Option<PathBuf>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// テストでは set_signing_key_path で指定した場所か、スレッドごとの一時ディレクトリを使う
+#[cfg(test)]
+fn signing_key_path() -> io::Result<PathBuf> {
+    TEST_SIGNING_KEY_PATH.with(|path| {
+        let mut path = path.borrow_mut();
+        if path.is_none() {
+            let dir = tempfile::tempdir()?.keep();
+            *path = Some(dir.join(SIGNING_KEY_FILENAME));
+        }
+        Ok(path.clone().unwrap_or_default())
+    })
+}
+
+#[cfg(test)]
+pub fn set_signing_key_path(path: PathBuf) {
+    TEST_SIGNING_KEY_PATH.with(|current| *current.borrow_mut() = Some(path));
+}
+
+fn parse_signing_key(content: &str) -> io::Result<SigningKey> {
+    let bytes: [u8; 32] = hex::decode(content.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// 署名鍵を読み込む。無ければ作成する
+/// 以前の版の署名鍵があれば、同じ鍵で署名を続けられるよう設定ディレクトリに移す
+pub fn load_signing_key() -> io::Result<SigningKey> {
+    let key_path = signing_key_path()?;
+    match fs::read_to_string(&key_path) {
+        Ok(content) => return parse_signing_key(&content),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        Err(_) => {}
+    }
+
+    if let Some(parent) = key_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let key_path = key_path.to_string_lossy().to_string();
+
+    let legacy_content = legacy_signing_key_path().and_then(|path| fs::read_to_string(path).ok());
+    if let Some(content) = legacy_content {
+        let key = parse_signing_key(&content)?;
+        write_atomically(&key_path, hex::encode(key.to_bytes()))?;
+        return Ok(key);
+    }
+
+    let mut bytes = [0u8; 32];
+    getrandom::fill(&mut bytes).map_err(|_| io::Error::other("failed to get random bytes"))?;
+    write_atomically(&key_path, hex::encode(bytes))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+impl Manifest {
+    /// マニフェストが無ければ空の記録から始め、あるのに読めない場合はエラーにする
+    /// 読めないまま追記すると、それまでの記録を失うため
+    pub fn load(store: &dyn BackupStore) -> io::Result<Self> {
+        match store.load_index(MANIFEST_FILENAME)? {
+            Some(content) => serde_yaml::from_str(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            None => Ok(Manifest::default()),
+        }
+    }
+
+    pub fn save(&self, store: &dyn BackupStore) -> io::Result<()> {
+        let yaml = serde_yaml::to_string(self).map_err(io::Error::other)?;
+        store.write_index(MANIFEST_FILENAME, &yaml)
+    }
+
+    /// 新しいバージョンを署名して追記する
+    pub fn append(
+        store: &dyn BackupStore,
+        name: &str,
+        backup_filename: &str,
+        version: DateTime<Utc>,
+        size: u64,
+        hash: &str,
+    ) -> io::Result<()> {
+        Self::append_entry(
+            store,
+            ManifestEntry {
+                name: name.to_string(),
+                backup_filename: backup_filename.to_string(),
+                version,
+                size,
+                hash: hash.to_string(),
+                previous: "".to_string(),
+                signature: "".to_string(),
+                removed: false,
+            },
+        )
+    }
+
+    /// バージョンを削除したことを署名して追記する (検証で欠けたバージョンとみなさない)
+    pub fn append_removal(
+        store: &dyn BackupStore,
+        name: &str,
+        backup_filename: &str,
+    ) -> io::Result<()> {
+        Self::append_entry(
+            store,
+            ManifestEntry {
+                name: name.to_string(),
+                backup_filename: backup_filename.to_string(),
+                version: Utc::now(),
+                size: 0,
+                hash: "".to_string(),
+                previous: "".to_string(),
+                signature: "".to_string(),
+                removed: true,
+            },
+        )
+    }
+
+    /// 直前の記録につなげて署名し、追記する
+    fn append_entry(store: &dyn BackupStore, mut entry: ManifestEntry) -> io::Result<()> {
+        let signing_key = load_signing_key()?;
+        let mut manifest = Manifest::load(store)?;
+        if manifest.public_key.is_empty() {
+            manifest.public_key = hex::encode(signing_key.verifying_key().to_bytes());
+        }
+
+        entry.previous = manifest
+            .entries
+            .last()
+            .map(|e| e.entry_hash())
+            .unwrap_or_default();
+        entry.signature = hex::encode(signing_key.sign(entry.entry_hash().as_bytes()).to_bytes());
+
+        manifest.entries.push(entry);
+        manifest.save(store)
+    }
+
+    fn verifying_key(&self) -> Option<VerifyingKey> {
+        let bytes: [u8; 32] = hex::decode(&self.public_key).ok()?.try_into().ok()?;
+        VerifyingKey::from_bytes(&bytes).ok()
+    }
+}
+
+fn signature_valid(key: Option<&VerifyingKey>, entry: &ManifestEntry) -> bool {
+    let signature = hex::decode(&entry.signature)
+        .ok()
+        .and_then(|bytes| <[u8; 64]>::try_from(bytes).ok())
+        .map(|bytes| Signature::from_bytes(&bytes));
+
+    match (key, signature) {
+        (Some(key), Some(signature)) => key
+            .verify_strict(entry.entry_hash().as_bytes(), &signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// マニフェストの連鎖と署名をたどり、バックアップ先の内容と突き合わせる
+pub fn verify(store: &dyn BackupStore) -> VerifyReport {
+    let manifest = match Manifest::load(store) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            return VerifyReport {
+                error: Some(format!("unreadable manifest: {}", e)),
+                ..VerifyReport::default()
+            }
+        }
+    };
+    let verifying_key = manifest.verifying_key();
+
+    let mut report = VerifyReport {
+        unknown_key: !manifest.entries.is_empty()
+            && load_signing_key()
+                .map(|key| Some(key.verifying_key()) != verifying_key)
+                .unwrap_or(true),
+        ..VerifyReport::default()
+    };
+
+    // 暗号化されたバージョンは復号した内容になるため、他のユーザーから見えない場所に書く
+    let temp_dir = match PrivateTempDir::new("verify") {
+        Ok(temp_dir) => temp_dir,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        }
+    };
+    let temporary = temp_dir.file("version");
+
+    // 署名付きで削除を記録したバージョンは、欠けていても問題にしない
+    let removed: HashSet<&String> = manifest
+        .entries
+        .iter()
+        .filter(|e| e.removed)
+        .map(|e| &e.backup_filename)
+        .collect();
+
+    let mut previous = "".to_string();
+    for entry in &manifest.entries {
+        if entry.previous != previous || !signature_valid(verifying_key.as_ref(), entry) {
+            report.broken.push(entry.backup_filename.clone());
+        }
+        previous = entry.entry_hash();
+
+        if entry.removed {
+            continue;
+        }
+        if !store.has_version(&entry.backup_filename) {
+            if removed.contains(&entry.backup_filename) {
+                continue;
+            }
+            report.missing.push(entry.backup_filename.clone());
+            continue;
+        }
+
+        fs::remove_file(&temporary).ok();
+        let stored_hash = store
+            .read_version(&entry.backup_filename, &temporary)
+            .ok()
+            .and_then(|_| hash_file(&temporary));
+        let stored_size = fs::metadata(&temporary).map(|m| m.len()).ok();
+        if stored_hash.as_deref() != Some(entry.hash.as_str()) || stored_size != Some(entry.size) {
+            report.modified.push(entry.backup_filename.clone());
+        } else {
+            report.verified += 1;
+        }
+    }
+
+    let recorded: HashSet<&String> = manifest
+        .entries
+        .iter()
+        .map(|e| &e.backup_filename)
+        .collect();
+    report.unexpected = store
+        .list_versions()
+        .into_iter()
+        .filter(|v| !recorded.contains(v))
+        .collect();
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_store::FlatStore;
+    use crate::compression::Compression;
+    use crate::parity::Parity;
+    use crate::user_data::append_path;
+
+    /// 2 つのバージョンを記録したバックアップ先
+    fn backup_with_two_versions() -> (tempfile::TempDir, FlatStore, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let backup = append_path(&root, "backup");
+        fs::create_dir(&backup).unwrap();
+        let store = FlatStore::new(&backup, Compression::None, Parity::None);
+
+        for (backup_filename, contents) in [("v1_a.txt", "first"), ("v2_a.txt", "second")] {
+            let source = append_path(&root, "a.txt");
+            fs::write(&source, contents).unwrap();
+            store.write_version(&source, backup_filename).unwrap();
+            Manifest::append(
+                &store,
+                "a.txt",
+                backup_filename,
+                Utc::now(),
+                contents.len() as u64,
+                &hash_file(&source).unwrap(),
+            )
+            .unwrap();
+        }
+
+        (dir, store, backup)
+    }
+
+    #[test]
+    fn untouched_backup_verifies() {
+        let (_dir, store, _backup) = backup_with_two_versions();
+        let report = verify(&store);
+        assert!(report.is_ok(), "{}", report.summary());
+        assert_eq!(report.verified, 2);
+    }
+
+    #[test]
+    fn modified_version_is_detected() {
+        let (_dir, store, backup) = backup_with_two_versions();
+        fs::write(append_path(&backup, "v1_a.txt"), "fir5t").unwrap();
+
+        let report = verify(&store);
+        assert_eq!(report.modified, vec!["v1_a.txt".to_string()]);
+        assert_eq!(report.verified, 1);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn edited_manifest_entry_breaks_the_signature() {
+        let (_dir, store, backup) = backup_with_two_versions();
+        // 改ざんしたバージョンに合わせて記録のハッシュも書き換える
+        fs::write(append_path(&backup, "v1_a.txt"), "fir5t").unwrap();
+        let mut manifest = Manifest::load(&store).unwrap();
+        manifest.entries[0].hash = hash_file(&append_path(&backup, "v1_a.txt")).unwrap();
+        manifest.save(&store).unwrap();
+
+        // 書き換えた記録は署名が合わず、次の記録はハッシュの連鎖がつながらなくなる
+        let report = verify(&store);
+        assert_eq!(
+            report.broken,
+            vec!["v1_a.txt".to_string(), "v2_a.txt".to_string()]
+        );
+        assert!(report.modified.is_empty());
+
+        // 記録を取り除いても、残った記録の連鎖が切れる
+        let mut manifest = Manifest::load(&store).unwrap();
+        manifest.entries.remove(0);
+        manifest.save(&store).unwrap();
+        let report = verify(&store);
+        assert_eq!(report.broken, vec!["v2_a.txt".to_string()]);
+        assert!(report.unexpected.contains(&"v1_a.txt".to_string()));
+    }
+
+    #[test]
+    fn missing_and_unexpected_versions_are_reported() {
+        let (dir, store, backup) = backup_with_two_versions();
+        fs::remove_file(append_path(&backup, "v2_a.txt")).unwrap();
+        let source = append_path(&dir.path().to_string_lossy(), "b.txt");
+        fs::write(&source, "unrecorded").unwrap();
+        store.write_version(&source, "v3_b.txt").unwrap();
+
+        let report = verify(&store);
+        assert_eq!(report.missing, vec!["v2_a.txt".to_string()]);
+        assert_eq!(report.unexpected, vec!["v3_b.txt".to_string()]);
+    }
+
+    #[test]
+    fn foreign_public_key_is_reported() {
+        let (_dir, store, _backup) = backup_with_two_versions();
+        let mut manifest = Manifest::load(&store).unwrap();
+        manifest.public_key = hex::encode(
+            SigningKey::from_bytes(&[7u8; 32])
+                .verifying_key()
+                .to_bytes(),
+        );
+        manifest.save(&store).unwrap();
+
+        let report = verify(&store);
+        assert!(report.unknown_key);
+        assert_eq!(report.broken.len(), 2);
+    }
+
+    #[test]
+    fn missing_manifest_starts_a_new_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FlatStore::new(
+            &dir.path().to_string_lossy(),
+            Compression::None,
+            Parity::None,
+        );
+        assert!(Manifest::load(&store).unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn unreadable_manifest_is_not_overwritten() {
+        let (_dir, store, backup) = backup_with_two_versions();
+        let manifest_path = append_path(&backup, MANIFEST_FILENAME);
+        fs::write(&manifest_path, "entries: [").unwrap();
+
+        let error = Manifest::append(&store, "a.txt", "v3_a.txt", Utc::now(), 0, "").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read_to_string(&manifest_path).unwrap(), "entries: [");
+
+        let report = verify(&store);
+        assert!(report.error.is_some());
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn signing_key_is_kept_at_the_given_path() {
+        let keys = tempfile::tempdir().unwrap();
+        let key_path = keys.path().join("config").join(SIGNING_KEY_FILENAME);
+        set_signing_key_path(key_path.clone());
+        let (_dir, store, _backup) = backup_with_two_versions();
+
+        let key = load_signing_key().unwrap();
+        assert_eq!(
+            fs::read_to_string(&key_path).unwrap(),
+            hex::encode(key.to_bytes())
+        );
+        assert!(verify(&store).is_ok());
+
+        // 別の環境の署名鍵では、見知らぬ鍵で署名されたことになる
+        set_signing_key_path(keys.path().join("other").join(SIGNING_KEY_FILENAME));
+        assert!(verify(&store).unknown_key);
+    }
+
+    #[test]
+    fn config_directory_is_named_after_the_app() {
+        if let Some(dir) = config_directory() {
+            assert!(dir.ends_with(CONFIG_DIRECTORY_NAME));
+            assert!(dir.is_absolute());
+        }
+    }
+}
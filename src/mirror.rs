@@ -0,0 +1,334 @@
+use crate::atomic_file::{replace_atomically, write_atomically};
+use crate::trash::{create_trash_directory, TRASH_DIRECTORY};
+use crate::user_data::{append_path, create_parent_directory, is_valid_directory, DirectoryInfo};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::time::SystemTime;
+
+/// ミラー先に置く、前回の実行の記録
+/// これが無いミラー先では、実行の前に必ず差分を確認させる
+const MIRROR_STATE_FILENAME: &str = ".dd-backup-mirror.yaml";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct MirrorState {
+    source: String,
+    last_mirror: Option<DateTime<Utc>>,
+}
+
+/// ミラーの実行で行う操作 (dry-run の結果)
+#[derive(Debug, Clone, Default)]
+pub struct MirrorPlan {
+    pub source_directory: String,
+    pub mirror_directory: String,
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    /// ミラー先にだけあるファイル (削除の反映が有効ならごみ箱へ移す)
+    pub deleted: Vec<String>,
+    pub propagate_deletions: bool,
+    pub unchanged: usize,
+}
+
+impl MirrorPlan {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.changed.is_empty()
+            && (!self.propagate_deletions || self.deleted.is_empty())
+    }
+}
+
+/// ミラーの実行結果
+#[derive(Debug, Clone, Default)]
+pub struct MirrorReport {
+    pub copied: usize,
+    pub trashed: usize,
+    pub failed: Vec<String>,
+}
+
+impl MirrorReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} copied, {} moved to trash, {} failed",
+            self.copied,
+            self.trashed,
+            self.failed.len()
+        )
+    }
+}
+
+/// ミラー先が、このディレクトリから前回ミラーした場所か
+pub fn has_mirrored(dir: &DirectoryInfo) -> bool {
+    fs::read_to_string(append_path(&dir.mirror_directory, MIRROR_STATE_FILENAME))
+        .ok()
+        .and_then(|content| serde_yaml::from_str::<MirrorState>(&content).ok())
+        .is_some_and(|state| {
+            state.last_mirror.is_some() && state.source.to_lowercase() == dir.path.to_lowercase()
+        })
+}
+
+/// 一方が他方と同じか、その中にあるか
+/// 重なっていると、ミラーしたファイルをまたミラーしたり、元のファイルをごみ箱へ移したりしてしまう
+fn directories_overlap(a: &str, b: &str) -> bool {
+    let (Ok(a), Ok(b)) = (fs::canonicalize(a), fs::canonicalize(b)) else {
+        return false;
+    };
+
+    a.starts_with(&b) || b.starts_with(&a)
+}
+
+/// ミラー先として使えるか
+pub fn check_mirror_directory(dir: &DirectoryInfo) -> Result<(), String> {
+    if !is_valid_directory(&dir.mirror_directory) {
+        return Err("Mirror directory does not exist".to_string());
+    }
+    if directories_overlap(&dir.path, &dir.mirror_directory) {
+        return Err(
+            "Mirror directory must not be inside, contain or equal the current directory"
+                .to_string(),
+        );
+    }
+
+    Ok(())
+}
+
+/// ミラー先のファイルの相対パス (管理用のファイルとごみ箱は含めない)
+fn list_mirror_files(mirror_directory: &str) -> BTreeSet<String> {
+    let mut files = BTreeSet::new();
+    let mut pending = vec![String::new()];
+    while let Some(relative_directory) = pending.pop() {
+        let Ok(entries) = fs::read_dir(append_path(mirror_directory, &relative_directory)) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let relative_path = append_path(&relative_directory, &name);
+            if relative_path == TRASH_DIRECTORY || relative_path == MIRROR_STATE_FILENAME {
+                continue;
+            }
+
+            if entry.path().is_dir() {
+                pending.push(relative_path);
+            } else {
+                files.insert(relative_path);
+            }
+        }
+    }
+
+    files
+}
+
+/// 大きさか更新日時が違えば、変更されたものとみなす
+fn is_same_file(source_path: &str, target_path: &str) -> bool {
+    let (Ok(source), Ok(target)) = (fs::metadata(source_path), fs::metadata(target_path)) else {
+        return false;
+    };
+
+    source.len() == target.len() && source.modified().ok() == target.modified().ok()
+}
+
+/// 実際には何も変更せずに、ミラーで行う操作を調べる
+pub fn plan_mirror(dir: &DirectoryInfo) -> MirrorPlan {
+    let mut plan = MirrorPlan {
+        source_directory: dir.path.clone(),
+        mirror_directory: dir.mirror_directory.clone(),
+        propagate_deletions: dir.mirror_deletions,
+        ..MirrorPlan::default()
+    };
+    if !is_valid_directory(&dir.path) || check_mirror_directory(dir).is_err() {
+        return plan;
+    }
+
+    let mut target_files = list_mirror_files(&dir.mirror_directory);
+    for file in &dir.files {
+        let source_path = append_path(&dir.path, &file.name);
+        if !fs::metadata(&source_path).is_ok_and(|m| m.is_file()) {
+            continue;
+        }
+
+        let target_path = append_path(&dir.mirror_directory, &file.name);
+        if !target_files.remove(&file.name) {
+            plan.added.push(file.name.clone());
+        } else if is_same_file(&source_path, &target_path) {
+            plan.unchanged += 1;
+        } else {
+            plan.changed.push(file.name.clone());
+        }
+    }
+    plan.deleted = target_files.into_iter().collect();
+    plan.added.sort();
+    plan.changed.sort();
+
+    plan
+}
+
+/// 更新日時も元のファイルに合わせてコピーする
+fn copy_preserving_mtime(source_path: &str, target_path: &str) -> io::Result<()> {
+    create_parent_directory(target_path)?;
+    let modified: SystemTime = fs::metadata(source_path)?.modified()?;
+    replace_atomically(target_path, |temp| {
+        fs::copy(source_path, temp)?;
+        fs::OpenOptions::new()
+            .write(true)
+            .open(temp)?
+            .set_modified(modified)
+    })
+}
+
+/// plan のとおりにコピーし、削除の反映が有効ならミラー先にだけあるファイルをごみ箱へ移す
+pub fn run_mirror(plan: &MirrorPlan) -> MirrorReport {
+    let mut report = MirrorReport::default();
+    if directories_overlap(&plan.source_directory, &plan.mirror_directory) {
+        return report;
+    }
+
+    for name in plan.added.iter().chain(plan.changed.iter()) {
+        let source_path = append_path(&plan.source_directory, name);
+        let target_path = append_path(&plan.mirror_directory, name);
+        match copy_preserving_mtime(&source_path, &target_path) {
+            Ok(_) => report.copied += 1,
+            Err(_) => report.failed.push(name.clone()),
+        }
+    }
+
+    if plan.propagate_deletions && !plan.deleted.is_empty() {
+        match create_trash_directory(&plan.mirror_directory) {
+            Ok(trash) => {
+                for name in &plan.deleted {
+                    let trash_path = append_path(&trash, name);
+                    let moved = create_parent_directory(&trash_path).and_then(|_| {
+                        fs::rename(append_path(&plan.mirror_directory, name), &trash_path)
+                    });
+                    match moved {
+                        Ok(_) => report.trashed += 1,
+                        Err(_) => report.failed.push(name.clone()),
+                    }
+                }
+            }
+            Err(_) => report.failed.extend(plan.deleted.iter().cloned()),
+        }
+    }
+
+    let state = MirrorState {
+        source: plan.source_directory.clone(),
+        last_mirror: Some(Utc::now()),
+    };
+    if let Ok(yaml) = serde_yaml::to_string(&state) {
+        write_atomically(
+            &append_path(&plan.mirror_directory, MIRROR_STATE_FILENAME),
+            yaml,
+        )
+        .ok();
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_data::FileInfo;
+
+    /// a.txt と docs/b.txt を追跡しているディレクトリと、空のミラー先
+    fn mirrored_directory() -> (tempfile::TempDir, DirectoryInfo) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let source = append_path(&root, "source");
+        let mirror = append_path(&root, "mirror");
+        fs::create_dir_all(append_path(&source, "docs")).unwrap();
+        fs::create_dir(&mirror).unwrap();
+        fs::write(append_path(&source, "a.txt"), "aaaa").unwrap();
+        fs::write(append_path(&source, "docs/b.txt"), "bbbb").unwrap();
+
+        let mut directory = DirectoryInfo::new(source, append_path(&root, "backup"));
+        for name in ["a.txt", "docs/b.txt"] {
+            directory.add_file(FileInfo::new(name.to_string(), Utc::now(), String::new()));
+        }
+        directory.mirror_directory = mirror;
+        (dir, directory)
+    }
+
+    #[test]
+    fn plan_lists_added_changed_and_deleted_files() {
+        let (_dir, directory) = mirrored_directory();
+        let plan = plan_mirror(&directory);
+        assert_eq!(plan.added, vec!["a.txt", "docs/b.txt"]);
+        assert!(plan.changed.is_empty());
+
+        assert!(run_mirror(&plan).failed.is_empty());
+        let plan = plan_mirror(&directory);
+        assert!(plan.is_empty());
+        assert_eq!(plan.unchanged, 2);
+
+        fs::write(append_path(&directory.path, "a.txt"), "changed").unwrap();
+        fs::write(append_path(&directory.mirror_directory, "old.txt"), "old").unwrap();
+        let plan = plan_mirror(&directory);
+        assert_eq!(plan.changed, vec!["a.txt"]);
+        assert_eq!(plan.deleted, vec!["old.txt"]);
+        assert_eq!(plan.unchanged, 1);
+        // 削除の反映が無効なら、ミラー先にだけあるファイルは操作の対象にならない
+        assert!(!plan.propagate_deletions);
+    }
+
+    #[test]
+    fn deleted_files_are_moved_to_trash() {
+        let (_dir, mut directory) = mirrored_directory();
+        directory.mirror_deletions = true;
+        fs::write(append_path(&directory.mirror_directory, "old.txt"), "old").unwrap();
+
+        let report = run_mirror(&plan_mirror(&directory));
+        assert_eq!(report.copied, 2);
+        assert_eq!(report.trashed, 1);
+        assert!(!fs::exists(append_path(&directory.mirror_directory, "old.txt")).unwrap());
+
+        let trash = append_path(&directory.mirror_directory, TRASH_DIRECTORY);
+        let moved = fs::read_dir(&trash)
+            .unwrap()
+            .flatten()
+            .next()
+            .unwrap()
+            .path();
+        assert_eq!(fs::read_to_string(moved.join("old.txt")).unwrap(), "old");
+    }
+
+    #[test]
+    fn overlapping_mirror_directory_is_refused() {
+        let (_dir, mut directory) = mirrored_directory();
+        let inside = append_path(&directory.path, "docs");
+        let parent = fs::canonicalize(&directory.path)
+            .unwrap()
+            .parent()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+
+        for mirror in [directory.path.clone(), inside, parent] {
+            directory.mirror_directory = mirror;
+            assert!(check_mirror_directory(&directory).is_err());
+
+            let plan = plan_mirror(&directory);
+            assert!(plan.added.is_empty() && plan.deleted.is_empty());
+            let report = run_mirror(&MirrorPlan {
+                added: vec!["a.txt".to_string()],
+                ..plan
+            });
+            assert_eq!(report.copied, 0);
+        }
+    }
+
+    #[test]
+    fn has_mirrored_requires_the_same_source() {
+        let (dir, mut directory) = mirrored_directory();
+        assert!(!has_mirrored(&directory));
+
+        run_mirror(&plan_mirror(&directory));
+        assert!(has_mirrored(&directory));
+
+        let other = append_path(&dir.path().to_string_lossy(), "other");
+        fs::create_dir(&other).unwrap();
+        directory.path = other;
+        assert!(!has_mirrored(&directory));
+    }
+}
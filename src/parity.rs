@@ -0,0 +1,508 @@
+use crate::atomic_file::replace_atomically;
+use crate::user_data::{append_path, is_valid_file};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// バージョンのファイルと同じディレクトリに置く、パリティの保存先
+/// <backup_directory>/.dd-backup-parity/<保存したファイル名>.par
+pub const PARITY_DIRECTORY: &str = ".dd-backup-parity";
+
+const PARITY_MAGIC: &[u8] = b"DDBPAR1\n";
+
+/// 1 ストライプあたりのデータシャード数
+const STRIPE_DATA_SHARDS: usize = 20;
+const MIN_SHARD_SIZE: usize = 64;
+const MAX_SHARD_SIZE: usize = 64 * 1024;
+
+/// パリティの量 (元のデータに対する割合)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Parity {
+    #[default]
+    None,
+    Percent(u8),
+}
+
+impl Parity {
+    pub const ALL: [Parity; 4] = [
+        Parity::None,
+        Parity::Percent(5),
+        Parity::Percent(10),
+        Parity::Percent(25),
+    ];
+
+    /// 1 ストライプあたりのパリティシャード数
+    fn parity_shards(&self) -> usize {
+        match self {
+            Parity::None => 0,
+            Parity::Percent(percent) => (STRIPE_DATA_SHARDS * *percent as usize)
+                .div_ceil(100)
+                .max(1),
+        }
+    }
+}
+
+impl fmt::Display for Parity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Parity::None => write!(f, "No parity"),
+            Parity::Percent(percent) => write!(f, "Parity {}%", percent),
+        }
+    }
+}
+
+/// パリティファイルの先頭に置く情報
+/// シャードごとのハッシュで、どのブロックが壊れたかを判断する
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ParityHeader {
+    size: u64,
+    hash: String,
+    shard_size: usize,
+    parity_shards: usize,
+    data_hashes: Vec<String>,
+    parity_hashes: Vec<String>,
+}
+
+impl ParityHeader {
+    fn data_shard_count(&self) -> usize {
+        self.data_hashes.len()
+    }
+
+    /// ストライプごとのデータシャードの範囲
+    fn stripes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..self.data_shard_count())
+            .step_by(STRIPE_DATA_SHARDS)
+            .map(|start| {
+                (
+                    start,
+                    (start + STRIPE_DATA_SHARDS).min(self.data_shard_count()),
+                )
+            })
+    }
+}
+
+fn shard_hash(shard: &[u8]) -> String {
+    hex::encode(&Sha256::digest(shard)[..16])
+}
+
+fn hash_reader(reader: &mut impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn coding_error(error: reed_solomon_erasure::Error) -> io::Error {
+    io::Error::other(format!("{:?}", error))
+}
+
+/// ファイルの大きさから、ストライプに収まるようシャードの大きさを決める
+fn shard_size_for(size: u64) -> usize {
+    let size = size as usize;
+    size.div_ceil(STRIPE_DATA_SHARDS)
+        .next_multiple_of(MIN_SHARD_SIZE)
+        .clamp(MIN_SHARD_SIZE, MAX_SHARD_SIZE)
+}
+
+/// 保存したファイルに対応するパリティファイルのパス
+pub fn parity_path(stored_path: &str) -> String {
+    let path = Path::new(stored_path);
+    let directory = path
+        .parent()
+        .map(|p| p.display().to_string())
+        .unwrap_or_default();
+    let filename = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    append_path(
+        &append_path(&directory, PARITY_DIRECTORY),
+        &format!("{}.par", filename),
+    )
+}
+
+/// シャードを読む。ファイルが短くなっていた場合は 0 で埋める
+fn read_shard(file: &mut fs::File, index: usize, shard_size: usize) -> io::Result<Vec<u8>> {
+    let mut shard = vec![0u8; shard_size];
+    file.seek(SeekFrom::Start((index * shard_size) as u64))?;
+    let mut read = 0;
+    while read < shard_size {
+        match file.read(&mut shard[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    Ok(shard)
+}
+
+/// 保存したファイルのパリティを作成する
+pub fn create_parity(stored_path: &str, parity: Parity) -> io::Result<()> {
+    if parity == Parity::None {
+        return Ok(());
+    }
+
+    let mut file = fs::File::open(stored_path)?;
+    let size = file.metadata()?.len();
+    let shard_size = shard_size_for(size);
+    let mut header = ParityHeader {
+        size,
+        hash: hash_reader(&mut file)?,
+        shard_size,
+        parity_shards: parity.parity_shards(),
+        data_hashes: Vec::new(),
+        parity_hashes: Vec::new(),
+    };
+    let data_shard_count = (size as usize).div_ceil(shard_size).max(1);
+    header.data_hashes = vec!["".to_string(); data_shard_count];
+
+    let mut parity_data: Vec<u8> = Vec::new();
+    let stripes: Vec<(usize, usize)> = header.stripes().collect();
+    for (start, end) in stripes {
+        let mut shards = Vec::new();
+        for index in start..end {
+            let shard = read_shard(&mut file, index, shard_size)?;
+            header.data_hashes[index] = shard_hash(&shard);
+            shards.push(shard);
+        }
+        shards.extend(vec![vec![0u8; shard_size]; header.parity_shards]);
+
+        ReedSolomon::new(end - start, header.parity_shards)
+            .and_then(|rs| rs.encode(&mut shards))
+            .map_err(coding_error)?;
+
+        for shard in &shards[end - start..] {
+            header.parity_hashes.push(shard_hash(shard));
+            parity_data.extend_from_slice(shard);
+        }
+    }
+
+    let header_yaml = serde_yaml::to_string(&header).map_err(io::Error::other)?;
+    let path = parity_path(stored_path);
+    if let Some(directory) = Path::new(&path).parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    // 書きかけのパリティで修復しないよう、書き終えてから置き換える
+    replace_atomically(&path, |temp| {
+        let mut parity_file = fs::File::create(temp)?;
+        parity_file.write_all(PARITY_MAGIC)?;
+        parity_file.write_all(&(header_yaml.len() as u64).to_le_bytes())?;
+        parity_file.write_all(header_yaml.as_bytes())?;
+        parity_file.write_all(&parity_data)?;
+        parity_file.flush()
+    })
+}
+
+pub fn remove_parity(stored_path: &str) -> io::Result<()> {
+    let path = parity_path(stored_path);
+    if is_valid_file(&path) {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+fn load_parity(stored_path: &str) -> io::Result<(ParityHeader, Vec<u8>)> {
+    let content = fs::read(parity_path(stored_path))?;
+    let invalid_data = || io::Error::from(io::ErrorKind::InvalidData);
+
+    let rest = content
+        .strip_prefix(PARITY_MAGIC)
+        .ok_or_else(invalid_data)?;
+    let (length, rest) = rest.split_at_checked(8).ok_or_else(invalid_data)?;
+    let length = u64::from_le_bytes(length.try_into().map_err(|_| invalid_data())?) as usize;
+    let (header, parity_data) = rest.split_at_checked(length).ok_or_else(invalid_data)?;
+    let header: ParityHeader = serde_yaml::from_slice(header).map_err(|_| invalid_data())?;
+
+    let expected_parity_size = header.parity_hashes.len() * header.shard_size;
+    if parity_data.len() != expected_parity_size {
+        return Err(invalid_data());
+    }
+
+    Ok((header, parity_data.to_vec()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairResult {
+    Intact,
+    Repaired,
+    /// 壊れたブロックがパリティで補える数を超えている
+    Unrepairable,
+}
+
+/// パリティを使って、壊れたブロックを書き直す
+/// 一時ファイルの上で修復し、元の内容に戻った場合だけ置き換える (修復できなければ元のファイルは変えない)
+pub fn repair_file(stored_path: &str) -> io::Result<RepairResult> {
+    let (header, parity_data) = load_parity(stored_path)?;
+
+    let mut file = fs::File::open(stored_path)?;
+    if file.metadata()?.len() == header.size && hash_reader(&mut file)? == header.hash {
+        return Ok(RepairResult::Intact);
+    }
+    drop(file);
+
+    let mut result = RepairResult::Unrepairable;
+    let replaced = replace_atomically(stored_path, |temp| {
+        fs::copy(stored_path, temp)?;
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(temp)?;
+        result = repair_shards(&mut file, &header, &parity_data)?;
+        match result {
+            RepairResult::Repaired => Ok(()),
+            _ => Err(io::Error::other("not repaired")),
+        }
+    });
+
+    match (replaced, result) {
+        (Ok(_), result) => Ok(result),
+        (Err(_), RepairResult::Unrepairable) => Ok(RepairResult::Unrepairable),
+        (Err(e), _) => Err(e),
+    }
+}
+
+/// file の壊れたシャードをパリティから作り直して書き込む
+fn repair_shards(
+    file: &mut fs::File,
+    header: &ParityHeader,
+    parity_data: &[u8],
+) -> io::Result<RepairResult> {
+    let mut parity_shards = parity_data.chunks(header.shard_size.max(1));
+    for (stripe_index, (start, end)) in header.stripes().enumerate() {
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::new();
+        let mut damaged = Vec::new();
+        for index in start..end {
+            let shard = read_shard(file, index, header.shard_size)?;
+            if shard_hash(&shard) == header.data_hashes[index] {
+                shards.push(Some(shard));
+            } else {
+                shards.push(None);
+                damaged.push(index);
+            }
+        }
+
+        for parity_index in 0..header.parity_shards {
+            let shard = parity_shards.next().map(|s| s.to_vec());
+            let hash_index = stripe_index * header.parity_shards + parity_index;
+            shards.push(
+                shard.filter(|s| header.parity_hashes.get(hash_index) == Some(&shard_hash(s))),
+            );
+        }
+
+        if damaged.is_empty() {
+            continue;
+        }
+
+        let reconstructed = ReedSolomon::new(end - start, header.parity_shards)
+            .and_then(|rs| rs.reconstruct_data(&mut shards));
+        if reconstructed.is_err() {
+            return Ok(RepairResult::Unrepairable);
+        }
+
+        for index in damaged {
+            let Some(shard) = &shards[index - start] else {
+                return Ok(RepairResult::Unrepairable);
+            };
+
+            // 最後のシャードは元の大きさを超えないように書く
+            let offset = (index * header.shard_size) as u64;
+            let length = (header.size - offset).min(header.shard_size as u64) as usize;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&shard[..length])?;
+        }
+    }
+
+    file.set_len(header.size)?;
+    file.flush()?;
+
+    file.seek(SeekFrom::Start(0))?;
+    if hash_reader(file)? == header.hash {
+        Ok(RepairResult::Repaired)
+    } else {
+        Ok(RepairResult::Unrepairable)
+    }
+}
+
+/// バックアップ先ごとの修復結果
+#[derive(Debug, Clone, Default)]
+pub struct ParityReport {
+    pub checked: usize,
+    pub repaired: Vec<String>,
+    pub unrepairable: Vec<String>,
+}
+
+impl ParityReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} checked, {} repaired, {} unrepairable",
+            self.checked,
+            self.repaired.len(),
+            self.unrepairable.len()
+        )
+    }
+}
+
+/// パリティのあるすべてのファイルを確認し、壊れていれば修復する
+pub fn repair_directory(backup_directory: &str) -> ParityReport {
+    let mut report = ParityReport::default();
+    let Ok(entries) = fs::read_dir(append_path(backup_directory, PARITY_DIRECTORY)) else {
+        return report;
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(stored_name) = name.strip_suffix(".par") else {
+            continue;
+        };
+
+        report.checked += 1;
+        match repair_file(&append_path(backup_directory, stored_name)) {
+            Ok(RepairResult::Intact) => {}
+            Ok(RepairResult::Repaired) => report.repaired.push(stored_name.to_string()),
+            _ => report.unrepairable.push(stored_name.to_string()),
+        }
+    }
+
+    report
+}
+
+/// パリティを後から有効にした場合に、既存のファイルのパリティをまとめて作成する
+pub fn create_missing_parity(backup_directory: &str, parity: Parity) -> usize {
+    if parity == Parity::None {
+        return 0;
+    }
+
+    let Ok(entries) = fs::read_dir(backup_directory) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| !name.starts_with('.'))
+        .map(|name| append_path(backup_directory, &name))
+        .filter(|path| !is_valid_file(&parity_path(path)))
+        .filter(|path| create_parity(path, parity).is_ok())
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored_file(len: usize, parity: Parity) -> (tempfile::TempDir, String, Vec<u8>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = append_path(&dir.path().to_string_lossy(), "v1_a.bin");
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 % 253) as u8).collect();
+        fs::write(&path, &data).unwrap();
+        create_parity(&path, parity).unwrap();
+        (dir, path, data)
+    }
+
+    fn flip_byte(path: &str, offset: usize) {
+        let mut data = fs::read(path).unwrap();
+        data[offset] ^= 0xff;
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn intact_file_is_left_alone() {
+        let (_dir, path, _data) = stored_file(100_000, Parity::Percent(10));
+        assert_eq!(repair_file(&path).unwrap(), RepairResult::Intact);
+    }
+
+    #[test]
+    fn damaged_shards_are_repaired() {
+        // シャードが最大の大きさになり、ストライプが複数に分かれる大きさ
+        let (_dir, path, data) = stored_file(3_000_000, Parity::Percent(10));
+        let shard_size = shard_size_for(data.len() as u64);
+        assert_eq!(shard_size, MAX_SHARD_SIZE);
+        // 1 つのストライプでは、パリティシャードの数まで壊れても直せる
+        flip_byte(&path, 0);
+        flip_byte(&path, shard_size * 3 + 5);
+        // 別のストライプの最後のシャードも壊す
+        flip_byte(&path, data.len() - 1);
+
+        assert_eq!(repair_file(&path).unwrap(), RepairResult::Repaired);
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn truncated_file_is_repaired() {
+        let (_dir, path, data) = stored_file(10_000, Parity::Percent(25));
+        let shard_size = shard_size_for(data.len() as u64);
+        fs::write(&path, &data[..data.len() - shard_size / 2]).unwrap();
+
+        assert_eq!(repair_file(&path).unwrap(), RepairResult::Repaired);
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn too_much_damage_is_unrepairable() {
+        let (dir, path, data) = stored_file(100_000, Parity::Percent(5));
+        let shard_size = shard_size_for(data.len() as u64);
+        flip_byte(&path, 0);
+        flip_byte(&path, shard_size);
+        let damaged = fs::read(&path).unwrap();
+
+        assert_eq!(repair_file(&path).unwrap(), RepairResult::Unrepairable);
+        // 途中まで直した内容で上書きせず、一時ファイルも残さない
+        assert_eq!(fs::read(&path).unwrap(), damaged);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn missing_file_is_not_created() {
+        let (_dir, path, _data) = stored_file(1_000, Parity::Percent(25));
+        fs::remove_file(&path).unwrap();
+
+        let error = repair_file(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn parity_file_is_replaced_when_created_again() {
+        let (_dir, path, _data) = stored_file(10_000, Parity::Percent(5));
+        let data: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).unwrap();
+        create_parity(&path, Parity::Percent(25)).unwrap();
+
+        let parity_directory = Path::new(&parity_path(&path)).parent().unwrap().to_owned();
+        assert_eq!(fs::read_dir(parity_directory).unwrap().count(), 1);
+        flip_byte(&path, 100);
+        assert_eq!(repair_file(&path).unwrap(), RepairResult::Repaired);
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn repair_directory_reports_each_file() {
+        let (dir, path, data) = stored_file(50_000, Parity::Percent(10));
+        let backup_directory = dir.path().to_string_lossy().to_string();
+        let other = append_path(&backup_directory, "v2_b.bin");
+        fs::write(&other, b"untouched").unwrap();
+        assert_eq!(
+            create_missing_parity(&backup_directory, Parity::Percent(10)),
+            1
+        );
+
+        flip_byte(&path, 10);
+        let report = repair_directory(&backup_directory);
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.repaired, vec!["v1_a.bin".to_string()]);
+        assert!(report.unrepairable.is_empty());
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn remove_parity_deletes_the_parity_file() {
+        let (_dir, path, _data) = stored_file(1_000, Parity::Percent(5));
+        assert!(is_valid_file(&parity_path(&path)));
+        remove_parity(&path).unwrap();
+        assert!(!is_valid_file(&parity_path(&path)));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::backup_scan::parse_backup_filename;
+use crate::backup_store::BackupStore;
+use crate::file_metadata::MetadataIndex;
+use crate::manifest::Manifest;
+use std::collections::BTreeMap;
+use std::io;
+
+/// prune_versions の結果
+#[derive(Debug, Clone, Default)]
+pub struct PruneReport {
+    pub removed: usize,
+    /// 使われなくなって削除したチャンクの数
+    pub chunks_removed: usize,
+}
+
+impl PruneReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "Removed {} old versions and {} unused chunks",
+            self.removed, self.chunks_removed
+        )
+    }
+}
+
+/// ファイルごとに、新しい方から並べたバックアップファイル名
+fn versions_by_name(store: &dyn BackupStore) -> BTreeMap<String, Vec<String>> {
+    let mut versions: BTreeMap<String, Vec<(_, String)>> = BTreeMap::new();
+    for backup_filename in store.list_versions() {
+        if let Some((time, name)) = parse_backup_filename(&backup_filename) {
+            versions
+                .entry(name)
+                .or_default()
+                .push((time, backup_filename));
+        }
+    }
+
+    versions
+        .into_iter()
+        .map(|(name, mut versions)| {
+            // 同じ日時の連番付きのものは、ファイル名の大きい方を新しいとみなす
+            versions.sort_by(|a, b| b.cmp(a));
+            (name, versions.into_iter().map(|(_, v)| v).collect())
+        })
+        .collect()
+}
+
+/// ファイルごとに新しい方から keep 個 (最低 1 個) のバージョンを残し、それより古いものを削除する
+/// 削除したことはマニフェストに署名して記録し、使われなくなったチャンクはその後で削除する
+pub fn prune_versions(store: &dyn BackupStore, keep: usize) -> io::Result<PruneReport> {
+    let mut report = PruneReport::default();
+    let mut metadata = MetadataIndex::load(store);
+    for (name, versions) in versions_by_name(store) {
+        for backup_filename in versions.iter().skip(keep.max(1)) {
+            store.remove_version(backup_filename)?;
+            Manifest::append_removal(store, &name, backup_filename)?;
+            metadata.entries.remove(backup_filename);
+            report.removed += 1;
+        }
+    }
+
+    if report.removed > 0 {
+        metadata.save(store);
+    }
+    report.chunks_removed = store.collect_garbage()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_store::BackupMode;
+    use crate::hash_index::hash_file;
+    use crate::manifest::verify;
+    use crate::user_data::{append_path, DirectoryInfo};
+    use std::fs;
+
+    /// a.txt を 3 バージョン、b.txt を 1 バージョン持つバックアップ先
+    fn store_with_versions(mode: BackupMode) -> (tempfile::TempDir, Box<dyn BackupStore>) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let backup = append_path(&root, "backup");
+        fs::create_dir(&backup).unwrap();
+        let mut directory = DirectoryInfo::new(root.clone(), backup);
+        directory.backup_mode = mode;
+        let store = directory.backup_store();
+
+        let source = append_path(&root, "source");
+        for (backup_filename, content) in [
+            ("2024-01-01T00-00-00.000000000Z_a.txt", "first"),
+            ("2024-01-02T00-00-00.000000000Z_a.txt", "second"),
+            ("2024-01-03T00-00-00.000000000Z_a.txt", "third"),
+            ("2024-01-01T00-00-00.000000000Z_b.txt", "only"),
+        ] {
+            fs::write(&source, content).unwrap();
+            store.write_version(&source, backup_filename).unwrap();
+            let (time, name) = parse_backup_filename(backup_filename).unwrap();
+            let hash = hash_file(&source).unwrap();
+            Manifest::append(
+                store.as_ref(),
+                &name,
+                backup_filename,
+                time,
+                content.len() as u64,
+                &hash,
+            )
+            .unwrap();
+        }
+        (dir, store)
+    }
+
+    #[test]
+    fn older_versions_are_removed_and_recorded() {
+        let (_dir, store) = store_with_versions(BackupMode::Flat);
+        let report = prune_versions(store.as_ref(), 2).unwrap();
+        assert_eq!(report.removed, 1);
+
+        let mut versions = store.list_versions();
+        versions.sort();
+        assert_eq!(
+            versions,
+            [
+                "2024-01-01T00-00-00.000000000Z_b.txt",
+                "2024-01-02T00-00-00.000000000Z_a.txt",
+                "2024-01-03T00-00-00.000000000Z_a.txt",
+            ]
+        );
+        // 削除はマニフェストに記録されているため、欠けたバージョンにはならない
+        assert!(verify(store.as_ref()).is_ok());
+    }
+
+    #[test]
+    fn latest_version_is_always_kept() {
+        let (_dir, store) = store_with_versions(BackupMode::Flat);
+        assert_eq!(prune_versions(store.as_ref(), 0).unwrap().removed, 2);
+        assert_eq!(store.list_versions().len(), 2);
+    }
+
+    #[test]
+    fn pruning_frees_chunks() {
+        let (_dir, store) = store_with_versions(BackupMode::Chunked);
+        let report = prune_versions(store.as_ref(), 1).unwrap();
+        assert_eq!(report.removed, 2);
+        assert!(report.chunks_removed > 0);
+        assert!(verify(store.as_ref()).is_ok());
+    }
+}
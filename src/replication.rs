@@ -0,0 +1,459 @@
+use crate::atomic_file::PrivateTempDir;
+use crate::backup_store::BackupStore;
+use crate::encryption::copy_key_file;
+use crate::file_metadata::METADATA_INDEX_FILENAME;
+use crate::hash_index::{hash_file, HashIndex, HASH_INDEX_FILENAME};
+use crate::manifest::{Manifest, MANIFEST_FILENAME};
+use crate::user_data::DirectoryInfo;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+
+/// 複製先に置く進捗の記録
+/// 検証まで終えたバージョンだけを記録するため、中断しても続きから再開できる
+const REPLICATION_STATE_FILENAME: &str = ".dd-backup-replication.yaml";
+
+/// 前回の複製からこれだけ経つと、定期実行の対象になる
+pub const REPLICATION_INTERVAL_HOURS: i64 = 24;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ReplicationState {
+    source: String,
+    last_replication: Option<DateTime<Utc>>,
+    completed: BTreeSet<String>,
+}
+
+impl ReplicationState {
+    fn load(store: &dyn BackupStore) -> Self {
+        store
+            .read_index(REPLICATION_STATE_FILENAME)
+            .and_then(|content| serde_yaml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, store: &dyn BackupStore) {
+        if let Ok(yaml) = serde_yaml::to_string(self) {
+            store.write_index(REPLICATION_STATE_FILENAME, &yaml).ok();
+        }
+    }
+}
+
+/// 複製先ごとの結果
+#[derive(Debug, Clone, Default)]
+pub struct ReplicationReport {
+    pub backup_directory: String,
+    pub replica_directory: String,
+    pub copied: Vec<String>,
+    /// 前回までに複製を終えていたバージョンの数
+    pub skipped: usize,
+    pub failed: Vec<String>,
+    /// バックアップ先か複製先が使えない (暗号化されていて鍵が無い場合も含む)
+    pub unavailable: bool,
+    /// 複製を始められなかった理由
+    pub error: Option<String>,
+}
+
+impl ReplicationReport {
+    pub fn summary(&self) -> String {
+        if self.unavailable {
+            return format!(
+                "{} -> {} is not available",
+                self.backup_directory, self.replica_directory
+            );
+        }
+        if let Some(error) = &self.error {
+            return format!(
+                "{} -> {}: not replicated ({})",
+                self.backup_directory, self.replica_directory, error
+            );
+        }
+
+        format!(
+            "{} -> {}: {} copied, {} up to date, {} failed",
+            self.backup_directory,
+            self.replica_directory,
+            self.copied.len(),
+            self.skipped,
+            self.failed.len()
+        )
+    }
+}
+
+/// 複製先が設定されたディレクトリ設定を、組み合わせが重複しないように選ぶ
+fn replication_targets(directories: &[DirectoryInfo]) -> Vec<&DirectoryInfo> {
+    let mut targets: Vec<&DirectoryInfo> = Vec::new();
+    for dir in directories {
+        if dir.backup_directory.is_empty() || dir.replica_directory.is_empty() {
+            continue;
+        }
+
+        let duplicated = targets.iter().any(|t| {
+            t.backup_directory.to_lowercase() == dir.backup_directory.to_lowercase()
+                && t.replica_directory.to_lowercase() == dir.replica_directory.to_lowercase()
+        });
+        if !duplicated {
+            targets.push(dir);
+        }
+    }
+
+    targets
+}
+
+/// 前回の複製から REPLICATION_INTERVAL_HOURS 以上経った複製先があるか
+pub fn is_replication_due(directories: &[DirectoryInfo]) -> bool {
+    let due = Utc::now() - Duration::hours(REPLICATION_INTERVAL_HOURS);
+    replication_targets(directories).into_iter().any(|dir| {
+        let replica = dir.replica_store();
+        dir.backup_store().is_available()
+            && replica.is_available()
+            && ReplicationState::load(replica.as_ref())
+                .last_replication
+                .is_none_or(|last| last < due)
+    })
+}
+
+pub fn replicate_all(directories: &[DirectoryInfo]) -> Vec<ReplicationReport> {
+    replication_targets(directories)
+        .into_iter()
+        .map(replicate)
+        .collect()
+}
+
+/// バックアップ時に記録したハッシュ
+/// 署名付きの記録が読めない場合は、確かめずに複製しないようエラーにする
+fn expected_hashes(store: &dyn BackupStore) -> io::Result<HashMap<String, String>> {
+    let mut hashes: HashMap<String, String> = HashIndex::load(store)
+        .entries
+        .into_iter()
+        .filter(|e| !e.skipped && !e.hash.is_empty())
+        .map(|e| (e.backup_filename, e.hash))
+        .collect();
+    for entry in Manifest::load(store)?.entries {
+        hashes.insert(entry.backup_filename, entry.hash);
+    }
+
+    Ok(hashes)
+}
+
+/// バックアップ先にあって複製先に無いバージョンをコピーし、ハッシュを確かめる
+pub fn replicate(dir: &DirectoryInfo) -> ReplicationReport {
+    let mut report = ReplicationReport {
+        backup_directory: dir.backup_directory.clone(),
+        replica_directory: dir.replica_directory.clone(),
+        ..ReplicationReport::default()
+    };
+
+    let source = dir.backup_store();
+    let replica = dir.replica_store();
+    if !source.is_available() || !replica.is_available() {
+        report.unavailable = true;
+        return report;
+    }
+
+    // 暗号化されたバックアップ先は、同じパスフレーズで開けるよう鍵の情報をそろえる
+    if dir.encrypted {
+        let copied = copy_key_file(
+            dir.plain_store(&dir.backup_directory).as_ref(),
+            dir.plain_store(&dir.replica_directory).as_ref(),
+        );
+        if let Err(e) = copied {
+            report.error = Some(format!("failed to copy the key: {}", e));
+            return report;
+        }
+    }
+
+    // 別のバックアップ先から複製した記録は、このバックアップ先の内容を確かめたことにならない
+    let mut state = ReplicationState::load(replica.as_ref());
+    if state.source.to_lowercase() != dir.backup_directory.to_lowercase() {
+        state = ReplicationState {
+            source: dir.backup_directory.clone(),
+            ..ReplicationState::default()
+        };
+    }
+    let hashes = match expected_hashes(source.as_ref()) {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        }
+    };
+    // 暗号化されたバージョンは復号した内容になるため、他のユーザーから見えない場所に書く
+    let temp_dir = match PrivateTempDir::new("replicate") {
+        Ok(temp_dir) => temp_dir,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        }
+    };
+    let temporary = temp_dir.file("version");
+    let read_back = temp_dir.file("check");
+
+    for version in source.list_versions() {
+        if state.completed.contains(&version) && replica.has_version(&version) {
+            report.skipped += 1;
+            continue;
+        }
+
+        fs::remove_file(&temporary).ok();
+        fs::remove_file(&read_back).ok();
+        let source_hash = source
+            .read_version(&version, &temporary)
+            .ok()
+            .and_then(|_| hash_file(&temporary));
+        let source_valid = source_hash.is_some()
+            && hashes
+                .get(&version)
+                .is_none_or(|expected| Some(expected) == source_hash.as_ref());
+        if !source_valid {
+            report.failed.push(version);
+            continue;
+        }
+
+        // 記録が無いだけで複製済みのものはそのまま使い、
+        // 前回の途中で書きかけになったものだけを一時ファイル経由で置き換える
+        let exists = replica.has_version(&version);
+        if exists {
+            let replica_hash = replica
+                .read_version(&version, &read_back)
+                .ok()
+                .and_then(|_| hash_file(&read_back));
+            if replica_hash == source_hash {
+                state.completed.insert(version.clone());
+                state.save(replica.as_ref());
+                report.skipped += 1;
+                continue;
+            }
+            fs::remove_file(&read_back).ok();
+        }
+
+        let written = if exists {
+            replica.replace_version(&temporary, &version)
+        } else {
+            replica.write_version(&temporary, &version)
+        };
+        let copied = written
+            .and_then(|_| replica.read_version(&version, &read_back))
+            .is_ok()
+            && hash_file(&read_back) == source_hash;
+        if copied {
+            state.completed.insert(version.clone());
+            state.save(replica.as_ref());
+            report.copied.push(version);
+        } else {
+            // 確かめられなかったコピーは、正しいものと取り違えないよう残さない
+            replica.remove_version(&version).ok();
+            report.failed.push(version);
+        }
+    }
+
+    // 複製先でも重複の判定や検証、メタデータの復元ができるよう、履歴をそのまま写す
+    for index_name in [
+        HASH_INDEX_FILENAME,
+        MANIFEST_FILENAME,
+        METADATA_INDEX_FILENAME,
+    ] {
+        if let Some(content) = source.read_index(index_name) {
+            replica.write_index(index_name, &content).ok();
+        }
+    }
+
+    state.last_replication = Some(Utc::now());
+    state.save(replica.as_ref());
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::{create_key, unlock};
+    use crate::export_backup::ExportBackup;
+    use crate::file_metadata::MetadataOptions;
+    use crate::sftp::SftpOptions;
+    use crate::user_data::{append_path, FileInfo};
+    use crate::webdav::fake::{fake_webdav, url, FakeDav};
+    use std::sync::{Arc, Mutex};
+
+    /// 1 つのファイルをバックアップし、複製先を設定した状態
+    fn backed_up() -> (tempfile::TempDir, DirectoryInfo) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let source = append_path(&root, "source");
+        let backup = append_path(&root, "backup");
+        let replica = append_path(&root, "replica");
+        for path in [&source, &backup, &replica] {
+            fs::create_dir(path).unwrap();
+        }
+        fs::write(append_path(&source, "a.txt"), "hello").unwrap();
+
+        let mut directory = DirectoryInfo::new(source.clone(), backup);
+        directory.replica_directory = replica;
+        let mut file = FileInfo::new("a.txt".to_string(), Utc::now(), String::new());
+        file.refresh_metadata(&source);
+        file.sync(
+            &source,
+            directory.backup_store().as_ref(),
+            ExportBackup::Off,
+            MetadataOptions::default(),
+            &SftpOptions::default(),
+        );
+        (dir, directory)
+    }
+
+    /// 複製先に置かれたバージョンのファイル
+    fn replica_file(directory: &DirectoryInfo) -> String {
+        let name = fs::read_dir(&directory.replica_directory)
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .find(|name| !name.starts_with('.'))
+            .unwrap();
+        append_path(&directory.replica_directory, &name)
+    }
+
+    #[test]
+    fn copies_new_versions_once() {
+        let (_dir, directory) = backed_up();
+
+        let first = replicate(&directory);
+        assert_eq!(first.copied.len(), 1);
+        assert!(first.failed.is_empty());
+
+        let second = replicate(&directory);
+        assert!(second.copied.is_empty());
+        assert_eq!(second.skipped, 1);
+    }
+
+    #[test]
+    fn keeps_matching_copy_without_state() {
+        let (_dir, directory) = backed_up();
+        replicate(&directory);
+        let replica = directory.replica_store();
+        replica.write_index(REPLICATION_STATE_FILENAME, "").unwrap();
+        let modified = fs::metadata(replica_file(&directory))
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        let report = replicate(&directory);
+        assert!(report.copied.is_empty());
+        assert!(report.failed.is_empty());
+        assert_eq!(report.skipped, 1);
+        let after = fs::metadata(replica_file(&directory))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(modified, after);
+    }
+
+    #[test]
+    fn replaces_partial_copy() {
+        let (_dir, directory) = backed_up();
+        replicate(&directory);
+        let replica = directory.replica_store();
+        replica.write_index(REPLICATION_STATE_FILENAME, "").unwrap();
+        fs::write(replica_file(&directory), "hel").unwrap();
+
+        let report = replicate(&directory);
+        assert_eq!(report.copied.len(), 1);
+        assert!(report.failed.is_empty());
+        assert_eq!(
+            fs::read_to_string(replica_file(&directory)).unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn state_from_another_source_is_not_trusted() {
+        let (_dir, directory) = backed_up();
+        replicate(&directory);
+        fs::write(replica_file(&directory), "stale").unwrap();
+        let replica = directory.replica_store();
+        let mut state = ReplicationState::load(replica.as_ref());
+        state.source = "/elsewhere".to_string();
+        state.save(replica.as_ref());
+
+        let report = replicate(&directory);
+        assert_eq!(report.copied.len(), 1);
+        assert_eq!(
+            fs::read_to_string(replica_file(&directory)).unwrap(),
+            "hello"
+        );
+        let state = ReplicationState::load(replica.as_ref());
+        assert_eq!(state.source, directory.backup_directory);
+    }
+
+    #[test]
+    fn indexes_are_copied() {
+        let (_dir, directory) = backed_up();
+        let source = directory.backup_store();
+        source
+            .write_index(METADATA_INDEX_FILENAME, "entries: {}\n")
+            .unwrap();
+        replicate(&directory);
+
+        let replica = directory.replica_store();
+        for index_name in [
+            HASH_INDEX_FILENAME,
+            MANIFEST_FILENAME,
+            METADATA_INDEX_FILENAME,
+        ] {
+            assert!(source.read_index(index_name).is_some(), "{}", index_name);
+            assert_eq!(
+                replica.read_index(index_name),
+                source.read_index(index_name),
+                "{}",
+                index_name
+            );
+        }
+    }
+
+    #[test]
+    fn encrypted_backup_is_replicated_to_a_remote_replica() {
+        let dav = Arc::new(Mutex::new(FakeDav::default()));
+        let server = fake_webdav(dav.clone());
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let source = append_path(&root, "source");
+        let backup = append_path(&root, "backup");
+        for path in [&source, &backup] {
+            fs::create_dir(path).unwrap();
+        }
+        fs::write(append_path(&source, "a.txt"), "hello").unwrap();
+
+        let mut directory = DirectoryInfo::new(source.clone(), backup.clone());
+        directory.replica_directory = url(&server);
+        directory.encrypted = true;
+        directory.encryption_key =
+            Some(create_key(directory.plain_store(&backup).as_ref(), "correct horse").unwrap());
+        let mut file = FileInfo::new("a.txt".to_string(), Utc::now(), String::new());
+        file.refresh_metadata(&source);
+        file.sync(
+            &source,
+            directory.backup_store().as_ref(),
+            ExportBackup::Off,
+            MetadataOptions::default(),
+            &SftpOptions::default(),
+        );
+
+        let report = replicate(&directory);
+        assert!(report.error.is_none(), "{}", report.summary());
+        assert_eq!(report.copied.len(), 1);
+
+        // 複製先だけでも同じパスフレーズで開いて読み出せる
+        let key = unlock(
+            directory.plain_store(&directory.replica_directory).as_ref(),
+            "correct horse",
+        );
+        assert!(key.is_some());
+        let mut replica_only = directory.clone();
+        replica_only.encryption_key = key;
+        let replica = replica_only.replica_store();
+        let version = replica.list_versions()[0].clone();
+        let restored = append_path(&root, "restored.txt");
+        replica.read_version(&version, &restored).unwrap();
+        assert_eq!(fs::read_to_string(&restored).unwrap(), "hello");
+    }
+}
@@ -1,5 +1,13 @@
 use crate::app::App;
-use crate::user_data::FileInfo;
+use crate::atomic_file::write_atomically;
+use crate::backup_store::BackupMode;
+use crate::compression::Compression;
+use crate::export_backup::ExportBackup;
+use crate::file_metadata::MetadataOptions;
+use crate::parity::Parity;
+use crate::s3::RetentionMode;
+use crate::scrub::{DEFAULT_SCRUB_INTERVAL_DAYS, DEFAULT_SCRUB_SPEED_LIMIT};
+use crate::user_data::{ExportRecord, FileInfo};
 use serde::{Deserialize, Serialize};
 use std::fs;
 
@@ -7,6 +15,8 @@ use std::fs;
 struct SaveFileData {
     name: String,
     export: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    export_record: Option<ExportRecord>,
 }
 
 impl From<FileInfo> for SaveFileData {
@@ -14,23 +24,73 @@ impl From<FileInfo> for SaveFileData {
         SaveFileData {
             name: file_info.name.clone(),
             export: file_info.export_path.clone(),
+            export_record: file_info.export_record.clone(),
         }
     }
 }
 
-impl Into<FileInfo> for SaveFileData {
-    fn into(self) -> FileInfo {
+impl From<SaveFileData> for FileInfo {
+    fn from(save_file_data: SaveFileData) -> FileInfo {
         let mut file_info = FileInfo::empty();
-        file_info.name = self.name;
-        file_info.export_path = self.export;
+        file_info.name = save_file_data.name;
+        file_info.export_path = save_file_data.export;
+        file_info.export_record = save_file_data.export_record;
         file_info
     }
 }
 
+fn default_scrub_interval_days() -> u32 {
+    DEFAULT_SCRUB_INTERVAL_DAYS
+}
+
+fn default_scrub_speed_limit() -> u64 {
+    DEFAULT_SCRUB_SPEED_LIMIT
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct SaveDirectoryData {
     path: String,
     backup_directory: String,
+    #[serde(default)]
+    replica_directory: String,
+    #[serde(default)]
+    mirror_directory: String,
+    #[serde(default)]
+    mirror_deletions: bool,
+    #[serde(default)]
+    export_backup: ExportBackup,
+    #[serde(default)]
+    metadata: MetadataOptions,
+    #[serde(default)]
+    backup_mode: BackupMode,
+    #[serde(default)]
+    compression: Compression,
+    #[serde(default)]
+    parity: Parity,
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    keep_versions: usize,
+    #[serde(default = "default_scrub_interval_days")]
+    scrub_interval_days: u32,
+    #[serde(default = "default_scrub_speed_limit")]
+    scrub_speed_limit: u64,
+    #[serde(default)]
+    ssh_key: String,
+    #[serde(default)]
+    known_hosts: String,
+    #[serde(default)]
+    s3_endpoint: String,
+    #[serde(default)]
+    s3_retention_days: u32,
+    #[serde(default)]
+    s3_retention_mode: RetentionMode,
+    #[serde(default)]
+    git_remote: String,
+    #[serde(default)]
+    include: String,
+    #[serde(default)]
+    exclude: String,
     files: Vec<SaveFileData>,
 }
 
@@ -52,6 +112,26 @@ pub fn store_save_data(app: &App) {
         let mut save_directory = SaveDirectoryData {
             path: dir.path.clone(),
             backup_directory: dir.backup_directory.clone(),
+            replica_directory: dir.replica_directory.clone(),
+            mirror_directory: dir.mirror_directory.clone(),
+            mirror_deletions: dir.mirror_deletions,
+            export_backup: dir.export_backup,
+            metadata: dir.metadata_options,
+            backup_mode: dir.backup_mode,
+            compression: dir.compression,
+            parity: dir.parity,
+            encrypted: dir.encrypted,
+            keep_versions: dir.keep_versions,
+            scrub_interval_days: dir.scrub_interval_days,
+            scrub_speed_limit: dir.scrub_speed_limit,
+            ssh_key: dir.remote_options.sftp.key.clone(),
+            known_hosts: dir.remote_options.sftp.known_hosts.clone(),
+            s3_endpoint: dir.remote_options.s3.endpoint.clone(),
+            s3_retention_days: dir.remote_options.s3.retention_days,
+            s3_retention_mode: dir.remote_options.s3.retention_mode,
+            git_remote: dir.remote_options.git_remote.clone(),
+            include: dir.track_rules.include.clone(),
+            exclude: dir.track_rules.exclude.clone(),
             files: Vec::new(),
         };
 
@@ -63,7 +143,7 @@ pub fn store_save_data(app: &App) {
     }
 
     let yaml = serde_yaml::to_string(&save_data).unwrap();
-    fs::write(SAVE_PATH, yaml).unwrap();
+    write_atomically(SAVE_PATH, yaml).unwrap();
 }
 
 pub fn load_save_data() -> App {
@@ -79,14 +159,39 @@ pub fn load_save_data() -> App {
     for directory in save_data.directories {
         let dir_info = app.user_data.touch_directory_or_insert(&directory.path);
         dir_info.backup_directory = directory.backup_directory;
+        dir_info.replica_directory = directory.replica_directory;
+        dir_info.mirror_directory = directory.mirror_directory;
+        dir_info.mirror_deletions = directory.mirror_deletions;
+        dir_info.export_backup = directory.export_backup;
+        dir_info.metadata_options = directory.metadata;
+        dir_info.backup_mode = directory.backup_mode;
+        dir_info.compression = directory.compression;
+        dir_info.parity = directory.parity;
+        dir_info.encrypted = directory.encrypted;
+        dir_info.keep_versions = directory.keep_versions;
+        dir_info.scrub_interval_days = directory.scrub_interval_days;
+        dir_info.scrub_speed_limit = directory.scrub_speed_limit;
+        dir_info.remote_options.sftp.key = directory.ssh_key;
+        dir_info.remote_options.sftp.known_hosts = directory.known_hosts;
+        dir_info.remote_options.s3.endpoint = directory.s3_endpoint;
+        dir_info.remote_options.s3.retention_days = directory.s3_retention_days;
+        dir_info.remote_options.s3.retention_mode = directory.s3_retention_mode;
+        dir_info.remote_options.git_remote = directory.git_remote;
+        dir_info.track_rules.include = directory.include;
+        dir_info.track_rules.exclude = directory.exclude;
         for file in directory.files {
             let mut file_info: FileInfo = file.into();
-            file_info.refresh_last_edited(&dir_info.path);
-            file_info.refresh_synced(&dir_info.backup_directory);
-            file_info.refresh_export_valid();
+            file_info.refresh_metadata(&dir_info.path);
             dir_info.add_file(file_info);
         }
 
+        // 前回の終了後に増えたファイルを取り込む
+        if dir_info.track_rules.is_enabled() {
+            dir_info.refresh_files();
+        } else {
+            dir_info.refresh_ignore_rules();
+        }
+
         dir_info.sort_files_by_last_edited();
     }
 
@@ -0,0 +1,521 @@
+use crate::atomic_file::PrivateTempDir;
+use crate::backup_store::BackupStore;
+use crate::hash_index::{hash_file, HashIndex};
+use crate::manifest::Manifest;
+use crate::user_data::DirectoryInfo;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::thread;
+use std::time::Instant;
+
+const SCRUB_INDEX_FILENAME: &str = ".dd-backup-scrub.yaml";
+
+/// 読み込みの上限 (MiB/秒) の既定値。ディスクを占有しないよう、これを超えたら待つ
+pub const DEFAULT_SCRUB_SPEED_LIMIT: u64 = 32;
+
+/// 前回のスクラブからこれだけ経つと、定期実行の対象になる (日) の既定値
+pub const DEFAULT_SCRUB_INTERVAL_DAYS: u32 = 7;
+
+/// バージョンごとの内容のハッシュ
+/// 初回のスクラブで作り、以降はこれと突き合わせる
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ScrubIndex {
+    last_scrub: Option<DateTime<Utc>>,
+    digests: BTreeMap<String, String>,
+}
+
+impl ScrubIndex {
+    fn load(store: &dyn BackupStore) -> Self {
+        store
+            .read_index(SCRUB_INDEX_FILENAME)
+            .and_then(|content| serde_yaml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, store: &dyn BackupStore) {
+        if let Ok(yaml) = serde_yaml::to_string(self) {
+            store.write_index(SCRUB_INDEX_FILENAME, &yaml).ok();
+        }
+    }
+
+    /// 前回までのスクラブで確かめたハッシュが無ければ、バックアップ時に記録したハッシュを使う
+    fn expected_digest(&self, recorded: &RecordedDigests, backup_filename: &str) -> Option<String> {
+        self.digests
+            .get(backup_filename)
+            .or_else(|| recorded.0.get(backup_filename))
+            .cloned()
+    }
+}
+
+/// バックアップ時に記録したバージョンごとのハッシュ (署名付きの記録を優先する)
+/// バージョンごとに読み直さないよう、スクラブや修復の始めに 1 度だけ読み込む
+struct RecordedDigests(HashMap<String, String>);
+
+impl RecordedDigests {
+    /// 署名付きの記録が読めない場合は、壊れた内容を正しいとみなさないようエラーにする
+    fn load(store: &dyn BackupStore) -> io::Result<Self> {
+        let mut digests = HashMap::new();
+        for entry in Manifest::load(store)?.entries {
+            digests.entry(entry.backup_filename).or_insert(entry.hash);
+        }
+        for entry in HashIndex::load(store).entries {
+            if !entry.hash.is_empty() {
+                digests.entry(entry.backup_filename).or_insert(entry.hash);
+            }
+        }
+
+        Ok(RecordedDigests(digests))
+    }
+}
+
+/// バックアップ先ごとのスクラブ結果
+#[derive(Debug, Clone, Default)]
+pub struct ScrubReport {
+    pub backup_directory: String,
+    pub last_scrub: Option<DateTime<Utc>>,
+    pub checked: usize,
+    /// 今回初めてハッシュを記録したバージョンの数
+    pub indexed: usize,
+    pub corrupted: Vec<String>,
+    /// 他のバックアップ先に正しい内容が残っているもの
+    pub repairable: Vec<String>,
+    pub repaired: Vec<String>,
+    /// 暗号化されていて鍵が無いため確認できなかった
+    pub locked: bool,
+    /// 読み出したバージョンを置く場所を用意できなかった
+    pub error: Option<String>,
+}
+
+/// backup_directory のスクラブの記録と、バックアップ時に記録したハッシュ
+fn load_digests(
+    directories: &[DirectoryInfo],
+    backup_directory: &str,
+) -> Option<(ScrubIndex, RecordedDigests)> {
+    let target = directories
+        .iter()
+        .find(|d| d.backup_directory.to_lowercase() == backup_directory.to_lowercase())?;
+    let store = target.backup_store();
+    Some((
+        ScrubIndex::load(store.as_ref()),
+        RecordedDigests::load(store.as_ref()).ok()?,
+    ))
+}
+
+/// バックアップ先が重複しないように、ディレクトリ設定を選ぶ
+fn unique_targets(directories: &[DirectoryInfo]) -> Vec<&DirectoryInfo> {
+    let mut targets: Vec<&DirectoryInfo> = Vec::new();
+    for dir in directories {
+        if !dir.backup_directory.is_empty()
+            && !targets
+                .iter()
+                .any(|t| t.backup_directory.to_lowercase() == dir.backup_directory.to_lowercase())
+        {
+            targets.push(dir);
+        }
+    }
+
+    targets
+}
+
+pub fn last_scrub(dir: &DirectoryInfo) -> Option<DateTime<Utc>> {
+    ScrubIndex::load(dir.backup_store().as_ref()).last_scrub
+}
+
+/// 前回のスクラブからディレクトリごとの間隔以上経ったバックアップ先があるか
+/// 間隔が 0 のバックアップ先は定期実行しない
+pub fn is_scrub_due(directories: &[DirectoryInfo]) -> bool {
+    unique_targets(directories).into_iter().any(|dir| {
+        let due = Utc::now() - Duration::days(dir.scrub_interval_days.into());
+        dir.scrub_interval_days > 0
+            && dir.backup_store().is_available()
+            && last_scrub(dir).is_none_or(|last| last < due)
+    })
+}
+
+/// 読み込み量が上限 (MiB/秒) を超えないよう待つ時間 (上限が 0 なら待たない)
+fn throttle_wait(
+    bytes_read: u64,
+    elapsed: std::time::Duration,
+    speed_limit: u64,
+) -> Option<std::time::Duration> {
+    if speed_limit == 0 {
+        return None;
+    }
+
+    let expected_elapsed =
+        std::time::Duration::from_secs_f64(bytes_read as f64 / (speed_limit * 1024 * 1024) as f64);
+    expected_elapsed.checked_sub(elapsed)
+}
+
+/// すべてのバックアップ先のバージョンを読み直し、ハッシュを確かめる
+pub fn scrub_all(directories: &[DirectoryInfo]) -> Vec<ScrubReport> {
+    let mut reports: Vec<ScrubReport> = unique_targets(directories)
+        .into_iter()
+        .map(scrub_target)
+        .collect();
+
+    // 複製先や他のバックアップ先に同じバージョンの正しい内容があれば修復できる
+    for report in reports.iter_mut() {
+        if report.corrupted.is_empty() {
+            continue;
+        }
+        let Some((index, recorded)) = load_digests(directories, &report.backup_directory) else {
+            continue;
+        };
+        let Ok(temp_dir) = PrivateTempDir::new("repair") else {
+            continue;
+        };
+
+        let good_copy = temp_dir.file("version");
+        report.repairable = report
+            .corrupted
+            .iter()
+            .filter(|v| {
+                index.expected_digest(&recorded, v).is_some_and(|expected| {
+                    find_good_copy(
+                        directories,
+                        &report.backup_directory,
+                        v,
+                        &expected,
+                        &good_copy,
+                    )
+                })
+            })
+            .cloned()
+            .collect();
+    }
+
+    reports
+}
+
+fn scrub_target(dir: &DirectoryInfo) -> ScrubReport {
+    let store = dir.backup_store();
+    let mut report = ScrubReport {
+        backup_directory: dir.backup_directory.clone(),
+        ..ScrubReport::default()
+    };
+
+    if !store.is_available() {
+        report.locked = dir.encrypted && dir.encryption_key.is_none();
+        report.last_scrub = ScrubIndex::load(store.as_ref()).last_scrub;
+        return report;
+    }
+
+    let mut index = ScrubIndex::load(store.as_ref());
+    let recorded = match RecordedDigests::load(store.as_ref()) {
+        Ok(recorded) => recorded,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            report.last_scrub = index.last_scrub;
+            return report;
+        }
+    };
+    // 暗号化されたバージョンは復号した内容になるため、他のユーザーから見えない場所に書く
+    let temp_dir = match PrivateTempDir::new("scrub") {
+        Ok(temp_dir) => temp_dir,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            report.last_scrub = index.last_scrub;
+            return report;
+        }
+    };
+    let temporary = temp_dir.file("version");
+    let started = Instant::now();
+    let mut bytes_read: u64 = 0;
+
+    for version in store.list_versions() {
+        fs::remove_file(&temporary).ok();
+        let digest = store
+            .read_version(&version, &temporary)
+            .ok()
+            .and_then(|_| hash_file(&temporary));
+        bytes_read += fs::metadata(&temporary).map(|m| m.len()).unwrap_or(0);
+        report.checked += 1;
+
+        match (digest, index.expected_digest(&recorded, &version)) {
+            (Some(digest), Some(expected)) if digest == expected => {
+                index.digests.insert(version, digest);
+            }
+            (Some(digest), None) => {
+                index.digests.insert(version, digest);
+                report.indexed += 1;
+            }
+            _ => report.corrupted.push(version),
+        }
+
+        // 読み込み量に見合う時間が経つまで待つ
+        if let Some(wait) = throttle_wait(bytes_read, started.elapsed(), dir.scrub_speed_limit) {
+            thread::sleep(wait);
+        }
+    }
+
+    index.last_scrub = Some(Utc::now());
+    index.save(store.as_ref());
+    report.last_scrub = index.last_scrub;
+    report
+}
+
+/// 修復に使える場所 (複製先と、他のバックアップ先)
+/// バックアップ先自身の複製先を先に調べる
+fn repair_sources(
+    directories: &[DirectoryInfo],
+    backup_directory: &str,
+) -> Vec<Box<dyn BackupStore>> {
+    let mut seen = vec![backup_directory.to_lowercase()];
+    let mut claim = |location: &str| {
+        let location = location.to_lowercase();
+        if location.is_empty() || seen.contains(&location) {
+            return false;
+        }
+        seen.push(location);
+        true
+    };
+
+    let is_target = |dir: &&DirectoryInfo| {
+        dir.backup_directory.to_lowercase() == backup_directory.to_lowercase()
+    };
+    let (own, others): (Vec<&DirectoryInfo>, Vec<&DirectoryInfo>) =
+        directories.iter().partition(is_target);
+
+    let mut sources = Vec::new();
+    for dir in own.iter().chain(others.iter()) {
+        if claim(&dir.replica_directory) {
+            sources.push(dir.replica_store());
+        }
+    }
+    for dir in others {
+        if claim(&dir.backup_directory) {
+            sources.push(dir.backup_store());
+        }
+    }
+
+    sources
+}
+
+/// 複製先や他のバックアップ先から、期待するハッシュと一致する内容を探して dest_path へ読み出す
+fn find_good_copy(
+    directories: &[DirectoryInfo],
+    backup_directory: &str,
+    backup_filename: &str,
+    expected: &str,
+    dest_path: &str,
+) -> bool {
+    for store in repair_sources(directories, backup_directory) {
+        if !store.is_available() || !store.has_version(backup_filename) {
+            continue;
+        }
+
+        fs::remove_file(dest_path).ok();
+        if store.read_version(backup_filename, dest_path).is_ok()
+            && hash_file(dest_path).as_deref() == Some(expected)
+        {
+            return true;
+        }
+    }
+
+    fs::remove_file(dest_path).ok();
+    false
+}
+
+/// 壊れたバージョンを複製先や他のバックアップ先の正しい内容で置き換える
+pub fn repair(directories: &[DirectoryInfo], report: &ScrubReport) -> ScrubReport {
+    let mut report = report.clone();
+    let Some(target) = directories
+        .iter()
+        .find(|d| d.backup_directory.to_lowercase() == report.backup_directory.to_lowercase())
+    else {
+        return report;
+    };
+
+    let store = target.backup_store();
+    let index = ScrubIndex::load(store.as_ref());
+    let recorded = match RecordedDigests::load(store.as_ref()) {
+        Ok(recorded) => recorded,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        }
+    };
+    let temp_dir = match PrivateTempDir::new("repair") {
+        Ok(temp_dir) => temp_dir,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        }
+    };
+    let good_copy = temp_dir.file("version");
+
+    for version in report.repairable.clone() {
+        let Some(expected) = index.expected_digest(&recorded, &version) else {
+            continue;
+        };
+        if !find_good_copy(
+            directories,
+            &report.backup_directory,
+            &version,
+            &expected,
+            &good_copy,
+        ) {
+            continue;
+        }
+
+        // 正しい内容を書き終えるまで、壊れた実体は残しておく
+        let repaired = store.replace_version(&good_copy, &version).is_ok();
+
+        if repaired {
+            report.corrupted.retain(|v| *v != version);
+            report.repairable.retain(|v| *v != version);
+            report.repaired.push(version);
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export_backup::ExportBackup;
+    use crate::file_metadata::MetadataOptions;
+    use crate::sftp::SftpOptions;
+    use crate::user_data::{append_path, FileInfo};
+
+    /// 同じファイルを 2 つのバックアップ先へバックアップした状態
+    fn two_backups() -> (tempfile::TempDir, Vec<DirectoryInfo>, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let source = append_path(&root, "source");
+        fs::create_dir(&source).unwrap();
+        fs::write(append_path(&source, "a.txt"), "hello").unwrap();
+
+        let mut directories = Vec::new();
+        for backup in ["backup1", "backup2"] {
+            let backup = append_path(&root, backup);
+            fs::create_dir(&backup).unwrap();
+            let directory = DirectoryInfo::new(source.clone(), backup);
+            let mut file = FileInfo::new("a.txt".to_string(), Utc::now(), String::new());
+            file.refresh_metadata(&source);
+            file.sync(
+                &source,
+                directory.backup_store().as_ref(),
+                ExportBackup::Off,
+                MetadataOptions::default(),
+                &SftpOptions::default(),
+            );
+            directories.push(directory);
+        }
+
+        let version = directories[0].backup_store().list_versions()[0].clone();
+        (dir, directories, version)
+    }
+
+    #[test]
+    fn intact_backups_pass() {
+        let (_dir, directories, _version) = two_backups();
+        let reports = scrub_all(&directories);
+        assert_eq!(reports.len(), 2);
+        for report in reports {
+            assert_eq!(report.checked, 1);
+            assert!(report.corrupted.is_empty());
+            assert!(report.last_scrub.is_some());
+        }
+    }
+
+    #[test]
+    fn corrupted_version_is_repaired_from_another_backup() {
+        let (_dir, directories, version) = two_backups();
+        let corrupted_path = append_path(&directories[0].backup_directory, &version);
+        fs::write(&corrupted_path, "hellp").unwrap();
+
+        let reports = scrub_all(&directories);
+        assert_eq!(reports[0].corrupted, vec![version.clone()]);
+        assert_eq!(reports[0].repairable, vec![version.clone()]);
+        assert!(reports[1].corrupted.is_empty());
+
+        let report = repair(&directories, &reports[0]);
+        assert_eq!(report.repaired, vec![version.clone()]);
+        assert!(report.corrupted.is_empty());
+        assert_eq!(fs::read_to_string(&corrupted_path).unwrap(), "hello");
+    }
+
+    #[test]
+    fn corrupted_everywhere_is_not_repairable() {
+        let (_dir, directories, version) = two_backups();
+        for directory in &directories {
+            fs::write(append_path(&directory.backup_directory, &version), "hellp").unwrap();
+        }
+
+        let reports = scrub_all(&directories);
+        assert_eq!(reports[0].corrupted, vec![version.clone()]);
+        assert!(reports[0].repairable.is_empty());
+
+        // 修復できないものは、壊れた実体もそのまま残す
+        let report = repair(&directories, &reports[0]);
+        assert!(report.repaired.is_empty());
+        assert_eq!(
+            fs::read_to_string(append_path(&directories[0].backup_directory, &version)).unwrap(),
+            "hellp"
+        );
+    }
+
+    #[test]
+    fn corrupted_version_is_repaired_from_the_replica() {
+        let (dir, mut directories, version) = two_backups();
+        let mut directory = directories.remove(0);
+        let replica = append_path(&dir.path().to_string_lossy(), "replica");
+        fs::create_dir(&replica).unwrap();
+        directory.replica_directory = replica.clone();
+        fs::copy(
+            append_path(&directory.backup_directory, &version),
+            append_path(&replica, &version),
+        )
+        .unwrap();
+
+        let corrupted_path = append_path(&directory.backup_directory, &version);
+        fs::write(&corrupted_path, "hellp").unwrap();
+        let directories = vec![directory];
+        let reports = scrub_all(&directories);
+        assert_eq!(reports[0].repairable, vec![version.clone()]);
+
+        let report = repair(&directories, &reports[0]);
+        assert_eq!(report.repaired, vec![version]);
+        assert_eq!(fs::read_to_string(&corrupted_path).unwrap(), "hello");
+    }
+
+    #[test]
+    fn schedule_follows_the_directory_setting() {
+        let (_dir, mut directories, _version) = two_backups();
+        directories.truncate(1);
+        assert!(is_scrub_due(&directories));
+
+        directories[0].scrub_interval_days = 0;
+        assert!(!is_scrub_due(&directories));
+
+        directories[0].scrub_interval_days = 1;
+        scrub_all(&directories);
+        assert!(!is_scrub_due(&directories));
+
+        // 前回のスクラブが間隔より前なら、また対象になる
+        let store = directories[0].backup_store();
+        let mut index = ScrubIndex::load(store.as_ref());
+        index.last_scrub = Some(Utc::now() - Duration::days(2));
+        index.save(store.as_ref());
+        assert!(is_scrub_due(&directories));
+    }
+
+    #[test]
+    fn throttle_waits_for_the_speed_limit() {
+        let second = std::time::Duration::from_secs(1);
+        let read = 64 * 1024 * 1024;
+        assert_eq!(throttle_wait(read, second, 32), Some(second));
+        assert_eq!(
+            throttle_wait(read, second, 64),
+            Some(std::time::Duration::ZERO)
+        );
+        assert_eq!(throttle_wait(read, second * 3, 32), None);
+        assert_eq!(throttle_wait(read, second, 0), None);
+    }
+}
@@ -0,0 +1,929 @@
+use crate::backup_store::{missing_as_none, BackupStore};
+use crate::trash::{claim_trash_directory, TRASH_DIRECTORY};
+use crate::user_data::{append_path, get_parent_path};
+use ssh2::{CheckResult, KnownHostFileKind, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+use std::cell::OnceCell;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub const SFTP_SCHEME: &str = "sftp://";
+const DEFAULT_PORT: u16 = 22;
+
+/// 秘密鍵のパス (バックアップ先ごとの設定が無い時に使い、これも無ければ ssh-agent と ~/.ssh の既定の鍵を試す)
+const KEY_ENV: &str = "DD_BACKUP_SSH_KEY";
+/// known_hosts のパス (バックアップ先ごとの設定が無い時に使い、これも無ければ ~/.ssh/known_hosts)
+const KNOWN_HOSTS_ENV: &str = "DD_BACKUP_KNOWN_HOSTS";
+
+/// アップロード中のファイルは `.<name>.part` として置き、書き終えてから名前を変える
+const PART_SUFFIX: &str = ".part";
+
+pub fn is_sftp_url(path: &str) -> bool {
+    path.starts_with(SFTP_SCHEME)
+}
+
+/// `sftp://user@host:port/path` を分解したもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SftpLocation {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl SftpLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix(SFTP_SCHEME)?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (user.to_string(), host_port),
+            None => (default_user(), authority),
+        };
+
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (host.to_string(), port.parse().ok()?),
+            None => (host_port.to_string(), DEFAULT_PORT),
+        };
+
+        if host.is_empty() || user.is_empty() {
+            return None;
+        }
+
+        Some(SftpLocation {
+            user,
+            host,
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// 接続に使う秘密鍵と known_hosts のディレクトリごとの設定 (空なら環境変数か既定の場所を使う)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SftpOptions {
+    pub key: String,
+    pub known_hosts: String,
+}
+
+impl SftpOptions {
+    fn key_path(&self) -> Option<PathBuf> {
+        if !self.key.is_empty() {
+            return Some(PathBuf::from(&self.key));
+        }
+
+        env::var(KEY_ENV).ok().map(PathBuf::from)
+    }
+
+    fn known_hosts_path(&self) -> PathBuf {
+        if !self.known_hosts.is_empty() {
+            return PathBuf::from(&self.known_hosts);
+        }
+
+        env::var(KNOWN_HOSTS_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| home_directory().join(".ssh").join("known_hosts"))
+    }
+}
+
+fn default_user() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+fn home_directory() -> PathBuf {
+    env::var("HOME")
+        .or_else(|_| env::var("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_default()
+}
+
+/// SFTP の「ファイルが無い」は NotFound にする
+const SFTP_NO_SUCH_FILE: i32 = 2;
+
+fn ssh_error(error: ssh2::Error) -> io::Error {
+    if error.code() == ssh2::ErrorCode::SFTP(SFTP_NO_SUCH_FILE) {
+        return io::Error::new(io::ErrorKind::NotFound, error.to_string());
+    }
+
+    io::Error::other(error.to_string())
+}
+
+/// 接続先のファイルの情報
+struct RemoteStat {
+    size: Option<u64>,
+    is_dir: bool,
+    is_file: bool,
+    /// UNIX 時刻 (秒)
+    mtime: Option<u64>,
+}
+
+impl From<ssh2::FileStat> for RemoteStat {
+    fn from(stat: ssh2::FileStat) -> Self {
+        RemoteStat {
+            size: stat.size,
+            is_dir: stat.is_dir(),
+            is_file: stat.is_file(),
+            mtime: stat.mtime,
+        }
+    }
+}
+
+trait RemoteFile: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> RemoteFile for T {}
+
+/// SFTP で行うファイル操作
+/// テストではローカルのディレクトリで置き換えて、サーバー無しで確かめる
+trait RemoteFiles {
+    fn stat(&self, path: &str) -> io::Result<RemoteStat>;
+    /// ディレクトリの中の名前と情報
+    fn read_directory(&self, path: &str) -> io::Result<Vec<(String, RemoteStat)>>;
+    fn open(&self, path: &str) -> io::Result<Box<dyn RemoteFile>>;
+    /// 書き込み用に開く (無ければ作り、truncate なら空にする)
+    fn open_write(&self, path: &str, truncate: bool) -> io::Result<Box<dyn RemoteFile>>;
+    /// overwrite でなければ、移動先が既にある時に失敗する
+    fn rename(&self, from: &str, to: &str, overwrite: bool) -> io::Result<()>;
+    fn unlink(&self, path: &str) -> io::Result<()>;
+    fn mkdir(&self, path: &str) -> io::Result<()>;
+}
+
+/// ssh2 のセッション上の SFTP
+/// Session を保持しておかないと Sftp が使えなくなる
+struct SshFiles {
+    _session: Session,
+    sftp: Sftp,
+}
+
+impl RemoteFiles for SshFiles {
+    fn stat(&self, path: &str) -> io::Result<RemoteStat> {
+        self.sftp
+            .stat(Path::new(path))
+            .map(RemoteStat::from)
+            .map_err(ssh_error)
+    }
+
+    fn read_directory(&self, path: &str) -> io::Result<Vec<(String, RemoteStat)>> {
+        let entries = self.sftp.readdir(Path::new(path)).map_err(ssh_error)?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|(path, stat)| {
+                let name = path.file_name()?.to_string_lossy().to_string();
+                Some((name, RemoteStat::from(stat)))
+            })
+            .collect())
+    }
+
+    fn open(&self, path: &str) -> io::Result<Box<dyn RemoteFile>> {
+        let file = self.sftp.open(Path::new(path)).map_err(ssh_error)?;
+        Ok(Box::new(file))
+    }
+
+    fn open_write(&self, path: &str, truncate: bool) -> io::Result<Box<dyn RemoteFile>> {
+        let mut flags = OpenFlags::WRITE | OpenFlags::CREATE;
+        if truncate {
+            flags |= OpenFlags::TRUNCATE;
+        }
+
+        let file = self
+            .sftp
+            .open_mode(Path::new(path), flags, 0o644, OpenType::File)
+            .map_err(ssh_error)?;
+        Ok(Box::new(file))
+    }
+
+    fn rename(&self, from: &str, to: &str, overwrite: bool) -> io::Result<()> {
+        let flags = if overwrite {
+            RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE
+        } else {
+            RenameFlags::NATIVE
+        };
+        self.sftp
+            .rename(Path::new(from), Path::new(to), Some(flags))
+            .map_err(ssh_error)
+    }
+
+    fn unlink(&self, path: &str) -> io::Result<()> {
+        self.sftp.unlink(Path::new(path)).map_err(ssh_error)
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.sftp.mkdir(Path::new(path), 0o755).map_err(ssh_error)
+    }
+}
+
+/// 接続済みのセッション
+pub struct SftpConnection {
+    files: Box<dyn RemoteFiles>,
+}
+
+impl SftpConnection {
+    /// known_hosts に登録されたホスト鍵と一致する場合だけ接続する
+    pub fn connect(location: &SftpLocation, options: &SftpOptions) -> io::Result<Self> {
+        let stream = TcpStream::connect((location.host.as_str(), location.port))?;
+        let mut session = Session::new().map_err(ssh_error)?;
+        session.set_tcp_stream(stream);
+        session.handshake().map_err(ssh_error)?;
+
+        check_known_host(&session, location, options)?;
+        authenticate(&session, &location.user, options)?;
+
+        let sftp = session.sftp().map_err(ssh_error)?;
+        Ok(SftpConnection {
+            files: Box::new(SshFiles {
+                _session: session,
+                sftp,
+            }),
+        })
+    }
+
+    pub fn is_directory(&self, path: &str) -> bool {
+        self.files.stat(path).is_ok_and(|stat| stat.is_dir)
+    }
+
+    pub fn is_file(&self, path: &str) -> bool {
+        self.files.stat(path).is_ok_and(|stat| stat.is_file)
+    }
+
+    fn file_size(&self, path: &str) -> Option<u64> {
+        self.files.stat(path).ok().and_then(|s| s.size)
+    }
+
+    pub fn list_file_names(&self, directory: &str) -> Vec<String> {
+        match self.files.read_directory(directory) {
+            Ok(entries) => entries
+                .into_iter()
+                .filter(|(_, stat)| stat.is_file)
+                .map(|(name, _)| name)
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// 前回の途中までのアップロードがあり、その中身がローカルのファイルの先頭と同じであれば、続きから送る
+    pub fn upload(&self, local_path: &str, remote_path: &str) -> io::Result<()> {
+        let mut local = fs::File::open(local_path)?;
+        let local_size = local.metadata()?.len();
+        let part_path = part_path(remote_path);
+
+        let offset = self
+            .file_size(&part_path)
+            .filter(|size| *size <= local_size)
+            .filter(|size| self.part_matches(&part_path, &mut local, *size))
+            .unwrap_or(0);
+
+        let mut remote = self.files.open_write(&part_path, offset == 0)?;
+        remote.seek(SeekFrom::Start(offset))?;
+        local.seek(SeekFrom::Start(offset))?;
+        io::copy(&mut local, &mut remote)?;
+        remote.flush()?;
+        drop(remote);
+
+        if self.file_size(&part_path) != Some(local_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "upload incomplete",
+            ));
+        }
+
+        self.files.rename(&part_path, remote_path, true)
+    }
+
+    /// 書きかけの `.part` が、別の内容のアップロードの残りでないか確かめる
+    fn part_matches(&self, part_path: &str, local: &mut fs::File, size: u64) -> bool {
+        let Ok(mut remote) = self.files.open(part_path) else {
+            return false;
+        };
+
+        local.seek(SeekFrom::Start(0)).is_ok()
+            && same_prefix(&mut remote, local, size).unwrap_or(false)
+    }
+
+    pub fn download(&self, remote_path: &str, local_path: &str) -> io::Result<()> {
+        let mut remote = self.files.open(remote_path)?;
+        let mut local = fs::File::create(local_path)?;
+        io::copy(&mut remote, &mut local)?;
+        local.flush()
+    }
+
+    pub fn read_to_string(&self, remote_path: &str) -> io::Result<String> {
+        let mut remote = self.files.open(remote_path)?;
+        let mut content = String::new();
+        remote.read_to_string(&mut content)?;
+        Ok(content)
+    }
+
+    /// `.part` に書き終えてから名前を変え、書きかけの内容が読まれないようにする
+    pub fn write(&self, remote_path: &str, content: &str) -> io::Result<()> {
+        let part_path = part_path(remote_path);
+        let mut remote = self.files.open_write(&part_path, true)?;
+        remote.write_all(content.as_bytes())?;
+        remote.flush()?;
+        drop(remote);
+
+        self.files.rename(&part_path, remote_path, true)
+    }
+
+    pub fn remove(&self, remote_path: &str) -> io::Result<()> {
+        self.files.unlink(remote_path)
+    }
+
+    pub fn create_directory(&self, remote_path: &str) -> io::Result<()> {
+        self.files.mkdir(remote_path)
+    }
+
+    /// 同じサーバーの別の場所へ移す (既にあれば失敗する)
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.files.rename(from, to, false)
+    }
+
+    fn modified(&self, remote_path: &str) -> Option<SystemTime> {
+        let stat = self.files.stat(remote_path).ok()?;
+        stat.mtime
+            .map(|mtime| SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))
+    }
+}
+
+fn part_path(remote_path: &str) -> String {
+    match remote_path.rsplit_once('/') {
+        Some((directory, name)) => format!("{}/.{}{}", directory, name, PART_SUFFIX),
+        None => format!(".{}{}", remote_path, PART_SUFFIX),
+    }
+}
+
+/// 2 つの内容の先頭 length バイトが一致するか
+fn same_prefix(a: &mut impl Read, b: &mut impl Read, length: u64) -> io::Result<bool> {
+    let mut buffer_a = vec![0u8; 64 * 1024];
+    let mut buffer_b = vec![0u8; 64 * 1024];
+    let mut remaining = length;
+    while remaining > 0 {
+        let n = remaining.min(buffer_a.len() as u64) as usize;
+        if !fill(a, &mut buffer_a[..n])? || !fill(b, &mut buffer_b[..n])? {
+            return Ok(false);
+        }
+        if buffer_a[..n] != buffer_b[..n] {
+            return Ok(false);
+        }
+        remaining -= n as u64;
+    }
+
+    Ok(true)
+}
+
+/// buffer を埋めきる前に終わったら false
+fn fill(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn check_known_host(
+    session: &Session,
+    location: &SftpLocation,
+    options: &SftpOptions,
+) -> io::Result<()> {
+    let mut known_hosts = session.known_hosts().map_err(ssh_error)?;
+    known_hosts
+        .read_file(&options.known_hosts_path(), KnownHostFileKind::OpenSSH)
+        .map_err(ssh_error)?;
+
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| io::Error::other("no host key"))?;
+    match known_hosts.check_port(&location.host, location.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("host key mismatch for {}", location.host),
+        )),
+        CheckResult::NotFound | CheckResult::Failure => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not in known_hosts", location.host),
+        )),
+    }
+}
+
+fn authenticate(session: &Session, user: &str, options: &SftpOptions) -> io::Result<()> {
+    if let Some(key_path) = options.key_path() {
+        session
+            .userauth_pubkey_file(user, None, &key_path, None)
+            .map_err(ssh_error)?;
+        return Ok(());
+    }
+
+    if session.userauth_agent(user).is_ok() && session.authenticated() {
+        return Ok(());
+    }
+
+    for key_name in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+        let key_path = home_directory().join(".ssh").join(key_name);
+        if key_path.is_file()
+            && session
+                .userauth_pubkey_file(user, None, &key_path, None)
+                .is_ok()
+        {
+            return Ok(());
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("authentication failed for {}", user),
+    ))
+}
+
+/// `sftp://` のエクスポート先のファイル
+pub struct RemoteExport {
+    connection: SftpConnection,
+    pub path: String,
+}
+
+impl RemoteExport {
+    /// 場所がディレクトリであれば、その中の name を指す
+    pub fn connect(url: &str, name: &str, options: &SftpOptions) -> io::Result<Self> {
+        let location = SftpLocation::parse(url)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, url))?;
+        let connection = SftpConnection::connect(&location, options)?;
+
+        let path = if connection.is_directory(&location.path) {
+            append_path(&location.path, name)
+        } else {
+            location.path.clone()
+        };
+        Ok(RemoteExport { connection, path })
+    }
+
+    pub fn exists(&self) -> bool {
+        self.connection.is_file(&self.path)
+    }
+
+    pub fn upload(&self, local_path: &str) -> io::Result<()> {
+        self.connection.upload(local_path, &self.path)
+    }
+
+    /// 更新日時もリモートのファイルに合わせる
+    pub fn download(&self, local_path: &str) -> io::Result<()> {
+        self.connection.download(&self.path, local_path)?;
+        if let Some(modified) = self.connection.modified(&self.path) {
+            fs::OpenOptions::new()
+                .write(true)
+                .open(local_path)?
+                .set_modified(modified)?;
+        }
+        Ok(())
+    }
+
+    /// 同じサーバーの別の場所へ移す (既にあれば失敗する)
+    pub fn rename(&self, to: &str) -> io::Result<()> {
+        self.connection.rename(&self.path, to)
+    }
+
+    /// <エクスポート先のディレクトリ>/.dd-backup-trash/<日時>/<ファイル名> へ移す
+    pub fn move_to_trash(&self) -> io::Result<()> {
+        let directory = get_parent_path(&self.path);
+        let trash_root = append_path(&directory, TRASH_DIRECTORY);
+        if !self.connection.is_directory(&trash_root) {
+            self.connection.create_directory(&trash_root)?;
+        }
+
+        let trash = claim_trash_directory(&directory, |trash| {
+            if self.connection.is_directory(trash) {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+            self.connection.create_directory(trash)
+        })?;
+        let file_name = Path::new(&self.path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.rename(&append_path(&trash, &file_name))
+    }
+}
+
+/// SFTP サーバー上のディレクトリに、バージョンごとのファイルを並べる方式
+/// 圧縮やパリティは行わない
+pub struct SftpStore {
+    url: String,
+    location: Option<SftpLocation>,
+    /// 最初の操作で接続し、このバックアップ先を使い終わるまで使い回す
+    connection: OnceCell<SftpConnection>,
+    connect: Connector,
+}
+
+type Connector = Box<dyn Fn(&SftpLocation) -> io::Result<SftpConnection>>;
+
+impl SftpStore {
+    pub fn new(url: &str, options: &SftpOptions) -> Self {
+        let options = options.clone();
+        Self::with_connector(
+            url,
+            Box::new(move |location| SftpConnection::connect(location, &options)),
+        )
+    }
+
+    fn with_connector(url: &str, connect: Connector) -> Self {
+        SftpStore {
+            url: url.to_string(),
+            location: SftpLocation::parse(url),
+            connection: OnceCell::new(),
+            connect,
+        }
+    }
+
+    /// 接続に失敗した時は覚えておかず、次の操作でもう一度接続する
+    fn connection(&self) -> io::Result<&SftpConnection> {
+        if let Some(connection) = self.connection.get() {
+            return Ok(connection);
+        }
+
+        let location = self
+            .location
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, self.url.clone()))?;
+        let connection = (self.connect)(location)?;
+        Ok(self.connection.get_or_init(|| connection))
+    }
+
+    fn remote_path(&self, name: &str) -> String {
+        let directory = self
+            .location
+            .as_ref()
+            .map(|l| l.path.as_str())
+            .unwrap_or_default();
+        append_path(directory, name)
+    }
+}
+
+impl BackupStore for SftpStore {
+    fn is_available(&self) -> bool {
+        self.connection()
+            .is_ok_and(|connection| connection.is_directory(&self.remote_path("")))
+    }
+
+    fn list_versions(&self) -> Vec<String> {
+        match self.connection() {
+            Ok(connection) => connection
+                .list_file_names(&self.remote_path(""))
+                .into_iter()
+                .filter(|name| !name.starts_with('.'))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn has_version(&self, backup_filename: &str) -> bool {
+        self.connection()
+            .is_ok_and(|connection| connection.is_file(&self.remote_path(backup_filename)))
+    }
+
+    fn write_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()> {
+        self.connection()?
+            .upload(source_path, &self.remote_path(backup_filename))
+    }
+
+    fn read_version(&self, backup_filename: &str, dest_path: &str) -> io::Result<()> {
+        self.connection()?
+            .download(&self.remote_path(backup_filename), dest_path)
+    }
+
+    fn remove_version(&self, backup_filename: &str) -> io::Result<()> {
+        let connection = self.connection()?;
+        let remote_path = self.remote_path(backup_filename);
+        if connection.is_file(&remote_path) {
+            connection.remove(&remote_path)?;
+        }
+
+        Ok(())
+    }
+
+    fn load_index(&self, index_name: &str) -> io::Result<Option<String>> {
+        missing_as_none(
+            self.connection()?
+                .read_to_string(&self.remote_path(index_name)),
+        )
+    }
+
+    fn write_index(&self, index_name: &str, content: &str) -> io::Result<()> {
+        self.connection()?
+            .write(&self.remote_path(index_name), content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn parse_location() {
+        let location = SftpLocation::parse("sftp://alice@example.com:2222/backup").unwrap();
+        assert_eq!(location.user, "alice");
+        assert_eq!(location.host, "example.com");
+        assert_eq!(location.port, 2222);
+        assert_eq!(location.path, "/backup");
+
+        let location = SftpLocation::parse("sftp://alice@example.com").unwrap();
+        assert_eq!(location.port, DEFAULT_PORT);
+        assert_eq!(location.path, "/");
+
+        assert!(SftpLocation::parse("sftp://alice@example.com:port/backup").is_none());
+        assert!(SftpLocation::parse("/backup").is_none());
+    }
+
+    #[test]
+    fn part_path_is_hidden() {
+        assert_eq!(part_path("/backup/a.txt"), "/backup/.a.txt.part");
+        assert_eq!(part_path("a.txt"), ".a.txt.part");
+    }
+
+    #[test]
+    fn same_prefix_compares_content() {
+        let content = vec![7u8; 200 * 1024];
+        let mut part = content[..150 * 1024].to_vec();
+        assert!(same_prefix(
+            &mut part.as_slice(),
+            &mut content.as_slice(),
+            part.len() as u64
+        )
+        .unwrap());
+
+        part[100 * 1024] = 0;
+        assert!(!same_prefix(
+            &mut part.as_slice(),
+            &mut content.as_slice(),
+            part.len() as u64
+        )
+        .unwrap());
+
+        // 書きかけの方が短ければ一致しない
+        assert!(!same_prefix(&mut &content[..10], &mut content.as_slice(), 20).unwrap());
+    }
+
+    /// ローカルのディレクトリを SFTP サーバーの代わりにする
+    /// 接続先のパスは root の中のパスとして扱い、書き込みと名前の変更を operations に残す
+    struct LocalFiles {
+        root: PathBuf,
+        operations: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl LocalFiles {
+        fn local_path(&self, path: &str) -> PathBuf {
+            self.root.join(path.trim_start_matches('/'))
+        }
+    }
+
+    impl RemoteFiles for LocalFiles {
+        fn stat(&self, path: &str) -> io::Result<RemoteStat> {
+            let metadata = fs::metadata(self.local_path(path))?;
+            Ok(RemoteStat {
+                size: Some(metadata.len()),
+                is_dir: metadata.is_dir(),
+                is_file: metadata.is_file(),
+                mtime: metadata
+                    .modified()?
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs()),
+            })
+        }
+
+        fn read_directory(&self, path: &str) -> io::Result<Vec<(String, RemoteStat)>> {
+            fs::read_dir(self.local_path(path))?
+                .map(|entry| {
+                    let name = entry?.file_name().to_string_lossy().to_string();
+                    let stat = self.stat(&append_path(path, &name))?;
+                    Ok((name, stat))
+                })
+                .collect()
+        }
+
+        fn open(&self, path: &str) -> io::Result<Box<dyn RemoteFile>> {
+            Ok(Box::new(fs::File::open(self.local_path(path))?))
+        }
+
+        fn open_write(&self, path: &str, truncate: bool) -> io::Result<Box<dyn RemoteFile>> {
+            self.operations.borrow_mut().push(format!("write {}", path));
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(truncate)
+                .open(self.local_path(path))?;
+            Ok(Box::new(file))
+        }
+
+        fn rename(&self, from: &str, to: &str, overwrite: bool) -> io::Result<()> {
+            self.operations
+                .borrow_mut()
+                .push(format!("rename {} {}", from, to));
+            if !overwrite && self.local_path(to).exists() {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+            fs::rename(self.local_path(from), self.local_path(to))
+        }
+
+        fn unlink(&self, path: &str) -> io::Result<()> {
+            fs::remove_file(self.local_path(path))
+        }
+
+        fn mkdir(&self, path: &str) -> io::Result<()> {
+            fs::create_dir(self.local_path(path))
+        }
+    }
+
+    const LOCAL_URL: &str = "sftp://alice@example.com/backup";
+
+    fn local_connection(root: &Path, operations: &Rc<RefCell<Vec<String>>>) -> SftpConnection {
+        SftpConnection {
+            files: Box::new(LocalFiles {
+                root: root.to_path_buf(),
+                operations: operations.clone(),
+            }),
+        }
+    }
+
+    /// root/backup をバックアップ先にした SftpStore
+    fn local_store(root: &Path) -> (SftpStore, Rc<RefCell<Vec<String>>>) {
+        fs::create_dir(root.join("backup")).unwrap();
+        let operations = Rc::new(RefCell::new(Vec::new()));
+        let root = root.to_path_buf();
+        let recorded = operations.clone();
+        let store = SftpStore::with_connector(
+            LOCAL_URL,
+            Box::new(move |_| Ok(local_connection(&root, &recorded))),
+        );
+        (store, operations)
+    }
+
+    #[test]
+    fn store_round_trips_over_sftp_operations() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, _) = local_store(dir.path());
+        let source = dir.path().join("a.txt").to_string_lossy().to_string();
+        fs::write(&source, "hello").unwrap();
+
+        assert!(store.is_available());
+        store
+            .write_version(&source, "2024-01-01T00-00-00.000000000Z_a.txt")
+            .unwrap();
+        assert_eq!(
+            store.list_versions(),
+            ["2024-01-01T00-00-00.000000000Z_a.txt"]
+        );
+        assert!(store.has_version("2024-01-01T00-00-00.000000000Z_a.txt"));
+
+        let restored = dir.path().join("restored").to_string_lossy().to_string();
+        store
+            .read_version("2024-01-01T00-00-00.000000000Z_a.txt", &restored)
+            .unwrap();
+        assert_eq!(fs::read_to_string(&restored).unwrap(), "hello");
+
+        assert_eq!(store.load_index(".hash_index").unwrap(), None);
+        store.write_index(".hash_index", "index").unwrap();
+        assert_eq!(
+            store.load_index(".hash_index").unwrap().as_deref(),
+            Some("index")
+        );
+
+        store
+            .remove_version("2024-01-01T00-00-00.000000000Z_a.txt")
+            .unwrap();
+        assert!(store.list_versions().is_empty());
+    }
+
+    #[test]
+    fn index_is_written_to_a_temp_name_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, operations) = local_store(dir.path());
+        store.write_index(".hash_index", "old").unwrap();
+        operations.borrow_mut().clear();
+
+        store.write_index(".hash_index", "new").unwrap();
+        assert_eq!(
+            *operations.borrow(),
+            [
+                "write /backup/..hash_index.part",
+                "rename /backup/..hash_index.part /backup/.hash_index",
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("backup/.hash_index")).unwrap(),
+            "new"
+        );
+        assert!(!dir.path().join("backup/..hash_index.part").exists());
+    }
+
+    #[test]
+    fn failed_connection_is_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("backup")).unwrap();
+        let attempts = Rc::new(RefCell::new(0));
+        let root = dir.path().to_path_buf();
+        let counted = attempts.clone();
+        let store = SftpStore::with_connector(
+            LOCAL_URL,
+            Box::new(move |_| {
+                *counted.borrow_mut() += 1;
+                if *counted.borrow() == 1 {
+                    return Err(io::ErrorKind::ConnectionRefused.into());
+                }
+                Ok(local_connection(&root, &Rc::default()))
+            }),
+        );
+
+        assert!(!store.is_available());
+        assert!(store.is_available());
+        // つながった後は同じ接続を使い回す
+        assert!(store.is_available());
+        assert_eq!(*attempts.borrow(), 2);
+    }
+
+    #[test]
+    fn directory_settings_come_before_the_environment() {
+        let options = SftpOptions {
+            key: "/keys/backup".to_string(),
+            known_hosts: "/keys/known_hosts".to_string(),
+        };
+        assert_eq!(options.key_path(), Some(PathBuf::from("/keys/backup")));
+        assert_eq!(
+            options.known_hosts_path(),
+            PathBuf::from("/keys/known_hosts")
+        );
+    }
+
+    #[test]
+    fn export_is_moved_to_trash() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("export")).unwrap();
+        fs::write(dir.path().join("export/a.txt"), "old").unwrap();
+        let export = RemoteExport {
+            connection: local_connection(dir.path(), &Rc::default()),
+            path: "/export/a.txt".to_string(),
+        };
+
+        assert!(export.exists());
+        export.move_to_trash().unwrap();
+        assert!(!export.exists());
+        let trash = fs::read_dir(dir.path().join("export").join(TRASH_DIRECTORY))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        assert_eq!(fs::read_to_string(trash.join("a.txt")).unwrap(), "old");
+    }
+
+    /// 途中で止まったアップロードが残っていても、正しい内容を送る
+    fn check_upload_restarts_mismatched_part(connection: &SftpConnection, remote_path: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let local_path = dir.path().join("a.txt").to_string_lossy().to_string();
+        fs::write(&local_path, "hello world").unwrap();
+
+        // 別の内容のアップロードが途中で止まっていた
+        connection.write(&part_path(remote_path), "HELLO").unwrap();
+        connection.upload(&local_path, remote_path).unwrap();
+        assert_eq!(
+            connection.read_to_string(remote_path).unwrap(),
+            "hello world"
+        );
+
+        // 同じ内容の途中からは続きを送る
+        connection.write(&part_path(remote_path), "hello").unwrap();
+        connection.upload(&local_path, remote_path).unwrap();
+        assert_eq!(
+            connection.read_to_string(remote_path).unwrap(),
+            "hello world"
+        );
+        connection.remove(remote_path).unwrap();
+    }
+
+    #[test]
+    fn upload_restarts_mismatched_part() {
+        let dir = tempfile::tempdir().unwrap();
+        let connection = local_connection(dir.path(), &Rc::default());
+        check_upload_restarts_mismatched_part(&connection, "/a.txt");
+    }
+
+    /// DD_BACKUP_TEST_SFTP_URL に書き込めるディレクトリの `sftp://` URL を設定して実行する
+    #[test]
+    #[ignore = "needs an SFTP server"]
+    fn upload_restarts_mismatched_part_on_a_server() {
+        let Ok(url) = env::var("DD_BACKUP_TEST_SFTP_URL") else {
+            return;
+        };
+        let location = SftpLocation::parse(&url).unwrap();
+        let connection = SftpConnection::connect(&location, &SftpOptions::default()).unwrap();
+        let remote_path = append_path(&location.path, "dd-backup-upload-test.txt");
+        check_upload_restarts_mismatched_part(&connection, &remote_path);
+    }
+}
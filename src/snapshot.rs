@@ -0,0 +1,398 @@
+use crate::hash_index::hash_file;
+use crate::user_data::{
+    append_path, create_parent_directory, is_valid_directory, is_valid_file, DirectoryInfo,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+/// スナップショットを置くディレクトリ
+/// <backup_directory>/.dd-backup-snapshots/<スナップショットの ID>/<相対パス>
+pub const SNAPSHOT_DIRECTORY: &str = ".dd-backup-snapshots";
+
+/// スナップショットごとの、ファイルの一覧
+const SNAPSHOT_MANIFEST_FILENAME: &str = ".dd-backup-snapshot.yaml";
+
+/// 作成中のスナップショットに付ける拡張子 (作り終えてから外す)
+const PARTIAL_SUFFIX: &str = ".partial";
+
+const SNAPSHOT_ID_FORMAT: &str = "%Y-%m-%dT%H-%M-%SZ";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub name: String,
+    pub size: u64,
+    pub last_edited: DateTime<Utc>,
+    pub hash: String,
+    /// 前回のスナップショットからハードリンクしたもの
+    #[serde(default)]
+    pub linked: bool,
+}
+
+/// 1 つのスナップショット
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Snapshot {
+    pub id: String,
+    pub source_directory: String,
+    pub time: DateTime<Utc>,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+impl Snapshot {
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+
+    /// 前回から変わらずハードリンクで済んだファイルの数
+    pub fn linked_count(&self) -> usize {
+        self.entries.iter().filter(|e| e.linked).count()
+    }
+}
+
+fn snapshots_path(backup_directory: &str) -> String {
+    append_path(backup_directory, SNAPSHOT_DIRECTORY)
+}
+
+fn snapshot_path(backup_directory: &str, id: &str) -> String {
+    append_path(&snapshots_path(backup_directory), id)
+}
+
+fn load_snapshot(snapshot_path: &str) -> Option<Snapshot> {
+    let content =
+        fs::read_to_string(append_path(snapshot_path, SNAPSHOT_MANIFEST_FILENAME)).ok()?;
+    serde_yaml::from_str(&content).ok()
+}
+
+/// スナップショットが使えるか
+/// ハードリンクを作るためローカルのディレクトリに限り、暗号化したバックアップ先では平文を残さないよう使わない
+pub fn is_snapshot_available(dir: &DirectoryInfo) -> bool {
+    !dir.encrypted && is_valid_directory(&dir.backup_directory)
+}
+
+/// ソースディレクトリのスナップショットを新しい順に返す (作成中のものは含めない)
+pub fn list_snapshots(dir: &DirectoryInfo) -> Vec<Snapshot> {
+    let Ok(entries) = fs::read_dir(snapshots_path(&dir.backup_directory)) else {
+        return Vec::new();
+    };
+
+    let mut snapshots: Vec<Snapshot> = entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|id| !id.ends_with(PARTIAL_SUFFIX))
+        .filter_map(|id| load_snapshot(&snapshot_path(&dir.backup_directory, &id)))
+        .filter(|snapshot| snapshot.source_directory.to_lowercase() == dir.path.to_lowercase())
+        .collect();
+    snapshots.sort_by_key(|snapshot| Reverse(snapshot.time));
+    snapshots
+}
+
+/// 追跡しているすべてのファイルのスナップショットを作る
+/// 前回のスナップショットから内容が変わっていないファイルはハードリンクにする
+pub fn take_snapshot(dir: &DirectoryInfo) -> io::Result<Snapshot> {
+    if !is_snapshot_available(dir) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "snapshots need an unencrypted local backup directory",
+        ));
+    }
+
+    let time = Utc::now();
+    let id = time.format(SNAPSHOT_ID_FORMAT).to_string();
+    let destination = snapshot_path(&dir.backup_directory, &id);
+    if is_valid_directory(&destination) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, id));
+    }
+
+    let previous = list_snapshots(dir).into_iter().next();
+    let previous_entries: BTreeMap<&str, &SnapshotEntry> = previous
+        .iter()
+        .flat_map(|snapshot| snapshot.entries.iter())
+        .map(|entry| (entry.name.as_str(), entry))
+        .collect();
+
+    let partial = format!("{}{}", destination, PARTIAL_SUFFIX);
+    if is_valid_directory(&partial) {
+        fs::remove_dir_all(&partial)?;
+    }
+    fs::create_dir_all(&partial)?;
+
+    let mut snapshot = Snapshot {
+        id: id.clone(),
+        source_directory: dir.path.clone(),
+        time,
+        entries: Vec::new(),
+    };
+    for file in &dir.files {
+        let source_path = append_path(&dir.path, &file.name);
+        let Ok(metadata) = fs::metadata(&source_path) else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let size = metadata.len();
+        let last_edited = metadata
+            .modified()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or(time);
+        let target_path = append_path(&partial, &file.name);
+        create_parent_directory(&target_path)?;
+
+        // 大きさと更新日時は、書き換えた後に元に戻されることがあるため、内容のハッシュで比べる
+        let unchanged = previous.as_ref().and_then(|previous| {
+            let previous_entry = previous_entries
+                .get(file.name.as_str())
+                .filter(|e| e.size == size)?;
+            let hash = hash_file(&source_path).filter(|hash| *hash == previous_entry.hash)?;
+            Some((
+                append_path(
+                    &snapshot_path(&dir.backup_directory, &previous.id),
+                    &previous_entry.name,
+                ),
+                hash,
+            ))
+        });
+
+        // ハードリンクできないファイルシステムでは、変わっていなくてもコピーする
+        let entry = match unchanged {
+            Some((previous_path, hash)) if fs::hard_link(&previous_path, &target_path).is_ok() => {
+                SnapshotEntry {
+                    name: file.name.clone(),
+                    size,
+                    last_edited,
+                    hash,
+                    linked: true,
+                }
+            }
+            _ => {
+                fs::copy(&source_path, &target_path)?;
+                let hash = hash_file(&target_path).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("cannot hash {}", target_path),
+                    )
+                })?;
+                SnapshotEntry {
+                    name: file.name.clone(),
+                    size: fs::metadata(&target_path)?.len(),
+                    last_edited,
+                    hash,
+                    linked: false,
+                }
+            }
+        };
+        snapshot.entries.push(entry);
+    }
+
+    snapshot.entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let yaml = serde_yaml::to_string(&snapshot).map_err(io::Error::other)?;
+    fs::write(append_path(&partial, SNAPSHOT_MANIFEST_FILENAME), yaml)?;
+    fs::rename(&partial, &destination)?;
+    Ok(snapshot)
+}
+
+/// スナップショットをまとめて削除する
+/// ハードリンクされた内容は、他のスナップショットから参照されている間は残る
+pub fn delete_snapshot(dir: &DirectoryInfo, id: &str) -> io::Result<()> {
+    let path = snapshot_path(&dir.backup_directory, id);
+    if id.is_empty() || !is_valid_file(&append_path(&path, SNAPSHOT_MANIFEST_FILENAME)) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, id.to_string()));
+    }
+
+    fs::remove_dir_all(path)
+}
+
+/// スナップショット内のファイルのパス (ファイルマネージャーで開く用)
+pub fn snapshot_directory(dir: &DirectoryInfo, id: &str) -> String {
+    snapshot_path(&dir.backup_directory, id)
+}
+
+/// 2 つのスナップショットの違い
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+    pub unchanged: usize,
+}
+
+/// older から newer への変化を調べる
+pub fn compare_snapshots(older: &Snapshot, newer: &Snapshot) -> SnapshotDiff {
+    let older_entries: BTreeMap<&str, &SnapshotEntry> =
+        older.entries.iter().map(|e| (e.name.as_str(), e)).collect();
+    let newer_entries: BTreeMap<&str, &SnapshotEntry> =
+        newer.entries.iter().map(|e| (e.name.as_str(), e)).collect();
+
+    let mut diff = SnapshotDiff::default();
+    for (name, entry) in &newer_entries {
+        match older_entries.get(name) {
+            None => diff.added.push(name.to_string()),
+            Some(old) if old.hash != entry.hash || old.size != entry.size => {
+                diff.modified.push(name.to_string())
+            }
+            Some(_) => diff.unchanged += 1,
+        }
+    }
+    diff.removed = older_entries
+        .keys()
+        .filter(|name| !newer_entries.contains_key(*name))
+        .map(|name| name.to_string())
+        .collect();
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_data::FileInfo;
+    use std::time::SystemTime;
+
+    /// a.txt と docs/b.txt を追跡しているディレクトリ
+    fn tracked_directory() -> (tempfile::TempDir, DirectoryInfo) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let source = append_path(&root, "source");
+        let backup = append_path(&root, "backup");
+        fs::create_dir_all(append_path(&source, "docs")).unwrap();
+        fs::create_dir(&backup).unwrap();
+        fs::write(append_path(&source, "a.txt"), "aaaa").unwrap();
+        fs::write(append_path(&source, "docs/b.txt"), "bbbb").unwrap();
+
+        let mut directory = DirectoryInfo::new(source, backup);
+        for name in ["a.txt", "docs/b.txt"] {
+            directory.add_file(FileInfo::new(name.to_string(), Utc::now(), String::new()));
+        }
+        (dir, directory)
+    }
+
+    fn content(directory: &DirectoryInfo, snapshot: &Snapshot, name: &str) -> String {
+        fs::read_to_string(append_path(
+            &snapshot_directory(directory, &snapshot.id),
+            name,
+        ))
+        .unwrap()
+    }
+
+    /// 1 秒ごとの ID が重ならないよう、前のスナップショットの ID を変える
+    fn age(directory: &DirectoryInfo, snapshot: &mut Snapshot) {
+        let old_id = format!("{}-old", snapshot.id);
+        fs::rename(
+            snapshot_directory(directory, &snapshot.id),
+            snapshot_directory(directory, &old_id),
+        )
+        .unwrap();
+        snapshot.id = old_id;
+        snapshot.time -= chrono::Duration::seconds(1);
+        let yaml = serde_yaml::to_string(&snapshot).unwrap();
+        fs::write(
+            append_path(
+                &snapshot_directory(directory, &snapshot.id),
+                SNAPSHOT_MANIFEST_FILENAME,
+            ),
+            yaml,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn unchanged_files_are_linked() {
+        let (_dir, directory) = tracked_directory();
+        let mut first = take_snapshot(&directory).unwrap();
+        assert_eq!(first.entries.len(), 2);
+        assert_eq!(first.linked_count(), 0);
+        age(&directory, &mut first);
+
+        fs::write(append_path(&directory.path, "a.txt"), "AAAAA").unwrap();
+        let second = take_snapshot(&directory).unwrap();
+        assert_eq!(second.linked_count(), 1);
+        assert!(second
+            .entries
+            .iter()
+            .any(|e| e.name == "docs/b.txt" && e.linked));
+        assert_eq!(content(&directory, &second, "a.txt"), "AAAAA");
+        assert_eq!(content(&directory, &first, "a.txt"), "aaaa");
+        assert_eq!(list_snapshots(&directory).len(), 2);
+    }
+
+    #[test]
+    fn same_size_and_time_with_new_content_is_copied() {
+        let (_dir, directory) = tracked_directory();
+        let mut first = take_snapshot(&directory).unwrap();
+        age(&directory, &mut first);
+
+        // 大きさと更新日時を元のままにして内容だけ変える
+        let path = append_path(&directory.path, "a.txt");
+        let modified: SystemTime = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, "zzzz").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let second = take_snapshot(&directory).unwrap();
+        let entry = second.entries.iter().find(|e| e.name == "a.txt").unwrap();
+        assert!(!entry.linked);
+        assert_eq!(content(&directory, &second, "a.txt"), "zzzz");
+        assert_eq!(content(&directory, &first, "a.txt"), "aaaa");
+    }
+
+    #[test]
+    fn delete_removes_only_snapshots() {
+        let (_dir, directory) = tracked_directory();
+        let snapshot = take_snapshot(&directory).unwrap();
+
+        assert_eq!(
+            delete_snapshot(&directory, "").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            delete_snapshot(&directory, "missing").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        delete_snapshot(&directory, &snapshot.id).unwrap();
+        assert!(list_snapshots(&directory).is_empty());
+    }
+
+    #[test]
+    fn encrypted_directory_has_no_snapshots() {
+        let (_dir, mut directory) = tracked_directory();
+        directory.encrypted = true;
+        assert!(!is_snapshot_available(&directory));
+        assert_eq!(
+            take_snapshot(&directory).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn compare_finds_changes() {
+        let entry = |name: &str, hash: &str| SnapshotEntry {
+            name: name.to_string(),
+            size: 1,
+            last_edited: DateTime::default(),
+            hash: hash.to_string(),
+            linked: false,
+        };
+        let older = Snapshot {
+            entries: vec![entry("a", "1"), entry("b", "2"), entry("c", "3")],
+            ..Snapshot::default()
+        };
+        let newer = Snapshot {
+            entries: vec![entry("a", "1"), entry("b", "9"), entry("d", "4")],
+            ..Snapshot::default()
+        };
+
+        let diff = compare_snapshots(&older, &newer);
+        assert_eq!(diff.added, ["d"]);
+        assert_eq!(diff.removed, ["c"]);
+        assert_eq!(diff.modified, ["b"]);
+        assert_eq!(diff.unchanged, 1);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::uri::uri_decode;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use tiny_http::{Header, Response, Server};
+
+/// テスト用のサーバーが受けたリクエスト
+pub struct TestRequest {
+    pub method: String,
+    /// クエリを含まないパス (エンコードされたまま)
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestRequest {
+    /// ヘッダーの値 (名前は大文字小文字を区別しない)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// テスト用のサーバーが返す応答
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        TestResponse {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// 受けたリクエストを handler に渡して応答する、同じプロセス内の HTTP サーバー
+/// drop すると止まる
+pub struct TestServer {
+    pub base_url: String,
+    server: Arc<Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start(mut handler: impl FnMut(TestRequest) -> TestResponse + Send + 'static) -> Self {
+        let server = Arc::new(Server::http("127.0.0.1:0").expect("test server starts"));
+        let port = server.server_addr().to_ip().expect("bound to TCP").port();
+
+        let incoming = server.clone();
+        let thread = thread::spawn(move || {
+            for mut request in incoming.incoming_requests() {
+                let (path, query) = match request.url().split_once('?') {
+                    Some((path, query)) => (path.to_string(), parse_query(query)),
+                    None => (request.url().to_string(), Vec::new()),
+                };
+                let mut body = Vec::new();
+                request.as_reader().read_to_end(&mut body).ok();
+
+                let response = handler(TestRequest {
+                    method: request.method().to_string(),
+                    path,
+                    query,
+                    headers: request
+                        .headers()
+                        .iter()
+                        .map(|h| (h.field.to_string(), h.value.to_string()))
+                        .collect(),
+                    body,
+                });
+
+                let mut reply =
+                    Response::from_data(response.body).with_status_code(response.status);
+                for (name, value) in response.headers {
+                    if let Ok(header) = Header::from_bytes(name.as_bytes(), value.as_bytes()) {
+                        reply.add_header(header);
+                    }
+                }
+                request.respond(reply).ok();
+            }
+        });
+
+        TestServer {
+            base_url: format!("http://127.0.0.1:{}", port),
+            server,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (name.to_string(), uri_decode(value))
+        })
+        .collect()
+}
@@ -0,0 +1,212 @@
+use crate::atomic_file::is_temp_file;
+use crate::ignore_rules::IgnoreRules;
+use crate::user_data::append_path;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::fs;
+use std::path::PathBuf;
+
+/// ディレクトリごと追跡する場合の対象の条件
+/// パターンは空白で区切って並べる (例: `**/*.docx` と `~$*`)
+/// `/` を含まないパターンはファイル名だけ、含むパターンは相対パス全体と照合する
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackRules {
+    pub include: String,
+    pub exclude: String,
+}
+
+struct PatternSet {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl PatternSet {
+    /// 書きかけのパターンなど、glob として読めないものは無視する
+    fn new(patterns: &str) -> Self {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for pattern in patterns.split_whitespace() {
+            let Ok(glob) = Glob::new(pattern) else {
+                continue;
+            };
+
+            if pattern.contains('/') {
+                paths.add(glob);
+            } else {
+                names.add(glob);
+            }
+        }
+
+        PatternSet {
+            names: names.build().unwrap_or_else(|_| GlobSet::empty()),
+            paths: paths.build().unwrap_or_else(|_| GlobSet::empty()),
+        }
+    }
+
+    fn is_match(&self, relative_path: &str) -> bool {
+        let name = relative_path.rsplit('/').next().unwrap_or(relative_path);
+        self.names.is_match(name) || self.paths.is_match(relative_path)
+    }
+}
+
+/// 比べるための絶対パス (シンボリックリンクや `..` を解決する)
+fn canonical_path(path: &str) -> Option<PathBuf> {
+    if path.is_empty() {
+        return None;
+    }
+
+    fs::canonicalize(path).ok()
+}
+
+impl TrackRules {
+    /// 含めるパターンが無ければ、ディレクトリの追跡はしない
+    pub fn is_enabled(&self) -> bool {
+        !self.include.trim().is_empty()
+    }
+
+    /// glob として読めないパターン
+    pub fn invalid_patterns(&self) -> Vec<String> {
+        self.include
+            .split_whitespace()
+            .chain(self.exclude.split_whitespace())
+            .filter(|pattern| Glob::new(pattern).is_err())
+            .map(|pattern| pattern.to_string())
+            .collect()
+    }
+
+    /// 条件に合うファイルの、directory からの相対パス (区切りは `/`)
+    /// skip_directories に含まれるディレクトリ (バックアップ先など) と、`.ddbackupignore` で除外されたものは探さない
+    /// シンボリックリンクのディレクトリは、外や同じ場所を何度もたどらないよう中を探さない
+    pub fn scan(
+        &self,
+        directory: &str,
+        skip_directories: &[&str],
+        ignore_rules: &IgnoreRules,
+    ) -> Vec<String> {
+        if !self.is_enabled() {
+            return Vec::new();
+        }
+
+        let include = PatternSet::new(&self.include);
+        let exclude = PatternSet::new(&self.exclude);
+        let skip_directories: Vec<PathBuf> = skip_directories
+            .iter()
+            .filter_map(|path| canonical_path(path))
+            .collect();
+
+        let mut matched = Vec::new();
+        let mut pending = vec![String::new()];
+        while let Some(relative_directory) = pending.pop() {
+            let Ok(entries) = fs::read_dir(append_path(directory, &relative_directory)) else {
+                continue;
+            };
+
+            for entry in entries.flatten() {
+                let Ok(file_type) = entry.file_type() else {
+                    continue;
+                };
+                let name = entry.file_name().to_string_lossy().to_string();
+                let relative_path = append_path(&relative_directory, &name);
+                let path = entry.path();
+                let is_dir = if file_type.is_symlink() {
+                    path.is_dir()
+                } else {
+                    file_type.is_dir()
+                };
+                // 書き込み中の一時ファイルは追跡しない
+                if is_temp_file(&name) || ignore_rules.is_ignored(&relative_path, is_dir) {
+                    continue;
+                }
+
+                if is_dir {
+                    let skipped = file_type.is_symlink()
+                        || fs::canonicalize(&path).is_ok_and(|p| skip_directories.contains(&p));
+                    if !skipped {
+                        pending.push(relative_path);
+                    }
+                } else if include.is_match(&relative_path) && !exclude.is_match(&relative_path) {
+                    matched.push(relative_path);
+                }
+            }
+        }
+
+        matched.sort();
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn rules(include: &str, exclude: &str) -> TrackRules {
+        TrackRules {
+            include: include.to_string(),
+            exclude: exclude.to_string(),
+        }
+    }
+
+    /// 相対パスのファイルを作る
+    fn create(root: &str, relative_paths: &[&str]) {
+        for relative_path in relative_paths {
+            let path = append_path(root, relative_path);
+            fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+    }
+
+    #[test]
+    fn names_and_paths_are_matched() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        create(
+            &root,
+            &[
+                "a.docx",
+                "~$a.docx",
+                "sub/b.docx",
+                "sub/c.txt",
+                "notes/d.txt",
+            ],
+        );
+
+        let found = rules("*.docx notes/*.txt", "~$*").scan(&root, &[], &IgnoreRules::default());
+        assert_eq!(found, ["a.docx", "notes/d.txt", "sub/b.docx"]);
+        assert!(rules("", "")
+            .scan(&root, &[], &IgnoreRules::default())
+            .is_empty());
+    }
+
+    #[test]
+    fn skip_directories_are_compared_by_canonical_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        create(&root, &["a.txt", "backup/b.txt", "replica/c.txt"]);
+
+        // 末尾の区切りや `..` を含む書き方でも同じディレクトリとして扱う
+        let backup = format!("{}/backup/", root);
+        let replica = format!("{}/backup/../replica", root);
+        let found =
+            rules("**/*", "").scan(&root, &[&backup, &replica, ""], &IgnoreRules::default());
+        assert_eq!(found, ["a.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories_are_not_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        create(&root, &["real/a.txt"]);
+        std::os::unix::fs::symlink(append_path(&root, "real"), append_path(&root, "link")).unwrap();
+        std::os::unix::fs::symlink(&root, append_path(&root, "real/loop")).unwrap();
+
+        let found = rules("**/*", "").scan(&root, &[], &IgnoreRules::default());
+        assert_eq!(found, ["real/a.txt"]);
+    }
+
+    #[test]
+    fn invalid_patterns_are_reported() {
+        assert!(rules("**/*.docx", "~$*").invalid_patterns().is_empty());
+        assert_eq!(rules("*.docx a[", "b{").invalid_patterns(), ["a[", "b{"]);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::user_data::append_path;
+use chrono::Utc;
+use std::fs;
+use std::io;
+
+/// ミラー先で削除したファイルや、置き換えたエクスポート先の移動先
+/// <ディレクトリ>/.dd-backup-trash/<日時>/<相対パス>
+pub const TRASH_DIRECTORY: &str = ".dd-backup-trash";
+
+const TRASH_ID_FORMAT: &str = "%Y-%m-%dT%H-%M-%SZ";
+
+/// directory の中に、まだ使われていないごみ箱を create で作る
+/// 同じ秒に作ったごみ箱は `<日時>.<連番>` で区別する
+/// create は既にある場合に AlreadyExists を返すこと (作成で場所を確保するため、同時に作っても重ならない)
+pub fn claim_trash_directory(
+    directory: &str,
+    create: impl Fn(&str) -> io::Result<()>,
+) -> io::Result<String> {
+    let trash_root = append_path(directory, TRASH_DIRECTORY);
+    let id = Utc::now().format(TRASH_ID_FORMAT).to_string();
+    let mut trash = append_path(&trash_root, &id);
+    let mut sequence = 1;
+    loop {
+        match create(&trash) {
+            Ok(_) => return Ok(trash),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+
+        trash = append_path(&trash_root, &format!("{}.{}", id, sequence));
+        sequence += 1;
+    }
+}
+
+/// ローカルの directory の中に、まだ使われていないごみ箱を作る
+pub fn create_trash_directory(directory: &str) -> io::Result<String> {
+    fs::create_dir_all(append_path(directory, TRASH_DIRECTORY))?;
+    claim_trash_directory(directory, |trash| fs::create_dir(trash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trash_directories_are_not_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().to_string_lossy().to_string();
+
+        let first = create_trash_directory(&directory).unwrap();
+        let second = create_trash_directory(&directory).unwrap();
+        assert_ne!(first, second);
+        assert!(fs::metadata(&first).unwrap().is_dir());
+        assert!(fs::metadata(&second).unwrap().is_dir());
+    }
+
+    #[test]
+    fn later_trash_directories_get_a_sequence() {
+        let taken = std::cell::RefCell::new(Vec::new());
+        let create = |trash: &str| {
+            if taken.borrow().len() < 2 {
+                taken.borrow_mut().push(trash.to_string());
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+            Ok(())
+        };
+
+        let trash = claim_trash_directory("mirror", create).unwrap();
+        let taken = taken.into_inner();
+        assert_eq!(trash, format!("{}.2", taken[0]));
+        assert_eq!(taken[1], format!("{}.1", taken[0]));
+        assert!(taken[0].starts_with(&append_path("mirror", TRASH_DIRECTORY)));
+    }
+}
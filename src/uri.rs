@@ -0,0 +1,61 @@
+/// RFC 3986 の非予約文字以外をパーセントエンコードする
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+/// パーセントエンコードを戻す。`%` の後が 16 進数でなければそのまま残す
+pub fn uri_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex_byte = value
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex_byte {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_reserved_characters() {
+        assert_eq!(uri_encode("a b/c~d.txt", false), "a%20b/c~d.txt");
+        assert_eq!(uri_encode("a b/c", true), "a%20b%2Fc");
+        assert_eq!(uri_encode("日", false), "%E6%97%A5");
+    }
+
+    #[test]
+    fn decode_round_trip() {
+        for value in ["a b/c", "100%", "日本語.txt", "%zz"] {
+            assert_eq!(uri_decode(&uri_encode(value, true)), value);
+        }
+        assert_eq!(uri_decode("100%"), "100%");
+        assert_eq!(uri_decode("%zz"), "%zz");
+    }
+}
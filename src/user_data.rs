@@ -1,22 +1,169 @@
-use chrono::{DateTime, Local};
+use crate::atomic_file::{copy_atomically, replace_atomically, PrivateTempDir};
+use crate::backup_scan::{encode_name, parse_backup_filename, ScannedFile};
+use crate::backup_store::{
+    detect_backup_mode, open_backup_store, BackupMode, BackupStore, RemoteOptions,
+};
+use crate::compression::Compression;
+use crate::encryption::{has_key_file, is_encrypted_target, EncryptedStore, EncryptionKey};
+use crate::export_backup::{
+    preserve_previous_export, preserve_previous_remote_export, ExportBackup,
+};
+use crate::file_metadata::{copy_metadata, FileMetadata, MetadataIndex, MetadataOptions};
+use crate::hash_index::{hash_file, hash_file_cached, HashIndex, HashIndexEntry};
+use crate::ignore_rules::IgnoreRules;
+use crate::manifest::Manifest;
+use crate::parity::Parity;
+use crate::s3::{is_s3_url, S3Location};
+use crate::scrub::{DEFAULT_SCRUB_INTERVAL_DAYS, DEFAULT_SCRUB_SPEED_LIMIT};
+use crate::sftp::{is_sftp_url, RemoteExport, SftpLocation, SftpOptions};
+use crate::tracking::TrackRules;
+use crate::webdav::{is_webdav_url, WebDavLocation};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::fs;
+use std::io;
 use std::path::Path;
 
 #[derive(Debug, Clone)]
 pub struct FileInfo {
     pub name: String,
-    pub last_edited: String,
+    pub last_edited: DateTime<Utc>,
     pub export_path: String,
     pub synced: bool,
+    pub source_exists: bool,
     pub remove_allowed: bool,
     pub export_valid: bool,
+    /// 前回エクスポートした時の、エクスポート先の内容
+    pub export_record: Option<ExportRecord>,
+    pub export_status: ExportStatus,
+    /// 追跡を始めた後で `.ddbackupignore` の対象になった (保存しない)
+    pub ignored: bool,
+}
+
+/// エクスポート先の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportStatus {
+    /// エクスポート先が無いか、無効
+    #[default]
+    NoExport,
+    /// リモートのエクスポート先は確認しない
+    Unchecked,
+    UpToDate,
+    /// 手元のファイルの方が新しい
+    Stale,
+    /// エクスポート先にファイルが無い
+    Missing,
+    /// エクスポート先が前回のエクスポートの後で他の人に変更された
+    ModifiedExternally,
+    /// 手元のファイルが無いか読めないため、エクスポートできない
+    SourceMissing,
+}
+
+impl ExportStatus {
+    /// 一括エクスポートで上書きしてよいか
+    pub fn needs_export(&self) -> bool {
+        matches!(self, ExportStatus::Stale | ExportStatus::Missing)
+    }
+}
+
+impl std::fmt::Display for ExportStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ExportStatus::NoExport => "",
+                ExportStatus::Unchecked => "Unchecked",
+                ExportStatus::UpToDate => "Up to date",
+                ExportStatus::Stale => "Stale",
+                ExportStatus::Missing => "Missing",
+                ExportStatus::ModifiedExternally => "Modified externally",
+                ExportStatus::SourceMissing => "Source missing",
+            }
+        )
+    }
+}
+
+/// エクスポートした直後のエクスポート先の状態
+/// 次の同期の前に比べて、エクスポート先が書き換えられていないかを調べる
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExportRecord {
+    pub path: String,
+    pub hash: String,
+    pub modified: DateTime<Utc>,
+    /// 記録していなかった頃のエクスポートは None
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+/// 別スレッドで調べた、1 つのファイルの同期の状態
+#[derive(Debug, Clone)]
+pub struct SyncStatus {
+    pub directory: String,
+    pub name: String,
+    /// 調べた時の更新日時 (その後に変更されていれば反映しない)
+    pub last_edited: DateTime<Utc>,
+    pub synced: bool,
+    pub export_status: ExportStatus,
+}
+
+/// 追跡しているファイルがバックアップ、エクスポート先と一致しているかを調べる
+/// ファイルを読むことがあるため、UI のスレッドでは呼ばない
+pub fn check_sync_status(directories: &[DirectoryInfo]) -> Vec<SyncStatus> {
+    let mut statuses = Vec::new();
+    for dir in directories {
+        let store = dir.backup_store();
+        let index = HashIndex::load(store.as_ref());
+        for file in &dir.files {
+            statuses.push(SyncStatus {
+                directory: dir.path.clone(),
+                name: file.name.clone(),
+                last_edited: file.last_edited,
+                synced: file.check_synced(&dir.path, store.as_ref(), &index),
+                export_status: file.check_export_status(&dir.path),
+            });
+        }
+    }
+
+    statuses
 }
 
 #[derive(Debug, Clone)]
 pub struct DirectoryInfo {
     pub path: String,
     pub backup_directory: String,
+    /// バックアップ先の内容を複製する場所 (空なら複製しない)
+    pub replica_directory: String,
+    /// 追跡しているファイルをそのまま保つ場所 (空ならミラーしない)
+    pub mirror_directory: String,
+    /// ミラー先にだけあるファイルをごみ箱へ移す
+    pub mirror_deletions: bool,
+    /// エクスポート先を上書きする前に、そこにあったファイルを残す場所
+    pub export_backup: ExportBackup,
+    /// バックアップとエクスポートで引き継ぐメタデータ
+    pub metadata_options: MetadataOptions,
+    pub backup_mode: BackupMode,
+    pub compression: Compression,
+    pub parity: Parity,
+    pub encrypted: bool,
+    /// 整理の時にファイルごとに残すバージョンの数 (0 なら整理しない)
+    pub keep_versions: usize,
+    /// 定期スクラブの間隔 (日、0 なら定期実行しない)
+    pub scrub_interval_days: u32,
+    /// スクラブで読み込む速さの上限 (MiB/秒、0 なら制限しない)
+    pub scrub_speed_limit: u64,
+    /// バックアップ先や複製先、エクスポート先がリモートの時の接続の設定
+    pub remote_options: RemoteOptions,
+    /// パスフレーズから導出した鍵 (保存しない)
+    pub encryption_key: Option<EncryptionKey>,
+    /// バックアップ先に鍵ファイルがあるか (描画のたびにバックアップ先を読まないよう、更新の時に確かめておく)
+    pub key_file_exists: bool,
+    /// ディレクトリごと追跡する条件 (含めるパターンが空なら追跡しない)
+    pub track_rules: TrackRules,
     pub files: Vec<FileInfo>,
+    /// 前回の更新で読んだ `.ddbackupignore` の条件 (保存しない)
+    pub ignore_rules: IgnoreRules,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -24,7 +171,7 @@ pub struct UserData {
     pub directories: Vec<DirectoryInfo>,
 }
 
-pub fn is_valid_directory(directory_path: &String) -> bool {
+pub fn is_valid_directory(directory_path: &str) -> bool {
     if directory_path.len() <= 1 {
         return false;
     }
@@ -33,34 +180,88 @@ pub fn is_valid_directory(directory_path: &String) -> bool {
     path.is_dir() && fs::metadata(path).is_ok()
 }
 
-pub fn is_valid_file(file_path: &String) -> bool {
+/// バックアップ先として使えるか
+/// `sftp://` や `s3://`、`webdav://` の場所は接続せずに、URL として正しいかだけを確認する
+pub fn is_valid_backup_directory(directory_path: &str) -> bool {
+    if is_sftp_url(directory_path) {
+        return SftpLocation::parse(directory_path).is_some();
+    }
+
+    if is_s3_url(directory_path) {
+        return S3Location::parse(directory_path).is_some();
+    }
+
+    if is_webdav_url(directory_path) {
+        return WebDavLocation::parse(directory_path).is_some();
+    }
+
+    is_valid_directory(directory_path)
+}
+
+pub fn is_valid_file(file_path: &str) -> bool {
     let path = Path::new(file_path);
     path.is_file() && fs::metadata(path).is_ok()
 }
 
-// pub fn get_backup_filename(target_file: &String) -> String {
-//     let metadata = Path::new(target_file).metadata().ok();
-//     let last_edited: DateTime<Local> = metadata
-//         .and_then(|m| m.modified().ok().map(DateTime::from))
-//         .unwrap_or_else(Local::now);
-//     let date = last_edited.format("%Y-%m-%d-%H-%M-%S").to_string();
-//     format!("{}_{}", date, target_file)
-// }
+pub const VERSION_ID_FORMAT: &str = "%Y-%m-%dT%H-%M-%S%.9fZ";
+
+/// バックアップファイル名に使うバージョン識別子 (UTC, ナノ秒まで)
+pub fn version_id(last_edited: &DateTime<Utc>) -> String {
+    last_edited.format(VERSION_ID_FORMAT).to_string()
+}
 
-pub fn append_path(base: &String, path: &String) -> String {
-    if base.len() == 0 {
-        return path.clone();
+pub fn append_path(base: &str, path: &str) -> String {
+    if base.is_empty() {
+        return path.to_string();
     }
 
-    if path.len() == 0 {
-        return base.clone();
+    if path.is_empty() {
+        return base.to_string();
     }
 
-    let base = base.trim_end_matches(|c| c == '/' || c == '\\');
-    let path = path.trim_start_matches(|c| c == '/' || c == '\\');
+    let base = base.trim_end_matches(['/', '\\']);
+    let path = path.trim_start_matches(['/', '\\']);
     format!("{}/{}", base, path)
 }
 
+/// directory の中にあるファイルの、directory からの相対パス (区切りは `/`)
+pub fn relative_path(directory: &str, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(directory).ok()?;
+    let components: Vec<String> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    (!components.is_empty()).then(|| components.join("/"))
+}
+
+/// 相対パスで追跡しているファイルのために、親ディレクトリを作る
+pub fn create_parent_directory(path: &str) -> io::Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+}
+
+/// `report.docx` -> `report (conflict 2026-01-02 03-04-05).docx`
+fn conflict_copy_path(path: &str) -> String {
+    let path = Path::new(path);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let name = format!(
+        "{} (conflict {}){}",
+        stem,
+        Local::now().format("%Y-%m-%d %H-%M-%S"),
+        extension
+    );
+    path.with_file_name(name).to_string_lossy().to_string()
+}
+
 pub fn get_parent_path(path: &String) -> String {
     let path = Path::new(path);
     let parent = path.parent();
@@ -75,11 +276,18 @@ enum ExportPathState {
     Invalid,
     AsDirectoryPath,
     AsFilePath,
+    /// `sftp://` の場所 (ディレクトリかどうかはコピーする時に確認する)
+    AsRemotePath,
 }
 
 impl ExportPathState {
     pub fn new(path: &String) -> Self {
-        if is_valid_directory(path) {
+        if is_sftp_url(path) {
+            match SftpLocation::parse(path) {
+                Some(_) => ExportPathState::AsRemotePath,
+                None => ExportPathState::Invalid,
+            }
+        } else if is_valid_directory(path) {
             ExportPathState::AsDirectoryPath
         } else if is_valid_directory(&get_parent_path(path)) {
             ExportPathState::AsFilePath
@@ -89,10 +297,7 @@ impl ExportPathState {
     }
 
     pub fn is_valid(&self) -> bool {
-        match self {
-            ExportPathState::Invalid => false,
-            _ => true,
-        }
+        !matches!(self, ExportPathState::Invalid)
     }
 }
 
@@ -100,22 +305,30 @@ impl FileInfo {
     pub fn empty() -> Self {
         FileInfo {
             name: "".to_string(),
-            last_edited: "".to_string(),
+            last_edited: DateTime::default(),
             export_path: "".to_string(),
             synced: false,
+            source_exists: false,
             remove_allowed: false,
             export_valid: false,
+            export_record: None,
+            export_status: ExportStatus::NoExport,
+            ignored: false,
         }
     }
 
-    pub fn new(name: String, modified: String, export_path: String) -> Self {
+    pub fn new(name: String, modified: DateTime<Utc>, export_path: String) -> Self {
         FileInfo {
             name,
             last_edited: modified,
             export_path,
             synced: false,
+            source_exists: false,
             remove_allowed: false,
             export_valid: false,
+            export_record: None,
+            export_status: ExportStatus::NoExport,
+            ignored: false,
         }
     }
 
@@ -127,69 +340,492 @@ impl FileInfo {
             last_edited: Self::get_last_edited(path),
             export_path: "".to_string(),
             synced: false,
+            source_exists: false,
             remove_allowed: false,
             export_valid: false,
+            export_record: None,
+            export_status: ExportStatus::NoExport,
+            ignored: false,
         }
     }
 
-    pub fn refresh_last_edited(&mut self, self_directory: &String) {
-        self.last_edited =
-            Self::get_last_edited(&Path::new(&append_path(&self_directory, &self.name)));
+    pub fn refresh_last_edited(&mut self, self_directory: &str) {
+        let self_path = append_path(self_directory, &self.name);
+        self.source_exists = is_valid_file(&self_path);
+        self.last_edited = Self::get_last_edited(Path::new(&self_path));
     }
 
-    fn get_last_edited(path: &Path) -> String {
+    fn get_last_edited(path: &Path) -> DateTime<Utc> {
         let metadata = path.metadata().ok();
-        let last_edited: DateTime<Local> = metadata
+        metadata
             .and_then(|m| m.modified().ok().map(DateTime::from))
-            .unwrap_or_else(Local::now);
-        last_edited.format("%Y-%m-%d %H:%M:%S").to_string()
+            .unwrap_or_else(Utc::now)
     }
 
     pub fn backup_filename(&self) -> String {
-        let date = self.last_edited.replace(" ", "-").replace(":", "-");
-        format!("{}_{}", date, self.name)
+        format!(
+            "{}_{}",
+            version_id(&self.last_edited),
+            encode_name(&self.name)
+        )
+    }
+
+    /// backup_filename が既に別の内容で使われている場合は、連番を付けて重複を避ける
+    fn unused_backup_filename(&self, store: &dyn BackupStore) -> String {
+        let mut backup_filename = self.backup_filename();
+        let mut sequence = 1;
+        while store.has_version(&backup_filename) {
+            backup_filename = format!(
+                "{}.{}_{}",
+                version_id(&self.last_edited),
+                sequence,
+                encode_name(&self.name)
+            );
+            sequence += 1;
+        }
+
+        backup_filename
     }
 
-    pub fn refresh_synced(&mut self, backup_directory: &String) {
-        let backup_synced = is_valid_directory(backup_directory)
-            && is_valid_file(&append_path(backup_directory, &self.backup_filename()));
-        // let export_synced = !is_valid_directory(&self.export_path)
-        //     || is_valid_file(&append_path(&self.export_path, &self.name));
-        self.synced = backup_synced;
+    /// 最新のバックアップと内容が一致しているかを確認する
+    /// ハッシュの記録があればそれを優先し、なければバックアップファイルの有無で判断する
+    /// 大きさと更新日時が記録と同じなら、ファイルは読まない
+    fn check_synced(
+        &self,
+        self_directory: &str,
+        store: &dyn BackupStore,
+        index: &HashIndex,
+    ) -> bool {
+        if !store.is_available() {
+            return false;
+        }
+
+        match index.latest_stored(&self.name, store) {
+            Some(latest) => {
+                hash_file_cached(&append_path(self_directory, &self.name), Some(latest)).as_ref()
+                    == Some(&latest.hash)
+            }
+            None => store.has_version(&self.backup_filename()),
+        }
     }
 
     pub fn refresh_export_valid(&mut self) {
         self.export_valid = ExportPathState::new(&self.export_path).is_valid();
     }
 
-    pub fn sync(&mut self, self_directory: &String, backup_directory: &String) {
-        let self_path = append_path(&self_directory, &self.name);
+    /// ローカルのエクスポート先のファイルのパス (リモートや無効な場合は None)
+    fn local_export_target(&self) -> Option<String> {
+        match ExportPathState::new(&self.export_path) {
+            ExportPathState::AsDirectoryPath => Some(append_path(&self.export_path, &self.name)),
+            ExportPathState::AsFilePath => Some(self.export_path.clone()),
+            ExportPathState::Invalid | ExportPathState::AsRemotePath => None,
+        }
+    }
+
+    /// 大きさ、更新日時、ハッシュをエクスポート先と比べて、エクスポートの状態を調べる
+    /// 記録が無い (以前のバージョンでエクスポートした) 場合は、他の人による変更とはみなさない
+    pub fn refresh_export_status(&mut self, self_directory: &str) {
+        self.export_status = self.check_export_status(self_directory);
+    }
+
+    fn check_export_status(&self, self_directory: &str) -> ExportStatus {
+        let Some(target) = self.local_export_target() else {
+            return match ExportPathState::new(&self.export_path) {
+                // リモートは接続しないと調べられないため、エクスポートの時に見つけた変更を残しておく
+                ExportPathState::AsRemotePath
+                    if self.export_status == ExportStatus::ModifiedExternally =>
+                {
+                    ExportStatus::ModifiedExternally
+                }
+                ExportPathState::AsRemotePath => ExportStatus::Unchecked,
+                _ => ExportStatus::NoExport,
+            };
+        };
+        let Ok(source_metadata) = fs::metadata(append_path(self_directory, &self.name)) else {
+            return ExportStatus::SourceMissing;
+        };
+        let Ok(target_metadata) = fs::metadata(&target) else {
+            return ExportStatus::Missing;
+        };
+
+        let source_path = append_path(self_directory, &self.name);
+        let Some(record) = self.export_record.as_ref().filter(|r| r.path == target) else {
+            // 記録が無い時は、大きさが違えばエクスポート先を読まずに古いと判断する
+            if source_metadata.len() != target_metadata.len() {
+                return ExportStatus::Stale;
+            }
+            return if hash_file(&source_path) == hash_file(&target) {
+                ExportStatus::UpToDate
+            } else {
+                ExportStatus::Stale
+            };
+        };
+
+        // 大きさと更新日時が記録と同じなら、エクスポート先の内容は読まずに記録のハッシュを使う
+        let target_modified = target_metadata.modified().ok().map(DateTime::<Utc>::from);
+        let target_unchanged =
+            target_modified == Some(record.modified) && record.size == Some(target_metadata.len());
+        if !target_unchanged && hash_file(&target).as_ref() != Some(&record.hash) {
+            return ExportStatus::ModifiedExternally;
+        }
+
+        if source_metadata.len() != target_metadata.len() {
+            return ExportStatus::Stale;
+        }
+
+        // エクスポートした後に手元で変更していなければ、ハッシュは計算しない
+        let source_modified = source_metadata.modified().ok().map(DateTime::<Utc>::from);
+        if source_modified.is_some_and(|m| m <= record.modified) {
+            return ExportStatus::UpToDate;
+        }
+
+        if hash_file(&source_path).as_ref() == Some(&record.hash) {
+            ExportStatus::UpToDate
+        } else {
+            ExportStatus::Stale
+        }
+    }
+
+    /// エクスポートした直後の状態を記録する
+    fn record_export(&mut self, target: &str) {
+        let metadata = fs::metadata(target).ok();
+        let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+        self.export_record = match (hash_file(target), modified) {
+            (Some(hash), Some(modified)) => Some(ExportRecord {
+                path: target.to_string(),
+                hash,
+                modified: DateTime::from(modified),
+                size: metadata.map(|m| m.len()),
+            }),
+            _ => None,
+        };
+        self.export_status = ExportStatus::UpToDate;
+    }
+
+    /// リモートへエクスポートした直後の状態を記録する (リモートの更新日時は使わない)
+    fn record_remote_export(&mut self, self_path: &str) {
+        let size = fs::metadata(self_path).ok().map(|m| m.len());
+        self.export_record = hash_file(self_path).map(|hash| ExportRecord {
+            path: self.export_path.clone(),
+            hash,
+            modified: Utc::now(),
+            size,
+        });
+        self.export_status = ExportStatus::UpToDate;
+    }
 
-        if (is_valid_directory(backup_directory)) {
-            let backup_path = append_path(&backup_directory, &self.backup_filename());
-            fs::copy(&self_path, backup_path).ok();
+    fn is_remote_export(&self) -> bool {
+        matches!(
+            ExportPathState::new(&self.export_path),
+            ExportPathState::AsRemotePath
+        )
+    }
+
+    /// リモートのエクスポート先は同期の状態の確認では読まないため、上書きする前に記録と比べる
+    fn check_remote_export(&mut self, remote: &RemoteExport) -> io::Result<()> {
+        let Some(record) = self
+            .export_record
+            .as_ref()
+            .filter(|r| r.path == self.export_path)
+        else {
+            return Ok(());
+        };
+        if !remote.exists() {
+            return Ok(());
         }
 
-        match ExportPathState::new(&self.export_path) {
-            ExportPathState::Invalid => {}
-            ExportPathState::AsDirectoryPath => {
-                let export_path = append_path(&self.export_path, &self.name);
-                fs::copy(&self_path, export_path).ok();
+        let temp = PrivateTempDir::new("export")?;
+        let current = temp.file("current");
+        remote.download(&current)?;
+        if hash_file(&current).as_ref() == Some(&record.hash) {
+            return Ok(());
+        }
+
+        self.export_status = ExportStatus::ModifiedExternally;
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} was modified externally", self.export_path),
+        ))
+    }
+
+    fn export_to_remote(
+        &mut self,
+        self_path: &str,
+        remote: &RemoteExport,
+        export_backup: ExportBackup,
+        store: &dyn BackupStore,
+    ) -> io::Result<()> {
+        preserve_previous_remote_export(export_backup, &self.name, self_path, remote, store)?;
+        remote.upload(self_path)?;
+        self.record_remote_export(self_path);
+        Ok(())
+    }
+
+    /// 上書きする前のエクスポート先は、export_backup の設定に従って残す (残せなければ上書きしない)
+    /// メタデータを引き継げないファイルシステムもあるため、その失敗ではエクスポートを失敗にしない
+    fn export_to(
+        &mut self,
+        self_path: &str,
+        target: &str,
+        export_backup: ExportBackup,
+        metadata: MetadataOptions,
+        store: &dyn BackupStore,
+    ) -> io::Result<()> {
+        preserve_previous_export(export_backup, &self.name, self_path, target, store)?;
+        create_parent_directory(target)?;
+        copy_atomically(self_path, target)?;
+        copy_metadata(self_path, target, metadata).ok();
+        self.record_export(target);
+        Ok(())
+    }
+
+    pub fn sync(
+        &mut self,
+        self_directory: &str,
+        store: &dyn BackupStore,
+        export_backup: ExportBackup,
+        metadata: MetadataOptions,
+        sftp: &SftpOptions,
+    ) {
+        let self_path = append_path(self_directory, &self.name);
+
+        if store.is_available() {
+            self.backup(&self_path, store, metadata).ok();
+        }
+
+        self.export(self_directory, export_backup, metadata, store, sftp)
+            .ok();
+    }
+
+    /// エクスポート先へコピーする
+    /// 他の人が変更したエクスポート先は上書きせず、どうするかを選んでもらう
+    pub fn export(
+        &mut self,
+        self_directory: &str,
+        export_backup: ExportBackup,
+        metadata: MetadataOptions,
+        store: &dyn BackupStore,
+        sftp: &SftpOptions,
+    ) -> io::Result<()> {
+        let self_path = append_path(self_directory, &self.name);
+        if self.is_remote_export() {
+            let remote = RemoteExport::connect(&self.export_path, &self.name, sftp)?;
+            self.check_remote_export(&remote)?;
+            return self.export_to_remote(&self_path, &remote, export_backup, store);
+        }
+
+        self.refresh_export_status(self_directory);
+        if self.export_status == ExportStatus::ModifiedExternally {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} was modified externally", self.export_path),
+            ));
+        }
+
+        match self.local_export_target() {
+            Some(target) => self.export_to(&self_path, &target, export_backup, metadata, store),
+            None => Ok(()),
+        }
+    }
+
+    /// エクスポート先の内容を取り込む (手元の内容は先にバックアップし、できなければ取り込まない)
+    pub fn pull_export(
+        &mut self,
+        self_directory: &str,
+        metadata: MetadataOptions,
+        store: &dyn BackupStore,
+        sftp: &SftpOptions,
+    ) -> io::Result<()> {
+        let target = self.local_export_target();
+        if target.is_none() && !self.is_remote_export() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                self.export_path.clone(),
+            ));
+        }
+        if !store.is_available() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a backup directory is needed to keep the current version",
+            ));
+        }
+
+        let self_path = append_path(self_directory, &self.name);
+        self.refresh_last_edited(self_directory);
+        if self.source_exists {
+            self.backup(&self_path, store, metadata)?;
+        }
+
+        create_parent_directory(&self_path)?;
+        let Some(target) = target else {
+            let temp = PrivateTempDir::new("pull")?;
+            let pulled = temp.file("export");
+            RemoteExport::connect(&self.export_path, &self.name, sftp)?.download(&pulled)?;
+            copy_atomically(&pulled, &self_path)?;
+            self.record_remote_export(&self_path);
+            return Ok(());
+        };
+
+        copy_atomically(&target, &self_path)?;
+        copy_metadata(&target, &self_path, metadata).ok();
+        self.record_export(&target);
+        Ok(())
+    }
+
+    /// エクスポート先の変更を捨てて、手元の内容で上書きする
+    pub fn overwrite_export(
+        &mut self,
+        self_directory: &str,
+        export_backup: ExportBackup,
+        metadata: MetadataOptions,
+        store: &dyn BackupStore,
+        sftp: &SftpOptions,
+    ) -> io::Result<()> {
+        let self_path = append_path(self_directory, &self.name);
+        if self.is_remote_export() {
+            let remote = RemoteExport::connect(&self.export_path, &self.name, sftp)?;
+            return self.export_to_remote(&self_path, &remote, export_backup, store);
+        }
+
+        let target = self
+            .local_export_target()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, self.export_path.clone()))?;
+        self.export_to(&self_path, &target, export_backup, metadata, store)
+    }
+
+    /// エクスポート先の内容を衝突したコピーとして残してから、手元の内容をエクスポートする
+    pub fn keep_both_exports(
+        &mut self,
+        self_directory: &str,
+        metadata: MetadataOptions,
+        store: &dyn BackupStore,
+        sftp: &SftpOptions,
+    ) -> io::Result<()> {
+        let self_path = append_path(self_directory, &self.name);
+        if self.is_remote_export() {
+            let remote = RemoteExport::connect(&self.export_path, &self.name, sftp)?;
+            remote.rename(&conflict_copy_path(&remote.path))?;
+            return self.export_to_remote(&self_path, &remote, ExportBackup::Off, store);
+        }
+
+        let target = self
+            .local_export_target()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, self.export_path.clone()))?;
+        fs::rename(&target, conflict_copy_path(&target))?;
+        self.export_to(&self_path, &target, ExportBackup::Off, metadata, store)
+    }
+
+    /// 最新のバックアップと内容が同じならコピーを省略し、その旨を履歴に残す
+    fn backup(
+        &self,
+        self_path: &str,
+        store: &dyn BackupStore,
+        metadata: MetadataOptions,
+    ) -> io::Result<()> {
+        let mut index = HashIndex::load(store);
+        let hash = hash_file(self_path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("cannot hash {}", self_path),
+            )
+        })?;
+        let size = fs::metadata(self_path).ok().map(|m| m.len());
+
+        let unchanged = index
+            .latest_stored(&self.name, store)
+            .filter(|latest| latest.hash == hash)
+            .map(|latest| latest.backup_filename.clone());
+
+        let entry = if let Some(backup_filename) = unchanged {
+            HashIndexEntry {
+                name: self.name.clone(),
+                last_edited: self.last_edited,
+                hash,
+                backup_filename,
+                skipped: true,
+                size,
             }
-            ExportPathState::AsFilePath => {
-                fs::copy(&self_path, &self.export_path).ok();
+        } else {
+            let backup_filename = self.unused_backup_filename(store);
+            store.write_version(self_path, &backup_filename)?;
+            Manifest::append(
+                store,
+                &self.name,
+                &backup_filename,
+                self.last_edited,
+                size.unwrap_or(0),
+                &hash,
+            )
+            .ok();
+            self.record_metadata(self_path, &backup_filename, store, metadata);
+
+            HashIndexEntry {
+                name: self.name.clone(),
+                last_edited: self.last_edited,
+                hash,
+                backup_filename,
+                skipped: false,
+                size,
             }
+        };
+
+        index.push(entry);
+        index.save(store);
+        Ok(())
+    }
+
+    /// 元のファイルのメタデータをバージョンに付け、復元できるよう記録しておく
+    fn record_metadata(
+        &self,
+        self_path: &str,
+        backup_filename: &str,
+        store: &dyn BackupStore,
+        options: MetadataOptions,
+    ) {
+        let Ok(metadata) = FileMetadata::read(self_path, options) else {
+            return;
+        };
+        if metadata == FileMetadata::default() {
+            return;
         }
 
-        if (is_valid_directory(&self.export_path)) {
-            let export_path = append_path(&self.export_path, &self.name);
-            fs::copy(&self_path, export_path).ok();
+        store.apply_metadata(backup_filename, &metadata).ok();
+        let mut index = MetadataIndex::load(store);
+        index.entries.insert(backup_filename.to_string(), metadata);
+        index.save(store);
+    }
+
+    /// 最新のバックアップを元の場所へ書き戻す
+    /// バックアップした時のメタデータが記録されていれば、それも元に戻す
+    pub fn restore(&mut self, self_directory: &str, store: &dyn BackupStore) -> io::Result<()> {
+        let index = HashIndex::load(store);
+        let backup_filename = match index.latest_stored(&self.name, store) {
+            Some(latest) => latest.backup_filename.clone(),
+            None => store
+                .list_versions()
+                .into_iter()
+                .filter_map(|v| parse_backup_filename(&v).map(|(time, name)| (time, name, v)))
+                .filter(|(_, name, _)| *name == self.name)
+                .max_by_key(|(time, _, _)| *time)
+                .map(|(_, _, v)| v)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, self.name.clone()))?,
+        };
+
+        let self_path = append_path(self_directory, &self.name);
+        create_parent_directory(&self_path)?;
+        replace_atomically(&self_path, |temp| {
+            store.read_version(&backup_filename, temp)
+        })?;
+        if let Some(metadata) = MetadataIndex::load(store).entries.get(&backup_filename) {
+            metadata.apply(&self_path).ok();
         }
+
+        Ok(())
     }
 
-    pub fn refresh_metadata(&mut self, self_directory: &String, backup_directory: &String) {
+    /// ファイルを読まずに分かることだけを更新する (同期の状態は check_sync_status で調べる)
+    pub fn refresh_metadata(&mut self, self_directory: &str) {
         self.refresh_last_edited(self_directory);
-        self.refresh_synced(backup_directory);
         self.refresh_export_valid();
     }
 }
@@ -199,10 +835,73 @@ impl DirectoryInfo {
         DirectoryInfo {
             path: name,
             backup_directory,
+            replica_directory: String::new(),
+            mirror_directory: String::new(),
+            mirror_deletions: false,
+            export_backup: ExportBackup::Off,
+            metadata_options: MetadataOptions::default(),
+            backup_mode: BackupMode::default(),
+            compression: Compression::default(),
+            parity: Parity::default(),
+            encrypted: false,
+            keep_versions: 0,
+            scrub_interval_days: DEFAULT_SCRUB_INTERVAL_DAYS,
+            scrub_speed_limit: DEFAULT_SCRUB_SPEED_LIMIT,
+            remote_options: RemoteOptions::default(),
+            encryption_key: None,
+            key_file_exists: false,
+            track_rules: TrackRules::default(),
             files: Vec::new(),
+            ignore_rules: IgnoreRules::default(),
         }
     }
 
+    pub fn backup_store(&self) -> Box<dyn BackupStore> {
+        self.open_store(&self.backup_directory)
+    }
+
+    /// 複製先をバックアップ先と同じ設定で開く
+    pub fn replica_store(&self) -> Box<dyn BackupStore> {
+        self.open_store(&self.replica_directory)
+    }
+
+    /// directory を暗号化せずに開く
+    /// 鍵ファイルの読み書きと、暗号化する場合の内側の保存方式に使う
+    pub fn plain_store(&self, directory: &str) -> Box<dyn BackupStore> {
+        open_backup_store(
+            self.backup_mode,
+            directory,
+            Compression::None,
+            self.parity,
+            &self.remote_options_for(directory),
+        )
+    }
+
+    /// directory を開く時の接続設定
+    /// git の push 先はバックアップ先のためのもので、複製先からは push しない
+    fn remote_options_for(&self, directory: &str) -> RemoteOptions {
+        let mut remote_options = self.remote_options.clone();
+        if directory != self.backup_directory {
+            remote_options.git_remote.clear();
+        }
+        remote_options
+    }
+
+    fn open_store(&self, directory: &str) -> Box<dyn BackupStore> {
+        if self.encrypted {
+            let inner = self.plain_store(directory);
+            return Box::new(EncryptedStore::new(inner, self.encryption_key.clone()));
+        }
+
+        open_backup_store(
+            self.backup_mode,
+            directory,
+            self.compression,
+            self.parity,
+            &self.remote_options_for(directory),
+        )
+    }
+
     pub fn add_file(&mut self, file: FileInfo) {
         self.files.push(file);
     }
@@ -211,27 +910,102 @@ impl DirectoryInfo {
         self.files.get_mut(index)
     }
 
+    /// 追跡の条件に合うファイルのうち、まだ一覧に無いものを加える
+    pub fn discover_files(&mut self) {
+        let found = self.track_rules.scan(
+            &self.path,
+            &[
+                &self.backup_directory,
+                &self.replica_directory,
+                &self.mirror_directory,
+            ],
+            &self.ignore_rules,
+        );
+        for name in found {
+            if !self.files.iter().any(|f| f.name == name) {
+                self.add_file(FileInfo::new(name, DateTime::default(), "".to_string()));
+            }
+        }
+    }
+
+    /// `.ddbackupignore` を読み直し、追跡中のファイルが対象になっていないか確かめる
+    pub fn refresh_ignore_rules(&mut self) {
+        self.ignore_rules = IgnoreRules::load(&self.path);
+        for file in self.files.iter_mut() {
+            file.ignored = self.ignore_rules.is_ignored(&file.name, false);
+        }
+    }
+
     pub fn refresh_files(&mut self) {
+        self.refresh_ignore_rules();
+        if self.track_rules.is_enabled() {
+            self.discover_files();
+        }
+
         for file in self.files.iter_mut() {
-            file.refresh_metadata(&self.path, &self.backup_directory);
+            file.refresh_metadata(&self.path);
         }
+
+        self.key_file_exists =
+            self.encrypted && has_key_file(self.plain_store(&self.backup_directory).as_ref());
+    }
+
+    /// バックアップ先、複製先、エクスポート先のどれかが `sftp://` か
+    pub fn uses_sftp(&self) -> bool {
+        is_sftp_url(&self.backup_directory)
+            || is_sftp_url(&self.replica_directory)
+            || self.files.iter().any(|f| is_sftp_url(&f.export_path))
+    }
+
+    /// バックアップ先か複製先が `s3://` か
+    pub fn uses_s3(&self) -> bool {
+        is_s3_url(&self.backup_directory) || is_s3_url(&self.replica_directory)
     }
 
     /// files について last_edited 降順でソートする
     pub fn sort_files_by_last_edited(&mut self) {
-        self.files.sort_by(|a, b| b.last_edited.cmp(&a.last_edited));
+        self.files.sort_by_key(|f| Reverse(f.last_edited));
     }
 }
 
 impl UserData {
-    pub fn new() -> Self {
-        UserData {
-            directories: Vec::new(),
+    pub fn apply_sync_status(&mut self, statuses: Vec<SyncStatus>) {
+        for status in statuses {
+            let Some(dir) = self.touch_directory(&status.directory) else {
+                continue;
+            };
+            let Some(file) = dir
+                .files
+                .iter_mut()
+                .find(|f| f.name == status.name && f.last_edited == status.last_edited)
+            else {
+                continue;
+            };
+
+            file.synced = status.synced;
+            file.export_status = status.export_status;
         }
     }
 
-    pub fn add_directory(&mut self, directory: DirectoryInfo) {
-        self.directories.push(directory);
+    /// 別スレッドで操作したファイルを一覧に戻す
+    /// 操作中にエクスポート先が書き換えられていれば、その入力を残す
+    pub fn apply_file_updates(&mut self, directory: &str, files: Vec<FileInfo>) {
+        let Some(dir) = self.touch_directory(directory) else {
+            return;
+        };
+        for updated in files {
+            let Some(file) = dir.files.iter_mut().find(|f| f.name == updated.name) else {
+                continue;
+            };
+
+            let export_path = std::mem::take(&mut file.export_path);
+            *file = updated;
+            if file.export_path != export_path {
+                file.export_path = export_path;
+                file.refresh_export_valid();
+                file.export_status = ExportStatus::default();
+            }
+        }
     }
 
     pub fn find_directory(&self, name: &str) -> Option<&DirectoryInfo> {
@@ -254,9 +1028,357 @@ impl UserData {
         {
             &mut self.directories[index]
         } else {
-            let directory = DirectoryInfo::new(name.to_string(), "".to_string());
+            let mut directory = DirectoryInfo::new(name.to_string(), "".to_string());
+            directory.refresh_ignore_rules();
             self.directories.push(directory);
             self.directories.last_mut().unwrap()
         }
     }
+
+    /// スキャンしたバックアップから、ソースディレクトリが指定されたファイルを取り込む
+    pub fn adopt_backup(
+        &mut self,
+        backup_directory: &str,
+        scanned_files: &[ScannedFile],
+        encryption_key: Option<EncryptionKey>,
+    ) {
+        for scanned in scanned_files {
+            if !scanned.source_directory_valid() {
+                continue;
+            }
+
+            let dir = self.touch_directory_or_insert(&scanned.source_directory);
+            dir.backup_directory = backup_directory.to_string();
+            dir.backup_mode = detect_backup_mode(backup_directory);
+            dir.encrypted = is_encrypted_target(backup_directory);
+            dir.encryption_key = encryption_key.clone();
+            if !dir.files.iter().any(|f| f.name == scanned.name) {
+                let mut file_info = FileInfo::empty();
+                file_info.name = scanned.name.clone();
+                dir.add_file(file_info);
+            }
+
+            dir.refresh_files();
+            dir.sort_files_by_last_edited();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ignore_rules::IGNORE_FILENAME;
+
+    fn tracked_file(directory: &str, name: &str, contents: &str) -> FileInfo {
+        fs::write(append_path(directory, name), contents).unwrap();
+        let mut file = FileInfo::new(name.to_string(), DateTime::default(), String::new());
+        file.refresh_metadata(directory);
+        file
+    }
+
+    #[test]
+    fn tracked_file_is_flagged_when_ignored_later() {
+        let source = tempfile::tempdir().unwrap();
+        let source_path = source.path().to_string_lossy().to_string();
+        let mut dir = DirectoryInfo::new(source_path.clone(), String::new());
+        dir.add_file(tracked_file(&source_path, "a.log", "log"));
+        dir.add_file(tracked_file(&source_path, "a.txt", "text"));
+        dir.refresh_files();
+        assert!(dir.files.iter().all(|f| !f.ignored));
+
+        fs::write(append_path(&source_path, IGNORE_FILENAME), "*.log\n").unwrap();
+        dir.refresh_files();
+        let ignored: Vec<&str> = dir
+            .files
+            .iter()
+            .filter(|f| f.ignored)
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(ignored, ["a.log"]);
+        assert_eq!(dir.ignore_rules.ignored, ["a.log"]);
+    }
+
+    #[test]
+    fn relative_path_inside_directory() {
+        assert_eq!(
+            relative_path("/home/a", Path::new("/home/a/docs/b.txt")).unwrap(),
+            "docs/b.txt"
+        );
+        assert_eq!(relative_path("/home/a", Path::new("/home/ab/b.txt")), None);
+        assert_eq!(relative_path("/home/a", Path::new("/home/a")), None);
+    }
+
+    #[test]
+    fn unchanged_content_is_recorded_without_copying() {
+        let source = tempfile::tempdir().unwrap();
+        let backup = tempfile::tempdir().unwrap();
+        let source_path = source.path().to_string_lossy().to_string();
+        let dir = DirectoryInfo::new(
+            source_path.clone(),
+            backup.path().to_string_lossy().to_string(),
+        );
+        let store = dir.backup_store();
+
+        let mut file = tracked_file(&source_path, "a.txt", "hello");
+        file.sync(
+            &source_path,
+            store.as_ref(),
+            ExportBackup::Off,
+            MetadataOptions::default(),
+            &SftpOptions::default(),
+        );
+        // 更新日時だけが変わった場合は、コピーせずに履歴に残す
+        file.last_edited += chrono::Duration::seconds(1);
+        file.sync(
+            &source_path,
+            store.as_ref(),
+            ExportBackup::Off,
+            MetadataOptions::default(),
+            &SftpOptions::default(),
+        );
+
+        let index = HashIndex::load(store.as_ref());
+        assert_eq!(index.entries.len(), 2);
+        assert!(!index.entries[0].skipped);
+        assert!(index.entries[1].skipped);
+        assert_eq!(
+            index.entries[0].backup_filename,
+            index.entries[1].backup_filename
+        );
+        assert_eq!(index.entries[1].size, Some(5));
+        assert_eq!(store.list_versions().len(), 1);
+    }
+
+    #[test]
+    fn sync_status_follows_source_changes() {
+        let source = tempfile::tempdir().unwrap();
+        let backup = tempfile::tempdir().unwrap();
+        let source_path = source.path().to_string_lossy().to_string();
+        let mut dir = DirectoryInfo::new(
+            source_path.clone(),
+            backup.path().to_string_lossy().to_string(),
+        );
+        let store = dir.backup_store();
+        let mut file = tracked_file(&source_path, "a.txt", "hello");
+        file.sync(
+            &source_path,
+            store.as_ref(),
+            ExportBackup::Off,
+            MetadataOptions::default(),
+            &SftpOptions::default(),
+        );
+        dir.add_file(file);
+
+        let mut user_data = UserData {
+            directories: vec![dir.clone()],
+        };
+        user_data.apply_sync_status(check_sync_status(&user_data.directories));
+        assert!(user_data.directories[0].files[0].synced);
+
+        fs::write(append_path(&source_path, "a.txt"), "changed").unwrap();
+        user_data.apply_sync_status(check_sync_status(&user_data.directories));
+        assert!(!user_data.directories[0].files[0].synced);
+    }
+
+    /// 使えるように見えるが、バージョンを書き込めないバックアップ先
+    struct FailingStore;
+
+    impl BackupStore for FailingStore {
+        fn is_available(&self) -> bool {
+            true
+        }
+
+        fn list_versions(&self) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn has_version(&self, _backup_filename: &str) -> bool {
+            false
+        }
+
+        fn write_version(&self, _source_path: &str, _backup_filename: &str) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn read_version(&self, _backup_filename: &str, _dest_path: &str) -> io::Result<()> {
+            Err(io::ErrorKind::NotFound.into())
+        }
+
+        fn remove_version(&self, _backup_filename: &str) -> io::Result<()> {
+            Err(io::ErrorKind::NotFound.into())
+        }
+
+        fn load_index(&self, _index_name: &str) -> io::Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn write_index(&self, _index_name: &str, _content: &str) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// 手元に mine、エクスポート先に theirs がある a.txt
+    fn diverged_file(source_path: &str, export: &tempfile::TempDir) -> FileInfo {
+        let export_path = export.path().to_string_lossy().to_string();
+        fs::write(append_path(&export_path, "a.txt"), "theirs").unwrap();
+        let mut file = tracked_file(source_path, "a.txt", "mine");
+        file.export_path = export_path;
+        file
+    }
+
+    #[test]
+    fn pull_keeps_the_current_version_in_backup() {
+        let source = tempfile::tempdir().unwrap();
+        let backup = tempfile::tempdir().unwrap();
+        let export = tempfile::tempdir().unwrap();
+        let source_path = source.path().to_string_lossy().to_string();
+        let dir = DirectoryInfo::new(
+            source_path.clone(),
+            backup.path().to_string_lossy().to_string(),
+        );
+        let store = dir.backup_store();
+
+        let mut file = diverged_file(&source_path, &export);
+        file.pull_export(
+            &source_path,
+            MetadataOptions::default(),
+            store.as_ref(),
+            &SftpOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(append_path(&source_path, "a.txt")).unwrap(),
+            "theirs"
+        );
+
+        let versions = store.list_versions();
+        assert_eq!(versions.len(), 1);
+        let restored = append_path(&backup.path().to_string_lossy(), "restored");
+        store.read_version(&versions[0], &restored).unwrap();
+        assert_eq!(fs::read_to_string(restored).unwrap(), "mine");
+    }
+
+    #[test]
+    fn pull_is_aborted_when_backup_fails() {
+        let source = tempfile::tempdir().unwrap();
+        let export = tempfile::tempdir().unwrap();
+        let source_path = source.path().to_string_lossy().to_string();
+
+        let mut file = diverged_file(&source_path, &export);
+        let result = file.pull_export(
+            &source_path,
+            MetadataOptions::default(),
+            &FailingStore,
+            &SftpOptions::default(),
+        );
+        assert!(result.is_err());
+        assert_eq!(
+            fs::read_to_string(append_path(&source_path, "a.txt")).unwrap(),
+            "mine"
+        );
+        assert_eq!(file.export_record, None);
+    }
+
+    #[test]
+    fn remote_divergence_is_kept_until_resolved() {
+        let mut file = FileInfo::new(
+            "a.txt".to_string(),
+            DateTime::default(),
+            "sftp://alice@example.com/exports".to_string(),
+        );
+        assert_eq!(file.check_export_status(""), ExportStatus::Unchecked);
+
+        file.export_status = ExportStatus::ModifiedExternally;
+        assert_eq!(
+            file.check_export_status(""),
+            ExportStatus::ModifiedExternally
+        );
+    }
+
+    #[test]
+    fn export_status_reports_missing_source() {
+        let source = tempfile::tempdir().unwrap();
+        let export = tempfile::tempdir().unwrap();
+        let source_path = source.path().to_string_lossy().to_string();
+        let mut file = diverged_file(&source_path, &export);
+        assert_eq!(file.check_export_status(&source_path), ExportStatus::Stale);
+
+        fs::remove_file(append_path(&source_path, "a.txt")).unwrap();
+        assert_eq!(
+            file.check_export_status(&source_path),
+            ExportStatus::SourceMissing
+        );
+
+        // エクスポート先も無い時は、エクスポートできないことの方を示す
+        file.export_path = append_path(&file.export_path, "missing");
+        assert_eq!(
+            file.check_export_status(&source_path),
+            ExportStatus::SourceMissing
+        );
+        assert!(!ExportStatus::SourceMissing.needs_export());
+    }
+
+    #[test]
+    fn sftp_is_used_by_any_target() {
+        let mut dir = DirectoryInfo::new("/source".to_string(), "/backup".to_string());
+        assert!(!dir.uses_sftp());
+
+        dir.replica_directory = "sftp://alice@example.com/replica".to_string();
+        assert!(dir.uses_sftp());
+
+        dir.replica_directory = String::new();
+        dir.add_file(FileInfo::new(
+            "a.txt".to_string(),
+            Utc::now(),
+            "sftp://alice@example.com/export".to_string(),
+        ));
+        assert!(dir.uses_sftp());
+    }
+
+    #[test]
+    fn export_path_edited_during_a_task_is_kept() {
+        let mut user_data = UserData::default();
+        let dir = user_data.touch_directory_or_insert("/source");
+        dir.add_file(FileInfo::new(
+            "a.txt".to_string(),
+            Utc::now(),
+            "/old".to_string(),
+        ));
+        dir.add_file(FileInfo::new(
+            "b.txt".to_string(),
+            Utc::now(),
+            "/old".to_string(),
+        ));
+
+        let mut a = dir.files[0].clone();
+        a.export_status = ExportStatus::UpToDate;
+        let mut b = dir.files[1].clone();
+        b.export_status = ExportStatus::UpToDate;
+        dir.files[1].export_path = "/new".to_string();
+
+        user_data.apply_file_updates("/source", vec![a, b]);
+        let files = &user_data.find_directory("/source").unwrap().files;
+        assert_eq!(files[0].export_status, ExportStatus::UpToDate);
+        assert_eq!(files[1].export_path, "/new");
+        assert_eq!(files[1].export_status, ExportStatus::default());
+    }
+
+    #[test]
+    fn s3_is_used_by_backup_or_replica() {
+        let mut dir = DirectoryInfo::new("/source".to_string(), "/backup".to_string());
+        assert!(!dir.uses_s3());
+
+        dir.replica_directory = "s3://bucket/replica".to_string();
+        assert!(dir.uses_s3());
+    }
+
+    #[test]
+    fn git_remote_is_only_used_by_the_backup_directory() {
+        let mut dir = DirectoryInfo::new("/source".to_string(), "/backup".to_string());
+        dir.replica_directory = "/replica".to_string();
+        dir.remote_options.git_remote = "origin".to_string();
+
+        assert_eq!(dir.remote_options_for("/backup").git_remote, "origin");
+        assert!(dir.remote_options_for("/replica").git_remote.is_empty());
+    }
 }
@@ -0,0 +1,644 @@
+use crate::backup_store::{missing_as_none, BackupStore};
+use crate::hash_index::hash_file;
+use crate::uri::{uri_decode, uri_encode};
+use base64::Engine;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+
+pub const WEBDAV_SCHEME: &str = "webdav://";
+pub const WEBDAVS_SCHEME: &str = "webdavs://";
+
+/// URL にユーザー名が無い場合に使うユーザー名
+const USER_ENV: &str = "DD_BACKUP_WEBDAV_USER";
+/// パスワード (Nextcloud ではアプリパスワード)
+const PASSWORD_ENV: &str = "DD_BACKUP_WEBDAV_PASSWORD";
+
+/// これより大きいファイルは Nextcloud のチャンクアップロードで送る
+const CHUNKED_UPLOAD_THRESHOLD: u64 = 16 * 1024 * 1024;
+const CHUNK_SIZE: usize = 10 * 1024 * 1024;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0"?>
+<d:propfind xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getcontentlength/>
+    <d:getetag/>
+    <oc:checksums/>
+  </d:prop>
+</d:propfind>"#;
+
+pub fn is_webdav_url(path: &str) -> bool {
+    path.starts_with(WEBDAV_SCHEME) || path.starts_with(WEBDAVS_SCHEME)
+}
+
+/// `webdav(s)://user@host:port/path` を分解したもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebDavLocation {
+    /// `http(s)://host:port`
+    pub base_url: String,
+    pub user: String,
+    /// 先頭に `/` を付け、末尾には付けない
+    pub path: String,
+}
+
+impl WebDavLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let (scheme, rest) = if let Some(rest) = url.strip_prefix(WEBDAVS_SCHEME) {
+            ("https", rest)
+        } else {
+            ("http", url.strip_prefix(WEBDAV_SCHEME)?)
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (user.to_string(), host),
+            None => (env::var(USER_ENV).unwrap_or_default(), authority),
+        };
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(WebDavLocation {
+            base_url: format!("{}://{}", scheme, host),
+            user,
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, uri_encode(path, false))
+    }
+
+    /// Nextcloud の `/remote.php/dav/files/<user>/...` であれば、チャンクアップロードの置き場所を返す
+    fn nextcloud_uploads_path(&self) -> Option<String> {
+        let (root, rest) = self.path.split_once("/remote.php/dav/files/")?;
+        let user = rest.split('/').next().filter(|u| !u.is_empty())?;
+        Some(format!("{}/remote.php/dav/uploads/{}", root, user))
+    }
+}
+
+/// PROPFIND の応答 1 件分
+#[derive(Debug, Clone, Default)]
+struct DavEntry {
+    href: String,
+    is_collection: bool,
+    size: Option<u64>,
+    etag: Option<String>,
+    /// Nextcloud が返す `SHA256:<hex>` などの一覧
+    checksums: Vec<String>,
+}
+
+fn parse_multistatus(xml: &str) -> Vec<DavEntry> {
+    let mut reader = Reader::from_str(xml);
+    let mut entries = Vec::new();
+    let mut current: Option<DavEntry> = None;
+    let mut element = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => {
+                element = e.local_name().as_ref().to_vec();
+                if element == b"response" {
+                    current = Some(DavEntry::default());
+                } else if let Some(entry) = current.as_mut().filter(|_| element == b"collection") {
+                    entry.is_collection = true;
+                }
+            }
+            Ok(Event::Empty(e)) if e.local_name().as_ref() == b"collection" => {
+                if let Some(entry) = current.as_mut() {
+                    entry.is_collection = true;
+                }
+            }
+            Ok(Event::Text(text)) => {
+                let (Some(entry), Ok(text)) = (current.as_mut(), text.unescape()) else {
+                    continue;
+                };
+                match element.as_slice() {
+                    b"href" => entry.href = uri_decode(text.trim()),
+                    b"getcontentlength" => entry.size = text.trim().parse().ok(),
+                    b"getetag" => entry.etag = Some(text.trim().to_string()),
+                    b"checksum" | b"checksums" => entry
+                        .checksums
+                        .extend(text.split_whitespace().map(|c| c.to_string())),
+                    _ => {}
+                }
+            }
+            Ok(Event::End(e)) => {
+                if e.local_name().as_ref() == b"response" {
+                    entries.extend(current.take());
+                }
+                element.clear();
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+
+    entries
+}
+
+fn http_error(error: ureq::Error) -> io::Error {
+    match error {
+        ureq::Error::Status(404, _) => io::Error::from(io::ErrorKind::NotFound),
+        ureq::Error::Status(code, _) => io::Error::other(format!("WebDAV error {}", code)),
+        error => io::Error::other(error.to_string()),
+    }
+}
+
+/// WebDAV のコレクションに、バージョンごとのファイルを並べる方式
+pub struct WebDavStore {
+    location: Option<WebDavLocation>,
+    password: String,
+    agent: ureq::Agent,
+}
+
+impl WebDavStore {
+    pub fn new(url: &str) -> Self {
+        WebDavStore {
+            location: WebDavLocation::parse(url),
+            password: env::var(PASSWORD_ENV).unwrap_or_default(),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    fn location(&self) -> io::Result<&WebDavLocation> {
+        self.location
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid WebDAV URL"))
+    }
+
+    fn request(&self, method: &str, path: &str) -> io::Result<ureq::Request> {
+        let location = self.location()?;
+        let mut request = self.agent.request(method, &location.url(path));
+        if !location.user.is_empty() {
+            let credentials = base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", location.user, self.password));
+            request = request.set("Authorization", &format!("Basic {}", credentials));
+        }
+
+        Ok(request)
+    }
+
+    fn remote_path(&self, name: &str) -> String {
+        let directory = self
+            .location
+            .as_ref()
+            .map(|l| l.path.as_str())
+            .unwrap_or_default();
+        format!("{}/{}", directory, name)
+    }
+
+    fn propfind(&self, path: &str, depth: &str) -> io::Result<Vec<DavEntry>> {
+        let response = self
+            .request("PROPFIND", path)?
+            .set("Depth", depth)
+            .set("Content-Type", "application/xml")
+            .send_string(PROPFIND_BODY)
+            .map_err(http_error)?;
+        Ok(parse_multistatus(&response.into_string()?))
+    }
+
+    /// アップロード後の大きさ・ETag・ハッシュを確かめる
+    fn verify_upload(
+        &self,
+        remote_path: &str,
+        size: u64,
+        hash: &str,
+        etag: Option<String>,
+    ) -> io::Result<()> {
+        let entry = self
+            .propfind(remote_path, "0")?
+            .into_iter()
+            .next()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+        if entry.size != Some(size) {
+            return Err(invalid("uploaded size does not match"));
+        }
+
+        // 弱い ETag の印は応答によって付いたり付かなかったりする
+        let normalize = |etag: Option<String>| etag.map(|e| e.trim_start_matches("W/").to_string());
+        if etag.is_some() && entry.etag.is_some() && normalize(etag) != normalize(entry.etag) {
+            return Err(invalid("file was changed during upload"));
+        }
+
+        let expected = format!("sha256:{}", hash);
+        let checksums: Vec<String> = entry.checksums.iter().map(|c| c.to_lowercase()).collect();
+        if checksums.iter().any(|c| c.starts_with("sha256:")) && !checksums.contains(&expected) {
+            return Err(invalid("uploaded hash does not match"));
+        }
+
+        Ok(())
+    }
+
+    fn upload(&self, local_path: &str, remote_path: &str) -> io::Result<()> {
+        let size = fs::metadata(local_path)?.len();
+        let hash = hash_file(local_path).unwrap_or_default();
+        let location = self.location()?;
+
+        let etag = match location.nextcloud_uploads_path() {
+            Some(uploads_path) if size > CHUNKED_UPLOAD_THRESHOLD => {
+                self.chunked_upload(local_path, remote_path, &uploads_path, size, &hash)?;
+                None
+            }
+            _ => {
+                // 大きさを指定しない Reader は Transfer-Encoding: chunked で少しずつ送られる
+                let response = self
+                    .request("PUT", remote_path)?
+                    .set("OC-Checksum", &format!("SHA256:{}", hash))
+                    .send(fs::File::open(local_path)?)
+                    .map_err(http_error)?;
+                response.header("ETag").map(|e| e.to_string())
+            }
+        };
+
+        self.verify_upload(remote_path, size, &hash, etag)
+    }
+
+    /// Nextcloud のチャンクアップロード (v2)
+    /// 一時的なコレクションにチャンクを置き、最後に MOVE で 1 つのファイルにする
+    fn chunked_upload(
+        &self,
+        local_path: &str,
+        remote_path: &str,
+        uploads_path: &str,
+        size: u64,
+        hash: &str,
+    ) -> io::Result<()> {
+        let location = self.location()?;
+        let destination = location.url(remote_path);
+        let upload_id = format!("dd-backup-{}", &hash[..hash.len().min(32)]);
+        let upload_directory = format!("{}/{}", uploads_path, upload_id);
+
+        self.request("MKCOL", &upload_directory)?
+            .set("Destination", &destination)
+            .call()
+            .map_err(http_error)?;
+
+        let result = (|| {
+            let mut file = fs::File::open(local_path)?;
+            let mut buffer = vec![0u8; CHUNK_SIZE];
+            let mut chunk_number = 1;
+            loop {
+                let mut read = 0;
+                while read < CHUNK_SIZE {
+                    match file.read(&mut buffer[read..])? {
+                        0 => break,
+                        n => read += n,
+                    }
+                }
+
+                if read == 0 {
+                    break;
+                }
+
+                self.request("PUT", &format!("{}/{:05}", upload_directory, chunk_number))?
+                    .set("Destination", &destination)
+                    .set("OC-Total-Length", &size.to_string())
+                    .send_bytes(&buffer[..read])
+                    .map_err(http_error)?;
+                chunk_number += 1;
+            }
+
+            self.request("MOVE", &format!("{}/.file", upload_directory))?
+                .set("Destination", &destination)
+                .set("OC-Total-Length", &size.to_string())
+                .set("OC-Checksum", &format!("SHA256:{}", hash))
+                .call()
+                .map_err(http_error)
+                .map(|_| ())
+        })();
+
+        if result.is_err() {
+            self.request("DELETE", &upload_directory)?.call().ok();
+        }
+
+        result
+    }
+}
+
+impl BackupStore for WebDavStore {
+    fn is_available(&self) -> bool {
+        let Ok(location) = self.location() else {
+            return false;
+        };
+
+        self.propfind(&format!("{}/", location.path), "0")
+            .is_ok_and(|entries| entries.first().is_some_and(|e| e.is_collection))
+    }
+
+    fn list_versions(&self) -> Vec<String> {
+        let Ok(location) = self.location() else {
+            return Vec::new();
+        };
+
+        self.propfind(&format!("{}/", location.path), "1")
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|entry| !entry.is_collection)
+            .filter_map(|entry| {
+                entry
+                    .href
+                    .trim_end_matches('/')
+                    .rsplit('/')
+                    .next()
+                    .map(|name| name.to_string())
+            })
+            .filter(|name| !name.is_empty() && !name.starts_with('.'))
+            .collect()
+    }
+
+    fn has_version(&self, backup_filename: &str) -> bool {
+        self.request("HEAD", &self.remote_path(backup_filename))
+            .is_ok_and(|request| request.call().is_ok())
+    }
+
+    fn write_version(&self, source_path: &str, backup_filename: &str) -> io::Result<()> {
+        self.upload(source_path, &self.remote_path(backup_filename))
+    }
+
+    fn read_version(&self, backup_filename: &str, dest_path: &str) -> io::Result<()> {
+        let response = self
+            .request("GET", &self.remote_path(backup_filename))?
+            .call()
+            .map_err(http_error)?;
+        let mut dest = fs::File::create(dest_path)?;
+        io::copy(&mut response.into_reader(), &mut dest)?;
+        dest.flush()
+    }
+
+    fn remove_version(&self, backup_filename: &str) -> io::Result<()> {
+        match self
+            .request("DELETE", &self.remote_path(backup_filename))?
+            .call()
+        {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(error) => Err(http_error(error)),
+        }
+    }
+
+    fn load_index(&self, index_name: &str) -> io::Result<Option<String>> {
+        missing_as_none(
+            self.request("GET", &self.remote_path(index_name))?
+                .call()
+                .map_err(http_error)
+                .and_then(|response| response.into_string()),
+        )
+    }
+
+    fn write_index(&self, index_name: &str, content: &str) -> io::Result<()> {
+        self.request("PUT", &self.remote_path(index_name))?
+            .send_string(content)
+            .map(|_| ())
+            .map_err(http_error)
+    }
+}
+
+/// WebDAV のバックアップ先として振る舞う、テスト用のサーバー
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use crate::test_server::{TestRequest, TestResponse, TestServer};
+    use sha2::{Digest, Sha256};
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::sync::{Arc, Mutex};
+
+    pub const ROOT: &str = "/remote.php/dav/files/alice/backup";
+
+    #[derive(Default)]
+    pub struct FakeDav {
+        pub base_url: String,
+        pub files: BTreeMap<String, Vec<u8>>,
+        pub collections: BTreeSet<String>,
+        /// PROPFIND で違うハッシュを返す (転送中に壊れた場合)
+        pub corrupt_checksums: bool,
+        pub requests: Vec<String>,
+    }
+
+    impl FakeDav {
+        fn propfind_entry(&self, path: &str) -> Option<String> {
+            if self.collections.contains(path) {
+                return Some(format!(
+                    "<d:response><d:href>{}/</d:href><d:propstat><d:prop>\
+                     <d:resourcetype><d:collection/></d:resourcetype>\
+                     </d:prop></d:propstat></d:response>",
+                    uri_encode(path, false)
+                ));
+            }
+
+            let body = self.files.get(path)?;
+            let mut hash = hex::encode(Sha256::digest(body));
+            if self.corrupt_checksums {
+                hash = hash.replace(|c: char| c != '0', "0");
+            }
+            Some(format!(
+                "<d:response><d:href>{}</d:href><d:propstat><d:prop>\
+                 <d:resourcetype/><d:getcontentlength>{}</d:getcontentlength>\
+                 <d:getetag>\"{}\"</d:getetag>\
+                 <oc:checksums><oc:checksum>SHA256:{}</oc:checksum></oc:checksums>\
+                 </d:prop></d:propstat></d:response>",
+                uri_encode(path, false),
+                body.len(),
+                body.len(),
+                hash
+            ))
+        }
+
+        fn propfind(&self, path: &str, depth: Option<&str>) -> TestResponse {
+            let Some(entry) = self.propfind_entry(path) else {
+                return TestResponse::new(404, "");
+            };
+
+            let mut xml =
+                String::from(r#"<d:multistatus xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">"#);
+            xml.push_str(&entry);
+            if depth == Some("1") {
+                let children = self
+                    .files
+                    .keys()
+                    .filter(|p| p.rsplit_once('/').is_some_and(|(parent, _)| parent == path));
+                for child in children {
+                    xml.push_str(&self.propfind_entry(child).unwrap());
+                }
+            }
+            xml.push_str("</d:multistatus>");
+            TestResponse::new(207, xml)
+        }
+
+        /// Nextcloud のチャンクをつなげて、Destination に置く
+        fn assemble(&mut self, request: &TestRequest, upload_directory: &str) -> TestResponse {
+            let destination = request.header("Destination").unwrap();
+            let destination = uri_decode(destination.strip_prefix(&self.base_url).unwrap());
+            let prefix = format!("{}/", upload_directory);
+            let chunks: Vec<String> = self
+                .files
+                .keys()
+                .filter(|p| p.starts_with(&prefix))
+                .cloned()
+                .collect();
+            let body: Vec<u8> = chunks
+                .iter()
+                .flat_map(|chunk| self.files.remove(chunk).unwrap())
+                .collect();
+            self.collections.remove(upload_directory);
+            self.files.insert(destination, body);
+            TestResponse::new(201, "")
+        }
+    }
+
+    pub fn fake_webdav(dav: Arc<Mutex<FakeDav>>) -> TestServer {
+        let handler_dav = dav.clone();
+        let server = TestServer::start(move |request: TestRequest| {
+            let mut dav = handler_dav.lock().unwrap();
+            let path = uri_decode(&request.path).trim_end_matches('/').to_string();
+            dav.requests.push(format!("{} {}", request.method, path));
+            match request.method.as_str() {
+                "PROPFIND" => dav.propfind(&path, request.header("Depth")),
+                "MKCOL" => {
+                    dav.collections.insert(path);
+                    TestResponse::new(201, "")
+                }
+                "MOVE" => match path.strip_suffix("/.file") {
+                    Some(upload_directory) => dav.assemble(&request, upload_directory),
+                    None => TestResponse::new(400, ""),
+                },
+                "PUT" => {
+                    let etag = format!("\"{}\"", request.body.len());
+                    dav.files.insert(path, request.body);
+                    TestResponse::new(201, "").with_header("ETag", &etag)
+                }
+                "DELETE" => match dav.files.remove(&path) {
+                    Some(_) => TestResponse::new(204, ""),
+                    None if dav.collections.remove(&path) => TestResponse::new(204, ""),
+                    None => TestResponse::new(404, ""),
+                },
+                _ => match dav.files.get(&path) {
+                    Some(body) => TestResponse::new(200, body.clone()),
+                    None => TestResponse::new(404, ""),
+                },
+            }
+        });
+
+        let mut state = dav.lock().unwrap();
+        state.base_url = server.base_url.clone();
+        state.collections.insert(ROOT.to_string());
+        drop(state);
+        server
+    }
+
+    /// fake_webdav の ROOT を指す URL
+    pub fn url(server: &TestServer) -> String {
+        let authority = server.base_url.strip_prefix("http://").unwrap();
+        format!("webdav://alice@{}{}", authority, ROOT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fake::*;
+    use super::*;
+    use crate::test_server::TestServer;
+    use std::sync::{Arc, Mutex};
+
+    fn store(server: &TestServer) -> WebDavStore {
+        WebDavStore::new(&url(server))
+    }
+
+    #[test]
+    fn parse_location() {
+        let location =
+            WebDavLocation::parse("webdavs://bob@cloud.example.com/dav/backup/").unwrap();
+        assert_eq!(location.base_url, "https://cloud.example.com");
+        assert_eq!(location.user, "bob");
+        assert_eq!(location.path, "/dav/backup");
+        assert_eq!(location.nextcloud_uploads_path(), None);
+
+        let location =
+            WebDavLocation::parse("webdav://bob@host:8080/remote.php/dav/files/bob/backup")
+                .unwrap();
+        assert_eq!(location.base_url, "http://host:8080");
+        assert_eq!(
+            location.nextcloud_uploads_path().unwrap(),
+            "/remote.php/dav/uploads/bob"
+        );
+
+        assert!(WebDavLocation::parse("webdav:///backup").is_none());
+    }
+
+    #[test]
+    fn versions_round_trip() {
+        let dav = Arc::new(Mutex::new(FakeDav::default()));
+        let server = fake_webdav(dav.clone());
+        let store = store(&server);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source").to_string_lossy().to_string();
+        let dest = dir.path().join("dest").to_string_lossy().to_string();
+        fs::write(&source, "hello").unwrap();
+
+        assert!(store.is_available());
+        store.write_version(&source, "a b.txt").unwrap();
+        store.write_version(&source, "c.txt").unwrap();
+        store.write_index(".index.yaml", "entries: []").unwrap();
+
+        let mut versions = store.list_versions();
+        versions.sort();
+        assert_eq!(versions, ["a b.txt", "c.txt"]);
+
+        assert!(store.has_version("a b.txt"));
+        store.read_version("a b.txt", &dest).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "hello");
+        assert_eq!(store.read_index(".index.yaml").unwrap(), "entries: []");
+        // 無い管理ファイルはエラーではなく None になる
+        assert!(store.load_index(".missing.yaml").unwrap().is_none());
+
+        store.remove_version("a b.txt").unwrap();
+        assert!(!store.has_version("a b.txt"));
+        // 無いものを消しても失敗にしない
+        store.remove_version("a b.txt").unwrap();
+    }
+
+    #[test]
+    fn checksum_mismatch_fails_upload() {
+        let dav = Arc::new(Mutex::new(FakeDav::default()));
+        let server = fake_webdav(dav.clone());
+        let store = store(&server);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source").to_string_lossy().to_string();
+        fs::write(&source, "hello").unwrap();
+
+        dav.lock().unwrap().corrupt_checksums = true;
+        let error = store.write_version(&source, "a.txt").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn large_file_is_uploaded_in_chunks() {
+        let dav = Arc::new(Mutex::new(FakeDav::default()));
+        let server = fake_webdav(dav.clone());
+        let store = store(&server);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source").to_string_lossy().to_string();
+        let content: Vec<u8> = (0..CHUNKED_UPLOAD_THRESHOLD + 1).map(|i| i as u8).collect();
+        fs::write(&source, &content).unwrap();
+
+        store.write_version(&source, "large.bin").unwrap();
+
+        let dav = dav.lock().unwrap();
+        assert_eq!(dav.files[&format!("{}/large.bin", ROOT)], content);
+        let chunk_uploads = dav
+            .requests
+            .iter()
+            .filter(|r| r.starts_with("PUT /remote.php/dav/uploads/alice/"))
+            .count();
+        assert_eq!(chunk_uploads, 2);
+        // 一時的なコレクションは残らない
+        assert_eq!(dav.collections.len(), 1);
+    }
+}